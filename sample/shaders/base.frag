@@ -1,6 +1,6 @@
 #version 450
 
-layout(location = 0) in vec3 inColor;
+layout(location = 0) in vec2 inUV;
 
 layout(location = 0) out vec4 outFragColor;
 
@@ -10,8 +10,20 @@ layout(set = 0, binding = 1) uniform SceneData {
     vec4 ambientColor;
     vec4 sunlightDirection;
     vec4 sunlighColor;
+    vec4 pointLightPosition;
+    vec4 pointLightColor;
 } sceneData;
 
+layout(set = 1, binding = 0) uniform MaterialData {
+    // KHR_texture_transform matrix in the upper-left 3x3
+    mat4 baseColorUvTransform;
+} materialData;
+
+layout(set = 1, binding = 1) uniform texture2D baseColorTexture;
+layout(set = 1, binding = 2) uniform sampler baseColorSampler;
+
 void main() {
-    outFragColor = vec4(inColor + sceneData.ambientColor.xyz, 1.0);
-}
\ No newline at end of file
+    vec2 uv = (materialData.baseColorUvTransform * vec4(inUV, 1.0, 0.0)).xy;
+    vec3 baseColor = texture(sampler2D(baseColorTexture, baseColorSampler), uv).rgb;
+    outFragColor = vec4(baseColor + sceneData.ambientColor.xyz, 1.0);
+}
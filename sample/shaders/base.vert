@@ -3,12 +3,13 @@
 layout(location = 0) in vec3 position;
 layout(location = 1) in vec3 normal;
 layout(location = 2) in vec2 uv;
+// Per-instance model matrix, one column per location
+layout(location = 3) in vec4 model0;
+layout(location = 4) in vec4 model1;
+layout(location = 5) in vec4 model2;
+layout(location = 6) in vec4 model3;
 
-layout(location = 0) out vec3 outColor;
-
-layout (push_constant) uniform PushConstants {
-    mat4 model;
-} PC;
+layout(location = 0) out vec2 outUV;
 
 layout(set = 0, binding = 0) uniform CameraBuffer {
     mat4 view;
@@ -16,7 +17,8 @@ layout(set = 0, binding = 0) uniform CameraBuffer {
 } CB;
 
 void main() {
-    outColor = normal;
-    mat4 mvp = CB.proj * CB.view * PC.model;
+    outUV = uv;
+    mat4 model = mat4(model0, model1, model2, model3);
+    mat4 mvp = CB.proj * CB.view * model;
     gl_Position = mvp * vec4(position, 1.0);
-}
\ No newline at end of file
+}
@@ -0,0 +1,42 @@
+#version 450
+
+layout(location = 0) in vec3 nearPoint;
+layout(location = 1) in vec3 farPoint;
+
+layout(location = 0) out vec4 outFragColor;
+
+layout(set = 0, binding = 0) uniform CameraBuffer {
+    mat4 view;
+    mat4 proj;
+} CB;
+
+layout(push_constant) uniform GridParams {
+    vec4 color;
+    float cellSize;
+    float fadeDistance;
+} GP;
+
+void main() {
+    // Intersect the view ray with the y = 0 plane
+    float t = -nearPoint.y / (farPoint.y - nearPoint.y);
+    if (t <= 0.0) {
+        discard;
+    }
+    vec3 worldPosition = nearPoint + t * (farPoint - nearPoint);
+
+    vec2 coord = worldPosition.xz / GP.cellSize;
+    vec2 grid = abs(fract(coord - 0.5) - 0.5) / fwidth(coord);
+    float line = 1.0 - min(min(grid.x, grid.y), 1.0);
+
+    vec3 cameraPosition = inverse(CB.view)[3].xyz;
+    float fade = max(1.0 - length(worldPosition - cameraPosition) / GP.fadeDistance, 0.0);
+
+    float alpha = GP.color.a * line * fade;
+    if (alpha <= 0.0) {
+        discard;
+    }
+
+    vec4 clip = CB.proj * CB.view * vec4(worldPosition, 1.0);
+    gl_FragDepth = clip.z / clip.w;
+    outFragColor = vec4(GP.color.rgb, alpha);
+}
@@ -0,0 +1,23 @@
+#version 450
+
+layout(location = 0) out vec3 nearPoint;
+layout(location = 1) out vec3 farPoint;
+
+layout(set = 0, binding = 0) uniform CameraBuffer {
+    mat4 view;
+    mat4 proj;
+} CB;
+
+vec3 unproject(vec2 position, float depth, mat4 inverseViewProj) {
+    vec4 world = inverseViewProj * vec4(position, depth, 1.0);
+    return world.xyz / world.w;
+}
+
+void main() {
+    // Fullscreen triangle, no vertex buffer needed
+    vec2 position = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2) * 2.0 - 1.0;
+    mat4 inverseViewProj = inverse(CB.proj * CB.view);
+    nearPoint = unproject(position, 0.0, inverseViewProj);
+    farPoint = unproject(position, 1.0, inverseViewProj);
+    gl_Position = vec4(position, 0.0, 1.0);
+}
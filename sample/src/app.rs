@@ -1,52 +1,32 @@
-use ash::vk::{CommandBuffer, CommandPoolCreateFlags, Extent2D, Format};
 use vulkan_renderer::{
-    command_pool::VCommandPool, device::VDevice, enums::EOperationType, instance::VInstance,
-    pipeline::VGraphicsPipeline, swapchain::VSwapchain,
+    device::VDevice, pipeline::VGraphicsPipeline, renderer::Renderer, swapchain::VSwapchain,
 };
 
+/// The sample's view of the [`Renderer`], which owns everything and handles teardown
 pub struct App {
-    pub instance: VInstance,
-    pub device: VDevice,
-    pub swapchain: VSwapchain,
-    pub command_pool: VCommandPool,
-    pub pipeline: VGraphicsPipeline,
-    pub commandbuffers: Vec<CommandBuffer>,
-
-    pub extent: Extent2D,
-    pub color_format: Format,
+    pub renderer: Renderer,
+    pipeline_index: usize,
 }
 
 impl App {
-    pub fn init(
-        instance: VInstance,
-        device: VDevice,
-        swapchain: VSwapchain,
-        extent: Extent2D,
-    ) -> Self {
+    pub fn init(mut renderer: Renderer, pipeline: VGraphicsPipeline) -> Self {
+        let pipeline_index = renderer.add_graphics_pipeline(pipeline);
         Self {
-            instance,
-            device,
-            swapchain,
-            pipeline: VGraphicsPipeline::default(),
-            command_pool: VCommandPool::default(),
-            commandbuffers: Vec::default(),
-
-            extent,
-            color_format: Format::B8G8R8A8_SRGB,
+            renderer,
+            pipeline_index,
         }
     }
 
-    pub fn create_command_pool(&mut self, flags: CommandPoolCreateFlags) {
-        self.command_pool = VCommandPool::new(
-            &self.device,
-            self.device.get_queue_family_index(EOperationType::Graphics),
-            flags,
-        )
-        .expect("Failed to create command pool.");
+    pub fn device(&self) -> &VDevice {
+        self.renderer.device()
+    }
+
+    pub fn swapchain(&self) -> &VSwapchain {
+        self.renderer.swapchain()
     }
 
-    pub fn create_graphics_pipeline(&mut self, pipeline: VGraphicsPipeline) {
-        self.pipeline = pipeline;
+    pub fn pipeline(&self) -> &VGraphicsPipeline {
+        self.renderer.graphics_pipeline(self.pipeline_index)
     }
 
     #[allow(dead_code)]
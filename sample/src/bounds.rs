@@ -0,0 +1,119 @@
+use vulkan_renderer::glam::{Mat4, Vec3};
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct BoundingSphere {
+    pub center: Vec3,
+    pub radius: f32,
+}
+
+impl BoundingSphere {
+    /// Approximate bounding sphere using Ritter's algorithm, at most about 5% larger than the optimal one
+    pub fn from_points(points: &[Vec3]) -> Self {
+        let first = match points.first() {
+            Some(&first) => first,
+            None => return Self::default(),
+        };
+        let farthest_from = |from: Vec3| {
+            points
+                .iter()
+                .copied()
+                .max_by(|a, b| {
+                    a.distance_squared(from)
+                        .total_cmp(&b.distance_squared(from))
+                })
+                .unwrap_or(from)
+        };
+        let a = farthest_from(first);
+        let b = farthest_from(a);
+
+        let mut sphere = Self {
+            center: (a + b) * 0.5,
+            radius: a.distance(b) * 0.5,
+        };
+        for &point in points {
+            sphere = sphere.expanded_to(point);
+        }
+        sphere
+    }
+
+    /// Smallest sphere containing both spheres
+    pub fn union(&self, other: &Self) -> Self {
+        let offset = other.center - self.center;
+        let distance = offset.length();
+        if distance + other.radius <= self.radius {
+            return *self;
+        }
+        if distance + self.radius <= other.radius {
+            return *other;
+        }
+        let radius = (distance + self.radius + other.radius) * 0.5;
+        Self {
+            center: self.center + offset * ((radius - self.radius) / distance),
+            radius,
+        }
+    }
+
+    /// Sphere containing this one after `matrix` is applied, non-uniform scales use the largest axis
+    pub fn transformed(&self, matrix: Mat4) -> Self {
+        let scale = matrix
+            .x_axis
+            .truncate()
+            .length()
+            .max(matrix.y_axis.truncate().length())
+            .max(matrix.z_axis.truncate().length());
+        Self {
+            center: matrix.transform_point3(self.center),
+            radius: self.radius * scale,
+        }
+    }
+
+    fn expanded_to(&self, point: Vec3) -> Self {
+        let distance = point.distance(self.center);
+        if distance <= self.radius {
+            return *self;
+        }
+        let radius = (self.radius + distance) * 0.5;
+        Self {
+            center: self.center + (point - self.center) * ((radius - self.radius) / distance),
+            radius,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(sphere: &BoundingSphere, point: Vec3) -> bool {
+        point.distance(sphere.center) <= sphere.radius + 1e-4
+    }
+
+    #[test]
+    fn sphere_from_points_contains_every_point() {
+        let points = [
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(-1.0, 0.0, 0.0),
+            Vec3::new(0.0, 2.0, 0.0),
+            Vec3::new(0.0, 0.0, -3.0),
+            Vec3::new(0.5, 0.5, 0.5),
+        ];
+        let sphere = BoundingSphere::from_points(&points);
+        assert!(points.iter().all(|&point| contains(&sphere, point)));
+    }
+
+    #[test]
+    fn union_contains_both_spheres() {
+        let a = BoundingSphere {
+            center: Vec3::new(-2.0, 0.0, 0.0),
+            radius: 1.0,
+        };
+        let b = BoundingSphere {
+            center: Vec3::new(3.0, 0.0, 0.0),
+            radius: 2.0,
+        };
+        let union = a.union(&b);
+        assert!(contains(&union, Vec3::new(-3.0, 0.0, 0.0)));
+        assert!(contains(&union, Vec3::new(5.0, 0.0, 0.0)));
+        assert!((union.radius - 4.0).abs() < 1e-5);
+    }
+}
@@ -1,13 +1,320 @@
-use glam::{Mat4, Vec3};
+use crate::bounds::BoundingSphere;
+use vulkan_renderer::{
+    glam::{Mat4, Vec3},
+    utils::AsBytes,
+};
 
+/// Vertical field of view of cameras that don't come with their own
+pub const FOV_Y_DEGREES: f32 = 70.0;
+/// How far the pitch stays from straight up or down, where the view would flip over
+pub const MAX_PITCH_DEGREES: f32 = 89.0;
+
+/// Directions [`Camera::process_keyboard`] moves in, relative to where the camera looks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CameraMovement {
+    Forward,
+    Backward,
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    /// Uses the viewport's aspect ratio unless the camera was authored with a fixed one
+    Perspective {
+        fov_y_degrees: f32,
+        aspect_ratio: Option<f32>,
+    },
+    /// Half the width and height of the view volume
+    Orthographic { x_mag: f32, y_mag: f32 },
+}
+
+impl Default for Projection {
+    fn default() -> Self {
+        Self::Perspective {
+            fov_y_degrees: FOV_Y_DEGREES,
+            aspect_ratio: None,
+        }
+    }
+}
+
+impl Projection {
+    /// Right-handed projection into Vulkan's [0, 1] depth range, without flipping Y
+    pub fn matrix(&self, viewport_aspect_ratio: f32, near: f32, far: f32) -> Mat4 {
+        match *self {
+            Self::Perspective {
+                fov_y_degrees,
+                aspect_ratio,
+            } => Mat4::perspective_rh(
+                fov_y_degrees.to_radians(),
+                aspect_ratio.unwrap_or(viewport_aspect_ratio),
+                near,
+                far,
+            ),
+            Self::Orthographic { x_mag, y_mag } => {
+                Mat4::orthographic_rh(-x_mag, x_mag, -y_mag, y_mag, near, far)
+            }
+        }
+    }
+}
+
+#[repr(C)]
 #[derive(Default, Debug, Clone, Copy)]
 pub struct CameraData {
     pub view: Mat4,
     pub projection: Mat4,
 }
 
-#[derive(Default, Debug, Clone, Copy)]
+// SAFETY: `repr(C)` and made of two matrices, so there are no padding bytes
+unsafe impl AsBytes for CameraData {}
+
+#[derive(Debug, Clone, Copy)]
 pub struct Camera {
     pub position: Vec3,
+    pub target: Vec3,
+    pub up: Vec3,
+    pub near: f32,
+    pub far: f32,
+    pub projection: Projection,
     pub camera_data: CameraData,
+    /// Units per second moved by [`process_keyboard`](Self::process_keyboard)
+    pub movement_speed: f32,
+    /// Radians turned per pixel of mouse motion by [`process_mouse`](Self::process_mouse)
+    pub mouse_sensitivity: f32,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            position: Vec3::ZERO,
+            target: Vec3::ZERO,
+            up: Vec3::Y,
+            near: 0.1,
+            far: 100.0,
+            projection: Projection::default(),
+            camera_data: CameraData::default(),
+            movement_speed: 5.0,
+            mouse_sensitivity: 0.003,
+        }
+    }
+}
+
+impl Camera {
+    /// A glTF camera placed by its node's `world` transform, looking down the node's -Z axis
+    ///
+    /// Infinite perspective cameras keep the default far plane
+    pub fn from_gltf(camera: &gltf::Camera, world: Mat4) -> Self {
+        let default = Self::default();
+        let (projection, near, far) = match camera.projection() {
+            gltf::camera::Projection::Perspective(perspective) => (
+                Projection::Perspective {
+                    fov_y_degrees: perspective.yfov().to_degrees(),
+                    aspect_ratio: perspective.aspect_ratio(),
+                },
+                perspective.znear(),
+                perspective.zfar().unwrap_or(default.far),
+            ),
+            gltf::camera::Projection::Orthographic(orthographic) => (
+                Projection::Orthographic {
+                    x_mag: orthographic.xmag(),
+                    y_mag: orthographic.ymag(),
+                },
+                orthographic.znear(),
+                orthographic.zfar(),
+            ),
+        };
+        let position = world.transform_point3(Vec3::ZERO);
+        Self {
+            position,
+            target: position + world.transform_vector3(-Vec3::Z),
+            up: world.transform_vector3(Vec3::Y).normalize(),
+            near,
+            far,
+            projection,
+            ..default
+        }
+    }
+
+    pub fn view_matrix(&self) -> Mat4 {
+        Mat4::look_at_rh(self.position, self.target, self.up)
+    }
+
+    pub fn forward(&self) -> Vec3 {
+        (self.target - self.position)
+            .try_normalize()
+            .unwrap_or(-Vec3::Z)
+    }
+
+    /// Yaw around +Y from -Z towards +X and pitch above the horizon of the look direction, in radians
+    pub fn yaw_pitch(&self) -> (f32, f32) {
+        let forward = self.forward();
+        (
+            forward.x.atan2(-forward.z),
+            forward.y.clamp(-1.0, 1.0).asin(),
+        )
+    }
+
+    /// Moves the camera and its target by `movement_speed * dt`, up and down follow the world Y axis
+    pub fn process_keyboard(&mut self, movement: CameraMovement, dt: f32) {
+        let forward = self.forward();
+        let right = forward.cross(Vec3::Y).try_normalize().unwrap_or(Vec3::X);
+        let direction = match movement {
+            CameraMovement::Forward => forward,
+            CameraMovement::Backward => -forward,
+            CameraMovement::Right => right,
+            CameraMovement::Left => -right,
+            CameraMovement::Up => Vec3::Y,
+            CameraMovement::Down => -Vec3::Y,
+        };
+        let offset = direction * self.movement_speed * dt;
+        self.position += offset;
+        self.target += offset;
+    }
+
+    /// Turns the camera by a mouse motion of `dx`, `dy` pixels, moving the mouse down looks down
+    ///
+    /// The target keeps its distance so [`frame`](Self::frame) still orbits around it afterwards
+    pub fn process_mouse(&mut self, dx: f32, dy: f32) {
+        let (yaw, pitch) = self.yaw_pitch();
+        let yaw = yaw + dx * self.mouse_sensitivity;
+        let max_pitch = MAX_PITCH_DEGREES.to_radians();
+        let pitch = (pitch - dy * self.mouse_sensitivity).clamp(-max_pitch, max_pitch);
+        let forward = Vec3::new(
+            pitch.cos() * yaw.sin(),
+            pitch.sin(),
+            -pitch.cos() * yaw.cos(),
+        );
+        let distance = (self.target - self.position).length().max(1.0);
+        self.target = self.position + forward * distance;
+    }
+
+    /// The projection matrix for a viewport of `aspect_ratio`, with Y flipped for Vulkan
+    pub fn projection_matrix(&self, aspect_ratio: f32) -> Mat4 {
+        let mut projection = self.projection.matrix(aspect_ratio, self.near, self.far);
+        projection.col_mut(1)[1] *= -1.0;
+        projection
+    }
+
+    /// Moves the camera back along its view direction until `bounds` fits the vertical field of view
+    ///
+    /// The far plane is pushed out if the far side of the bounds would be clipped
+    pub fn frame(&mut self, bounds: BoundingSphere) {
+        let direction = (self.position - self.target)
+            .try_normalize()
+            .unwrap_or(Vec3::Z);
+        let half_fov = (FOV_Y_DEGREES * 0.5).to_radians();
+        let distance = bounds.radius.max(f32::EPSILON) / half_fov.sin();
+
+        self.target = bounds.center;
+        self.position = bounds.center + direction * distance;
+        self.far = self.far.max(distance + bounds.radius);
+    }
+
+    /// Converts a [0, 1] depth buffer value back to view-space distance
+    #[allow(dead_code)]
+    pub fn linearize_depth(&self, depth: f32) -> f32 {
+        self.near * self.far / (self.far - depth * (self.far - self.near))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_PI_2;
+
+    #[test]
+    fn frames_bounds_along_view_direction() {
+        let mut camera = Camera {
+            position: Vec3::new(0.0, 0.0, -5.0),
+            ..Default::default()
+        };
+        let bounds = BoundingSphere {
+            center: Vec3::new(1.0, 0.0, 0.0),
+            radius: 10.0,
+        };
+        camera.frame(bounds);
+
+        assert_eq!(camera.target, bounds.center);
+        let offset = camera.position - bounds.center;
+        assert!(offset.normalize().abs_diff_eq(-Vec3::Z, 1e-5));
+        let half_fov = (FOV_Y_DEGREES * 0.5).to_radians();
+        assert!((bounds.radius / offset.length() - half_fov.sin()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn looks_down_negative_z_of_gltf_camera_node() {
+        let json = r#"{
+            "asset": {"version": "2.0"},
+            "cameras": [{"type": "orthographic", "orthographic": {"xmag": 2.0, "ymag": 1.0, "znear": 0.5, "zfar": 50.0}}]
+        }"#;
+        let document = gltf::Gltf::from_slice(json.as_bytes()).unwrap().document;
+        let world =
+            Mat4::from_translation(Vec3::new(0.0, 3.0, 0.0)) * Mat4::from_rotation_x(-FRAC_PI_2);
+        let camera = Camera::from_gltf(&document.cameras().next().unwrap(), world);
+
+        assert!(camera.position.abs_diff_eq(Vec3::new(0.0, 3.0, 0.0), 1e-5));
+        assert!((camera.target - camera.position).abs_diff_eq(-Vec3::Y, 1e-5));
+        assert!(camera.up.abs_diff_eq(-Vec3::Z, 1e-5));
+        assert_eq!((camera.near, camera.far), (0.5, 50.0));
+        assert_eq!(
+            camera.projection,
+            Projection::Orthographic {
+                x_mag: 2.0,
+                y_mag: 1.0
+            }
+        );
+    }
+
+    #[test]
+    fn moves_along_look_direction_and_clamps_pitch() {
+        let mut camera = Camera {
+            position: Vec3::new(0.0, 0.0, 5.0),
+            ..Default::default()
+        };
+        camera.process_mouse(FRAC_PI_2 / camera.mouse_sensitivity, 0.0);
+        assert!(camera.forward().abs_diff_eq(Vec3::X, 1e-5));
+
+        camera.process_keyboard(CameraMovement::Forward, 0.5);
+        let expected = Vec3::new(camera.movement_speed * 0.5, 0.0, 5.0);
+        assert!(camera.position.abs_diff_eq(expected, 1e-5));
+
+        camera.process_mouse(0.0, -1.0e6);
+        let (_, pitch) = camera.yaw_pitch();
+        assert!((pitch - MAX_PITCH_DEGREES.to_radians()).abs() < 1e-4);
+    }
+
+    #[test]
+    fn flips_projected_y_once() {
+        let camera = Camera {
+            position: Vec3::new(0.0, 0.0, 5.0),
+            ..Default::default()
+        };
+        let clip = camera.projection_matrix(16.0 / 9.0)
+            * camera.view_matrix()
+            * Vec3::new(0.0, 1.0, 0.0).extend(1.0);
+        let unflipped = camera
+            .projection
+            .matrix(16.0 / 9.0, camera.near, camera.far)
+            * camera.view_matrix()
+            * Vec3::new(0.0, 1.0, 0.0).extend(1.0);
+        assert!(clip.y < 0.0);
+        assert_eq!(clip.y, -unflipped.y);
+        assert_eq!(
+            (clip.x, clip.z, clip.w),
+            (unflipped.x, unflipped.z, unflipped.w)
+        );
+    }
+
+    #[test]
+    fn linearizes_depth_to_near_and_far() {
+        let camera = Camera {
+            near: 0.5,
+            far: 200.0,
+            ..Default::default()
+        };
+        assert!((camera.linearize_depth(0.0) - 0.5).abs() < 1e-5);
+        assert!((camera.linearize_depth(1.0) - 200.0).abs() < 1e-3);
+    }
 }
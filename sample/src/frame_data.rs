@@ -1,97 +1,163 @@
 use crate::{camera::CameraData, scene::SceneData};
 use ash::vk::{
-    CommandBuffer, CommandPool, CommandPoolCreateFlags, DescriptorBufferInfo, DescriptorPool,
-    DescriptorSet, DescriptorSetLayout, DescriptorType, MemoryPropertyFlags,
+    CommandBuffer, CommandBufferLevel, CommandPoolCreateFlags, DescriptorBufferInfo,
+    DescriptorPool, DescriptorSet, DescriptorSetLayout, DescriptorType, PipelineStageFlags,
 };
-use std::mem::size_of;
+use std::{cell::Cell, mem::size_of};
 use vulkan_renderer::{
-    buffer::VBuffer,
-    cmd::*,
-    command_pool::VCommandPool,
-    descriptorset::VDescriptorSet,
-    device::VDevice,
-    sync::{VFence, VSemaphore},
-    RendererResult,
+    cmd::cmd_write_timestamp, command_pool::VCommandPool, descriptorset::VDescriptorSet,
+    device::VDevice, enums::EOperationType, object_buffer::VObjectBuffer, query::VQueryPool,
+    utils::AsBytes, RendererError, RendererResult,
 };
 
+/// Per-frame resources, the frame's slots of the shared buffers can only be written while it isn't in flight
 pub struct FrameData {
-    pub fence: VFence,
-    pub present_semaphore: VSemaphore,
-    pub render_semaphore: VSemaphore,
-    pub command_pool: CommandPool,
+    pub command_pool: VCommandPool,
     pub command_buffer: CommandBuffer,
-    pub camera_buffer: VBuffer,
     pub desc_set: DescriptorSet,
     pub frame_index: usize,
+    in_flight: Cell<bool>,
+    /// Brackets the frame's commands to measure its GPU time, `None` if the queue can't time them
+    timestamps: Option<VQueryPool>,
+    /// Whether the last submission wrote the timestamps, so there's something to read back
+    timed: Cell<bool>,
 }
 
 impl FrameData {
     pub fn new(
         device: &VDevice,
-        queue_family_index: u32,
         descriptor_pool: DescriptorPool,
         descriptor_set_layouts: &[DescriptorSetLayout],
-        scene_buffer: VBuffer,
+        camera_buffer: &VObjectBuffer<CameraData>,
+        scene_buffer: &VObjectBuffer<SceneData>,
         frame_index: usize,
     ) -> RendererResult<Self> {
-        let fence = VFence::new(device, true)?;
-        let present_semaphore = VSemaphore::new(device)?;
-        let render_semaphore = VSemaphore::new(device)?;
         let command_pool = VCommandPool::new(
             device,
-            queue_family_index,
+            device.get_queue_family_index(EOperationType::Graphics),
             CommandPoolCreateFlags::RESET_COMMAND_BUFFER,
-        )?
-        .get();
-        let command_buffer = allocate_command_buffers(device, command_pool, 1)?[0];
-
-        let camera_buffer = VBuffer::new_uniform_buffer(
-            device,
-            size_of::<CameraData>() as u64,
-            MemoryPropertyFlags::HOST_COHERENT | MemoryPropertyFlags::HOST_VISIBLE,
         )?;
+        let command_buffer = command_pool.allocate(device, CommandBufferLevel::PRIMARY, 1)?[0];
 
         let desc_set = VDescriptorSet::new(device, descriptor_pool, descriptor_set_layouts)?.get();
-
-        let camera_buffer_info = DescriptorBufferInfo {
-            buffer: camera_buffer.buffer(),
-            range: size_of::<CameraData>() as u64,
-            offset: 0,
-        };
-        let scene_buffer_info = DescriptorBufferInfo {
-            buffer: scene_buffer.buffer(),
-            range: size_of::<SceneData>() as u64,
-            offset: 0,
+        let timestamps = match VQueryPool::new_timestamp(device, 2) {
+            Ok(timestamps) => Some(timestamps),
+            Err(RendererError::InvalidArgument(_)) => None,
+            Err(err) => return Err(err),
         };
 
-        let camera_write_set = VDescriptorSet::write_descriptor_set(
-            desc_set,
-            0,
-            DescriptorType::UNIFORM_BUFFER,
-            &camera_buffer_info,
-        );
-        let scene_write_set = VDescriptorSet::write_descriptor_set(
+        let frame_data = Self {
+            command_buffer,
+            command_pool,
             desc_set,
-            1,
-            DescriptorType::UNIFORM_BUFFER_DYNAMIC,
-            &scene_buffer_info,
-        );
+            frame_index,
+            in_flight: Cell::new(false),
+            timestamps,
+            timed: Cell::new(false),
+        };
+        frame_data.update_descriptors(device, camera_buffer, scene_buffer);
 
+        Ok(frame_data)
+    }
+
+    /// Points the camera and scene bindings at new buffers, e.g. after they were recreated
+    ///
+    /// The descriptor set must not be in use, so the frame has to be waited on first
+    pub fn update_descriptors(
+        &self,
+        device: &VDevice,
+        camera_buffer: &VObjectBuffer<CameraData>,
+        scene_buffer: &VObjectBuffer<SceneData>,
+    ) {
+        self.debug_assert_writable();
+        self.write_buffer_descriptor(device, 0, camera_buffer);
+        self.write_buffer_descriptor(device, 1, scene_buffer);
+    }
+
+    /// Marks the frame's buffer slots as reusable, once its fence was waited on
+    pub fn mark_completed(&self) {
+        self.in_flight.set(false);
+    }
+
+    /// Resets the timestamps and writes the first, must be recorded outside a render pass
+    pub fn cmd_begin_timing(&self, device: &VDevice) {
+        if let Some(timestamps) = &self.timestamps {
+            timestamps.cmd_reset(device, self.command_buffer);
+            cmd_write_timestamp(
+                device,
+                self.command_buffer,
+                timestamps,
+                PipelineStageFlags::TOP_OF_PIPE,
+                0,
+            );
+        }
+    }
+
+    /// Writes the second timestamp once every earlier command finished
+    pub fn cmd_end_timing(&self, device: &VDevice) {
+        if let Some(timestamps) = &self.timestamps {
+            cmd_write_timestamp(
+                device,
+                self.command_buffer,
+                timestamps,
+                PipelineStageFlags::BOTTOM_OF_PIPE,
+                1,
+            );
+            self.timed.set(true);
+        }
+    }
+
+    /// Nanoseconds the last submission took on the GPU, its fence must have been waited on
+    pub fn gpu_time(&self, device: &VDevice) -> RendererResult<Option<u64>> {
+        match &self.timestamps {
+            Some(timestamps) if self.timed.get() => timestamps.elapsed(device, 0, 1).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// The descriptor set is freed with its pool, the frame must not be in flight
+    pub fn destroy(&self, device: &VDevice) {
+        if let Some(timestamps) = &self.timestamps {
+            timestamps.destroy(device);
+        }
         unsafe {
             device
                 .get()
-                .update_descriptor_sets(&[camera_write_set, scene_write_set], &[]);
-        };
+                .destroy_command_pool(self.command_pool.get(), None);
+        }
+    }
 
-        Ok(Self {
-            fence,
-            present_semaphore,
-            render_semaphore,
-            command_buffer,
-            command_pool,
-            camera_buffer,
-            desc_set,
-            frame_index,
-        })
+    /// Marks the frame as in flight until the next [`mark_completed`](Self::mark_completed)
+    pub fn mark_submitted(&self) {
+        self.in_flight.set(true);
+    }
+
+    /// In debug builds, asserts that no submitted work still reads this frame's buffer slots
+    pub fn debug_assert_writable(&self) {
+        debug_assert!(
+            !self.in_flight.get(),
+            "Frame {} is still in flight, wait for it before writing its buffers.",
+            self.frame_index
+        );
+    }
+
+    fn write_buffer_descriptor<T: AsBytes>(
+        &self,
+        device: &VDevice,
+        binding: u32,
+        buffer: &VObjectBuffer<T>,
+    ) {
+        let buffer_info = DescriptorBufferInfo {
+            buffer: buffer.buffer().buffer(),
+            range: size_of::<T>() as u64,
+            offset: 0,
+        };
+        let write_set = VDescriptorSet::write_descriptor_set(
+            self.desc_set,
+            binding,
+            DescriptorType::UNIFORM_BUFFER_DYNAMIC,
+            &buffer_info,
+        );
+        unsafe { device.get().update_descriptor_sets(&[write_set], &[]) };
     }
 }
@@ -0,0 +1,117 @@
+use crate::frame_data::FrameData;
+use ash::vk::{
+    BlendFactor, CullModeFlags, DescriptorSetLayout, DynamicState, FrontFace, PipelineBindPoint,
+    PipelineColorBlendAttachmentState, PolygonMode, PushConstantRange, RenderPass,
+    ShaderStageFlags,
+};
+use std::mem::size_of;
+use vulkan_renderer::{
+    cmd::*,
+    device::VDevice,
+    glam::Vec4,
+    pipeline::{VGraphicsPipeline, VGraphicsPipelineBuilder},
+    shader_utils::VShaderUtils,
+    utils::AsBytes,
+    RendererResult,
+};
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct GridParams {
+    pub color: Vec4,
+    pub cell_size: f32,
+    pub fade_distance: f32,
+    _padding: [f32; 2],
+}
+
+// SAFETY: `repr(C)` with the tail padding spelled out as a field
+unsafe impl AsBytes for GridParams {}
+
+impl GridParams {
+    pub fn new(color: Vec4, cell_size: f32, fade_distance: f32) -> Self {
+        Self {
+            color,
+            cell_size,
+            fade_distance,
+            _padding: [0.0; 2],
+        }
+    }
+}
+
+impl Default for GridParams {
+    fn default() -> Self {
+        Self::new(Vec4::new(0.5, 0.5, 0.5, 1.0), 1.0, 50.0)
+    }
+}
+
+/// Infinite ground grid on the y = 0 plane, drawn as a fullscreen triangle
+pub struct Grid {
+    pub params: GridParams,
+    pipeline: VGraphicsPipeline,
+}
+
+impl Grid {
+    pub fn new(
+        device: &VDevice,
+        render_pass: RenderPass,
+        descriptor_set_layout: DescriptorSetLayout,
+        params: GridParams,
+    ) -> RendererResult<Self> {
+        let vertex_code = VShaderUtils::load_shader("sample/shaders/grid.vert.spv")?;
+        let vertex_shader_module = VShaderUtils::create_shader_module(device, &vertex_code)?;
+        let fragment_code = VShaderUtils::load_shader("sample/shaders/grid.frag.spv")?;
+        let fragment_shader_module = VShaderUtils::create_shader_module(device, &fragment_code)?;
+
+        let shader_infos = &[
+            (ShaderStageFlags::VERTEX, vertex_shader_module),
+            (ShaderStageFlags::FRAGMENT, fragment_shader_module),
+        ];
+        // Writes the grid's alpha as is instead of accumulating it into the attachment's
+        let color_blend_attachments = &[PipelineColorBlendAttachmentState {
+            dst_alpha_blend_factor: BlendFactor::ZERO,
+            ..VGraphicsPipelineBuilder::blend_alpha()
+        }];
+        let push_constants = &[PushConstantRange {
+            stage_flags: ShaderStageFlags::FRAGMENT,
+            size: size_of::<GridParams>() as u32,
+            offset: 0,
+        }];
+        let descriptor_set_layouts = &[descriptor_set_layout];
+        let pipeline = VGraphicsPipelineBuilder::start()
+            .shader_stages(shader_infos)
+            .rasterization(
+                CullModeFlags::NONE,
+                PolygonMode::FILL,
+                FrontFace::COUNTER_CLOCKWISE,
+            )
+            .dynamic_state(&[DynamicState::VIEWPORT, DynamicState::SCISSOR])
+            .color_blend_state(color_blend_attachments)
+            .pipeline_layout(descriptor_set_layouts, push_constants)
+            .build(device, render_pass)?;
+
+        Ok(Self { params, pipeline })
+    }
+
+    /// Must be recorded after the scene so the grid blends over it, uses the viewport the view last set
+    pub fn draw(&self, device: &VDevice, frame_data: &FrameData, camera_offset: u32) {
+        cmd_bind_graphics_pipeline(device, frame_data.command_buffer, &self.pipeline);
+        // Only the camera is read, the dynamic bindings just need valid offsets
+        cmd_bind_descriptor_sets(
+            device,
+            frame_data.command_buffer,
+            PipelineBindPoint::GRAPHICS,
+            self.pipeline.pipeline_layout(),
+            0,
+            &[frame_data.desc_set],
+            &[camera_offset, 0],
+        );
+        cmd_push_constants(
+            device,
+            frame_data.command_buffer,
+            &self.pipeline,
+            ShaderStageFlags::FRAGMENT,
+            &self.params,
+        );
+        cmd_draw(device, frame_data.command_buffer, 3, 1);
+    }
+}
@@ -0,0 +1,43 @@
+use vulkan_renderer::glam::{Mat4, Vec3};
+
+/// A `KHR_lights_punctual` light in world space
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Light {
+    /// Shines along `direction` from infinitely far away, intensity in lux
+    Directional {
+        direction: Vec3,
+        color: Vec3,
+        intensity: f32,
+    },
+    /// Intensity in candela, `range` is where the light fades out, `None` meaning unlimited
+    Point {
+        position: Vec3,
+        color: Vec3,
+        intensity: f32,
+        range: Option<f32>,
+    },
+}
+
+impl Light {
+    /// A glTF light placed by its node's `world` transform, shining down the node's -Z axis
+    ///
+    /// Spot lights become point lights, their cones aren't supported
+    pub fn from_gltf(light: &gltf::khr_lights_punctual::Light, world: Mat4) -> Self {
+        let color = Vec3::from(light.color());
+        let intensity = light.intensity();
+        match light.kind() {
+            gltf::khr_lights_punctual::Kind::Directional => Self::Directional {
+                direction: world.transform_vector3(-Vec3::Z).normalize(),
+                color,
+                intensity,
+            },
+            gltf::khr_lights_punctual::Kind::Point
+            | gltf::khr_lights_punctual::Kind::Spot { .. } => Self::Point {
+                position: world.transform_point3(Vec3::ZERO),
+                color,
+                intensity,
+                range: light.range(),
+            },
+        }
+    }
+}
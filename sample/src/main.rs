@@ -1,42 +1,49 @@
 use app::App;
 use ash::vk::{
-    ClearColorValue, ClearDepthStencilValue, ClearValue, ColorComponentFlags,
-    CommandPoolCreateFlags, DescriptorType, Extent2D, MemoryPropertyFlags, PipelineBindPoint,
-    PipelineColorBlendAttachmentState, PipelineStageFlags, PushConstantRange, Rect2D,
+    BufferUsageFlags, ClearAttachment, ClearRect, DescriptorPoolCreateFlags, DescriptorPoolSize,
+    DescriptorType, DynamicState, ImageAspectFlags, MemoryPropertyFlags, PresentModeKHR,
     ShaderStageFlags, Viewport,
 };
-use camera::Camera;
+use camera::{Camera, CameraData, CameraMovement};
 use frame_data::FrameData;
-use glam::Vec3;
-use mesh::{Mesh, MeshPushConstants};
-use model::Model;
+use grid::{Grid, GridParams};
+use material::{Material, MaterialLayout};
+use model::ObjectData;
 use scene::{Scene, SceneData};
-use std::{collections::HashMap, mem::size_of};
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
 use transform::Transform;
-use vertex::Vertex;
 use vulkan_renderer::{
     buffer::VBuffer,
     cmd::*,
-    descriptorset::{VDescriptorPool, VDescriptorSetLayout},
-    device::VDevice,
+    descriptorset::VDescriptorSetLayout,
     enums::EOperationType,
-    instance::VInstance,
+    glam::Vec3,
+    object_buffer::VObjectBuffer,
     pipeline::VGraphicsPipelineBuilder,
+    renderer::Renderer,
     shader_utils::VShaderUtils,
-    swapchain::VSwapchain,
-    utils::pad_uniform_buffer_size,
+    sync::{FrameSubmit, VFrameSync},
+    utils::save_png,
 };
 use winit::{
     dpi::PhysicalSize,
-    event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
+    event::{
+        DeviceEvent, ElementState, Event, KeyboardInput, MouseButton, VirtualKeyCode, WindowEvent,
+    },
     event_loop::{ControlFlow, EventLoop},
     window::WindowBuilder,
 };
 
 mod app;
+mod bounds;
 mod camera;
 mod frame_data;
-mod macros;
+mod grid;
+mod light;
+mod material;
 mod mesh;
 mod model;
 mod scene;
@@ -44,6 +51,18 @@ mod transform;
 mod vertex;
 
 const NUM_FRAMES: usize = 3;
+const MAX_OBJECTS: usize = 100;
+const MAX_VIEWS: usize = 2;
+/// One material per loaded glTF primitive
+const MAX_MATERIALS: u32 = 16;
+/// Nanoseconds to wait for a swapchain image before giving up on a hung GPU
+const ACQUIRE_TIMEOUT: u64 = 1_000_000_000;
+/// Frames wrapped in capture labels when C is pressed
+const CAPTURE_FRAME_COUNT: u32 = 3;
+/// Where the frame is saved when P is pressed
+const SCREENSHOT_PATH: &str = "screenshot.png";
+/// How often the average GPU frame time is printed
+const GPU_TIME_REPORT_INTERVAL: Duration = Duration::from_secs(1);
 
 fn main() {
     // Window and Event Loop
@@ -53,36 +72,34 @@ fn main() {
         .with_inner_size(PhysicalSize::new(1920, 1080))
         .build(&event_loop)
         .expect("Failed to create window.");
-    let extent = Extent2D {
-        width: window.inner_size().width,
-        height: window.inner_size().height,
-    };
 
     // Instance, Device and Swapchain
-    let instance = VInstance::new("Sample", 0).expect("Failed to create instance.");
-    let device = VDevice::new(&instance, &window).expect("Failed to create device.");
-    let swapchain =
-        VSwapchain::new(&instance, &device, extent).expect("Failed to create swapchain.");
-
-    let mut app = App::init(instance, device, swapchain, extent);
-    app.create_command_pool(CommandPoolCreateFlags::TRANSIENT);
+    let mut renderer = Renderer::new(
+        "Sample",
+        &window,
+        PresentModeKHR::MAILBOX,
+        NUM_FRAMES as u32,
+    )
+    .expect("Failed to create renderer.");
+    let extent = renderer.extent();
 
     // ! Move the shader code into the graphics pipeline
     let vertex_code = VShaderUtils::load_shader("sample/shaders/base.vert.spv")
         .expect("Failed to load vertex shader code.");
-    let vertex_shader_module = VShaderUtils::create_shader_module(&app.device, &vertex_code)
+    let vertex_shader_module = VShaderUtils::create_shader_module(renderer.device(), &vertex_code)
         .expect("Failed to create vertex shader module.");
     let fragment_code = VShaderUtils::load_shader("sample/shaders/base.frag.spv")
         .expect("Failed to load fragment shader code.");
-    let fragment_shader_module = VShaderUtils::create_shader_module(&app.device, &fragment_code)
-        .expect("Failed to create fragment shader module.");
+    let fragment_shader_module =
+        VShaderUtils::create_shader_module(renderer.device(), &fragment_code)
+            .expect("Failed to create fragment shader module.");
 
     // Descriptor Set
     let bindings = &[
         VDescriptorSetLayout::layout_binding(
             0,
             1,
-            DescriptorType::UNIFORM_BUFFER,
+            DescriptorType::UNIFORM_BUFFER_DYNAMIC,
             ShaderStageFlags::VERTEX,
         ),
         VDescriptorSetLayout::layout_binding(
@@ -92,10 +109,35 @@ fn main() {
             ShaderStageFlags::VERTEX | ShaderStageFlags::FRAGMENT,
         ),
     ];
-    let descriptor_pool =
-        VDescriptorPool::new(&app.device).expect("Failed to create descriptor pool.");
-    let descriptor_set_layout = VDescriptorSetLayout::new(&app.device, bindings)
+    let descriptor_set_layout = renderer
+        .create_descriptor_set_layout(bindings)
         .expect("Failed to create descriptor set layout.");
+    let material_pool_sizes = [
+        DescriptorPoolSize {
+            ty: DescriptorType::UNIFORM_BUFFER,
+            descriptor_count: MAX_MATERIALS,
+        },
+        DescriptorPoolSize {
+            ty: DescriptorType::SAMPLED_IMAGE,
+            descriptor_count: MAX_MATERIALS,
+        },
+        DescriptorPoolSize {
+            ty: DescriptorType::SAMPLER,
+            descriptor_count: MAX_MATERIALS,
+        },
+    ];
+    let material_layout = MaterialLayout {
+        descriptor_pool: renderer
+            .create_descriptor_pool(
+                DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET,
+                MAX_MATERIALS,
+                &material_pool_sizes,
+            )
+            .expect("Failed to create material descriptor pool."),
+        descriptor_set_layout: renderer
+            .create_descriptor_set_layout(&Material::layout_bindings())
+            .expect("Failed to create material descriptor set layout."),
+    };
 
     // Graphics Pipeline
     let builder = VGraphicsPipelineBuilder::start();
@@ -103,58 +145,49 @@ fn main() {
         (ShaderStageFlags::VERTEX, vertex_shader_module),
         (ShaderStageFlags::FRAGMENT, fragment_shader_module),
     ];
-    let viewports = &[Viewport {
-        x: 0.0,
-        y: 0.0,
-        max_depth: 1.0,
-        min_depth: 0.0,
-        height: extent.height as f32,
-        width: extent.width as f32,
-    }];
-    let scissors = &[Rect2D {
-        extent,
-        ..Default::default()
-    }];
-    let color_blend_attachments = &[PipelineColorBlendAttachmentState {
-        color_write_mask: ColorComponentFlags::RGBA,
-        ..Default::default()
-    }];
-    let vertex_input_desc = Vertex::vertex_description();
-    let push_constants = &[PushConstantRange {
-        stage_flags: ShaderStageFlags::VERTEX,
-        size: size_of::<MeshPushConstants>() as u32,
-        offset: 0,
-    }];
-    let descriptor_set_layouts = &[descriptor_set_layout.get()];
+    let color_blend_attachments = &[VGraphicsPipelineBuilder::blend_opaque()];
+    let vertex_input_desc = vertex::vertex_description();
+    let descriptor_set_layouts = &[descriptor_set_layout, material_layout.descriptor_set_layout];
     let builder = builder
         .shader_stages(shader_infos)
         .vertex_input(&vertex_input_desc.bindings, &vertex_input_desc.attributes)
-        .viewport(viewports, scissors)
+        .dynamic_state(&[DynamicState::VIEWPORT, DynamicState::SCISSOR])
         .color_blend_state(color_blend_attachments)
-        .pipeline_layout(descriptor_set_layouts, push_constants);
+        .pipeline_layout(descriptor_set_layouts, &[]);
     let pipeline = builder
-        .build(&app.device, app.swapchain.get_renderpass())
+        .build(renderer.device(), renderer.swapchain().get_renderpass())
         .expect("Failed to create graphics pipeline.");
 
-    app.create_graphics_pipeline(pipeline);
+    let mut app = App::init(renderer, pipeline);
+
+    let grid = Grid::new(
+        app.device(),
+        app.swapchain().get_renderpass(),
+        descriptor_set_layout,
+        GridParams::default(),
+    )
+    .expect("Failed to create grid.");
 
     // Frame Data
-    let scene_buffer_size =
-        NUM_FRAMES as u64 * pad_uniform_buffer_size(&app.device, size_of::<SceneData>());
-    let scene_buffer = VBuffer::new_uniform_buffer(
-        &app.device,
-        scene_buffer_size,
+    let scene_buffer = VObjectBuffer::<SceneData>::new(app.device(), 1, NUM_FRAMES)
+        .expect("Failed to create scene buffer.");
+    let camera_buffer = VObjectBuffer::<CameraData>::new(app.device(), MAX_VIEWS, NUM_FRAMES)
+        .expect("Failed to create camera buffer.");
+    let instance_buffer = VBuffer::new_mapped(
+        app.device(),
+        &vec![ObjectData::default(); MAX_OBJECTS * NUM_FRAMES],
+        BufferUsageFlags::VERTEX_BUFFER,
         MemoryPropertyFlags::HOST_COHERENT | MemoryPropertyFlags::HOST_VISIBLE,
     )
-    .expect("Failed to create scene buffer.");
+    .expect("Failed to create instance buffer.");
     let frame_datas = (0..NUM_FRAMES)
         .map(|frame_ind| {
             FrameData::new(
-                &app.device,
-                app.device.get_queue_family_index(EOperationType::Graphics),
-                descriptor_pool.get(),
-                &[descriptor_set_layout.get()],
-                scene_buffer,
+                app.device(),
+                app.renderer.descriptor_pool(),
+                &[descriptor_set_layout],
+                &camera_buffer,
+                &scene_buffer,
                 frame_ind,
             )
             .expect("Failed to create FrameData.")
@@ -166,125 +199,82 @@ fn main() {
         position: Vec3::new(0.0, 0.0, -5.0),
         ..Default::default()
     };
-    let meshes = HashMap::from_iter([(
-        "Helmet".to_owned(),
-        Mesh::from_file(
-            &app.device,
+    let mut scene = Scene::new(
+        camera,
+        SceneData::default(),
+        camera_buffer,
+        scene_buffer,
+        instance_buffer,
+        MAX_OBJECTS,
+    );
+    let helmet_keys = scene
+        .load_gltf(
+            app.device(),
+            material_layout,
             "sample/assets/damaged_helmet/damaged_helmet.glb",
+            "Helmet",
         )
-        .expect("Failed to load model."),
-    )]);
-
-    let mut scene = Scene::new(camera, SceneData::default(), scene_buffer, meshes);
-    scene.add_models(vec![
-        Model {
-            mesh_uuid: "Helmet".to_owned(),
-            transform: Transform {
-                position: Vec3::new(-2.0, 0.0, 0.0),
-                ..Default::default()
-            },
-        },
-        Model {
-            mesh_uuid: "Helmet".to_owned(),
-            transform: Transform {
-                position: Vec3::new(2.0, 0.0, 0.0),
-                ..Default::default()
-            },
-        },
-    ]);
-
-    let mut frame_count = 0;
-    event_loop.run(move |event, _, control_flow| {
-        let frame_index = frame_count % NUM_FRAMES;
-        let frame_data = &frame_datas[frame_index];
-
-        let fences = &[frame_data.fence.get()];
-        app.device
-            .wait_for_fences(fences, 1_000_000_000)
-            .expect("Failed to wait for fences.");
-        app.device
-            .reset_fences(fences)
-            .expect("Failed to reset fences.");
-
-        let _is_suboptimal = app
-            .swapchain
-            .acquire_next_image(Some(frame_data.present_semaphore.get()), None)
-            .expect("Failed to acquire next image.");
-
-        begin_command_buffer(&app.device, frame_data.command_buffer)
-            .expect("Failed to begin command buffer.");
-
-        let clear_values = &[
-            ClearValue {
-                color: ClearColorValue {
-                    float32: [0.0, 0.0, 0.0, 1.0],
-                },
-            },
-            ClearValue {
-                depth_stencil: ClearDepthStencilValue {
-                    depth: 1.0,
+        .expect("Failed to load model.");
+    // Despawned from the back with Delete
+    let mut spawned_helmets = Vec::new();
+    for position in [Vec3::new(-2.0, 0.0, 0.0), Vec3::new(2.0, 0.0, 0.0)] {
+        for key in &helmet_keys {
+            spawned_helmets.push(scene.instantiate(
+                key,
+                Transform {
+                    position,
                     ..Default::default()
                 },
-            },
-        ];
-        cmd_begin_render_pass(
-            &app.device,
-            frame_data.command_buffer,
-            app.swapchain.get_renderpass(),
-            app.swapchain.get_current_framebuffer(),
-            clear_values,
-            extent,
-        );
-
-        cmd_bind_pipeline(
-            &app.device,
-            frame_data.command_buffer,
-            PipelineBindPoint::GRAPHICS,
-            pipeline.pipeline(),
-        );
-
-        scene_buffer
-            .map_padded_memory(
-                &app.device,
-                &[scene.scene_data],
-                (frame_index as u64 * pad_uniform_buffer_size(&app.device, size_of::<SceneData>()))
-                    as isize,
-            )
-            .expect("Failed to map padded memory.");
-
-        scene.draw(&app.device, pipeline.pipeline_layout(), frame_data);
-
-        cmd_end_render_pass(&app.device, frame_data.command_buffer);
-        end_command_buffer(&app.device, frame_data.command_buffer)
-            .expect("Failed to end command buffer.");
-
-        let command_buffers = &[frame_data.command_buffer];
-        let wait_semaphores = &[frame_data.present_semaphore.get()];
-        let dst_semaphores = &[frame_data.render_semaphore.get()];
-        let pipeline_stage_flags = &[PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
-        let submit_info = VDevice::create_queue_submit_info(
-            command_buffers,
-            wait_semaphores,
-            dst_semaphores,
-            pipeline_stage_flags,
-        );
-
-        app.device
-            .queue_submit(
-                app.device.get_queue(EOperationType::Graphics),
-                &[submit_info],
-                frame_data.fence.get(),
-            )
-            .expect("Failed to submit queue.");
+            ));
+        }
+    }
+    // Prefer what the asset was authored with over the defaults
+    match scene.cameras.first() {
+        Some(&camera) => scene.camera = camera,
+        None => scene.frame_camera(),
+    }
+    let authored_lights = scene.lights.clone();
+    scene.scene_data.apply_lights(&authored_lights);
 
-        let wait_semaphores = &[frame_data.render_semaphore.get()];
-        app.swapchain
-            .queue_present(
-                app.device.get_queue(EOperationType::Graphics),
-                wait_semaphores,
-            )
-            .expect("Failed to present queue.");
+    let main_viewport = Viewport {
+        x: 0.0,
+        y: 0.0,
+        max_depth: 1.0,
+        min_depth: 0.0,
+        height: extent.height as f32,
+        width: extent.width as f32,
+    };
+    // Top-down view drawn into the upper-right corner
+    let minimap_camera = Camera {
+        position: Vec3::new(0.0, 20.0, 0.0),
+        up: -Vec3::Z,
+        ..Default::default()
+    };
+    let minimap_viewport = Viewport {
+        x: extent.width as f32 * 0.75,
+        y: 0.0,
+        width: extent.width as f32 * 0.25,
+        height: extent.height as f32 * 0.25,
+        min_depth: 0.0,
+        max_depth: 1.0,
+    };
+    let mut show_minimap = true;
+    let mut take_screenshot = false;
+    // WASD, Q and E move the camera, dragging with the right mouse button turns it
+    let mut held_keys = HashSet::new();
+    let mut is_mouse_looking = false;
+    let mut last_frame = Instant::now();
+    // GPU times of the frames finished since the last report
+    let mut gpu_frame_times = Vec::new();
+    let mut last_gpu_report = Instant::now();
 
+    let mut frame_sync = VFrameSync::new(app.device(), NUM_FRAMES, app.swapchain().image_count())
+        .expect("Failed to create frame sync objects.");
+    // Input events only update state, a frame is drawn once per loop iteration: MainEventsCleared
+    // requests a redraw and RedrawRequested waits for the frame's fence, acquires through
+    // VFrameSync so an image still rendered by an older frame is waited on, then records,
+    // submits and presents
+    event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Poll;
         match event {
             Event::WindowEvent {
@@ -301,9 +291,272 @@ fn main() {
                     },
                 ..
             } => *control_flow = ControlFlow::Exit,
-            Event::MainEventsCleared => {}
+            Event::LoopDestroyed => {
+                unsafe { app.device().get().device_wait_idle() }
+                    .expect("Failed to wait for device idle.");
+                scene.destroy(app.device());
+                for frame_data in &frame_datas {
+                    frame_data.destroy(app.device());
+                }
+                frame_sync.destroy(app.device());
+            }
+            Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                state: ElementState::Pressed,
+                                virtual_keycode: Some(VirtualKeyCode::M),
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } => show_minimap = !show_minimap,
+            Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                state: ElementState::Pressed,
+                                virtual_keycode: Some(VirtualKeyCode::F),
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } => scene.frame_camera(),
+            Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                state: ElementState::Pressed,
+                                virtual_keycode: Some(VirtualKeyCode::C),
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } => app.renderer.capture_frames(CAPTURE_FRAME_COUNT),
+            Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                state: ElementState::Pressed,
+                                virtual_keycode: Some(VirtualKeyCode::P),
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } => take_screenshot = true,
+            Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                state: ElementState::Pressed,
+                                virtual_keycode: Some(VirtualKeyCode::Delete),
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } => {
+                if let Some(handle) = spawned_helmets.pop() {
+                    scene.despawn(handle);
+                }
+            }
+            Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                state,
+                                virtual_keycode: Some(key),
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } => match state {
+                ElementState::Pressed => {
+                    held_keys.insert(key);
+                }
+                ElementState::Released => {
+                    held_keys.remove(&key);
+                }
+            },
+            Event::WindowEvent {
+                event:
+                    WindowEvent::MouseInput {
+                        state,
+                        button: MouseButton::Right,
+                        ..
+                    },
+                ..
+            } => is_mouse_looking = state == ElementState::Pressed,
+            Event::DeviceEvent {
+                event: DeviceEvent::MouseMotion { delta: (dx, dy) },
+                ..
+            } if is_mouse_looking => scene.camera.process_mouse(dx as f32, dy as f32),
+            Event::MainEventsCleared => window.request_redraw(),
+            Event::RedrawRequested(_) => {
+                let sync = frame_sync
+                    .begin_frame(app.device())
+                    .expect("Failed to wait for frame.");
+                let frame_index = sync.frame_index();
+                let frame_data = &frame_datas[frame_index];
+                frame_data.mark_completed();
+                if let Some(gpu_time) = frame_data
+                    .gpu_time(app.device())
+                    .expect("Failed to read GPU timestamps.")
+                {
+                    gpu_frame_times.push(gpu_time);
+                }
+                if last_gpu_report.elapsed() >= GPU_TIME_REPORT_INTERVAL
+                    && !gpu_frame_times.is_empty()
+                {
+                    let average =
+                        gpu_frame_times.iter().sum::<u64>() / gpu_frame_times.len() as u64;
+                    println!("GPU frame time: {:.3} ms", average as f64 / 1e6);
+                    gpu_frame_times.clear();
+                    last_gpu_report = Instant::now();
+                }
+
+                let acquired = app
+                    .swapchain()
+                    .acquire_next_image(Some(sync.image_available()), None, ACQUIRE_TIMEOUT)
+                    .expect("Failed to acquire next image.");
+                // The swapchain isn't recreated yet, so anything but an image is fatal
+                let image_index = acquired
+                    .image_index()
+                    .unwrap_or_else(|| panic!("Failed to acquire next image: {:?}", acquired));
+                frame_sync
+                    .image_acquired(app.device(), image_index)
+                    .expect("Failed to wait for image.");
+
+                let now = Instant::now();
+                let dt = (now - last_frame).as_secs_f32();
+                last_frame = now;
+                for movement in held_keys.iter().filter_map(|&key| camera_movement(key)) {
+                    scene.camera.process_keyboard(movement, dt);
+                }
+
+                begin_command_buffer(app.device(), frame_data.command_buffer)
+                    .expect("Failed to begin command buffer.");
+                app.renderer.begin_frame(frame_data.command_buffer);
+                frame_data.cmd_begin_timing(app.device());
+
+                let clear_values = &ClearValues::start().build();
+                cmd_begin_render_pass(
+                    app.device(),
+                    frame_data.command_buffer,
+                    app.swapchain().get_renderpass(),
+                    app.swapchain().get_framebuffer(image_index),
+                    clear_values,
+                    extent,
+                );
+
+                scene.draw(app.device(), app.pipeline(), frame_data, main_viewport);
+                grid.draw(
+                    app.device(),
+                    frame_data,
+                    scene.camera_buffer.offset(frame_index, 0),
+                );
+
+                if show_minimap {
+                    let clear_attachments = &[
+                        ClearAttachment {
+                            aspect_mask: ImageAspectFlags::COLOR,
+                            color_attachment: 0,
+                            clear_value: clear_values[0],
+                        },
+                        ClearAttachment {
+                            aspect_mask: ImageAspectFlags::DEPTH,
+                            color_attachment: 0,
+                            clear_value: clear_values[1],
+                        },
+                    ];
+                    let clear_rects = &[ClearRect {
+                        rect: Scene::viewport_scissor(minimap_viewport),
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    }];
+                    cmd_clear_attachments(
+                        app.device(),
+                        frame_data.command_buffer,
+                        clear_attachments,
+                        clear_rects,
+                    );
+                    scene.draw_view(
+                        app.device(),
+                        app.pipeline(),
+                        frame_data,
+                        1,
+                        &minimap_camera,
+                        minimap_viewport,
+                    );
+                    grid.draw(
+                        app.device(),
+                        frame_data,
+                        scene.camera_buffer.offset(frame_index, 1),
+                    );
+                }
+
+                cmd_end_render_pass(app.device(), frame_data.command_buffer);
+                frame_data.cmd_end_timing(app.device());
+                app.renderer.end_frame(frame_data.command_buffer);
+                end_command_buffer(app.device(), frame_data.command_buffer)
+                    .expect("Failed to end command buffer.");
+
+                let frame_submit = FrameSubmit::new(
+                    sync.image_available(),
+                    sync.render_finished(),
+                    sync.in_flight(),
+                );
+                frame_submit
+                    .submit(
+                        app.device(),
+                        app.device().get_queue(EOperationType::Graphics),
+                        &[frame_data.command_buffer],
+                    )
+                    .expect("Failed to submit queue.");
+                frame_data.mark_submitted();
+
+                if take_screenshot {
+                    take_screenshot = false;
+                    let pixels = app
+                        .swapchain()
+                        .capture_current_image(app.device())
+                        .expect("Failed to capture swapchain image.");
+                    save_png(SCREENSHOT_PATH, extent, &pixels).expect("Failed to save screenshot.");
+                }
+
+                frame_submit
+                    .present(
+                        app.swapchain(),
+                        app.device().get_queue(EOperationType::Graphics),
+                        image_index,
+                    )
+                    .expect("Failed to present queue.");
+                frame_sync.end_frame();
+            }
             _ => (),
         }
-        frame_count += 1;
     });
 }
+
+fn camera_movement(key: VirtualKeyCode) -> Option<CameraMovement> {
+    match key {
+        VirtualKeyCode::W => Some(CameraMovement::Forward),
+        VirtualKeyCode::S => Some(CameraMovement::Backward),
+        VirtualKeyCode::A => Some(CameraMovement::Left),
+        VirtualKeyCode::D => Some(CameraMovement::Right),
+        VirtualKeyCode::E => Some(CameraMovement::Up),
+        VirtualKeyCode::Q => Some(CameraMovement::Down),
+        _ => None,
+    }
+}
@@ -0,0 +1,234 @@
+use ash::vk::{
+    DescriptorBufferInfo, DescriptorImageInfo, DescriptorPool, DescriptorSet, DescriptorSetLayout,
+    DescriptorSetLayoutBinding, DescriptorType, ImageLayout, MemoryPropertyFlags, ShaderStageFlags,
+};
+use std::mem::size_of;
+use vulkan_renderer::{
+    buffer::VBuffer,
+    descriptorset::{VDescriptorSet, VDescriptorSetLayout},
+    device::VDevice,
+    glam::{Mat3, Mat4, Vec2, Vec3},
+    image::VImage,
+    sampler::VSampler,
+    RendererResult,
+};
+
+/// Per-material shader data, matches `MaterialData` in base.frag
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MaterialData {
+    /// The `KHR_texture_transform` matrix of the base color texture in the upper-left 3x3
+    pub base_color_uv_transform: Mat4,
+}
+
+/// A texture reference's `KHR_texture_transform`, identity if the extension is absent
+///
+/// The transform's `texCoord` override isn't supported since only `TEXCOORD_0` is loaded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextureTransform {
+    pub offset: Vec2,
+    /// Counter-clockwise in UV space, in radians
+    pub rotation: f32,
+    pub scale: Vec2,
+}
+
+impl Default for TextureTransform {
+    fn default() -> Self {
+        Self {
+            offset: Vec2::ZERO,
+            rotation: 0.0,
+            scale: Vec2::ONE,
+        }
+    }
+}
+
+impl TextureTransform {
+    pub fn from_gltf(info: &gltf::texture::Info) -> Self {
+        info.texture_transform()
+            .map(|transform| Self {
+                offset: transform.offset().into(),
+                rotation: transform.rotation(),
+                scale: transform.scale().into(),
+            })
+            .unwrap_or_default()
+    }
+
+    /// `translation * rotation * scale` as defined by the extension
+    pub fn matrix(&self) -> Mat3 {
+        let (sin, cos) = self.rotation.sin_cos();
+        let translation = Mat3::from_cols(Vec3::X, Vec3::Y, self.offset.extend(1.0));
+        let rotation =
+            Mat3::from_cols(Vec3::new(cos, -sin, 0.0), Vec3::new(sin, cos, 0.0), Vec3::Z);
+        let scale = Mat3::from_diagonal(self.scale.extend(1.0));
+        translation * rotation * scale
+    }
+}
+
+/// Where materials allocate their descriptor sets from, set 1 of the mesh pipeline
+///
+/// The pool needs `FREE_DESCRIPTOR_SET` since each material frees its own set
+#[derive(Debug, Clone, Copy)]
+pub struct MaterialLayout {
+    pub descriptor_pool: DescriptorPool,
+    pub descriptor_set_layout: DescriptorSetLayout,
+}
+
+/// A material's uniform buffer, base color texture and sampler, bound together as one descriptor set
+///
+/// The texture isn't owned, it's shared by every material sampling it
+#[derive(Default, Debug)]
+pub struct Material {
+    pub data_buffer: VBuffer,
+    pub sampler: VSampler,
+    pub descriptor_pool: DescriptorPool,
+    pub descriptor_set: DescriptorSet,
+}
+
+impl Material {
+    pub fn new(
+        device: &VDevice,
+        layout: MaterialLayout,
+        data: MaterialData,
+        base_color_texture: &VImage,
+    ) -> RendererResult<Self> {
+        let data_buffer = VBuffer::new_uniform_buffer(
+            device,
+            size_of::<MaterialData>() as u64,
+            MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT,
+        )?;
+        let sampler = match data_buffer
+            .map_memory(device, &[data])
+            .and_then(|_| VSampler::for_texture(device, base_color_texture.format()))
+        {
+            Ok(sampler) => sampler,
+            Err(err) => {
+                data_buffer.destroy(device);
+                return Err(err);
+            }
+        };
+        let descriptor_set = match VDescriptorSet::new(
+            device,
+            layout.descriptor_pool,
+            &[layout.descriptor_set_layout],
+        ) {
+            Ok(descriptor_set) => descriptor_set.get(),
+            Err(err) => {
+                data_buffer.destroy(device);
+                sampler.destroy(device);
+                return Err(err);
+            }
+        };
+
+        let buffer_info = DescriptorBufferInfo {
+            buffer: data_buffer.buffer(),
+            offset: 0,
+            range: size_of::<MaterialData>() as u64,
+        };
+        let image_info = DescriptorImageInfo {
+            image_view: base_color_texture.image_view(),
+            image_layout: ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            ..Default::default()
+        };
+        let sampler_info = DescriptorImageInfo {
+            sampler: sampler.get(),
+            ..Default::default()
+        };
+        let write_sets = [
+            VDescriptorSet::write_descriptor_set(
+                descriptor_set,
+                0,
+                DescriptorType::UNIFORM_BUFFER,
+                &buffer_info,
+            ),
+            VDescriptorSet::write_image_descriptor_set(
+                descriptor_set,
+                1,
+                DescriptorType::SAMPLED_IMAGE,
+                &image_info,
+            ),
+            VDescriptorSet::write_image_descriptor_set(
+                descriptor_set,
+                2,
+                DescriptorType::SAMPLER,
+                &sampler_info,
+            ),
+        ];
+        unsafe { device.get().update_descriptor_sets(&write_sets, &[]) };
+
+        Ok(Self {
+            data_buffer,
+            sampler,
+            descriptor_pool: layout.descriptor_pool,
+            descriptor_set,
+        })
+    }
+
+    /// Frees the descriptor set back to its pool and destroys the buffer and sampler
+    pub fn destroy(&self, device: &VDevice) {
+        unsafe {
+            device
+                .get()
+                .free_descriptor_sets(self.descriptor_pool, &[self.descriptor_set])
+        }
+        .expect("Failed to free material descriptor set.");
+        self.data_buffer.destroy(device);
+        self.sampler.destroy(device);
+    }
+
+    /// Matches `base.frag`, which declares the base color texture and its sampler as separate bindings
+    pub fn layout_bindings() -> [DescriptorSetLayoutBinding; 3] {
+        [
+            VDescriptorSetLayout::layout_binding(
+                0,
+                1,
+                DescriptorType::UNIFORM_BUFFER,
+                ShaderStageFlags::FRAGMENT,
+            ),
+            VDescriptorSetLayout::layout_binding(
+                1,
+                1,
+                DescriptorType::SAMPLED_IMAGE,
+                ShaderStageFlags::FRAGMENT,
+            ),
+            VDescriptorSetLayout::layout_binding(
+                2,
+                1,
+                DescriptorType::SAMPLER,
+                ShaderStageFlags::FRAGMENT,
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform_uv(transform: TextureTransform, uv: Vec2) -> Vec2 {
+        transform.matrix().transform_point2(uv)
+    }
+
+    #[test]
+    fn applies_scale_then_offset() {
+        let transform = TextureTransform {
+            offset: Vec2::new(0.5, 0.25),
+            scale: Vec2::new(2.0, 3.0),
+            ..Default::default()
+        };
+        assert_eq!(
+            transform_uv(TextureTransform::default(), Vec2::ONE),
+            Vec2::ONE
+        );
+        assert_eq!(transform_uv(transform, Vec2::ONE), Vec2::new(2.5, 3.25));
+    }
+
+    #[test]
+    fn rotates_counter_clockwise_in_uv_space() {
+        let transform = TextureTransform {
+            rotation: std::f32::consts::FRAC_PI_2,
+            ..Default::default()
+        };
+        let uv = transform_uv(transform, Vec2::X);
+        assert!((uv - Vec2::new(0.0, -1.0)).length() < 1e-6);
+    }
+}
@@ -1,27 +1,109 @@
-use crate::{macros::impl_u8_slice, vertex::Vertex};
-use ash::vk::BufferUsageFlags;
-use glam::Mat4;
+use crate::{
+    bounds::BoundingSphere,
+    material::{Material, MaterialData, MaterialLayout, TextureTransform},
+};
+use ash::vk::{
+    BufferUsageFlags, Extent3D, Format, ImageAspectFlags, ImageLayout, ImageUsageFlags,
+    MemoryPropertyFlags,
+};
 use gltf::image::Data;
 use itertools::izip;
-use vulkan_renderer::{buffer::VBuffer, device::VDevice, image::VImage};
+use std::{borrow::Cow, collections::HashMap};
+use vulkan_renderer::{
+    buffer::VBuffer,
+    device::VDevice,
+    enums::ETextureColorSpace,
+    glam::{Mat4, Vec2, Vec3},
+    image::VImage,
+    vertex_input::VVertex,
+    RendererResult,
+};
 
+/// The geometry and material of one glTF primitive, read before anything is uploaded
 #[derive(Default, Debug, Clone)]
+pub struct PrimitiveData {
+    pub vertices: Vec<VVertex>,
+    pub indices: Vec<u32>,
+    /// The texture index and UV transform of the material's base color texture
+    pub base_color: Option<(usize, TextureTransform)>,
+}
+
+/// The base color textures of a glTF file, each uploaded once and shared by the materials
+/// sampling it
+#[derive(Default, Debug)]
+pub struct GltfTextures {
+    /// Keyed by glTF texture index
+    textures: HashMap<usize, VImage>,
+    /// 1x1 white texture for materials without a base color texture
+    fallback: VImage,
+}
+
+impl GltfTextures {
+    pub fn new(device: &VDevice, document: &gltf::Document, images: &[Data]) -> Self {
+        let image_color_spaces = Mesh::image_color_spaces(document, images.len());
+        let mut textures = HashMap::new();
+        for material in document.materials() {
+            let texture = match material.pbr_metallic_roughness().base_color_texture() {
+                Some(info) => info.texture(),
+                None => continue,
+            };
+            textures.entry(texture.index()).or_insert_with(|| {
+                let image_index = texture.source().index();
+                let image = &images[image_index];
+                let (format, pixels) = Mesh::expand_to_four_channels(image);
+                let format = Mesh::convert_gltf_format_to_ash_format(
+                    format,
+                    image_color_spaces[image_index],
+                )
+                .expect("Textures are expanded to four channels before upload.");
+                Mesh::create_texture(device, &pixels, format, image.width, image.height)
+                    .expect("Failed to create texture.")
+            });
+        }
+        let fallback = Mesh::create_texture(device, &[255; 4], Format::R8G8B8A8_UNORM, 1, 1)
+            .expect("Failed to create fallback texture.");
+        Self { textures, fallback }
+    }
+
+    /// The texture at `texture_index`, the white fallback for `None`
+    pub fn get(&self, texture_index: Option<usize>) -> &VImage {
+        texture_index
+            .and_then(|index| self.textures.get(&index))
+            .unwrap_or(&self.fallback)
+    }
+
+    /// The GPU must be done with every material sampling the textures
+    pub fn destroy(&self, device: &VDevice) {
+        for texture in self.textures.values() {
+            texture.destroy(device);
+        }
+        self.fallback.destroy(device);
+    }
+}
+
+/// The GPU resources of one glTF primitive
+#[derive(Default, Debug)]
 pub struct Mesh {
-    pub vertices: Vec<Vertex>,
     pub indices: Vec<u32>,
-    pub images: Vec<Data>,
 
     pub vertex_buffer: VBuffer,
     pub index_buffer: VBuffer,
-    pub texture_images: Vec<VImage>,
+    pub material: Material,
+    /// World transform of every node placing the glTF mesh, each applied before the model's own
+    /// and drawn as its own instance, no transforms draw the mesh once untransformed
+    pub node_transforms: Vec<Mat4>,
+    bounding_sphere: BoundingSphere,
 }
 
 impl Mesh {
+    /// `base_color_texture` is sampled through `base_color_transform`, it must outlive the mesh
     pub fn new(
         device: &VDevice,
-        vertices: Vec<Vertex>,
+        material_layout: MaterialLayout,
+        vertices: Vec<VVertex>,
         indices: Vec<u32>,
-        images: Vec<Data>,
+        base_color_texture: &VImage,
+        base_color_transform: TextureTransform,
     ) -> Self {
         let vertex_buffer =
             VBuffer::new_device_local_buffer(device, &vertices, BufferUsageFlags::VERTEX_BUFFER)
@@ -31,86 +113,425 @@ impl Mesh {
             VBuffer::new_device_local_buffer(device, &indices, BufferUsageFlags::INDEX_BUFFER)
                 .expect("Failed to create index buffer.");
 
-        // let texture_images = images
-        //     .iter()
-        //     .map(|image| {
-        //         // let pixels = &image.pixels;
-        //         let format = Self::convert_gltf_format_to_ash_format(image.format);
-        //         let extent = Extent3D {
-        //             width: image.width,
-        //             height: image.height,
-        //             depth: 1,
-        //         };
-        //         VImage::new(
-        //             device,
-        //             ImageUsageFlags::SAMPLED,
-        //             format,
-        //             extent,
-        //             ImageAspectFlags::COLOR,
-        //         )
-        //         .expect("Failed to create image.")
-        //     })
-        //     .collect::<Vec<_>>();
+        let material_data = MaterialData {
+            base_color_uv_transform: Mat4::from_mat3(base_color_transform.matrix()),
+        };
+        let material = Material::new(device, material_layout, material_data, base_color_texture)
+            .expect("Failed to create material.");
+
+        let positions = vertices
+            .iter()
+            .map(|vertex| vertex.position)
+            .collect::<Vec<_>>();
+        let bounding_sphere = BoundingSphere::from_points(&positions);
+
         Self {
-            vertices,
             indices,
-            images,
 
             vertex_buffer,
             index_buffer,
-            texture_images: vec![],
+            material,
+            node_transforms: Vec::new(),
+            bounding_sphere,
         }
     }
 
-    pub fn from_file(device: &VDevice, file: &str) -> gltf::Result<Mesh> {
-        let (gltf, buffers, images) = gltf::import(file)?;
+    /// The GPU must be done drawing the mesh, its texture is destroyed with its [`GltfTextures`]
+    pub fn destroy(&self, device: &VDevice) {
+        self.vertex_buffer.destroy(device);
+        self.index_buffer.destroy(device);
+        self.material.destroy(device);
+    }
+
+    /// Bounds of the vertex positions in model space, computed once at load
+    pub fn bounding_sphere(&self) -> BoundingSphere {
+        self.bounding_sphere
+    }
 
-        let mut vertices = Vec::with_capacity(buffers.len());
-        let mut indices = Vec::with_capacity(buffers.len());
+    /// Builds one mesh per primitive of a glTF mesh, each with its own material sampling the
+    /// shared `textures`
+    pub fn from_gltf(
+        device: &VDevice,
+        material_layout: MaterialLayout,
+        textures: &GltfTextures,
+        mesh: &gltf::Mesh,
+        buffers: &[gltf::buffer::Data],
+        node_transforms: &[Mat4],
+    ) -> Vec<Mesh> {
+        Self::read_primitives(mesh, buffers)
+            .into_iter()
+            .map(|primitive| {
+                let (texture_index, transform) = match primitive.base_color {
+                    Some((index, transform)) => (Some(index), transform),
+                    None => (None, TextureTransform::default()),
+                };
+                Mesh {
+                    node_transforms: node_transforms.to_vec(),
+                    ..Mesh::new(
+                        device,
+                        material_layout,
+                        primitive.vertices,
+                        primitive.indices,
+                        textures.get(texture_index),
+                        transform,
+                    )
+                }
+            })
+            .collect()
+    }
 
-        for mesh in gltf.meshes() {
-            for primitive in mesh.primitives() {
+    /// Reads every primitive of `mesh` separately, in file order
+    pub fn read_primitives(
+        mesh: &gltf::Mesh,
+        buffers: &[gltf::buffer::Data],
+    ) -> Vec<PrimitiveData> {
+        mesh.primitives()
+            .map(|primitive| {
+                let mut data = PrimitiveData {
+                    base_color: primitive
+                        .material()
+                        .pbr_metallic_roughness()
+                        .base_color_texture()
+                        .map(|info| (info.texture().index(), TextureTransform::from_gltf(&info))),
+                    ..Default::default()
+                };
                 let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
-                if let (Some(pos_iter), Some(norm_iter), Some(tex_iter)) = (
-                    reader.read_positions(),
-                    reader.read_normals(),
-                    reader.read_tex_coords(0),
-                ) {
-                    assert_eq!(pos_iter.len(), norm_iter.len());
-                    for (position, normal, uv) in izip!(pos_iter, norm_iter, tex_iter.into_f32()) {
-                        vertices.push(Vertex::new(position.into(), normal.into(), uv.into()));
+                let positions = match reader.read_positions() {
+                    Some(iter) => iter.map(Vec3::from).collect::<Vec<_>>(),
+                    None => return data,
+                };
+                let indices = match reader.read_indices() {
+                    Some(iter) => iter.into_u32().collect(),
+                    None => (0..positions.len() as u32).collect::<Vec<_>>(),
+                };
+                // Attributes that don't match the positions are treated as missing
+                let normals = reader
+                    .read_normals()
+                    .map(|iter| iter.map(Vec3::from).collect::<Vec<_>>())
+                    .filter(|normals| normals.len() == positions.len());
+                let uvs = reader
+                    .read_tex_coords(0)
+                    .map(|iter| iter.into_f32().map(Vec2::from).collect::<Vec<_>>())
+                    .filter(|uvs| uvs.len() == positions.len())
+                    .unwrap_or_else(|| vec![Vec2::ZERO; positions.len()]);
+
+                match normals {
+                    Some(normals) => {
+                        data.indices = Self::valid_triangles(&indices, positions.len())
+                            .flatten()
+                            .copied()
+                            .collect();
+                        data.vertices = izip!(positions, normals, uvs)
+                            .map(|(position, normal, uv)| VVertex::new(position, normal, uv))
+                            .collect();
                     }
-                }
-                if let Some(iter) = reader.read_indices() {
-                    for index in iter.into_u32() {
-                        indices.push(index)
+                    None => {
+                        data.vertices = Self::flat_shaded_vertices(&positions, &uvs, &indices);
+                        data.indices = (0..data.vertices.len() as u32).collect();
                     }
                 }
+                data
+            })
+            .collect()
+    }
+
+    /// Triangles of `indices` whose corners all exist, malformed files can index past the vertices
+    fn valid_triangles(indices: &[u32], vertex_count: usize) -> impl Iterator<Item = &[u32]> {
+        indices.chunks_exact(3).filter(move |triangle| {
+            triangle
+                .iter()
+                .all(|&index| (index as usize) < vertex_count)
+        })
+    }
+
+    /// Unshares the vertices of every triangle so each gets its face normal
+    fn flat_shaded_vertices(positions: &[Vec3], uvs: &[Vec2], indices: &[u32]) -> Vec<VVertex> {
+        Self::valid_triangles(indices, positions.len().min(uvs.len()))
+            .flat_map(|triangle| {
+                let [a, b, c] = [0, 1, 2].map(|corner| positions[triangle[corner] as usize]);
+                let normal = (b - a).cross(c - a).normalize_or_zero();
+                triangle.iter().map(move |&index| {
+                    VVertex::new(positions[index as usize], normal, uvs[index as usize])
+                })
+            })
+            .collect()
+    }
+
+    /// Stages `pixels` through a host visible buffer into a sampled image
+    fn create_texture(
+        device: &VDevice,
+        pixels: &[u8],
+        format: Format,
+        width: u32,
+        height: u32,
+    ) -> RendererResult<VImage> {
+        let extent = Extent3D {
+            width,
+            height,
+            depth: 1,
+        };
+        let image = VImage::new(
+            device,
+            ImageUsageFlags::SAMPLED | ImageUsageFlags::TRANSFER_DST,
+            format,
+            extent,
+            ImageAspectFlags::COLOR,
+        )?;
+        let staging_buffer = VBuffer::new_mapped(
+            device,
+            pixels,
+            BufferUsageFlags::TRANSFER_SRC,
+            MemoryPropertyFlags::HOST_COHERENT | MemoryPropertyFlags::HOST_VISIBLE,
+        )?;
+        VBuffer::copy_buffer_to_image(
+            device,
+            staging_buffer.buffer(),
+            &image,
+            extent,
+            ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        )?;
+        staging_buffer.destroy(device);
+        Ok(image)
+    }
+
+    /// Three channel formats are rarely sampleable, so pad them with an opaque alpha
+    fn expand_to_four_channels(image: &Data) -> (gltf::image::Format, Cow<'_, [u8]>) {
+        use gltf::image::Format as GltfFormat;
+
+        let (expanded_format, channel_size) = match image.format {
+            GltfFormat::R8G8B8 => (GltfFormat::R8G8B8A8, 1),
+            GltfFormat::B8G8R8 => (GltfFormat::B8G8R8A8, 1),
+            GltfFormat::R16G16B16 => (GltfFormat::R16G16B16A16, 2),
+            format => return (format, Cow::Borrowed(&image.pixels)),
+        };
+        let alpha = vec![255; channel_size];
+        let pixels = image
+            .pixels
+            .chunks_exact(3 * channel_size)
+            .flat_map(|texel| texel.iter().chain(&alpha).copied())
+            .collect();
+        (expanded_format, Cow::Owned(pixels))
+    }
+
+    /// Color space of each image, based on the material slots that sample it
+    fn image_color_spaces(gltf: &gltf::Document, image_count: usize) -> Vec<ETextureColorSpace> {
+        let mut color_spaces = vec![ETextureColorSpace::Linear; image_count];
+        for material in gltf.materials() {
+            let color_textures = [
+                material
+                    .pbr_metallic_roughness()
+                    .base_color_texture()
+                    .map(|info| info.texture()),
+                material.emissive_texture().map(|info| info.texture()),
+            ];
+            for texture in color_textures.into_iter().flatten() {
+                color_spaces[texture.source().index()] = ETextureColorSpace::Srgb;
             }
         }
+        color_spaces
+    }
 
-        Ok(Mesh::new(device, vertices, indices, images))
-    }
-
-    #[allow(dead_code)]
-    fn convert_gltf_format_to_ash_format(format: gltf::image::Format) -> ash::vk::Format {
-        match format {
-            gltf::image::Format::B8G8R8 => ash::vk::Format::B8G8R8_SRGB,
-            gltf::image::Format::B8G8R8A8 => ash::vk::Format::B8G8R8A8_SRGB,
-            gltf::image::Format::R16 => ash::vk::Format::R16_SINT,
-            gltf::image::Format::R16G16 => ash::vk::Format::R16G16_SINT,
-            gltf::image::Format::R16G16B16 => ash::vk::Format::R16G16B16_SINT,
-            gltf::image::Format::R16G16B16A16 => ash::vk::Format::R16G16B16A16_SINT,
-            gltf::image::Format::R8 => ash::vk::Format::R8_SINT,
-            gltf::image::Format::R8G8 => ash::vk::Format::R8G8_SINT,
-            gltf::image::Format::R8G8B8 => ash::vk::Format::R8G8B8_SINT,
-            gltf::image::Format::R8G8B8A8 => ash::vk::Format::R8G8B8A8_SINT,
-        }
+    /// Three channel formats are rejected since they're rarely sampleable, expand them with
+    /// [`expand_to_four_channels`](Self::expand_to_four_channels) first
+    fn convert_gltf_format_to_ash_format(
+        format: gltf::image::Format,
+        color_space: ETextureColorSpace,
+    ) -> Option<Format> {
+        use gltf::image::Format as GltfFormat;
+
+        let srgb = color_space == ETextureColorSpace::Srgb;
+        let format = match format {
+            GltfFormat::B8G8R8 | GltfFormat::R8G8B8 | GltfFormat::R16G16B16 => return None,
+            GltfFormat::B8G8R8A8 if srgb => Format::B8G8R8A8_SRGB,
+            GltfFormat::B8G8R8A8 => Format::B8G8R8A8_UNORM,
+            GltfFormat::R8 if srgb => Format::R8_SRGB,
+            GltfFormat::R8 => Format::R8_UNORM,
+            GltfFormat::R8G8 if srgb => Format::R8G8_SRGB,
+            GltfFormat::R8G8 => Format::R8G8_UNORM,
+            GltfFormat::R8G8B8A8 if srgb => Format::R8G8B8A8_SRGB,
+            GltfFormat::R8G8B8A8 => Format::R8G8B8A8_UNORM,
+            // There are no sRGB 16-bit formats
+            GltfFormat::R16 => Format::R16_UNORM,
+            GltfFormat::R16G16 => Format::R16G16_UNORM,
+            GltfFormat::R16G16B16A16 => Format::R16G16B16A16_UNORM,
+        };
+        Some(format)
     }
 }
 
-pub struct MeshPushConstants {
-    pub mvp: Mat4,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_format_from_color_space() {
+        use gltf::image::Format as GltfFormat;
+
+        let cases = [
+            (
+                GltfFormat::R8,
+                Some(Format::R8_SRGB),
+                Some(Format::R8_UNORM),
+            ),
+            (
+                GltfFormat::R8G8,
+                Some(Format::R8G8_SRGB),
+                Some(Format::R8G8_UNORM),
+            ),
+            (GltfFormat::R8G8B8, None, None),
+            (GltfFormat::B8G8R8, None, None),
+            (
+                GltfFormat::R8G8B8A8,
+                Some(Format::R8G8B8A8_SRGB),
+                Some(Format::R8G8B8A8_UNORM),
+            ),
+            (
+                GltfFormat::B8G8R8A8,
+                Some(Format::B8G8R8A8_SRGB),
+                Some(Format::B8G8R8A8_UNORM),
+            ),
+            (
+                GltfFormat::R16,
+                Some(Format::R16_UNORM),
+                Some(Format::R16_UNORM),
+            ),
+            (
+                GltfFormat::R16G16,
+                Some(Format::R16G16_UNORM),
+                Some(Format::R16G16_UNORM),
+            ),
+            (GltfFormat::R16G16B16, None, None),
+            (
+                GltfFormat::R16G16B16A16,
+                Some(Format::R16G16B16A16_UNORM),
+                Some(Format::R16G16B16A16_UNORM),
+            ),
+        ];
+        for (format, srgb, linear) in cases {
+            assert_eq!(
+                Mesh::convert_gltf_format_to_ash_format(format, ETextureColorSpace::Srgb),
+                srgb
+            );
+            assert_eq!(
+                Mesh::convert_gltf_format_to_ash_format(format, ETextureColorSpace::Linear),
+                linear
+            );
+        }
+    }
 
-impl_u8_slice!(MeshPushConstants);
+    #[test]
+    fn pads_rgb_pixels_with_opaque_alpha() {
+        let image = Data {
+            pixels: vec![1, 2, 3, 4, 5, 6],
+            format: gltf::image::Format::R8G8B8,
+            width: 2,
+            height: 1,
+        };
+        let (format, pixels) = Mesh::expand_to_four_channels(&image);
+        assert_eq!(format, gltf::image::Format::R8G8B8A8);
+        assert_eq!(&*pixels, &[1, 2, 3, 255, 4, 5, 6, 255]);
+    }
+
+    /// Reads the primitives of a one triangle fixture, `primitives` picks from its accessors:
+    /// 0 positions, 1 normals, 2 UVs and 3 indices
+    fn read_fixture_primitives(primitives: &str) -> Vec<PrimitiveData> {
+        let json = format!(
+            r#"{{
+            "asset": {{"version": "2.0"}},
+            "buffers": [{{"byteLength": 108}}],
+            "bufferViews": [
+                {{"buffer": 0, "byteOffset": 0, "byteLength": 96}},
+                {{"buffer": 0, "byteOffset": 96, "byteLength": 12}}
+            ],
+            "accessors": [
+                {{"bufferView": 0, "byteOffset": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                 "min": [0.0, 0.0, 0.0], "max": [1.0, 1.0, 0.0]}},
+                {{"bufferView": 0, "byteOffset": 36, "componentType": 5126, "count": 3, "type": "VEC3"}},
+                {{"bufferView": 0, "byteOffset": 72, "componentType": 5126, "count": 3, "type": "VEC2"}},
+                {{"bufferView": 1, "componentType": 5125, "count": 3, "type": "SCALAR"}}
+            ],
+            "images": [{{"uri": "shell.png"}}],
+            "textures": [{{"source": 0}}],
+            "materials": [
+                {{"name": "Visor"}},
+                {{"name": "Shell", "pbrMetallicRoughness": {{"baseColorTexture": {{"index": 0}}}}}}
+            ],
+            "meshes": [{{"primitives": {}}}]
+        }}"#,
+            primitives
+        );
+        let document = gltf::Gltf::from_slice(json.as_bytes()).unwrap().document;
+        let floats: [f32; 24] = [
+            0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, // positions
+            0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, // normals
+            0.0, 0.0, 1.0, 0.0, 0.0, 1.0, // uvs
+        ];
+        let mut bytes = floats
+            .iter()
+            .flat_map(|f| f.to_le_bytes())
+            .collect::<Vec<_>>();
+        bytes.extend([0u32, 1, 2].iter().flat_map(|i| i.to_le_bytes()));
+        let buffers = [gltf::buffer::Data(bytes)];
+        Mesh::read_primitives(&document.meshes().next().unwrap(), &buffers)
+    }
+
+    #[test]
+    fn reads_each_primitive_with_its_own_material() {
+        let primitives = read_fixture_primitives(
+            r#"[
+                {"attributes": {"POSITION": 0, "NORMAL": 1, "TEXCOORD_0": 2}, "indices": 3, "material": 0},
+                {"attributes": {"POSITION": 0, "NORMAL": 1, "TEXCOORD_0": 2}, "indices": 3, "material": 1}
+            ]"#,
+        );
+        assert_eq!(primitives.len(), 2);
+        assert_eq!(
+            primitives
+                .iter()
+                .map(|primitive| primitive.base_color)
+                .collect::<Vec<_>>(),
+            [None, Some((0, TextureTransform::default()))]
+        );
+        for primitive in &primitives {
+            assert_eq!(primitive.vertices.len(), 3);
+            assert_eq!(primitive.indices, [0, 1, 2]);
+        }
+    }
+
+    #[test]
+    fn defaults_missing_uvs() {
+        let primitives = read_fixture_primitives(
+            r#"[{"attributes": {"POSITION": 0, "NORMAL": 1}, "indices": 3}]"#,
+        );
+        let vertices = &primitives[0].vertices;
+        assert_eq!(vertices.len(), 3);
+        assert!(vertices.iter().all(|vertex| vertex.uv == Vec2::ZERO));
+        assert!(vertices.iter().all(|vertex| vertex.normal == Vec3::Y));
+    }
+
+    #[test]
+    fn computes_flat_normals_of_positions_only_primitive() {
+        let primitives = read_fixture_primitives(r#"[{"attributes": {"POSITION": 0}}]"#);
+        let primitive = &primitives[0];
+        assert_eq!(primitive.indices, [0, 1, 2]);
+        assert_eq!(primitive.vertices[1].position, Vec3::X);
+        assert!(primitive
+            .vertices
+            .iter()
+            .all(|vertex| vertex.normal == Vec3::Z && vertex.uv == Vec2::ZERO));
+    }
+
+    #[test]
+    fn skips_triangles_with_out_of_range_indices() {
+        let positions = [Vec3::ZERO, Vec3::X, Vec3::Y];
+        let uvs = [Vec2::ZERO; 3];
+        let vertices = Mesh::flat_shaded_vertices(&positions, &uvs, &[0, 1, 2, 0, 1, 7]);
+        assert_eq!(vertices.len(), 3);
+        assert!(vertices.iter().all(|vertex| vertex.normal == Vec3::Z));
+
+        let indices = Mesh::valid_triangles(&[2, 9, 0, 2, 1, 0], positions.len())
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>();
+        assert_eq!(indices, [2, 1, 0]);
+    }
+}
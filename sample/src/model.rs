@@ -1,7 +1,113 @@
 use crate::transform::Transform;
+use vulkan_renderer::glam::Mat4;
 
 #[derive(Default, Debug, Clone)]
 pub struct Model {
     pub mesh_uuid: String,
     pub transform: Transform,
 }
+
+/// Per-instance data stored in the scene's instance buffer
+#[derive(Default, Debug, Clone, Copy)]
+pub struct ObjectData {
+    pub model: Mat4,
+}
+
+/// Stable reference to a model of a [`Scene`](crate::scene::Scene), stops resolving once the
+/// model is despawned even if its slot is reused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ModelHandle {
+    index: u32,
+    generation: u32,
+}
+
+#[derive(Debug, Clone)]
+struct ModelSlot {
+    generation: u32,
+    model: Option<Model>,
+}
+
+/// Generational slots of models, removing one doesn't move the others
+///
+/// Freed slots are reused with a bumped generation so old handles can't reach the new model
+#[derive(Default, Debug, Clone)]
+pub struct ModelSlots {
+    slots: Vec<ModelSlot>,
+    free: Vec<u32>,
+}
+
+impl ModelSlots {
+    pub fn insert(&mut self, model: Model) -> ModelHandle {
+        match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.model = Some(model);
+                ModelHandle {
+                    index,
+                    generation: slot.generation,
+                }
+            }
+            None => {
+                self.slots.push(ModelSlot {
+                    generation: 0,
+                    model: Some(model),
+                });
+                ModelHandle {
+                    index: self.slots.len() as u32 - 1,
+                    generation: 0,
+                }
+            }
+        }
+    }
+
+    /// Returns `None` if the handle was already removed
+    pub fn remove(&mut self, handle: ModelHandle) -> Option<Model> {
+        let slot = self.slots.get_mut(handle.index as usize)?;
+        if slot.generation != handle.generation {
+            return None;
+        }
+        let model = slot.model.take()?;
+        slot.generation += 1;
+        self.free.push(handle.index);
+        Some(model)
+    }
+
+    #[allow(dead_code)]
+    pub fn get(&self, handle: ModelHandle) -> Option<&Model> {
+        self.slots
+            .get(handle.index as usize)
+            .filter(|slot| slot.generation == handle.generation)?
+            .model
+            .as_ref()
+    }
+
+    /// The live models in slot order
+    pub fn iter(&self) -> impl Iterator<Item = &Model> {
+        self.slots.iter().filter_map(|slot| slot.model.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_handle_does_not_reach_reused_slot() {
+        let mut models = ModelSlots::default();
+        let helmet = models.insert(Model {
+            mesh_uuid: "Helmet/0".to_owned(),
+            ..Default::default()
+        });
+        assert!(models.remove(helmet).is_some());
+        assert!(models.remove(helmet).is_none());
+
+        let sphere = models.insert(Model {
+            mesh_uuid: "Sphere/0".to_owned(),
+            ..Default::default()
+        });
+        assert_ne!(helmet, sphere);
+        assert!(models.get(helmet).is_none());
+        assert_eq!(models.get(sphere).unwrap().mesh_uuid, "Sphere/0");
+        assert_eq!(models.iter().count(), 1);
+    }
+}
@@ -1,15 +1,26 @@
 use crate::{
+    bounds::BoundingSphere,
     camera::{Camera, CameraData},
     frame_data::FrameData,
-    macros::U8Slice,
-    mesh::{Mesh, MeshPushConstants},
-    model::Model,
+    light::Light,
+    material::MaterialLayout,
+    mesh::{GltfTextures, Mesh},
+    model::{Model, ModelHandle, ModelSlots, ObjectData},
+    transform::Transform,
 };
-use ash::vk::{PipelineBindPoint, PipelineLayout, ShaderStageFlags};
-use glam::{Mat4, Vec3, Vec4};
+use ash::vk::{Extent2D, Handle, Offset2D, PipelineBindPoint, Rect2D, Viewport};
 use std::{collections::HashMap, mem::size_of};
-use vulkan_renderer::{buffer::VBuffer, cmd::*, device::VDevice, utils::pad_uniform_buffer_size};
+use vulkan_renderer::{
+    buffer::VBuffer,
+    cmd::*,
+    device::VDevice,
+    glam::{Mat4, Vec4},
+    object_buffer::VObjectBuffer,
+    pipeline::VGraphicsPipeline,
+    utils::AsBytes,
+};
 
+#[repr(C)]
 #[derive(Default, Debug, Clone, Copy)]
 pub struct SceneData {
     pub fog_color: Vec4,
@@ -17,56 +28,354 @@ pub struct SceneData {
     pub ambient_color: Vec4,
     pub sunlight_direction: Vec4,
     pub sunlight_color: Vec4,
+    /// Position in xyz, range in w with 0 meaning unlimited
+    pub point_light_position: Vec4,
+    /// Color in rgb, intensity in w
+    pub point_light_color: Vec4,
 }
 
-#[derive(Default, Clone)]
+// SAFETY: `repr(C)` and made of `Vec4`s only, so there are no padding bytes
+unsafe impl AsBytes for SceneData {}
+
+impl SceneData {
+    /// Feeds the first directional and the first point light of `lights` into the uniforms
+    ///
+    /// The intensity of the sunlight ends up in the w of its color
+    pub fn apply_lights(&mut self, lights: &[Light]) {
+        if let Some(&Light::Directional {
+            direction,
+            color,
+            intensity,
+        }) = lights
+            .iter()
+            .find(|light| matches!(light, Light::Directional { .. }))
+        {
+            self.sunlight_direction = direction.extend(0.0);
+            self.sunlight_color = color.extend(intensity);
+        }
+        if let Some(&Light::Point {
+            position,
+            color,
+            intensity,
+            range,
+        }) = lights
+            .iter()
+            .find(|light| matches!(light, Light::Point { .. }))
+        {
+            self.point_light_position = position.extend(range.unwrap_or(0.0));
+            self.point_light_color = color.extend(intensity);
+        }
+    }
+}
+
+/// The instances of one mesh, drawn with a single call
+#[derive(Debug)]
+pub struct DrawBatch<'a> {
+    pub mesh: &'a Mesh,
+    pub objects: Vec<ObjectData>,
+}
+
+#[derive(Default)]
 pub struct Scene {
     pub camera: Camera,
     pub meshes: HashMap<String, Mesh>,
-    pub models: Vec<Model>,
+    /// The textures of each loaded glTF file, shared by its meshes' materials
+    pub textures: Vec<GltfTextures>,
+    pub models: ModelSlots,
+    /// Cameras and lights authored in the loaded glTF files, in world space
+    pub cameras: Vec<Camera>,
+    pub lights: Vec<Light>,
 
     pub scene_data: SceneData,
-    pub scene_buffer: VBuffer,
+    pub camera_buffer: VObjectBuffer<CameraData>,
+    pub scene_buffer: VObjectBuffer<SceneData>,
+    /// Per-instance vertex buffer with `max_instances` [`ObjectData`] slots per frame
+    pub instance_buffer: VBuffer,
+    pub max_instances: usize,
 }
 
 impl Scene {
+    /// Destroys the meshes, their textures and the buffers, the GPU must be done with them
+    pub fn destroy(&self, device: &VDevice) {
+        for mesh in self.meshes.values() {
+            mesh.destroy(device);
+        }
+        for textures in &self.textures {
+            textures.destroy(device);
+        }
+        self.camera_buffer.destroy(device);
+        self.scene_buffer.destroy(device);
+        self.instance_buffer.destroy(device);
+    }
+
     pub fn new(
         camera: Camera,
         scene_data: SceneData,
-        scene_buffer: VBuffer,
-        meshes: HashMap<String, Mesh>,
+        camera_buffer: VObjectBuffer<CameraData>,
+        scene_buffer: VObjectBuffer<SceneData>,
+        instance_buffer: VBuffer,
+        max_instances: usize,
     ) -> Self {
         Self {
             camera,
-            meshes,
             scene_data,
+            camera_buffer,
             scene_buffer,
+            instance_buffer,
+            max_instances,
             ..Default::default()
         }
     }
 
-    pub fn add_models(&mut self, mut models: Vec<Model>) {
-        self.models.append(&mut models);
+    /// Imports every primitive of a glTF file under the key `{name_prefix}/{mesh index}/{primitive index}`,
+    /// along with the cameras and lights placed in its default scene
+    ///
+    /// Meshes keep the world transform of every node placing them. Returns the keys of the loaded
+    /// meshes in file order
+    pub fn load_gltf(
+        &mut self,
+        device: &VDevice,
+        material_layout: MaterialLayout,
+        path: &str,
+        name_prefix: &str,
+    ) -> gltf::Result<Vec<String>> {
+        let (document, buffers, images) = gltf::import(path)?;
+        let mut node_transforms = HashMap::new();
+        if let Some(scene) = document
+            .default_scene()
+            .or_else(|| document.scenes().next())
+        {
+            for node in scene.nodes() {
+                self.import_node(&node, Mat4::IDENTITY, &mut node_transforms);
+            }
+        }
+
+        let textures = GltfTextures::new(device, &document, &images);
+        let mut keys = Vec::new();
+        for mesh in document.meshes() {
+            let node_transforms = node_transforms
+                .get(&mesh.index())
+                .map_or(&[][..], Vec::as_slice);
+            let sub_meshes = Mesh::from_gltf(
+                device,
+                material_layout,
+                &textures,
+                &mesh,
+                &buffers,
+                node_transforms,
+            );
+            for (primitive_index, sub_mesh) in sub_meshes.into_iter().enumerate() {
+                let key = format!("{}/{}/{}", name_prefix, mesh.index(), primitive_index);
+                self.meshes.insert(key.clone(), sub_mesh);
+                keys.push(key);
+            }
+        }
+        self.textures.push(textures);
+        Ok(keys)
+    }
+
+    /// Collects the cameras and lights below `node`, and the world transforms placing each mesh
+    fn import_node(
+        &mut self,
+        node: &gltf::Node,
+        parent: Mat4,
+        node_transforms: &mut HashMap<usize, Vec<Mat4>>,
+    ) {
+        let world = parent * Mat4::from_cols_array_2d(&node.transform().matrix());
+        if let Some(mesh) = node.mesh() {
+            node_transforms.entry(mesh.index()).or_default().push(world);
+        }
+        if let Some(camera) = node.camera() {
+            self.cameras.push(Camera::from_gltf(&camera, world));
+        }
+        if let Some(light) = node.light() {
+            self.lights.push(Light::from_gltf(&light, world));
+        }
+        for child in node.children() {
+            self.import_node(&child, world, node_transforms);
+        }
+    }
+
+    /// Adds a model drawing the mesh registered under `mesh_key`
+    pub fn instantiate(&mut self, mesh_key: &str, transform: Transform) -> ModelHandle {
+        self.spawn(Model {
+            mesh_uuid: mesh_key.to_owned(),
+            transform,
+        })
+    }
+
+    /// Adds `model` to the scene, the handle stays valid until it's despawned
+    pub fn spawn(&mut self, model: Model) -> ModelHandle {
+        self.models.insert(model)
+    }
+
+    /// Removes the model from the scene, returns `None` if it was already despawned
+    pub fn despawn(&mut self, handle: ModelHandle) -> Option<Model> {
+        self.models.remove(handle)
     }
 
     pub fn get_mesh(&self, model: &Model) -> Option<&Mesh> {
         self.meshes.get(&model.mesh_uuid)
     }
 
-    pub fn draw(&self, device: &VDevice, pipeline_layout: PipelineLayout, frame_data: &FrameData) {
-        for model in &self.models {
-            let mesh = if let Some(mesh) = self.get_mesh(model) {
-                mesh
-            } else {
-                eprintln!("Failed to find the mesh for the model {}.", model.mesh_uuid);
-                continue;
-            };
+    /// Points the camera at the whole scene, does nothing if there is nothing to frame
+    pub fn frame_camera(&mut self) {
+        if let Some(bounds) = self.bounds() {
+            self.camera.frame(bounds);
+        }
+    }
+
+    /// World space bounds of every model whose mesh is loaded
+    pub fn bounds(&self) -> Option<BoundingSphere> {
+        self.models
+            .iter()
+            .filter_map(|model| Some((model, self.get_mesh(model)?)))
+            .flat_map(|(model, mesh)| {
+                self.model_matrices(model)
+                    .into_iter()
+                    .map(|matrix| mesh.bounding_sphere().transformed(matrix))
+            })
+            .reduce(|bounds, sphere| bounds.union(&sphere))
+    }
+
+    /// The instance data of every model grouped by mesh key, in order of first appearance
+    ///
+    /// A model gets one instance per node placing its mesh
+    pub fn instance_batches(&self) -> Vec<(&str, Vec<ObjectData>)> {
+        let mut batches: Vec<(&str, Vec<ObjectData>)> = Vec::new();
+        for model in self.models.iter() {
+            let objects = self
+                .model_matrices(model)
+                .into_iter()
+                .map(|model| ObjectData { model });
+            match batches
+                .iter_mut()
+                .find(|(mesh_key, _)| *mesh_key == model.mesh_uuid)
+            {
+                Some((_, batch)) => batch.extend(objects),
+                None => batches.push((&model.mesh_uuid, objects.collect())),
+            }
+        }
+        batches
+    }
+
+    /// The instance batches of every loaded mesh, sorted so batches sharing a material are adjacent
+    ///
+    /// The scene has a single pipeline, so the material is the only state that changes between draws
+    pub fn draw_batches(&self) -> Vec<DrawBatch<'_>> {
+        let mut batches = self
+            .instance_batches()
+            .into_iter()
+            .filter_map(|(mesh_key, objects)| match self.meshes.get(mesh_key) {
+                Some(mesh) => Some(DrawBatch { mesh, objects }),
+                None => {
+                    eprintln!("Failed to find the mesh {}.", mesh_key);
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        batches.sort_by_key(|batch| batch.mesh.material.descriptor_set.as_raw());
+        batches
+    }
+
+    /// The model's transform applied after each node transform of its mesh, just the model's
+    /// transform if the mesh isn't loaded or placed by any node
+    fn model_matrices(&self, model: &Model) -> Vec<Mat4> {
+        let matrix = model.transform.matrix();
+        match self.get_mesh(model) {
+            Some(mesh) if !mesh.node_transforms.is_empty() => mesh
+                .node_transforms
+                .iter()
+                .map(|node_transform| matrix * *node_transform)
+                .collect(),
+            _ => vec![matrix],
+        }
+    }
+
+    /// Draws the scene from the scene's own camera into the given viewport
+    pub fn draw(
+        &self,
+        device: &VDevice,
+        pipeline: &VGraphicsPipeline,
+        frame_data: &FrameData,
+        viewport: Viewport,
+    ) {
+        self.draw_view(device, pipeline, frame_data, 0, &self.camera, viewport);
+    }
+
+    /// Draws the scene from `camera`, restricted to `viewport`
+    ///
+    /// Each view needs its own `view_index` so the camera data of earlier views in the same frame isn't overwritten
+    pub fn draw_view(
+        &self,
+        device: &VDevice,
+        pipeline: &VGraphicsPipeline,
+        frame_data: &FrameData,
+        view_index: usize,
+        camera: &Camera,
+        viewport: Viewport,
+    ) {
+        frame_data.debug_assert_writable();
+
+        cmd_bind_graphics_pipeline(device, frame_data.command_buffer, pipeline);
+        cmd_set_viewport(device, frame_data.command_buffer, &[viewport]);
+        cmd_set_scissor(
+            device,
+            frame_data.command_buffer,
+            &[Self::viewport_scissor(viewport)],
+        );
+
+        // Written once per view, every instance of the view reads the same camera and scene data
+        let camera_data = CameraData {
+            view: camera.view_matrix(),
+            projection: camera.projection_matrix(viewport.width / viewport.height),
+        };
+        let camera_offset = self
+            .camera_buffer
+            .write_at(frame_data.frame_index, view_index, &[camera_data])
+            .expect("Failed to write camera buffer.")[0];
+        let scene_offset = self
+            .scene_buffer
+            .write(frame_data.frame_index, &[self.scene_data])
+            .expect("Failed to write scene buffer.")[0];
+
+        let batches = self.draw_batches();
+        let objects = batches
+            .iter()
+            .flat_map(|batch| batch.objects.iter().copied())
+            .collect::<Vec<_>>();
+        assert!(
+            objects.len() <= self.max_instances,
+            "The scene has {} instances but the instance buffer holds {}.",
+            objects.len(),
+            self.max_instances
+        );
+        let object_size = size_of::<ObjectData>() as u64;
+        let frame_offset = (frame_data.frame_index * self.max_instances) as u64 * object_size;
+        self.instance_buffer
+            .map_strided_memory(device, &objects, frame_offset, object_size)
+            .expect("Failed to write instance buffer.");
+
+        cmd_bind_descriptor_sets(
+            device,
+            frame_data.command_buffer,
+            PipelineBindPoint::GRAPHICS,
+            pipeline.pipeline_layout(),
+            0,
+            &[frame_data.desc_set],
+            &[camera_offset, scene_offset],
+        );
+
+        let mut bound_material = None;
+        let mut first_instance = 0;
+        for DrawBatch { mesh, objects } in &batches {
+            let instance_count = objects.len() as u32;
 
             cmd_bind_vertex_buffer(
                 device,
                 frame_data.command_buffer,
-                &[mesh.vertex_buffer.buffer()],
-                &[0],
+                &[mesh.vertex_buffer.buffer(), self.instance_buffer.buffer()],
+                &[0, frame_offset],
             );
             cmd_bind_index_buffer(
                 device,
@@ -75,59 +384,208 @@ impl Scene {
                 0,
             );
 
-            // Camera and Model
-            let view = Mat4::look_at_rh(
-                self.camera.position,
-                Vec3::new(0.0, 0.0, 0.0),
-                Vec3::new(0.0, 1.0, 0.0),
-            );
-            // let view = Mat4::from_translation(camera);
-            let mut projection =
-                Mat4::perspective_rh(70.0f32.to_radians(), 1920.0 / 1080.0, 0.1, 100.0);
-            projection.col_mut(1)[1] *= -1.0;
-            let camera_data = CameraData { view, projection };
-
-            frame_data
-                .camera_buffer
-                .map_memory(device, &[camera_data])
-                .expect("Failed to map memory.");
-
-            self.scene_buffer
-                .map_memory(device, &[self.scene_data])
-                .expect("Failed to map memory.");
-
-            let dynamic_offsets =
-                &[
-                    pad_uniform_buffer_size(device, size_of::<SceneData>() * frame_data.frame_index)
-                        as u32,
-                ];
-            cmd_bind_descriptor_sets(
+            let material = mesh.material.descriptor_set;
+            if bound_material != Some(material) {
+                cmd_bind_descriptor_sets(
+                    device,
+                    frame_data.command_buffer,
+                    PipelineBindPoint::GRAPHICS,
+                    pipeline.pipeline_layout(),
+                    1,
+                    &[material],
+                    &[],
+                );
+                bound_material = Some(material);
+            }
+
+            cmd_draw_indexed(
                 device,
                 frame_data.command_buffer,
-                PipelineBindPoint::GRAPHICS,
-                pipeline_layout,
-                &[frame_data.desc_set],
-                dynamic_offsets,
+                mesh.indices.len() as u32,
+                instance_count,
+                first_instance,
             );
+            first_instance += instance_count;
+        }
+    }
 
-            let mvp = Mat4::from_translation(model.transform.position)
-                * Mat4::from_rotation_y(model.transform.rotation.y);
-            let constants = MeshPushConstants { mvp };
+    /// The scissor covering exactly `viewport`
+    pub fn viewport_scissor(viewport: Viewport) -> Rect2D {
+        Rect2D {
+            offset: Offset2D {
+                x: viewport.x as i32,
+                y: viewport.y as i32,
+            },
+            extent: Extent2D {
+                width: viewport.width as u32,
+                height: viewport.height as u32,
+            },
+        }
+    }
+}
 
-            cmd_push_constants(
-                device,
-                frame_data.command_buffer,
-                pipeline_layout,
-                ShaderStageFlags::VERTEX,
-                constants.as_u8_slice(),
-            );
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Material;
+    use ash::vk::DescriptorSet;
+    use vulkan_renderer::glam::Vec3;
 
-            cmd_draw_indexed(
-                device,
-                frame_data.command_buffer,
-                mesh.indices.len() as u32,
-                1,
+    #[test]
+    fn batches_instances_by_mesh_in_first_seen_order() {
+        let mut scene = Scene::default();
+        for (mesh_key, x) in [("Helmet/0", -2.0), ("Box/0", 0.0), ("Helmet/0", 2.0)] {
+            scene.instantiate(
+                mesh_key,
+                Transform {
+                    position: Vec3::new(x, 0.0, 0.0),
+                    ..Default::default()
+                },
             );
         }
+
+        let batches = scene.instance_batches();
+        let keys = batches.iter().map(|(key, _)| *key).collect::<Vec<_>>();
+        assert_eq!(keys, ["Helmet/0", "Box/0"]);
+        let helmet_x = batches[0]
+            .1
+            .iter()
+            .map(|object| object.model.w_axis.x)
+            .collect::<Vec<_>>();
+        assert_eq!(helmet_x, [-2.0, 2.0]);
+    }
+
+    #[test]
+    fn instances_model_once_per_node_placing_its_mesh() {
+        let mut scene = Scene::default();
+        let mut mesh = Mesh::default();
+        mesh.node_transforms = vec![
+            Mat4::from_translation(Vec3::X),
+            Mat4::from_translation(-Vec3::X),
+        ];
+        scene.meshes.insert("Helmet/0".to_owned(), mesh);
+        scene.instantiate(
+            "Helmet/0",
+            Transform {
+                position: Vec3::Y,
+                ..Default::default()
+            },
+        );
+
+        let batches = scene.instance_batches();
+        let translations = batches[0]
+            .1
+            .iter()
+            .map(|object| object.model.w_axis.truncate())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            translations,
+            [Vec3::new(1.0, 1.0, 0.0), Vec3::new(-1.0, 1.0, 0.0)]
+        );
+    }
+
+    #[test]
+    fn composes_parent_transforms_of_every_node_placing_a_mesh() {
+        let json = r#"{
+            "asset": {"version": "2.0"},
+            "buffers": [{"byteLength": 36}],
+            "bufferViews": [{"buffer": 0, "byteLength": 36}],
+            "accessors": [
+                {"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                 "min": [0.0, 0.0, 0.0], "max": [1.0, 1.0, 0.0]}
+            ],
+            "meshes": [{"primitives": [{"attributes": {"POSITION": 0}}]}],
+            "nodes": [
+                {"children": [1, 2], "translation": [0.0, 1.0, 0.0]},
+                {"mesh": 0, "translation": [1.0, 0.0, 0.0]},
+                {"mesh": 0, "translation": [-1.0, 0.0, 0.0]}
+            ],
+            "scenes": [{"nodes": [0]}]
+        }"#;
+        let document = gltf::Gltf::from_slice(json.as_bytes()).unwrap().document;
+        let mut scene = Scene::default();
+        let mut node_transforms = HashMap::new();
+        let root = document.nodes().next().unwrap();
+        scene.import_node(&root, Mat4::IDENTITY, &mut node_transforms);
+
+        let translations = node_transforms[&0]
+            .iter()
+            .map(|transform| transform.w_axis.truncate())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            translations,
+            [Vec3::new(1.0, 1.0, 0.0), Vec3::new(-1.0, 1.0, 0.0)]
+        );
+    }
+
+    #[test]
+    fn feeds_first_lights_of_each_kind_into_scene_data() {
+        let mut scene_data = SceneData::default();
+        scene_data.apply_lights(&[
+            Light::Point {
+                position: Vec3::new(1.0, 2.0, 3.0),
+                color: Vec3::X,
+                intensity: 10.0,
+                range: None,
+            },
+            Light::Directional {
+                direction: -Vec3::Y,
+                color: Vec3::ONE,
+                intensity: 2.0,
+            },
+            Light::Directional {
+                direction: Vec3::X,
+                color: Vec3::Z,
+                intensity: 1.0,
+            },
+        ]);
+
+        assert_eq!(
+            scene_data.sunlight_direction,
+            Vec4::new(0.0, -1.0, 0.0, 0.0)
+        );
+        assert_eq!(scene_data.sunlight_color, Vec4::new(1.0, 1.0, 1.0, 2.0));
+        assert_eq!(
+            scene_data.point_light_position,
+            Vec4::new(1.0, 2.0, 3.0, 0.0)
+        );
+        assert_eq!(scene_data.point_light_color, Vec4::new(1.0, 0.0, 0.0, 10.0));
+    }
+
+    #[test]
+    fn skips_despawned_models_when_batching() {
+        let mut scene = Scene::default();
+        let handles = ["Helmet/0", "Box/0", "Helmet/0"]
+            .map(|mesh_key| scene.instantiate(mesh_key, Transform::default()));
+
+        assert_eq!(scene.despawn(handles[1]).unwrap().mesh_uuid, "Box/0");
+        let keys = scene
+            .instance_batches()
+            .iter()
+            .map(|(key, objects)| (*key, objects.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(keys, [("Helmet/0", 2)]);
+        assert!(scene.models.get(handles[2]).is_some());
+    }
+
+    #[test]
+    fn sorts_batches_so_shared_materials_are_adjacent() {
+        let mut scene = Scene::default();
+        for (mesh_key, material) in [("Helmet/0", 2), ("Box/0", 1), ("Helmet/1", 2), ("Box/1", 1)] {
+            let mut mesh = Mesh::default();
+            mesh.material = Material {
+                descriptor_set: DescriptorSet::from_raw(material),
+                ..Default::default()
+            };
+            scene.meshes.insert(mesh_key.to_owned(), mesh);
+            scene.instantiate(mesh_key, Transform::default());
+        }
+
+        let materials = scene
+            .draw_batches()
+            .iter()
+            .map(|batch| batch.mesh.material.descriptor_set.as_raw())
+            .collect::<Vec<_>>();
+        assert_eq!(materials, [1, 1, 2, 2]);
     }
 }
@@ -1,9 +1,71 @@
-use glam::{Quat, Vec3};
+use vulkan_renderer::glam::{EulerRot, Mat4, Quat, Vec3};
 
-#[derive(Default, Debug, Clone, Copy)]
+/// `rotation` holds Euler angles in radians, applied in yaw (Y), pitch (X), roll (Z) order
+///
+/// A non-identity `quaternion` takes precedence over `rotation`
+#[derive(Debug, Clone, Copy)]
 pub struct Transform {
     pub position: Vec3,
     pub scale: Vec3,
     pub rotation: Vec3,
     pub quaternion: Quat,
 }
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            position: Vec3::ZERO,
+            scale: Vec3::ONE,
+            rotation: Vec3::ZERO,
+            quaternion: Quat::IDENTITY,
+        }
+    }
+}
+
+impl Transform {
+    /// Scales, then rotates, then translates
+    pub fn matrix(&self) -> Mat4 {
+        Mat4::from_scale_rotation_translation(self.scale, self.orientation(), self.position)
+    }
+
+    fn orientation(&self) -> Quat {
+        if self.quaternion != Quat::IDENTITY {
+            self.quaternion
+        } else {
+            Quat::from_euler(
+                EulerRot::YXZ,
+                self.rotation.y,
+                self.rotation.x,
+                self.rotation.z,
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_PI_2;
+
+    #[test]
+    fn composes_translation_rotation_and_scale() {
+        let euler = Transform {
+            position: Vec3::new(1.0, 2.0, 3.0),
+            scale: Vec3::new(2.0, 1.0, 1.0),
+            rotation: Vec3::new(0.0, FRAC_PI_2, 0.0),
+            ..Default::default()
+        };
+        // Scaled to (2, 0, 0), yawed onto -Z, then moved
+        let point = euler.matrix().transform_point3(Vec3::X);
+        assert!(point.abs_diff_eq(Vec3::new(1.0, 2.0, 1.0), 1e-5));
+
+        let quaternion = Transform {
+            quaternion: Quat::from_rotation_x(FRAC_PI_2),
+            ..euler
+        };
+        let point = quaternion.matrix().transform_point3(Vec3::Y);
+        assert!(point.abs_diff_eq(Vec3::new(1.0, 2.0, 4.0), 1e-5));
+
+        assert_eq!(Transform::default().matrix(), Mat4::IDENTITY);
+    }
+}
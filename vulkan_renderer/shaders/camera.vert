@@ -0,0 +1,24 @@
+#version 450
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 normal;
+layout(location = 2) in vec2 uv;
+// Per-instance model matrix, one column per location
+layout(location = 3) in vec4 model0;
+layout(location = 4) in vec4 model1;
+layout(location = 5) in vec4 model2;
+layout(location = 6) in vec4 model3;
+
+layout(location = 0) out vec2 outUV;
+
+layout(set = 0, binding = 0) uniform CameraBuffer {
+    mat4 view;
+    mat4 proj;
+} CB;
+
+void main() {
+    outUV = uv;
+    mat4 model = mat4(model0, model1, model2, model3);
+    mat4 mvp = CB.proj * CB.view * model;
+    gl_Position = mvp * vec4(position, 1.0);
+}
@@ -0,0 +1,12 @@
+#version 450
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec4 model0;
+layout(location = 2) in vec4 model1;
+layout(location = 3) in vec4 model2;
+layout(location = 4) in vec4 model3;
+
+void main() {
+    mat4 model = mat4(model0, model1, model2, model3);
+    gl_Position = model * vec4(position, 1.0);
+}
@@ -0,0 +1,11 @@
+#version 450
+
+layout(location = 0) in vec3 position;
+
+layout(push_constant) uniform LightSpace {
+    mat4 view_projection;
+} light;
+
+void main() {
+    gl_Position = light.view_projection * vec4(position, 1.0);
+}
@@ -0,0 +1,9 @@
+#version 450
+
+layout(constant_id = 0) const int LEVEL = 255;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    out_color = vec4(float(LEVEL) / 255.0);
+}
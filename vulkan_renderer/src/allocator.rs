@@ -0,0 +1,185 @@
+use crate::{buffer::VBuffer, device::VDevice, impl_get, RendererResult};
+use ash::vk::{DeviceMemory, MemoryAllocateInfo, MemoryPropertyFlags, MemoryRequirements};
+use std::cell::RefCell;
+
+/// A range of a [`VAllocator`] block, bind resources at `offset`
+#[derive(Default, Debug, Clone, Copy)]
+pub struct VAllocation {
+    memory: DeviceMemory,
+    offset: u64,
+    size: u64,
+    /// Of the memory type the block was allocated from, which can have more than requested
+    property_flags: MemoryPropertyFlags,
+}
+
+impl_get!(VAllocation, memory, DeviceMemory);
+impl_get!(VAllocation, offset, u64);
+impl_get!(VAllocation, size, u64);
+impl_get!(VAllocation, property_flags, MemoryPropertyFlags);
+
+#[derive(Debug)]
+struct VMemoryBlock {
+    memory: DeviceMemory,
+    memory_type_index: u32,
+    size: u64,
+    used: u64,
+}
+
+/// Suballocates buffers and images from a few large blocks per memory type, so scenes with many
+/// resources stay under `maxMemoryAllocationCount`
+///
+/// Allocations are linear and only freed all at once by [`destroy`](Self::destroy), resources made
+/// with it must not free their own memory. Requests bigger than a block get a block of their own
+#[derive(Debug)]
+pub struct VAllocator {
+    block_size: u64,
+    blocks: RefCell<Vec<VMemoryBlock>>,
+}
+
+impl Default for VAllocator {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_BLOCK_SIZE)
+    }
+}
+
+impl VAllocator {
+    pub const DEFAULT_BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+
+    pub fn new(block_size: u64) -> Self {
+        Self {
+            block_size,
+            blocks: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Every allocation is aligned to `bufferImageGranularity` and `nonCoherentAtomSize` too, so
+    /// buffers and images can share a block and non-coherent ranges can be flushed on their own
+    pub fn allocate(
+        &self,
+        device: &VDevice,
+        memory_requirements: MemoryRequirements,
+        flags: MemoryPropertyFlags,
+    ) -> RendererResult<VAllocation> {
+        let (memory_type_index, property_flags) =
+            VBuffer::find_memory_type(memory_requirements, device.get_memory_properties(), flags)?;
+        let limits = device.get_device_properties().limits;
+        let alignment = memory_requirements
+            .alignment
+            .max(limits.buffer_image_granularity)
+            .max(limits.non_coherent_atom_size);
+        let size = Self::align_up(memory_requirements.size, alignment);
+
+        let mut blocks = self.blocks.borrow_mut();
+        let fits = |block: &VMemoryBlock| {
+            block.memory_type_index == memory_type_index
+                && Self::align_up(block.used, alignment) + size <= block.size
+        };
+        let block = match blocks.iter_mut().position(|block| fits(block)) {
+            Some(ind) => &mut blocks[ind],
+            None => {
+                let block_size = self.block_size.max(size);
+                let allocate_info = MemoryAllocateInfo {
+                    allocation_size: block_size,
+                    memory_type_index,
+                    ..Default::default()
+                };
+                let memory = unsafe { device.get().allocate_memory(&allocate_info, None)? };
+                blocks.push(VMemoryBlock {
+                    memory,
+                    memory_type_index,
+                    size: block_size,
+                    used: 0,
+                });
+                blocks.last_mut().expect("A block was just pushed.")
+            }
+        };
+        let offset = Self::align_up(block.used, alignment);
+        block.used = offset + size;
+        Ok(VAllocation {
+            memory: block.memory,
+            offset,
+            size,
+            property_flags,
+        })
+    }
+
+    /// Number of `DeviceMemory` allocations made so far
+    pub fn block_count(&self) -> usize {
+        self.blocks.borrow().len()
+    }
+
+    /// Frees every block, the resources bound to them must be destroyed first
+    pub fn destroy(&self, device: &VDevice) {
+        for block in self.blocks.borrow_mut().drain(..) {
+            unsafe { device.get().free_memory(block.memory, None) };
+        }
+    }
+
+    fn align_up(value: u64, alignment: u64) -> u64 {
+        value.div_ceil(alignment) * alignment
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{image::VImage, instance::VInstance, test_utils::create_window};
+    use ash::vk::{BufferUsageFlags, Extent3D, Format, ImageAspectFlags, ImageUsageFlags};
+
+    #[test]
+    fn suballocates_many_buffers_from_few_blocks() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let allocator = VAllocator::default();
+        let buffers = (0..10000)
+            .map(|_| {
+                VBuffer::new_with_allocator(
+                    &device,
+                    &allocator,
+                    256,
+                    BufferUsageFlags::UNIFORM_BUFFER,
+                    MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT,
+                )
+            })
+            .collect::<RendererResult<Vec<_>>>()?;
+        let image = VImage::new_with_allocator(
+            &device,
+            &allocator,
+            ImageUsageFlags::SAMPLED,
+            Format::R8G8B8A8_UNORM,
+            Extent3D {
+                width: 64,
+                height: 64,
+                depth: 1,
+            },
+            ImageAspectFlags::COLOR,
+            1,
+        )?;
+        assert!(
+            allocator.block_count() < 10,
+            "{} blocks",
+            allocator.block_count()
+        );
+        assert!(buffers
+            .windows(2)
+            .all(|pair| pair[0].memory() != pair[1].memory()
+                || pair[0].offset() + pair[0].allocation() <= pair[1].offset()));
+
+        // Each buffer maps its own range
+        buffers[1].map_memory(&device, &[7u32; 64])?;
+        assert_eq!(buffers[1].read_back::<u32>(&device)?, [7; 64]);
+
+        unsafe {
+            for buffer in &buffers {
+                device.get().destroy_buffer(buffer.buffer(), None);
+            }
+            device.get().destroy_image_view(image.image_view(), None);
+            device.get().destroy_image(image.image(), None);
+        }
+        allocator.destroy(&device);
+        assert_eq!(allocator.block_count(), 0);
+        Ok(())
+    }
+}
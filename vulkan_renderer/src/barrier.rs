@@ -0,0 +1,330 @@
+use crate::{device::VDevice, image::VImage, impl_get, RendererError, RendererResult};
+use ash::vk::{
+    AccessFlags, Buffer, BufferMemoryBarrier, CommandBuffer, DependencyFlags, ImageLayout,
+    ImageMemoryBarrier, ImageSubresourceRange, PipelineStageFlags, QUEUE_FAMILY_IGNORED,
+    WHOLE_SIZE,
+};
+
+/// An image layout transition with access masks and stages inferred from its layouts
+///
+/// The source side only waits on writes, reads need no memory dependency. Layouts without a
+/// known use, such as `GENERAL`, wait on all commands, [`src`](Self::src) and [`dst`](Self::dst)
+/// replace the inferred values when that is too coarse or wrong
+#[derive(Debug, Clone, Copy)]
+pub struct VImageBarrier {
+    barrier: ImageMemoryBarrier,
+    src_stage_mask: PipelineStageFlags,
+    dst_stage_mask: PipelineStageFlags,
+}
+
+impl VImageBarrier {
+    /// Transitions every mip level of `image`
+    pub fn transition(image: &VImage, old_layout: ImageLayout, new_layout: ImageLayout) -> Self {
+        let (src_access_mask, src_stage_mask) = Self::src_access(old_layout)
+            .unwrap_or((AccessFlags::MEMORY_WRITE, PipelineStageFlags::ALL_COMMANDS));
+        let (dst_access_mask, dst_stage_mask) = Self::dst_access(new_layout).unwrap_or((
+            AccessFlags::MEMORY_READ | AccessFlags::MEMORY_WRITE,
+            PipelineStageFlags::ALL_COMMANDS,
+        ));
+        let barrier = image.image_memory_barrier(
+            image.subresource_range(image.aspect_mask()),
+            (old_layout, new_layout),
+            (src_access_mask, dst_access_mask),
+        );
+        Self {
+            barrier,
+            src_stage_mask,
+            dst_stage_mask,
+        }
+    }
+
+    /// Like [`transition`](Self::transition), but fails instead of waiting on all commands when
+    /// either layout has no known use
+    pub fn known_transition(
+        image: &VImage,
+        old_layout: ImageLayout,
+        new_layout: ImageLayout,
+    ) -> RendererResult<Self> {
+        if Self::src_access(old_layout).is_none() || Self::dst_access(new_layout).is_none() {
+            return Err(RendererError::InvalidArgument(format!(
+                "No known access masks for a transition from {:?} to {:?}.",
+                old_layout, new_layout
+            )));
+        }
+        Ok(Self::transition(image, old_layout, new_layout))
+    }
+
+    pub fn subresource_range(mut self, subresource_range: ImageSubresourceRange) -> Self {
+        self.barrier.subresource_range = subresource_range;
+        self
+    }
+
+    /// Replaces the inferred accesses and stages that must finish before the transition
+    pub fn src(mut self, access_mask: AccessFlags, stage_mask: PipelineStageFlags) -> Self {
+        self.barrier.src_access_mask = access_mask;
+        self.src_stage_mask = stage_mask;
+        self
+    }
+
+    /// Replaces the inferred accesses and stages that wait for the transition
+    pub fn dst(mut self, access_mask: AccessFlags, stage_mask: PipelineStageFlags) -> Self {
+        self.barrier.dst_access_mask = access_mask;
+        self.dst_stage_mask = stage_mask;
+        self
+    }
+
+    pub fn record(&self, device: &VDevice, command_buffer: CommandBuffer) {
+        BarrierBatch::new()
+            .add_transition(*self)
+            .flush(device, command_buffer);
+    }
+
+    /// Writes to an image in `layout` that later work has to wait for
+    fn src_access(layout: ImageLayout) -> Option<(AccessFlags, PipelineStageFlags)> {
+        let access = match layout {
+            ImageLayout::UNDEFINED => (AccessFlags::empty(), PipelineStageFlags::TOP_OF_PIPE),
+            ImageLayout::PREINITIALIZED => (AccessFlags::HOST_WRITE, PipelineStageFlags::HOST),
+            ImageLayout::TRANSFER_DST_OPTIMAL => {
+                (AccessFlags::TRANSFER_WRITE, PipelineStageFlags::TRANSFER)
+            }
+            ImageLayout::TRANSFER_SRC_OPTIMAL => {
+                (AccessFlags::empty(), PipelineStageFlags::TRANSFER)
+            }
+            ImageLayout::SHADER_READ_ONLY_OPTIMAL => {
+                (AccessFlags::empty(), PipelineStageFlags::FRAGMENT_SHADER)
+            }
+            ImageLayout::COLOR_ATTACHMENT_OPTIMAL => (
+                AccessFlags::COLOR_ATTACHMENT_WRITE,
+                PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            ),
+            ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL => (
+                AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                PipelineStageFlags::EARLY_FRAGMENT_TESTS | PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            ),
+            ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL => (
+                AccessFlags::empty(),
+                PipelineStageFlags::EARLY_FRAGMENT_TESTS | PipelineStageFlags::FRAGMENT_SHADER,
+            ),
+            // Acquired images are waited on with a semaphore at the color attachment output stage
+            ImageLayout::PRESENT_SRC_KHR => (
+                AccessFlags::empty(),
+                PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            ),
+            _ => return None,
+        };
+        Some(access)
+    }
+
+    /// Reads and writes of an image in `layout` that have to wait for the transition
+    fn dst_access(layout: ImageLayout) -> Option<(AccessFlags, PipelineStageFlags)> {
+        let access = match layout {
+            ImageLayout::TRANSFER_DST_OPTIMAL => {
+                (AccessFlags::TRANSFER_WRITE, PipelineStageFlags::TRANSFER)
+            }
+            ImageLayout::TRANSFER_SRC_OPTIMAL => {
+                (AccessFlags::TRANSFER_READ, PipelineStageFlags::TRANSFER)
+            }
+            ImageLayout::SHADER_READ_ONLY_OPTIMAL => (
+                AccessFlags::SHADER_READ,
+                PipelineStageFlags::FRAGMENT_SHADER,
+            ),
+            ImageLayout::COLOR_ATTACHMENT_OPTIMAL => (
+                AccessFlags::COLOR_ATTACHMENT_READ | AccessFlags::COLOR_ATTACHMENT_WRITE,
+                PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            ),
+            ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL => (
+                AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                    | AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                PipelineStageFlags::EARLY_FRAGMENT_TESTS | PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            ),
+            ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL => (
+                AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | AccessFlags::SHADER_READ,
+                PipelineStageFlags::EARLY_FRAGMENT_TESTS | PipelineStageFlags::FRAGMENT_SHADER,
+            ),
+            // Presentation is synchronized by the semaphore passed to the present call
+            ImageLayout::PRESENT_SRC_KHR => {
+                (AccessFlags::empty(), PipelineStageFlags::BOTTOM_OF_PIPE)
+            }
+            _ => return None,
+        };
+        Some(access)
+    }
+}
+
+impl_get!(VImageBarrier, barrier, ImageMemoryBarrier);
+impl_get!(VImageBarrier, src_stage_mask, PipelineStageFlags);
+impl_get!(VImageBarrier, dst_stage_mask, PipelineStageFlags);
+
+/// Collects buffer and image barriers and records them with a single `cmd_pipeline_barrier`
+///
+/// The stage masks of every added barrier are combined, so only batch barriers that can wait on
+/// the same work
+#[derive(Default, Debug, Clone)]
+pub struct BarrierBatch {
+    src_stage_mask: PipelineStageFlags,
+    dst_stage_mask: PipelineStageFlags,
+    buffer_barriers: Vec<BufferMemoryBarrier>,
+    image_barriers: Vec<ImageMemoryBarrier>,
+}
+
+impl BarrierBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_buffer_barrier(
+        &mut self,
+        barrier: BufferMemoryBarrier,
+        src_stage_mask: PipelineStageFlags,
+        dst_stage_mask: PipelineStageFlags,
+    ) -> &mut Self {
+        self.src_stage_mask |= src_stage_mask;
+        self.dst_stage_mask |= dst_stage_mask;
+        self.buffer_barriers.push(barrier);
+        self
+    }
+
+    pub fn add_image_barrier(
+        &mut self,
+        barrier: ImageMemoryBarrier,
+        src_stage_mask: PipelineStageFlags,
+        dst_stage_mask: PipelineStageFlags,
+    ) -> &mut Self {
+        self.src_stage_mask |= src_stage_mask;
+        self.dst_stage_mask |= dst_stage_mask;
+        self.image_barriers.push(barrier);
+        self
+    }
+
+    pub fn add_transition(&mut self, transition: VImageBarrier) -> &mut Self {
+        self.add_image_barrier(
+            transition.barrier,
+            transition.src_stage_mask,
+            transition.dst_stage_mask,
+        )
+    }
+
+    /// Transitions every subresource of `image` with the masks inferred by
+    /// [`VImageBarrier::transition`]
+    pub fn transition_image(
+        &mut self,
+        image: &VImage,
+        old_layout: ImageLayout,
+        new_layout: ImageLayout,
+    ) -> &mut Self {
+        self.add_transition(VImageBarrier::transition(image, old_layout, new_layout))
+    }
+
+    /// Makes a transfer write to the whole of `buffer` visible to `dst_access_mask` in `dst_stage_mask`
+    pub fn after_buffer_upload(
+        &mut self,
+        buffer: Buffer,
+        dst_access_mask: AccessFlags,
+        dst_stage_mask: PipelineStageFlags,
+    ) -> &mut Self {
+        let barrier = BufferMemoryBarrier {
+            src_access_mask: AccessFlags::TRANSFER_WRITE,
+            dst_access_mask,
+            src_queue_family_index: QUEUE_FAMILY_IGNORED,
+            dst_queue_family_index: QUEUE_FAMILY_IGNORED,
+            buffer,
+            offset: 0,
+            size: WHOLE_SIZE,
+            ..Default::default()
+        };
+        self.add_buffer_barrier(barrier, PipelineStageFlags::TRANSFER, dst_stage_mask)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer_barriers.is_empty() && self.image_barriers.is_empty()
+    }
+
+    /// Records the collected barriers and empties the batch, does nothing if it's already empty
+    pub fn flush(&mut self, device: &VDevice, command_buffer: CommandBuffer) {
+        if self.is_empty() {
+            return;
+        }
+        unsafe {
+            device.get().cmd_pipeline_barrier(
+                command_buffer,
+                self.src_stage_mask,
+                self.dst_stage_mask,
+                DependencyFlags::empty(),
+                &[],
+                &self.buffer_barriers,
+                &self.image_barriers,
+            );
+        }
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn combines_stage_masks_of_batched_transitions() {
+        let image = VImage::default();
+        let mut batch = BarrierBatch::new();
+        batch
+            .transition_image(
+                &image,
+                ImageLayout::UNDEFINED,
+                ImageLayout::TRANSFER_DST_OPTIMAL,
+            )
+            .transition_image(
+                &image,
+                ImageLayout::TRANSFER_DST_OPTIMAL,
+                ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            );
+
+        assert_eq!(batch.image_barriers.len(), 2);
+        assert_eq!(
+            batch.src_stage_mask,
+            PipelineStageFlags::TOP_OF_PIPE | PipelineStageFlags::TRANSFER
+        );
+        assert_eq!(
+            batch.dst_stage_mask,
+            PipelineStageFlags::TRANSFER | PipelineStageFlags::FRAGMENT_SHADER
+        );
+    }
+
+    #[test]
+    fn infers_masks_of_swapchain_transitions_unless_overridden() {
+        let image = VImage::default();
+        let to_color = VImageBarrier::transition(
+            &image,
+            ImageLayout::UNDEFINED,
+            ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        );
+        assert_eq!(to_color.barrier().src_access_mask, AccessFlags::empty());
+        assert_eq!(
+            to_color.barrier().dst_access_mask,
+            AccessFlags::COLOR_ATTACHMENT_READ | AccessFlags::COLOR_ATTACHMENT_WRITE
+        );
+        assert_eq!(
+            to_color.dst_stage_mask(),
+            PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+        );
+
+        let to_present = VImageBarrier::transition(
+            &image,
+            ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            ImageLayout::PRESENT_SRC_KHR,
+        );
+        assert_eq!(
+            to_present.barrier().src_access_mask,
+            AccessFlags::COLOR_ATTACHMENT_WRITE
+        );
+        assert_eq!(
+            to_present.dst_stage_mask(),
+            PipelineStageFlags::BOTTOM_OF_PIPE
+        );
+
+        let manual = to_present.dst(AccessFlags::MEMORY_READ, PipelineStageFlags::ALL_COMMANDS);
+        assert_eq!(manual.barrier().dst_access_mask, AccessFlags::MEMORY_READ);
+        assert_eq!(manual.dst_stage_mask(), PipelineStageFlags::ALL_COMMANDS);
+        assert_eq!(manual.src_stage_mask(), to_present.src_stage_mask());
+    }
+}
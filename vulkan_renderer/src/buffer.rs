@@ -1,20 +1,88 @@
 use crate::{
-    cmd::*, command_pool::VCommandPool, device::VDevice, enums::EOperationType, impl_get,
+    allocator::VAllocator, barrier::VImageBarrier, cmd::begin_command_buffer,
+    command_pool::VCommandPool, device::VDevice, enums::EOperationType, error::MemoryError,
+    image::VImage, impl_get, impl_get_ref, sync::VFence, utils::AsBytes, RendererError,
     RendererResult,
 };
 use ash::vk::{
-    Buffer, BufferCopy, BufferCreateInfo, BufferUsageFlags, CommandBufferBeginInfo,
-    CommandBufferUsageFlags, CommandPoolCreateFlags, DeviceMemory, Fence, MemoryAllocateInfo,
-    MemoryMapFlags, MemoryPropertyFlags, MemoryRequirements, PhysicalDeviceMemoryProperties,
-    SharingMode, SubmitInfo,
+    AccessFlags, Buffer, BufferCopy, BufferCreateInfo, BufferImageCopy, BufferUsageFlags,
+    CommandBuffer, CommandBufferLevel, DeviceMemory, Extent3D, ImageLayout, ImageSubresourceLayers,
+    MappedMemoryRange, MemoryAllocateInfo, MemoryMapFlags, MemoryPropertyFlags, MemoryRequirements,
+    PhysicalDeviceMemoryProperties, PipelineStageFlags, SharingMode, SubmitInfo, WHOLE_SIZE,
 };
-use std::mem::size_of;
+use std::{
+    mem::{size_of, size_of_val},
+    ptr,
+};
+
+/// A copy submitted with a fence by [`VBuffer::copy_buffer_deferred`]
+///
+/// Keeps the staging buffers alive until [`wait`](Self::wait) or [`wait_all`](Self::wait_all)
+/// reports the copy as finished, then destroys them with the fence and the command buffer.
+/// One of them must be called, dropping a transfer leaks all of these
+#[derive(Debug)]
+#[must_use = "a transfer leaks its fence, command buffer and staging buffers unless waited on"]
+pub struct VTransfer {
+    fence: VFence,
+    command_pool: VCommandPool,
+    command_buffer: CommandBuffer,
+    staging_buffers: Vec<VBuffer>,
+}
+
+impl VTransfer {
+    pub fn fence(&self) -> VFence {
+        self.fence
+    }
+
+    pub fn wait(self, device: &VDevice) -> RendererResult<()> {
+        Self::wait_all(device, vec![self])
+    }
+
+    /// Waits for every transfer at once, then frees their resources
+    pub fn wait_all(device: &VDevice, transfers: Vec<VTransfer>) -> RendererResult<()> {
+        if transfers.is_empty() {
+            return Ok(());
+        }
+        let fences = transfers
+            .iter()
+            .map(|transfer| transfer.fence.get())
+            .collect::<Vec<_>>();
+        device.wait_for_fences(&fences, u64::MAX)?;
+
+        for transfer in transfers {
+            transfer.free(device);
+        }
+        Ok(())
+    }
+
+    /// Frees whatever was created so far, the GPU must not be using any of it
+    fn free(self, device: &VDevice) {
+        unsafe {
+            if self.command_buffer != CommandBuffer::null() {
+                device
+                    .get()
+                    .free_command_buffers(self.command_pool.get(), &[self.command_buffer]);
+            }
+            device.get().destroy_fence(self.fence.get(), None);
+        }
+        for buffer in self.staging_buffers {
+            buffer.destroy(device);
+        }
+    }
+}
 
-#[derive(Default, Debug, Clone, Copy)]
+#[derive(Default, Debug)]
 pub struct VBuffer {
     buffer: Buffer,
     memory: DeviceMemory,
+    /// Where the buffer is bound in `memory`, only non-zero for [`new_with_allocator`](Self::new_with_allocator)
+    offset: u64,
     allocation: u64,
+    size: u64,
+    /// Whether writes skip [`flush`](Self::flush) and reads skip [`invalidate`](Self::invalidate)
+    coherent: bool,
+    /// The memory belongs to a [`VAllocator`] and outlives the buffer
+    suballocated: bool,
 }
 // Create a staging buffer
 // Create a transient command buffer
@@ -29,17 +97,12 @@ impl VBuffer {
         usage: BufferUsageFlags,
         flags: MemoryPropertyFlags,
     ) -> RendererResult<Self> {
-        let buffer = Self::create_buffer(device, (data.len() * size_of::<T>()) as u64, usage)?;
-        let memory_requirements = Self::memory_requirements(device, buffer);
-        let memory = Self::create_memory(device, memory_requirements, flags)?;
-        unsafe { device.get().bind_buffer_memory(buffer, memory, 0)? };
-
-        let vbuffer = Self {
-            buffer,
-            memory,
-            allocation: memory_requirements.size,
-        };
-        vbuffer.map_memory(device, data)?;
+        let size = (data.len() * size_of::<T>()) as u64;
+        let vbuffer = Self::new_bound(device, size, usage, flags)?;
+        if let Err(err) = vbuffer.map_memory(device, data) {
+            vbuffer.destroy(device);
+            return Err(err);
+        }
 
         Ok(vbuffer)
     }
@@ -53,35 +116,106 @@ impl VBuffer {
         usage: BufferUsageFlags,
         flags: MemoryPropertyFlags,
     ) -> RendererResult<Self> {
-        let buffer = Self::create_buffer(device, (data.len() * size_of::<T>()) as u64, usage)?;
-        let memory_requirements = Self::memory_requirements(device, buffer);
-        let memory = Self::create_memory(device, memory_requirements, flags)?;
-        unsafe { device.get().bind_buffer_memory(buffer, memory, 0)? };
+        let size = (data.len() * size_of::<T>()) as u64;
+        Self::new_bound(device, size, usage, flags)
+    }
 
-        Ok(Self {
+    /// Maps the memory once, writes through [`VMappedBuffer::write`] then only copy
+    ///
+    /// Errors unless `flags` has `HOST_VISIBLE`
+    pub fn new_persistently_mapped(
+        device: &VDevice,
+        size: u64,
+        usage: BufferUsageFlags,
+        flags: MemoryPropertyFlags,
+    ) -> RendererResult<VMappedBuffer> {
+        if !flags.contains(MemoryPropertyFlags::HOST_VISIBLE) {
+            return Err(RendererError::InvalidArgument(format!(
+                "Only HOST_VISIBLE memory can be mapped, got {:?}.",
+                flags
+            )));
+        }
+        let buffer = Self::new_bound(device, size, usage, flags)?;
+        let ptr = match unsafe {
+            device
+                .get()
+                .map_memory(buffer.memory, 0, buffer.allocation, MemoryMapFlags::empty())
+        } {
+            Ok(ptr) => ptr,
+            Err(err) => {
+                buffer.destroy(device);
+                return Err(err.into());
+            }
+        };
+
+        Ok(VMappedBuffer {
             buffer,
-            memory,
-            allocation: memory_requirements.size,
+            ptr: ptr.cast(),
         })
     }
 
-    pub fn new_uniform_buffer(
+    /// Binds the buffer to a range of one of `allocator`'s blocks instead of its own memory
+    ///
+    /// Destroy only the buffer, the memory is freed by [`VAllocator::destroy`]. Buffers sharing a
+    /// block can't be mapped at the same time, which rules out persistent mapping
+    pub fn new_with_allocator(
         device: &VDevice,
+        allocator: &VAllocator,
         size: u64,
+        usage: BufferUsageFlags,
         flags: MemoryPropertyFlags,
     ) -> RendererResult<Self> {
-        let buffer = Self::create_buffer(device, size, BufferUsageFlags::UNIFORM_BUFFER)?;
-        let memory_requirements = Self::memory_requirements(device, buffer);
-        let memory = Self::create_memory(device, memory_requirements, flags)?;
-        unsafe { device.get().bind_buffer_memory(buffer, memory, 0)? };
+        let buffer = Self::create_buffer(device, size, usage)?;
+        let bound = allocator
+            .allocate(device, Self::memory_requirements(device, buffer), flags)
+            .and_then(|allocation| {
+                unsafe {
+                    device.get().bind_buffer_memory(
+                        buffer,
+                        allocation.memory(),
+                        allocation.offset(),
+                    )?
+                };
+                Ok(allocation)
+            });
+        let allocation = match bound {
+            Ok(allocation) => allocation,
+            Err(err) => {
+                unsafe { device.get().destroy_buffer(buffer, None) };
+                return Err(err);
+            }
+        };
 
         Ok(Self {
             buffer,
-            memory,
-            allocation: memory_requirements.size,
+            memory: allocation.memory(),
+            offset: allocation.offset(),
+            allocation: allocation.size(),
+            size,
+            coherent: allocation
+                .property_flags()
+                .contains(MemoryPropertyFlags::HOST_COHERENT),
+            suballocated: true,
         })
     }
 
+    pub fn new_uniform_buffer(
+        device: &VDevice,
+        size: u64,
+        flags: MemoryPropertyFlags,
+    ) -> RendererResult<Self> {
+        Self::new_bound(device, size, BufferUsageFlags::UNIFORM_BUFFER, flags)
+    }
+
+    /// Creates a buffer that shaders can read and write through a `STORAGE_BUFFER` descriptor
+    pub fn new_storage_buffer(
+        device: &VDevice,
+        size: u64,
+        flags: MemoryPropertyFlags,
+    ) -> RendererResult<Self> {
+        Self::new_bound(device, size, BufferUsageFlags::STORAGE_BUFFER, flags)
+    }
+
     pub fn new_device_local_buffer<T: Copy>(
         device: &VDevice,
         data: &[T],
@@ -94,24 +228,69 @@ impl VBuffer {
             MemoryPropertyFlags::HOST_COHERENT | MemoryPropertyFlags::HOST_VISIBLE,
         )?;
 
-        let vertex_buffer = Self::new_unmapped(
+        let vertex_buffer = match Self::new_unmapped(
             device,
             data,
             BufferUsageFlags::TRANSFER_DST | dst_usage,
             MemoryPropertyFlags::DEVICE_LOCAL,
-        )?;
+        ) {
+            Ok(vertex_buffer) => vertex_buffer,
+            Err(err) => {
+                staging_buffer.destroy(device);
+                return Err(err);
+            }
+        };
 
-        Self::copy_buffer(device, data, staging_buffer.buffer, vertex_buffer.buffer)?;
+        let copied = Self::copy_buffer(device, data, staging_buffer.buffer, vertex_buffer.buffer);
+        staging_buffer.destroy(device);
+        if let Err(err) = copied {
+            vertex_buffer.destroy(device);
+            return Err(err);
+        }
 
         Ok(vertex_buffer)
     }
 
+    /// Creates a buffer bound to its own memory, destroying what was created if a step fails
+    fn new_bound(
+        device: &VDevice,
+        size: u64,
+        usage: BufferUsageFlags,
+        flags: MemoryPropertyFlags,
+    ) -> RendererResult<Self> {
+        let buffer = Self::create_buffer(device, size, usage)?;
+        let memory_requirements = Self::memory_requirements(device, buffer);
+        let (memory, property_flags) =
+            match Self::allocate_memory(device, memory_requirements, flags) {
+                Ok(allocated) => allocated,
+                Err(err) => {
+                    unsafe { device.get().destroy_buffer(buffer, None) };
+                    return Err(err);
+                }
+            };
+        let vbuffer = Self {
+            buffer,
+            memory,
+            offset: 0,
+            allocation: memory_requirements.size,
+            size,
+            coherent: property_flags.contains(MemoryPropertyFlags::HOST_COHERENT),
+            suballocated: false,
+        };
+        if let Err(err) = unsafe { device.get().bind_buffer_memory(buffer, memory, 0) } {
+            vbuffer.destroy(device);
+            return Err(err.into());
+        }
+        Ok(vbuffer)
+    }
+
     pub fn create_buffer(
         device: &VDevice,
         size: u64,
         usage: BufferUsageFlags,
     ) -> RendererResult<Buffer> {
-        let create_info = Self::buffer_create_info(size, usage);
+        let queue_family_indices = device.buffer_queue_family_indices();
+        let create_info = Self::buffer_create_info(size, usage, &queue_family_indices);
         unsafe { Ok(device.get().create_buffer(&create_info, None)?) }
     }
 
@@ -120,13 +299,20 @@ impl VBuffer {
         memory_requirements: MemoryRequirements,
         flags: MemoryPropertyFlags,
     ) -> RendererResult<DeviceMemory> {
-        let mem_type_ind = Self::find_memory_type_index(
-            memory_requirements,
-            device.get_memory_properties(),
-            flags,
-        );
+        Ok(Self::allocate_memory(device, memory_requirements, flags)?.0)
+    }
+
+    /// Also returns the property flags of the memory type the memory came from
+    fn allocate_memory(
+        device: &VDevice,
+        memory_requirements: MemoryRequirements,
+        flags: MemoryPropertyFlags,
+    ) -> RendererResult<(DeviceMemory, MemoryPropertyFlags)> {
+        let (mem_type_ind, property_flags) =
+            Self::find_memory_type(memory_requirements, device.get_memory_properties(), flags)?;
         let allocate_info = Self::memory_allocate_info(mem_type_ind, memory_requirements.size);
-        Ok(unsafe { device.get().allocate_memory(&allocate_info, None)? })
+        let memory = unsafe { device.get().allocate_memory(&allocate_info, None)? };
+        Ok((memory, property_flags))
     }
 
     pub fn copy_buffer<T>(
@@ -135,38 +321,197 @@ impl VBuffer {
         src: Buffer,
         dst: Buffer,
     ) -> RendererResult<()> {
-        let command_pool = VCommandPool::new(
+        let transfer = Self::submit_deferred(
             device,
-            device.get_queue_family_index(EOperationType::Graphics),
-            CommandPoolCreateFlags::TRANSIENT,
+            EOperationType::Transfer,
+            Vec::new(),
+            |command_buffer| unsafe {
+                let region = *BufferCopy::builder().size((data.len() * size_of::<T>()) as u64);
+                device
+                    .get()
+                    .cmd_copy_buffer(command_buffer, src, dst, &[region]);
+            },
         )?;
-        let command_buffer = allocate_command_buffers(device, command_pool.get(), 1)?[0];
+        transfer.wait(device)
+    }
+
+    /// Same as [`copy_buffer`](Self::copy_buffer) but doesn't wait for the copy to finish
+    ///
+    /// Takes ownership of the staging buffer `src`, which is destroyed once the returned transfer
+    /// is waited on, so many uploads can be in flight at once
+    pub fn copy_buffer_deferred<T>(
+        device: &VDevice,
+        data: &[T],
+        src: VBuffer,
+        dst: Buffer,
+    ) -> RendererResult<VTransfer> {
+        let src_buffer = src.buffer;
+        Self::submit_deferred(
+            device,
+            EOperationType::Transfer,
+            vec![src],
+            |command_buffer| unsafe {
+                let region = *BufferCopy::builder().size(size_of_val(data) as u64);
+                device
+                    .get()
+                    .cmd_copy_buffer(command_buffer, src_buffer, dst, &[region]);
+            },
+        )
+    }
+
+    /// Copies `src` into the whole first mip level of `image` and leaves it in `final_layout`
+    ///
+    /// The image's previous contents are discarded
+    pub fn copy_buffer_to_image(
+        device: &VDevice,
+        src: Buffer,
+        image: &VImage,
+        extent: Extent3D,
+        final_layout: ImageLayout,
+    ) -> RendererResult<()> {
+        Self::submit_one_time(device, |command_buffer| {
+            VImageBarrier::transition(
+                image,
+                ImageLayout::UNDEFINED,
+                ImageLayout::TRANSFER_DST_OPTIMAL,
+            )
+            .record(device, command_buffer);
+            let region = BufferImageCopy {
+                image_subresource: ImageSubresourceLayers {
+                    aspect_mask: image.aspect_mask(),
+                    mip_level: 0,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                },
+                image_extent: extent,
+                ..Default::default()
+            };
+            unsafe {
+                device.get().cmd_copy_buffer_to_image(
+                    command_buffer,
+                    src,
+                    image.image(),
+                    ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &[region],
+                );
+            }
+            VImageBarrier::transition(image, ImageLayout::TRANSFER_DST_OPTIMAL, final_layout)
+                .record(device, command_buffer);
+        })
+    }
+
+    /// Copies the whole first mip level of `image`, which must be in `TRANSFER_SRC_OPTIMAL`, into `dst`
+    ///
+    /// Waits for every earlier write to the image, e.g. by the render pass that left it in that layout
+    pub fn copy_image_to_buffer(
+        device: &VDevice,
+        image: &VImage,
+        dst: Buffer,
+    ) -> RendererResult<()> {
+        Self::submit_one_time(device, |command_buffer| {
+            VImageBarrier::transition(
+                image,
+                ImageLayout::TRANSFER_SRC_OPTIMAL,
+                ImageLayout::TRANSFER_SRC_OPTIMAL,
+            )
+            .subresource_range(image.mip_subresource_range(0))
+            .src(AccessFlags::MEMORY_WRITE, PipelineStageFlags::ALL_COMMANDS)
+            .record(device, command_buffer);
+            Self::record_image_to_buffer_copy(device, command_buffer, image, dst);
+        })
+    }
 
+    /// Records a tightly packed copy of the first mip level of `image` in `TRANSFER_SRC_OPTIMAL`
+    pub(crate) fn record_image_to_buffer_copy(
+        device: &VDevice,
+        command_buffer: CommandBuffer,
+        image: &VImage,
+        dst: Buffer,
+    ) {
+        let region = BufferImageCopy {
+            image_subresource: ImageSubresourceLayers {
+                aspect_mask: image.aspect_mask(),
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+            image_extent: image.extent(),
+            ..Default::default()
+        };
         unsafe {
-            device.get().begin_command_buffer(
+            device.get().cmd_copy_image_to_buffer(
                 command_buffer,
-                &CommandBufferBeginInfo::builder().flags(CommandBufferUsageFlags::ONE_TIME_SUBMIT),
-            )?;
+                image.image(),
+                ImageLayout::TRANSFER_SRC_OPTIMAL,
+                dst,
+                &[region],
+            );
+        }
+    }
 
-            let region = *BufferCopy::builder().size((data.len() * size_of::<T>()) as u64);
-            device
-                .get()
-                .cmd_copy_buffer(command_buffer, src, dst, &[region]);
+    /// Records a one-time command buffer for the graphics queue with `record`, submits it and
+    /// waits for it to finish
+    ///
+    /// Used for work on images, which stay owned by the graphics family
+    pub(crate) fn submit_one_time(
+        device: &VDevice,
+        record: impl FnOnce(CommandBuffer),
+    ) -> RendererResult<()> {
+        Self::submit_deferred(device, EOperationType::Graphics, Vec::new(), record)?.wait(device)
+    }
+
+    /// Records a one-time command buffer for the queue of `operation_type` with `record` and
+    /// submits it with a fence, `staging_buffers` are destroyed once the returned transfer is
+    /// waited on, or right away if the submission fails
+    fn submit_deferred(
+        device: &VDevice,
+        operation_type: EOperationType,
+        staging_buffers: Vec<VBuffer>,
+        record: impl FnOnce(CommandBuffer),
+    ) -> RendererResult<VTransfer> {
+        let mut transfer = VTransfer {
+            fence: VFence::default(),
+            command_pool: VCommandPool::default(),
+            command_buffer: CommandBuffer::null(),
+            staging_buffers,
+        };
+        match Self::record_and_submit(device, operation_type, &mut transfer, record) {
+            Ok(()) => Ok(transfer),
+            Err(err) => {
+                transfer.free(device);
+                Err(err)
+            }
+        }
+    }
 
+    /// Fills in `transfer`'s handles as they're created, so a failed step can free the earlier ones
+    fn record_and_submit(
+        device: &VDevice,
+        operation_type: EOperationType,
+        transfer: &mut VTransfer,
+        record: impl FnOnce(CommandBuffer),
+    ) -> RendererResult<()> {
+        transfer.command_pool = device.one_time_command_pool(operation_type)?;
+        transfer.command_buffer =
+            transfer
+                .command_pool
+                .allocate(device, CommandBufferLevel::PRIMARY, 1)?[0];
+        transfer.fence = VFence::new(device, false)?;
+        let command_buffer = transfer.command_buffer;
+
+        begin_command_buffer(device, command_buffer)?;
+        unsafe {
+            record(command_buffer);
             device.get().end_command_buffer(command_buffer)?;
 
             let command_buffers = &[command_buffer];
             let submit_info = *SubmitInfo::builder().command_buffers(command_buffers);
             device.get().queue_submit(
-                device.get_queue(EOperationType::Graphics),
+                device.get_queue(operation_type),
                 &[submit_info],
-                Fence::null(),
+                transfer.fence.get(),
             )?;
-            device
-                .get()
-                .queue_wait_idle(device.get_queue(EOperationType::Graphics))?;
         };
-
         Ok(())
     }
 
@@ -174,14 +519,15 @@ impl VBuffer {
         unsafe {
             let ptr = device.get().map_memory(
                 self.memory,
-                0,
+                self.offset,
                 self.allocation,
                 MemoryMapFlags::empty(),
             )?;
             std::ptr::copy_nonoverlapping(data.as_ptr(), ptr.cast(), data.len());
+            let flushed = self.flush(device, 0, WHOLE_SIZE);
             device.get().unmap_memory(self.memory);
-        };
-        Ok(())
+            flushed
+        }
     }
 
     pub fn map_padded_memory<T: Copy>(
@@ -193,22 +539,153 @@ impl VBuffer {
         unsafe {
             let ptr = device.get().map_memory(
                 self.memory,
-                0,
+                self.offset,
                 self.allocation,
                 MemoryMapFlags::empty(),
             )?;
             let ptr = ptr.offset(pad_offset);
             std::ptr::copy_nonoverlapping(data.as_ptr(), ptr.cast(), data.len());
+            let flushed = self.flush(device, 0, WHOLE_SIZE);
             device.get().unmap_memory(self.memory);
-        };
+            flushed
+        }
+    }
+
+    /// Copies each element of `data` to `offset + i * stride`
+    pub fn map_strided_memory<T: Copy>(
+        &self,
+        device: &VDevice,
+        data: &[T],
+        offset: u64,
+        stride: u64,
+    ) -> RendererResult<()> {
+        unsafe {
+            let ptr = device.get().map_memory(
+                self.memory,
+                self.offset,
+                self.allocation,
+                MemoryMapFlags::empty(),
+            )?;
+            for (ind, element) in data.iter().enumerate() {
+                let dst = ptr.offset((offset + ind as u64 * stride) as isize);
+                std::ptr::copy_nonoverlapping(element, dst.cast(), 1);
+            }
+            let flushed = self.flush(device, 0, WHOLE_SIZE);
+            device.get().unmap_memory(self.memory);
+            flushed
+        }
+    }
+
+    /// Copies the buffer's contents back to the host as `size / size_of::<T>()` elements
+    ///
+    /// The memory must be `HOST_VISIBLE` and the GPU must be done writing to it, errors for
+    /// zero-sized `T`
+    pub fn read_back<T: AsBytes>(&self, device: &VDevice) -> RendererResult<Vec<T>> {
+        if size_of::<T>() == 0 {
+            return Err(RendererError::InvalidArgument(
+                "Can't read a buffer back as zero-sized elements.".to_owned(),
+            ));
+        }
+        let count = self.size as usize / size_of::<T>();
+        unsafe {
+            let ptr = device.get().map_memory(
+                self.memory,
+                self.offset,
+                self.allocation,
+                MemoryMapFlags::empty(),
+            )?;
+            let data = self.invalidate(device, 0, WHOLE_SIZE).map(|_| {
+                let mut elements = Vec::with_capacity(count);
+                ptr::copy_nonoverlapping(ptr.cast::<T>(), elements.as_mut_ptr(), count);
+                elements.set_len(count);
+                elements
+            });
+            device.get().unmap_memory(self.memory);
+            data
+        }
+    }
+
+    /// Destroys the buffer and frees its memory unless it came from a [`VAllocator`]
+    ///
+    /// The GPU must be done with it and nothing else may destroy it
+    pub fn destroy(&self, device: &VDevice) {
+        unsafe {
+            device.get().destroy_buffer(self.buffer, None);
+            if !self.suballocated {
+                device.get().free_memory(self.memory, None);
+            }
+        }
+    }
+
+    /// Makes host writes to `size` bytes at `offset` visible to the device, a no-op for
+    /// `HOST_COHERENT` memory
+    ///
+    /// The memory must be mapped, the range is widened to multiples of `nonCoherentAtomSize`
+    pub fn flush(&self, device: &VDevice, offset: u64, size: u64) -> RendererResult<()> {
+        if self.coherent {
+            return Ok(());
+        }
+        let range = self.mapped_memory_range(device, offset, size);
+        unsafe { device.get().flush_mapped_memory_ranges(&[range])? };
         Ok(())
     }
 
-    fn buffer_create_info(size: u64, usage: BufferUsageFlags) -> BufferCreateInfo {
+    /// Makes device writes to `size` bytes at `offset` visible to the host, a no-op for
+    /// `HOST_COHERENT` memory
+    ///
+    /// The memory must be mapped, the range is widened to multiples of `nonCoherentAtomSize`
+    pub fn invalidate(&self, device: &VDevice, offset: u64, size: u64) -> RendererResult<()> {
+        if self.coherent {
+            return Ok(());
+        }
+        let range = self.mapped_memory_range(device, offset, size);
+        unsafe { device.get().invalidate_mapped_memory_ranges(&[range])? };
+        Ok(())
+    }
+
+    fn mapped_memory_range(&self, device: &VDevice, offset: u64, size: u64) -> MappedMemoryRange {
+        let atom_size = device.get_device_properties().limits.non_coherent_atom_size;
+        let (offset, size) = Self::align_to_atoms(offset, size, atom_size, self.allocation);
+        MappedMemoryRange {
+            memory: self.memory,
+            offset: self.offset + offset,
+            size,
+            ..Default::default()
+        }
+    }
+
+    /// Rounds the start down and the end up to `atom_size`, a range reaching the end of the
+    /// allocation becomes `WHOLE_SIZE` as the allocation itself may not be a multiple
+    fn align_to_atoms(offset: u64, size: u64, atom_size: u64, allocation: u64) -> (u64, u64) {
+        let start = offset / atom_size * atom_size;
+        if size == WHOLE_SIZE {
+            return (start, WHOLE_SIZE);
+        }
+        let end = (offset + size).div_ceil(atom_size) * atom_size;
+        if end >= allocation {
+            (start, WHOLE_SIZE)
+        } else {
+            (start, end - start)
+        }
+    }
+
+    /// Buffers are shared concurrently when the transfer or compute family is apart from graphics
+    fn buffer_create_info(
+        size: u64,
+        usage: BufferUsageFlags,
+        queue_family_indices: &[u32],
+    ) -> BufferCreateInfo {
+        let sharing_mode = if queue_family_indices.len() > 1 {
+            SharingMode::CONCURRENT
+        } else {
+            SharingMode::EXCLUSIVE
+        };
         BufferCreateInfo {
             size,
             usage,
-            sharing_mode: SharingMode::EXCLUSIVE,
+            sharing_mode,
+            queue_family_index_count: queue_family_indices.len() as u32,
+            p_queue_family_indices: queue_family_indices.as_ptr(),
             ..Default::default()
         }
     }
@@ -225,23 +702,446 @@ impl VBuffer {
         unsafe { device.get().get_buffer_memory_requirements(buffer) }
     }
 
-    fn find_memory_type_index(
+    /// The index and property flags of the first memory type with `flags`, the property flags can
+    /// have more than requested, e.g. `HOST_COHERENT` on top of `HOST_VISIBLE`
+    pub(crate) fn find_memory_type(
         memory_requirements: MemoryRequirements,
         memory_properties: PhysicalDeviceMemoryProperties,
         flags: MemoryPropertyFlags,
-    ) -> u32 {
-        for (ind, mem_type) in memory_properties.memory_types.iter().enumerate() {
+    ) -> RendererResult<(u32, MemoryPropertyFlags)> {
+        let memory_types =
+            &memory_properties.memory_types[..memory_properties.memory_type_count as usize];
+        for (ind, mem_type) in memory_types.iter().enumerate() {
             if mem_type.property_flags & flags == flags
                 && (1 << ind) & memory_requirements.memory_type_bits != 0
             {
-                return ind as u32;
+                return Ok((ind as u32, mem_type.property_flags));
             }
         }
 
-        panic!("Failed to find a suitable memory type.");
+        Err(MemoryError::NoSuitableMemoryType { requested: flags }.into())
     }
 }
 
 impl_get!(VBuffer, buffer, Buffer);
 impl_get!(VBuffer, memory, DeviceMemory);
+impl_get!(VBuffer, offset, u64);
 impl_get!(VBuffer, allocation, u64);
+impl_get!(VBuffer, size, u64);
+impl_get!(VBuffer, coherent, bool);
+
+/// A [`VBuffer`] whose memory stays mapped from creation until [`destroy`](Self::destroy)
+///
+/// Made with [`VBuffer::new_persistently_mapped`], the inner buffer's `map_*` methods must not be
+/// used as the memory can't be mapped twice. Writes without `HOST_COHERENT` memory still need a
+/// [`VBuffer::flush`] before the GPU reads them
+#[derive(Debug)]
+pub struct VMappedBuffer {
+    buffer: VBuffer,
+    ptr: *mut u8,
+}
+
+impl Default for VMappedBuffer {
+    fn default() -> Self {
+        Self {
+            buffer: VBuffer::default(),
+            ptr: ptr::null_mut(),
+        }
+    }
+}
+
+impl VMappedBuffer {
+    /// Copies `data` to `offset` bytes into the buffer, errors if it doesn't fit
+    pub fn write<T: AsBytes>(&self, data: &[T], offset: u64) -> RendererResult<()> {
+        self.check_bounds(offset + size_of_val(data) as u64)?;
+        unsafe {
+            ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                self.ptr.add(offset as usize).cast(),
+                data.len(),
+            )
+        };
+        Ok(())
+    }
+
+    /// Copies each element of `data` to `offset + i * stride`, errors if the elements would
+    /// overlap or the last doesn't fit
+    pub fn write_strided<T: AsBytes>(
+        &self,
+        data: &[T],
+        offset: u64,
+        stride: u64,
+    ) -> RendererResult<()> {
+        if stride < size_of::<T>() as u64 {
+            return Err(RendererError::InvalidArgument(format!(
+                "A stride of {} bytes overlaps the {} byte elements.",
+                stride,
+                size_of::<T>()
+            )));
+        }
+        if let Some(last) = data.len().checked_sub(1) {
+            self.check_bounds(offset + last as u64 * stride + size_of::<T>() as u64)?;
+        }
+        for (ind, element) in data.iter().enumerate() {
+            unsafe {
+                let dst = self.ptr.add((offset + ind as u64 * stride) as usize);
+                ptr::copy_nonoverlapping(element, dst.cast(), 1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmaps the memory and destroys the buffer
+    pub fn destroy(&self, device: &VDevice) {
+        unsafe { device.get().unmap_memory(self.buffer.memory) };
+        self.buffer.destroy(device);
+    }
+
+    fn check_bounds(&self, end: u64) -> RendererResult<()> {
+        if end > self.buffer.size {
+            return Err(RendererError::InvalidArgument(format!(
+                "Writing up to byte {} overflows the {} byte buffer.",
+                end, self.buffer.size
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl_get_ref!(VMappedBuffer, buffer, &VBuffer);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{instance::VInstance, test_utils::create_window, RendererError};
+    use ash::vk::{
+        Format, ImageAspectFlags, ImageUsageFlags, MemoryHeap, MemoryType, MAX_MEMORY_TYPES,
+    };
+    use std::time::Instant;
+
+    #[test]
+    fn skips_memory_types_past_the_count() {
+        let mut memory_properties = PhysicalDeviceMemoryProperties {
+            memory_type_count: 1,
+            memory_types: [MemoryType::default(); MAX_MEMORY_TYPES],
+            memory_heaps: [MemoryHeap::default(); 16],
+            ..Default::default()
+        };
+        memory_properties.memory_types[1].property_flags = MemoryPropertyFlags::DEVICE_LOCAL;
+        let memory_requirements = MemoryRequirements {
+            memory_type_bits: u32::MAX,
+            ..Default::default()
+        };
+
+        let result = VBuffer::find_memory_type(
+            memory_requirements,
+            memory_properties,
+            MemoryPropertyFlags::DEVICE_LOCAL,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn reports_property_flags_of_the_chosen_memory_type() {
+        let mut memory_properties = PhysicalDeviceMemoryProperties {
+            memory_type_count: 2,
+            memory_types: [MemoryType::default(); MAX_MEMORY_TYPES],
+            memory_heaps: [MemoryHeap::default(); 16],
+            ..Default::default()
+        };
+        let coherent = MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT;
+        memory_properties.memory_types[0].property_flags = coherent;
+        memory_properties.memory_types[1].property_flags = MemoryPropertyFlags::HOST_VISIBLE;
+        let requirements = |memory_type_bits| MemoryRequirements {
+            memory_type_bits,
+            ..Default::default()
+        };
+
+        let chosen = |memory_type_bits| {
+            VBuffer::find_memory_type(
+                requirements(memory_type_bits),
+                memory_properties,
+                MemoryPropertyFlags::HOST_VISIBLE,
+            )
+            .ok()
+        };
+        assert_eq!(chosen(0b11), Some((0, coherent)));
+        assert_eq!(chosen(0b10), Some((1, MemoryPropertyFlags::HOST_VISIBLE)));
+    }
+
+    #[test]
+    fn returns_error_for_impossible_memory_flags() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        // The spec forbids memory types that are both protected and host visible
+        let buffer = VBuffer::create_buffer(&device, 64, BufferUsageFlags::UNIFORM_BUFFER)?;
+        let memory_requirements = VBuffer::memory_requirements(&device, buffer);
+        let result = VBuffer::create_memory(
+            &device,
+            memory_requirements,
+            MemoryPropertyFlags::PROTECTED | MemoryPropertyFlags::HOST_VISIBLE,
+        );
+        unsafe { device.get().destroy_buffer(buffer, None) };
+
+        let err = result.expect_err("Protected host visible memory should not exist.");
+        assert!(matches!(
+            err,
+            RendererError::Memory(MemoryError::NoSuitableMemoryType { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn batches_deferred_uploads_behind_fences() -> RendererResult<()> {
+        const UPLOAD_COUNT: usize = 20;
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let data = vec![7u32; 64 * 1024];
+        let staging_buffer = || {
+            VBuffer::new_mapped(
+                &device,
+                &data,
+                BufferUsageFlags::TRANSFER_SRC,
+                MemoryPropertyFlags::HOST_COHERENT | MemoryPropertyFlags::HOST_VISIBLE,
+            )
+        };
+        let dst_buffers = (0..UPLOAD_COUNT)
+            .map(|_| {
+                VBuffer::new_unmapped(
+                    &device,
+                    &data,
+                    BufferUsageFlags::TRANSFER_DST,
+                    MemoryPropertyFlags::HOST_COHERENT | MemoryPropertyFlags::HOST_VISIBLE,
+                )
+            })
+            .collect::<RendererResult<Vec<_>>>()?;
+
+        let serialized_start = Instant::now();
+        for dst in &dst_buffers {
+            VBuffer::copy_buffer_deferred(&device, &data, staging_buffer()?, dst.buffer())?
+                .wait(&device)?;
+        }
+        let serialized = serialized_start.elapsed();
+
+        let batched_start = Instant::now();
+        let transfers = dst_buffers
+            .iter()
+            .map(|dst| {
+                VBuffer::copy_buffer_deferred(&device, &data, staging_buffer()?, dst.buffer())
+            })
+            .collect::<RendererResult<Vec<_>>>()?;
+        VTransfer::wait_all(&device, transfers)?;
+        let batched = batched_start.elapsed();
+        println!(
+            "{} uploads: serialized {:?}, batched {:?}",
+            UPLOAD_COUNT, serialized, batched
+        );
+
+        for dst in dst_buffers {
+            assert_eq!(dst.read_back::<u32>(&device)?, data);
+            unsafe {
+                device.get().destroy_buffer(dst.buffer(), None);
+                device.get().free_memory(dst.memory(), None);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reuses_transfer_command_pool_across_uploads() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let data = [1.0f32; 16];
+        let staging_buffer = VBuffer::new_mapped(
+            &device,
+            &data,
+            BufferUsageFlags::TRANSFER_SRC,
+            MemoryPropertyFlags::HOST_COHERENT | MemoryPropertyFlags::HOST_VISIBLE,
+        )?;
+        let mut command_pools = Vec::new();
+        for _ in 0..50 {
+            let buffer = VBuffer::new_unmapped(
+                &device,
+                &data,
+                BufferUsageFlags::TRANSFER_DST,
+                MemoryPropertyFlags::DEVICE_LOCAL,
+            )?;
+            VBuffer::copy_buffer(&device, &data, staging_buffer.buffer(), buffer.buffer())?;
+            command_pools.push(device.transfer_command_pool()?.get());
+            unsafe {
+                device.get().destroy_buffer(buffer.buffer(), None);
+                device.get().free_memory(buffer.memory(), None);
+            }
+        }
+        command_pools.dedup();
+        assert_eq!(command_pools.len(), 1);
+
+        unsafe {
+            device.get().destroy_buffer(staging_buffer.buffer(), None);
+            device.get().free_memory(staging_buffer.memory(), None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn writes_persistently_mapped_memory() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+        let flags = MemoryPropertyFlags::HOST_COHERENT | MemoryPropertyFlags::HOST_VISIBLE;
+        assert!(matches!(
+            VBuffer::new_persistently_mapped(
+                &device,
+                64,
+                BufferUsageFlags::UNIFORM_BUFFER,
+                MemoryPropertyFlags::DEVICE_LOCAL
+            ),
+            Err(RendererError::InvalidArgument(_))
+        ));
+
+        // Camera sized data written once per frame
+        let data = [0.5f32; 32];
+        let frames = 1000;
+        let mapped_buffer =
+            VBuffer::new_mapped(&device, &data, BufferUsageFlags::UNIFORM_BUFFER, flags)?;
+        let start = Instant::now();
+        for _ in 0..frames {
+            mapped_buffer.map_memory(&device, &data)?;
+        }
+        let map_per_write = start.elapsed();
+
+        let persistent_buffer = VBuffer::new_persistently_mapped(
+            &device,
+            size_of_val(&data) as u64,
+            BufferUsageFlags::UNIFORM_BUFFER,
+            flags,
+        )?;
+        let start = Instant::now();
+        for _ in 0..frames {
+            persistent_buffer.write(&data, 0)?;
+        }
+        let persistent = start.elapsed();
+        println!(
+            "{} writes took {:?} persistently mapped, {:?} mapping per write.",
+            frames, persistent, map_per_write
+        );
+        assert!(persistent_buffer.write(&data, 4).is_err());
+        assert!(persistent_buffer.write_strided(&data[..2], 0, 2).is_err());
+        persistent_buffer.write_strided(&data[..2], 0, 8)?;
+
+        persistent_buffer.destroy(&device);
+        mapped_buffer.destroy(&device);
+        Ok(())
+    }
+
+    #[test]
+    fn widens_flushed_ranges_to_atoms() {
+        assert_eq!(VBuffer::align_to_atoms(70, 10, 64, 1024), (64, 64));
+        assert_eq!(VBuffer::align_to_atoms(64, 64, 64, 1024), (64, 64));
+        assert_eq!(VBuffer::align_to_atoms(130, 1, 64, 1024), (128, 64));
+        assert_eq!(
+            VBuffer::align_to_atoms(1000, 10, 64, 1010),
+            (960, WHOLE_SIZE)
+        );
+        assert_eq!(
+            VBuffer::align_to_atoms(0, WHOLE_SIZE, 64, 1024),
+            (0, WHOLE_SIZE)
+        );
+    }
+
+    #[test]
+    fn flushes_writes_to_non_coherent_memory() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let data = [3u32; 7];
+        let size = size_of_val(&data) as u64;
+        let host_visible = MemoryPropertyFlags::HOST_VISIBLE;
+        // Cached memory is the kind drivers tend to leave non-coherent
+        for flags in [
+            host_visible,
+            host_visible | MemoryPropertyFlags::HOST_CACHED,
+        ] {
+            let buffer = match VBuffer::new_persistently_mapped(
+                &device,
+                size,
+                BufferUsageFlags::UNIFORM_BUFFER,
+                flags,
+            ) {
+                Ok(buffer) => buffer,
+                Err(RendererError::Memory(_)) => continue,
+                Err(err) => return Err(err),
+            };
+            let requirements = VBuffer::memory_requirements(&device, buffer.buffer().buffer());
+            let (_, property_flags) =
+                VBuffer::find_memory_type(requirements, device.get_memory_properties(), flags)?;
+            assert_eq!(
+                buffer.buffer().coherent(),
+                property_flags.contains(MemoryPropertyFlags::HOST_COHERENT)
+            );
+            buffer.write(&data[1..], 4)?;
+            buffer.buffer().flush(&device, 4, 24)?;
+            buffer.buffer().invalidate(&device, 0, WHOLE_SIZE)?;
+            buffer.destroy(&device);
+
+            // Mapping per write flushes before unmapping and reading back invalidates
+            let buffer =
+                VBuffer::new_mapped(&device, &data, BufferUsageFlags::TRANSFER_SRC, flags)?;
+            assert_eq!(buffer.read_back::<u32>(&device)?, data);
+            unsafe {
+                device.get().destroy_buffer(buffer.buffer(), None);
+                device.get().free_memory(buffer.memory(), None);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn destroys_buffers_and_images_without_leaking() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        // Leaked objects are reported by the validation layer when the device is destroyed
+        let device = VDevice::new(&instance, &window)?;
+        let allocator = VAllocator::default();
+        for _ in 0..3 {
+            let buffer = VBuffer::new_mapped(
+                &device,
+                &[1u32; 16],
+                BufferUsageFlags::UNIFORM_BUFFER,
+                MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT,
+            )?;
+            let suballocated_buffer = VBuffer::new_with_allocator(
+                &device,
+                &allocator,
+                64,
+                BufferUsageFlags::UNIFORM_BUFFER,
+                MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT,
+            )?;
+            let image = VImage::new(
+                &device,
+                ImageUsageFlags::SAMPLED,
+                Format::R8G8B8A8_UNORM,
+                Extent3D {
+                    width: 4,
+                    height: 4,
+                    depth: 1,
+                },
+                ImageAspectFlags::COLOR,
+            )?;
+            buffer.destroy(&device);
+            suballocated_buffer.destroy(&device);
+            image.destroy(&device);
+        }
+        // The suballocated buffers leave their memory to the allocator
+        assert_eq!(allocator.block_count(), 1);
+        allocator.destroy(&device);
+        Ok(())
+    }
+}
@@ -1,31 +1,76 @@
-use crate::{device::VDevice, RendererResult};
+use crate::{
+    device::VDevice, pipeline::VGraphicsPipeline, query::VQueryPool, render_pass::VRenderPass,
+    utils::AsBytes, RendererError, RendererResult,
+};
 use ash::vk::{
-    Buffer, ClearValue, CommandBuffer, CommandBufferAllocateInfo, CommandBufferBeginInfo,
-    CommandBufferLevel, CommandBufferUsageFlags, CommandPool, DescriptorSet, DeviceSize, Extent2D,
-    Framebuffer, IndexType, Offset2D, Pipeline, PipelineBindPoint, PipelineLayout, Rect2D,
-    RenderPass, RenderPassBeginInfo, ShaderStageFlags, SubpassContents,
+    AttachmentLoadOp, AttachmentStoreOp, Buffer, ClearAttachment, ClearColorValue,
+    ClearDepthStencilValue, ClearRect, ClearValue, CommandBuffer, CommandBufferBeginInfo,
+    CommandBufferUsageFlags, DebugUtilsLabelEXT, DescriptorSet, DeviceSize, Extent2D, Framebuffer,
+    ImageLayout, ImageView, IndexType, Offset2D, Pipeline, PipelineBindPoint, PipelineLayout,
+    PipelineStageFlags, Rect2D, RenderPass, RenderPassBeginInfo, RenderingAttachmentInfoKHR,
+    RenderingInfoKHR, ShaderStageFlags, SubpassContents, Viewport, WriteDescriptorSet,
 };
+use std::ffi::CString;
 
-pub fn allocate_command_buffers(
-    device: &VDevice,
-    command_pool: CommandPool,
-    command_buffer_count: u32,
-) -> RendererResult<Vec<CommandBuffer>> {
-    let command_buffer_allocate_info = CommandBufferAllocateInfo {
-        command_buffer_count,
-        level: CommandBufferLevel::PRIMARY,
-        command_pool,
-        ..Default::default()
-    };
+/// Builds the clear values of a [`VRenderPass`] in attachment order
+///
+/// Defaults to opaque black and a depth of 1.0
+#[derive(Debug, Clone, Copy)]
+pub struct ClearValues {
+    color: [f32; 4],
+    depth: f32,
+}
 
-    unsafe {
-        Ok(device
-            .get()
-            .allocate_command_buffers(&command_buffer_allocate_info)?)
+impl Default for ClearValues {
+    fn default() -> Self {
+        Self {
+            color: [0.0, 0.0, 0.0, 1.0],
+            depth: 1.0,
+        }
     }
 }
 
+impl ClearValues {
+    pub fn start() -> Self {
+        Self::default()
+    }
+
+    pub fn color(mut self, r: f32, g: f32, b: f32, a: f32) -> Self {
+        self.color = [r, g, b, a];
+        self
+    }
+
+    pub fn depth(mut self, depth: f32) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// The multisampled resolve attachment isn't cleared, so it needs no value
+    pub fn build(&self) -> Vec<ClearValue> {
+        let mut clear_values = vec![ClearValue::default(); 2];
+        clear_values[VRenderPass::COLOR_ATTACHMENT as usize] = ClearValue {
+            color: ClearColorValue {
+                float32: self.color,
+            },
+        };
+        clear_values[VRenderPass::DEPTH_ATTACHMENT as usize] = ClearValue {
+            depth_stencil: ClearDepthStencilValue {
+                depth: self.depth,
+                stencil: 0,
+            },
+        };
+        clear_values
+    }
+}
+
+/// Also forgets the debug labels left open in `command_buffer` by its last recording
 pub fn begin_command_buffer(device: &VDevice, command_buffer: CommandBuffer) -> RendererResult<()> {
+    if cfg!(debug_assertions) {
+        device
+            .debug_label_depths()
+            .borrow_mut()
+            .remove(&command_buffer);
+    }
     let begin_info = CommandBufferBeginInfo {
         flags: CommandBufferUsageFlags::ONE_TIME_SUBMIT,
         ..Default::default()
@@ -62,6 +107,12 @@ pub fn cmd_begin_render_pass(
         },
         ..Default::default()
     };
+    if cfg!(debug_assertions) {
+        device
+            .render_pass_tracker()
+            .borrow_mut()
+            .begin(command_buffer, render_pass);
+    }
     unsafe {
         device.get().cmd_begin_render_pass(
             command_buffer,
@@ -84,6 +135,35 @@ pub fn cmd_bind_pipeline(
     };
 }
 
+/// Binds a graphics pipeline, in debug builds asserts that it was built for a render pass
+/// compatible with the one being recorded
+pub fn cmd_bind_graphics_pipeline(
+    device: &VDevice,
+    command_buffer: CommandBuffer,
+    pipeline: &VGraphicsPipeline,
+) {
+    if cfg!(debug_assertions) {
+        device
+            .render_pass_tracker()
+            .borrow()
+            .assert_compatible(command_buffer, pipeline.render_pass());
+    }
+    cmd_bind_pipeline(
+        device,
+        command_buffer,
+        PipelineBindPoint::GRAPHICS,
+        pipeline.pipeline(),
+    );
+}
+
+pub fn cmd_set_viewport(device: &VDevice, command_buffer: CommandBuffer, viewports: &[Viewport]) {
+    unsafe { device.get().cmd_set_viewport(command_buffer, 0, viewports) }
+}
+
+pub fn cmd_set_scissor(device: &VDevice, command_buffer: CommandBuffer, scissors: &[Rect2D]) {
+    unsafe { device.get().cmd_set_scissor(command_buffer, 0, scissors) }
+}
+
 pub fn cmd_bind_vertex_buffer(
     device: &VDevice,
     command_buffer: CommandBuffer,
@@ -110,25 +190,43 @@ pub fn cmd_bind_index_buffer(
     }
 }
 
+/// Pushes `constants` at offset 0
+///
+/// In debug builds, asserts that their size matches the pipeline layout's range for `stage_flags`
 pub fn cmd_push_constants(
     device: &VDevice,
     command_buffer: CommandBuffer,
-    layout: PipelineLayout,
+    pipeline: &VGraphicsPipeline,
     stage_flags: ShaderStageFlags,
-    constants: &[u8],
+    constants: &impl AsBytes,
 ) {
+    let constants = constants.as_bytes();
+    debug_assert!(
+        pipeline
+            .push_constant_range(stage_flags)
+            .is_some_and(|range| range.offset == 0 && range.size as usize == constants.len()),
+        "Push constants of {} bytes do not match the pipeline layout's range for {:?}.",
+        constants.len(),
+        stage_flags
+    );
     unsafe {
-        device
-            .get()
-            .cmd_push_constants(command_buffer, layout, stage_flags, 0, constants);
+        device.get().cmd_push_constants(
+            command_buffer,
+            pipeline.pipeline_layout(),
+            stage_flags,
+            0,
+            constants,
+        );
     }
 }
 
+/// Binds `descriptor_sets` to the set numbers starting at `first_set`, leaving other sets bound
 pub fn cmd_bind_descriptor_sets(
     device: &VDevice,
     command_buffer: CommandBuffer,
     pipeline_bind_point: PipelineBindPoint,
     layout: PipelineLayout,
+    first_set: u32,
     descriptor_sets: &[DescriptorSet],
     dynamic_offsets: &[u32],
 ) {
@@ -137,7 +235,7 @@ pub fn cmd_bind_descriptor_sets(
             command_buffer,
             pipeline_bind_point,
             layout,
-            0,
+            first_set,
             descriptor_sets,
             dynamic_offsets,
         );
@@ -157,19 +255,396 @@ pub fn cmd_draw(
     }
 }
 
+/// Draws `instance_count` instances, starting at `first_instance` in the per-instance vertex buffers
 pub fn cmd_draw_indexed(
     device: &VDevice,
     command_buffer: CommandBuffer,
     index_count: u32,
     instance_count: u32,
+    first_instance: u32,
+) {
+    unsafe {
+        device.get().cmd_draw_indexed(
+            command_buffer,
+            index_count,
+            instance_count,
+            0,
+            0,
+            first_instance,
+        );
+    }
+}
+
+pub fn cmd_dispatch(
+    device: &VDevice,
+    command_buffer: CommandBuffer,
+    group_count_x: u32,
+    group_count_y: u32,
+    group_count_z: u32,
 ) {
     unsafe {
         device
             .get()
-            .cmd_draw_indexed(command_buffer, index_count, instance_count, 0, 0, 0);
+            .cmd_dispatch(command_buffer, group_count_x, group_count_y, group_count_z);
+    }
+}
+
+/// Fills `size` bytes of `buffer` with the repeated `data` word, must be called outside a render pass
+///
+/// `offset` and `size` must be multiples of 4, `WHOLE_SIZE` fills to the end of the buffer
+pub fn cmd_fill_buffer(
+    device: &VDevice,
+    command_buffer: CommandBuffer,
+    buffer: Buffer,
+    offset: DeviceSize,
+    size: DeviceSize,
+    data: u32,
+) {
+    unsafe {
+        device
+            .get()
+            .cmd_fill_buffer(command_buffer, buffer, offset, size, data);
+    }
+}
+
+/// Writes `data` inline into `buffer` without a staging copy, must be called outside a render pass
+///
+/// In debug builds, asserts that `data` is a multiple of 4 and at most 65536 bytes as Vulkan requires
+pub fn cmd_update_buffer(
+    device: &VDevice,
+    command_buffer: CommandBuffer,
+    buffer: Buffer,
+    offset: DeviceSize,
+    data: &[u8],
+) {
+    debug_assert!(
+        data.len().is_multiple_of(4) && data.len() <= 65536,
+        "Inline buffer updates must be a multiple of 4 and at most 65536 bytes, got {}.",
+        data.len()
+    );
+    unsafe {
+        device
+            .get()
+            .cmd_update_buffer(command_buffer, buffer, offset, data);
+    }
+}
+
+/// Clears regions of the current subpass' attachments, must be called inside a render pass
+pub fn cmd_clear_attachments(
+    device: &VDevice,
+    command_buffer: CommandBuffer,
+    attachments: &[ClearAttachment],
+    rects: &[ClearRect],
+) {
+    unsafe {
+        device
+            .get()
+            .cmd_clear_attachments(command_buffer, attachments, rects);
+    }
+}
+
+/// Writes the time at which all previous commands reached `stage` into query `index` of `pool`
+pub fn cmd_write_timestamp(
+    device: &VDevice,
+    command_buffer: CommandBuffer,
+    pool: &VQueryPool,
+    stage: PipelineStageFlags,
+    index: u32,
+) {
+    unsafe {
+        device
+            .get()
+            .cmd_write_timestamp(command_buffer, stage, pool.get(), index);
+    }
+}
+
+/// Opens a labeled region that captures in tools like RenderDoc show, e.g. "Shadow Pass"
+///
+/// Without the debug utils extension only the debug build bookkeeping is done. Errors if `name`
+/// contains a nul byte
+pub fn cmd_begin_debug_label(
+    device: &VDevice,
+    command_buffer: CommandBuffer,
+    name: &str,
+    color: [f32; 4],
+) -> RendererResult<()> {
+    let name = CString::new(name).map_err(|_| {
+        RendererError::InvalidArgument(format!("Debug label {:?} contains a nul byte.", name))
+    })?;
+    if cfg!(debug_assertions) {
+        *device
+            .debug_label_depths()
+            .borrow_mut()
+            .entry(command_buffer)
+            .or_default() += 1;
+    }
+    if let Some(debug_utils) = device.debug_utils() {
+        let label = DebugUtilsLabelEXT {
+            p_label_name: name.as_ptr(),
+            color,
+            ..Default::default()
+        };
+        unsafe { debug_utils.cmd_begin_debug_utils_label(command_buffer, &label) };
+    }
+    Ok(())
+}
+
+/// Begins rendering straight into `color_views` and `depth_view` without a render pass, clearing
+/// them to `clear`'s values
+///
+/// Needs Vulkan 1.3 or `VK_KHR_dynamic_rendering`, errors if [`VDevice::dynamic_rendering`] is
+/// `None`. The views must be in the attachment optimal layouts and the bound pipelines built with
+/// [`build_for_rendering`](crate::pipeline::VGraphicsPipelineBuilder::build_for_rendering)
+pub fn cmd_begin_rendering(
+    device: &VDevice,
+    command_buffer: CommandBuffer,
+    color_views: &[ImageView],
+    depth_view: Option<ImageView>,
+    extent: Extent2D,
+    clear: &ClearValues,
+) -> RendererResult<()> {
+    let dynamic_rendering = device.dynamic_rendering().ok_or_else(|| {
+        RendererError::InvalidArgument("Dynamic rendering is not enabled.".to_owned())
+    })?;
+    let color_attachments = color_views
+        .iter()
+        .map(|&image_view| RenderingAttachmentInfoKHR {
+            image_view,
+            image_layout: ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            load_op: AttachmentLoadOp::CLEAR,
+            store_op: AttachmentStoreOp::STORE,
+            clear_value: ClearValue {
+                color: ClearColorValue {
+                    float32: clear.color,
+                },
+            },
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+    let depth_attachment = depth_view.map(|image_view| RenderingAttachmentInfoKHR {
+        image_view,
+        image_layout: ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        load_op: AttachmentLoadOp::CLEAR,
+        store_op: AttachmentStoreOp::DONT_CARE,
+        clear_value: ClearValue {
+            depth_stencil: ClearDepthStencilValue {
+                depth: clear.depth,
+                stencil: 0,
+            },
+        },
+        ..Default::default()
+    });
+    let rendering_info = RenderingInfoKHR {
+        render_area: Rect2D {
+            offset: Offset2D { x: 0, y: 0 },
+            extent,
+        },
+        layer_count: 1,
+        color_attachment_count: color_attachments.len() as u32,
+        p_color_attachments: color_attachments.as_ptr(),
+        p_depth_attachment: depth_attachment
+            .as_ref()
+            .map_or(std::ptr::null(), |attachment| attachment as *const _),
+        ..Default::default()
+    };
+    unsafe { dynamic_rendering.cmd_begin_rendering(command_buffer, &rendering_info) };
+    Ok(())
+}
+
+/// Ends rendering begun with [`cmd_begin_rendering`]
+pub fn cmd_end_rendering(device: &VDevice, command_buffer: CommandBuffer) -> RendererResult<()> {
+    let dynamic_rendering = device.dynamic_rendering().ok_or_else(|| {
+        RendererError::InvalidArgument("Dynamic rendering is not enabled.".to_owned())
+    })?;
+    unsafe { dynamic_rendering.cmd_end_rendering(command_buffer) };
+    Ok(())
+}
+
+/// Pushes `writes` straight into the command buffer as set `set` of `layout`, their `dst_set` is
+/// ignored
+///
+/// The set's layout must be created with `PUSH_DESCRIPTOR_KHR`, see
+/// [`VDescriptorSetLayout::new_with_flags`](crate::descriptorset::VDescriptorSetLayout::new_with_flags).
+/// Errors if [`VDevice::push_descriptor`] is `None`
+pub fn cmd_push_descriptor_set(
+    device: &VDevice,
+    command_buffer: CommandBuffer,
+    bind_point: PipelineBindPoint,
+    layout: PipelineLayout,
+    set: u32,
+    writes: &[WriteDescriptorSet],
+) -> RendererResult<()> {
+    let push_descriptor = device.push_descriptor().ok_or_else(|| {
+        RendererError::InvalidArgument("Push descriptors are not enabled.".to_owned())
+    })?;
+    unsafe {
+        push_descriptor.cmd_push_descriptor_set(command_buffer, bind_point, layout, set, writes)
+    };
+    Ok(())
+}
+
+/// Closes the last region opened with [`cmd_begin_debug_label`], in debug builds asserts there is one
+pub fn cmd_end_debug_label(device: &VDevice, command_buffer: CommandBuffer) {
+    if cfg!(debug_assertions) {
+        let mut depths = device.debug_label_depths().borrow_mut();
+        let depth = depths.entry(command_buffer).or_default();
+        assert!(*depth > 0, "No debug label is open in the command buffer.");
+        *depth -= 1;
+        if *depth == 0 {
+            depths.remove(&command_buffer);
+        }
+    }
+    if let Some(debug_utils) = device.debug_utils() {
+        unsafe { debug_utils.cmd_end_debug_utils_label(command_buffer) };
     }
 }
 
 pub fn cmd_end_render_pass(device: &VDevice, command_buffer: CommandBuffer) {
+    if cfg!(debug_assertions) {
+        device
+            .render_pass_tracker()
+            .borrow_mut()
+            .end(command_buffer);
+    }
     unsafe { device.get().cmd_end_render_pass(command_buffer) }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        buffer::VBuffer, instance::VInstance, render_target::VOffscreenTarget,
+        test_utils::create_window,
+    };
+    use ash::vk::{Format, ImageAspectFlags};
+
+    #[test]
+    fn orders_clear_values_like_render_pass_attachments() {
+        let clear_values = ClearValues::start()
+            .color(0.1, 0.2, 0.3, 1.0)
+            .depth(0.5)
+            .build();
+        let attachments =
+            VRenderPass::attachment_descriptions(Format::B8G8R8A8_SRGB, Format::D32_SFLOAT);
+        assert_eq!(clear_values.len(), attachments.len());
+
+        let color_ind = VRenderPass::COLOR_ATTACHMENT as usize;
+        let depth_ind = VRenderPass::DEPTH_ATTACHMENT as usize;
+        assert_eq!(attachments[color_ind].format, Format::B8G8R8A8_SRGB);
+        assert_eq!(attachments[depth_ind].format, Format::D32_SFLOAT);
+        unsafe {
+            assert_eq!(clear_values[color_ind].color.float32, [0.1, 0.2, 0.3, 1.0]);
+            assert_eq!(clear_values[depth_ind].depth_stencil.depth, 0.5);
+        }
+    }
+
+    #[test]
+    fn balances_nested_debug_labels() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let open_labels = |command_buffer| {
+            device
+                .debug_label_depths()
+                .borrow()
+                .get(&command_buffer)
+                .copied()
+                .unwrap_or(0)
+        };
+        let mut recorded = CommandBuffer::null();
+        let mut opened = Ok(());
+        let mut nul_label = Ok(());
+        VBuffer::submit_one_time(&device, |command_buffer| {
+            recorded = command_buffer;
+            opened = cmd_begin_debug_label(&device, command_buffer, "Frame", [1.0, 1.0, 1.0, 1.0])
+                .and_then(|_| {
+                    cmd_begin_debug_label(
+                        &device,
+                        command_buffer,
+                        "Shadow Pass",
+                        [0.2, 0.2, 0.2, 1.0],
+                    )
+                });
+            assert_eq!(open_labels(command_buffer), 2);
+            nul_label =
+                cmd_begin_debug_label(&device, command_buffer, "Bad\0Pass", [1.0, 0.0, 0.0, 1.0]);
+            assert_eq!(open_labels(command_buffer), 2);
+            cmd_end_debug_label(&device, command_buffer);
+            cmd_end_debug_label(&device, command_buffer);
+        })?;
+        opened?;
+        assert!(matches!(nul_label, Err(RendererError::InvalidArgument(_))));
+        assert!(!device.debug_label_depths().borrow().contains_key(&recorded));
+        Ok(())
+    }
+
+    fn record_dynamic_clear(
+        device: &VDevice,
+        command_buffer: CommandBuffer,
+        target: &VOffscreenTarget,
+        clear: &ClearValues,
+    ) -> RendererResult<()> {
+        let color_image = target.color_image();
+        let depth_image = target.depth_image();
+        color_image.transition_layout(
+            device,
+            command_buffer,
+            ImageLayout::UNDEFINED,
+            ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            ImageAspectFlags::COLOR,
+        )?;
+        depth_image.transition_layout(
+            device,
+            command_buffer,
+            ImageLayout::UNDEFINED,
+            ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+            ImageAspectFlags::DEPTH,
+        )?;
+        cmd_begin_rendering(
+            device,
+            command_buffer,
+            &[color_image.image_view()],
+            Some(depth_image.image_view()),
+            target.extent(),
+            clear,
+        )?;
+        cmd_end_rendering(device, command_buffer)?;
+        color_image.transition_layout(
+            device,
+            command_buffer,
+            ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            ImageLayout::TRANSFER_SRC_OPTIMAL,
+            ImageAspectFlags::COLOR,
+        )
+    }
+
+    #[test]
+    fn clears_with_dynamic_rendering() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+        if device.dynamic_rendering().is_none() {
+            return Ok(());
+        }
+
+        let target = VOffscreenTarget::new(
+            &device,
+            Extent2D {
+                width: 4,
+                height: 4,
+            },
+        )?;
+        let clear = ClearValues::start().color(1.0, 0.0, 0.0, 1.0);
+        let mut recorded = Ok(());
+        VBuffer::submit_one_time(&device, |command_buffer| {
+            recorded = record_dynamic_clear(&device, command_buffer, &target, &clear);
+        })?;
+        recorded?;
+        let pixels = target.read_pixels(&device)?;
+        assert!(pixels.chunks(4).all(|pixel| pixel == [255, 0, 0, 255]));
+
+        target.destroy(&device);
+        Ok(())
+    }
+}
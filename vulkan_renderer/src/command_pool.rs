@@ -1,5 +1,8 @@
 use crate::{device::VDevice, RendererResult};
-use ash::vk::{CommandPool, CommandPoolCreateFlags, CommandPoolCreateInfo};
+use ash::vk::{
+    CommandBuffer, CommandBufferAllocateInfo, CommandBufferLevel, CommandPool,
+    CommandPoolCreateFlags, CommandPoolCreateInfo, CommandPoolResetFlags,
+};
 
 #[derive(Default, Debug, Clone, Copy)]
 pub struct VCommandPool {
@@ -21,6 +24,23 @@ impl VCommandPool {
         self.command_pool
     }
 
+    /// Allocates `count` primary or secondary command buffers from the pool
+    pub fn allocate(
+        &self,
+        device: &VDevice,
+        level: CommandBufferLevel,
+        count: u32,
+    ) -> RendererResult<Vec<CommandBuffer>> {
+        let allocate_info = Self::command_buffer_allocate_info(self.command_pool, level, count);
+        unsafe { Ok(device.get().allocate_command_buffers(&allocate_info)?) }
+    }
+
+    /// Resets every command buffer allocated from the pool
+    pub fn reset(&self, device: &VDevice, flags: CommandPoolResetFlags) -> RendererResult<()> {
+        unsafe { device.get().reset_command_pool(self.command_pool, flags)? };
+        Ok(())
+    }
+
     fn command_pool_create_info(
         queue_family_index: u32,
         flags: CommandPoolCreateFlags,
@@ -31,4 +51,17 @@ impl VCommandPool {
             ..Default::default()
         }
     }
+
+    fn command_buffer_allocate_info(
+        command_pool: CommandPool,
+        level: CommandBufferLevel,
+        command_buffer_count: u32,
+    ) -> CommandBufferAllocateInfo {
+        CommandBufferAllocateInfo {
+            command_buffer_count,
+            level,
+            command_pool,
+            ..Default::default()
+        }
+    }
 }
@@ -1,11 +1,13 @@
 use ash::vk::{
-    DescriptorBufferInfo, DescriptorPool, DescriptorPoolCreateInfo, DescriptorPoolSize,
-    DescriptorSet, DescriptorSetAllocateInfo, DescriptorSetLayout, DescriptorSetLayoutBinding,
+    DescriptorBufferInfo, DescriptorImageInfo, DescriptorPool, DescriptorPoolCreateFlags,
+    DescriptorPoolCreateInfo, DescriptorPoolSize, DescriptorSet, DescriptorSetAllocateInfo,
+    DescriptorSetLayout, DescriptorSetLayoutBinding, DescriptorSetLayoutCreateFlags,
     DescriptorSetLayoutCreateInfo, DescriptorType, ShaderStageFlags, WriteDescriptorSet,
 };
 
-use crate::{device::VDevice, RendererResult};
+use crate::{device::VDevice, RendererError, RendererResult};
 
+#[derive(Default)]
 pub struct VDescriptorPool {
     descriptor_pool: DescriptorPool,
 }
@@ -21,8 +23,48 @@ impl VDescriptorPool {
                 descriptor_count: 10,
                 ty: DescriptorType::UNIFORM_BUFFER_DYNAMIC,
             },
+            DescriptorPoolSize {
+                descriptor_count: 10,
+                ty: DescriptorType::COMBINED_IMAGE_SAMPLER,
+            },
+            DescriptorPoolSize {
+                descriptor_count: 10,
+                ty: DescriptorType::STORAGE_BUFFER,
+            },
+            DescriptorPoolSize {
+                descriptor_count: 10,
+                ty: DescriptorType::SAMPLED_IMAGE,
+            },
+            DescriptorPoolSize {
+                descriptor_count: 10,
+                ty: DescriptorType::SAMPLER,
+            },
         ];
-        let create_info = Self::create_info(pool_sizes);
+        Self::new_with_sizes(device, 10, pool_sizes)
+    }
+
+    /// A pool for `max_sets` sets sharing the descriptors of `pool_sizes`
+    pub fn new_with_sizes(
+        device: &VDevice,
+        max_sets: u32,
+        pool_sizes: &[DescriptorPoolSize],
+    ) -> RendererResult<Self> {
+        Self::new_with_flags(
+            device,
+            DescriptorPoolCreateFlags::empty(),
+            max_sets,
+            pool_sizes,
+        )
+    }
+
+    /// `FREE_DESCRIPTOR_SET` lets sets be freed one by one instead of only with the pool
+    pub fn new_with_flags(
+        device: &VDevice,
+        flags: DescriptorPoolCreateFlags,
+        max_sets: u32,
+        pool_sizes: &[DescriptorPoolSize],
+    ) -> RendererResult<Self> {
+        let create_info = Self::create_info(flags, max_sets, pool_sizes);
         let descriptor_pool = unsafe { device.get().create_descriptor_pool(&create_info, None)? };
         Ok(Self { descriptor_pool })
     }
@@ -31,9 +73,14 @@ impl VDescriptorPool {
         self.descriptor_pool
     }
 
-    fn create_info(pool_sizes: &[DescriptorPoolSize]) -> DescriptorPoolCreateInfo {
+    fn create_info(
+        flags: DescriptorPoolCreateFlags,
+        max_sets: u32,
+        pool_sizes: &[DescriptorPoolSize],
+    ) -> DescriptorPoolCreateInfo {
         DescriptorPoolCreateInfo {
-            max_sets: 10,
+            flags,
+            max_sets,
             pool_size_count: pool_sizes.len() as u32,
             p_pool_sizes: pool_sizes.as_ptr(),
             ..Default::default()
@@ -47,7 +94,25 @@ pub struct VDescriptorSetLayout {
 
 impl VDescriptorSetLayout {
     pub fn new(device: &VDevice, bindings: &[DescriptorSetLayoutBinding]) -> RendererResult<Self> {
-        let create_info = Self::create_info(bindings);
+        Self::new_with_flags(device, bindings, DescriptorSetLayoutCreateFlags::empty())
+    }
+
+    /// `PUSH_DESCRIPTOR_KHR` makes a layout whose sets are pushed with
+    /// [`cmd_push_descriptor_set`](crate::cmd::cmd_push_descriptor_set) instead of allocated,
+    /// it errors unless the device enabled `VK_KHR_push_descriptor`
+    pub fn new_with_flags(
+        device: &VDevice,
+        bindings: &[DescriptorSetLayoutBinding],
+        flags: DescriptorSetLayoutCreateFlags,
+    ) -> RendererResult<Self> {
+        if flags.contains(DescriptorSetLayoutCreateFlags::PUSH_DESCRIPTOR_KHR)
+            && device.push_descriptor().is_none()
+        {
+            return Err(RendererError::InvalidArgument(
+                "Push descriptors are not enabled.".to_owned(),
+            ));
+        }
+        let create_info = Self::create_info(bindings, flags);
         let descriptor_set_layout = unsafe {
             device
                 .get()
@@ -77,8 +142,12 @@ impl VDescriptorSetLayout {
         }
     }
 
-    fn create_info(bindings: &[DescriptorSetLayoutBinding]) -> DescriptorSetLayoutCreateInfo {
+    fn create_info(
+        bindings: &[DescriptorSetLayoutBinding],
+        flags: DescriptorSetLayoutCreateFlags,
+    ) -> DescriptorSetLayoutCreateInfo {
         DescriptorSetLayoutCreateInfo {
+            flags,
             binding_count: bindings.len() as u32,
             p_bindings: bindings.as_ptr(),
             ..Default::default()
@@ -117,12 +186,24 @@ impl VDescriptorSet {
         }
     }
 
+    /// Writes a uniform or storage buffer descriptor, dynamic or not
     pub fn write_descriptor_set(
         dst_set: DescriptorSet,
         binding: u32,
         descriptor_type: DescriptorType,
         buffer_info: &DescriptorBufferInfo,
     ) -> WriteDescriptorSet {
+        debug_assert!(
+            matches!(
+                descriptor_type,
+                DescriptorType::UNIFORM_BUFFER
+                    | DescriptorType::UNIFORM_BUFFER_DYNAMIC
+                    | DescriptorType::STORAGE_BUFFER
+                    | DescriptorType::STORAGE_BUFFER_DYNAMIC
+            ),
+            "{:?} is not a buffer descriptor type.",
+            descriptor_type
+        );
         WriteDescriptorSet {
             p_buffer_info: buffer_info,
             dst_set,
@@ -132,4 +213,263 @@ impl VDescriptorSet {
             ..Default::default()
         }
     }
+
+    pub fn write_image_descriptor_set(
+        dst_set: DescriptorSet,
+        binding: u32,
+        descriptor_type: DescriptorType,
+        image_info: &DescriptorImageInfo,
+    ) -> WriteDescriptorSet {
+        WriteDescriptorSet {
+            p_image_info: image_info,
+            dst_set,
+            dst_binding: binding,
+            descriptor_type,
+            descriptor_count: 1,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        buffer::VBuffer,
+        cmd::{
+            cmd_begin_render_pass, cmd_bind_graphics_pipeline, cmd_bind_vertex_buffer, cmd_draw,
+            cmd_end_render_pass, cmd_push_descriptor_set, cmd_set_scissor, cmd_set_viewport,
+            ClearValues,
+        },
+        glam::{Mat4, Vec2, Vec3},
+        image::VImage,
+        instance::VInstance,
+        pipeline::VGraphicsPipelineBuilder,
+        render_target::VOffscreenTarget,
+        sampler::VSampler,
+        test_utils::{create_window, destroy_pipelines, shader_module},
+        vertex_input::{VVertex, VVertexInputBuilder},
+    };
+    use ash::vk::{
+        BufferUsageFlags, CullModeFlags, DynamicState, Extent2D, Extent3D, Filter, Format,
+        FrontFace, ImageAspectFlags, ImageLayout, ImageUsageFlags, MemoryPropertyFlags,
+        PipelineBindPoint, PolygonMode, Rect2D, SamplerAddressMode, VertexInputRate, Viewport,
+        WHOLE_SIZE,
+    };
+    use std::mem::size_of;
+
+    #[test]
+    fn writes_combined_image_sampler() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let bindings = &[VDescriptorSetLayout::layout_binding(
+            0,
+            1,
+            DescriptorType::COMBINED_IMAGE_SAMPLER,
+            ShaderStageFlags::FRAGMENT,
+        )];
+        let layout = VDescriptorSetLayout::new(&device, bindings)?;
+        let pool = VDescriptorPool::new(&device)?;
+        let descriptor_set = VDescriptorSet::new(&device, pool.get(), &[layout.get()])?;
+
+        let image = VImage::new(
+            &device,
+            ImageUsageFlags::SAMPLED,
+            Format::R8G8B8A8_UNORM,
+            Extent3D {
+                width: 1,
+                height: 1,
+                depth: 1,
+            },
+            ImageAspectFlags::COLOR,
+        )?;
+        let sampler = VSampler::new(&device, Filter::LINEAR, SamplerAddressMode::REPEAT)?;
+        let image_info = DescriptorImageInfo {
+            sampler: sampler.get(),
+            image_view: image.image_view(),
+            image_layout: ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        };
+        let write_set = VDescriptorSet::write_image_descriptor_set(
+            descriptor_set.get(),
+            0,
+            DescriptorType::COMBINED_IMAGE_SAMPLER,
+            &image_info,
+        );
+        unsafe {
+            device.get().update_descriptor_sets(&[write_set], &[]);
+
+            device.get().destroy_sampler(sampler.get(), None);
+            device.get().destroy_image_view(image.image_view(), None);
+            device.get().destroy_image(image.image(), None);
+            device.get().free_memory(image.memory(), None);
+            device.get().destroy_descriptor_pool(pool.get(), None);
+            device
+                .get()
+                .destroy_descriptor_set_layout(layout.get(), None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn draws_with_pushed_uniform_buffer() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+        let bindings = &[VDescriptorSetLayout::layout_binding(
+            0,
+            1,
+            DescriptorType::UNIFORM_BUFFER,
+            ShaderStageFlags::VERTEX,
+        )];
+        if device.push_descriptor().is_none() {
+            assert!(VDescriptorSetLayout::new_with_flags(
+                &device,
+                bindings,
+                DescriptorSetLayoutCreateFlags::PUSH_DESCRIPTOR_KHR
+            )
+            .is_err());
+            return Ok(());
+        }
+
+        let layout = VDescriptorSetLayout::new_with_flags(
+            &device,
+            bindings,
+            DescriptorSetLayoutCreateFlags::PUSH_DESCRIPTOR_KHR,
+        )?;
+        let host_visible = MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT;
+        // Off screen unless the view matrix from the uniform buffer moves it back
+        let offset = Vec3::new(10.0, 0.0, 0.5);
+        let vertices = [
+            Vec3::new(0.0, -0.5, 0.0),
+            Vec3::new(0.5, 0.5, 0.0),
+            Vec3::new(-0.5, 0.5, 0.0),
+        ]
+        .map(|position| VVertex::new(position + offset, Vec3::Z, Vec2::ZERO));
+        let vertex_buffer = VBuffer::new_mapped(
+            &device,
+            &vertices,
+            BufferUsageFlags::VERTEX_BUFFER,
+            host_visible,
+        )?;
+        let instance_buffer = VBuffer::new_mapped(
+            &device,
+            &[Mat4::IDENTITY],
+            BufferUsageFlags::VERTEX_BUFFER,
+            host_visible,
+        )?;
+        // The view and projection matrices camera.vert reads
+        let uniform_buffer = VBuffer::new_mapped(
+            &device,
+            &[Mat4::from_translation(-offset * Vec3::X), Mat4::IDENTITY],
+            BufferUsageFlags::UNIFORM_BUFFER,
+            host_visible,
+        )?;
+
+        let extent = Extent2D {
+            width: 4,
+            height: 4,
+        };
+        let target = VOffscreenTarget::new(&device, extent)?;
+        let vertex_input = VVertexInputBuilder::start()
+            .vertex_layout(0, size_of::<VVertex>() as u32, &VVertex::attributes())
+            .binding(1, size_of::<Mat4>() as u32, VertexInputRate::INSTANCE)
+            .mat4_attribute(1, 3, 0)
+            .build();
+        let vertex_module = shader_module(&device, "camera.vert.spv")?;
+        let fragment_module = shader_module(&device, "solid.frag.spv")?;
+        let pipeline = VGraphicsPipelineBuilder::start()
+            .shader_stages(&[
+                (ShaderStageFlags::VERTEX, vertex_module),
+                (ShaderStageFlags::FRAGMENT, fragment_module),
+            ])
+            .vertex_input(&vertex_input.bindings, &vertex_input.attributes)
+            .rasterization(
+                CullModeFlags::NONE,
+                PolygonMode::FILL,
+                FrontFace::COUNTER_CLOCKWISE,
+            )
+            .color_blend_state(&[VGraphicsPipelineBuilder::blend_opaque()])
+            .pipeline_layout(&[layout.get()], &[])
+            .dynamic_state(&[DynamicState::VIEWPORT, DynamicState::SCISSOR])
+            .build(&device, target.render_pass().get())?;
+
+        let buffer_info = DescriptorBufferInfo {
+            buffer: uniform_buffer.buffer(),
+            offset: 0,
+            range: WHOLE_SIZE,
+        };
+        let write = VDescriptorSet::write_descriptor_set(
+            DescriptorSet::null(),
+            0,
+            DescriptorType::UNIFORM_BUFFER,
+            &buffer_info,
+        );
+        let clear_values = ClearValues::start().build();
+        let mut pushed = Ok(());
+        VBuffer::submit_one_time(&device, |command_buffer| {
+            cmd_begin_render_pass(
+                &device,
+                command_buffer,
+                target.render_pass().get(),
+                target.framebuffer(),
+                &clear_values,
+                extent,
+            );
+            cmd_bind_graphics_pipeline(&device, command_buffer, &pipeline);
+            pushed = cmd_push_descriptor_set(
+                &device,
+                command_buffer,
+                PipelineBindPoint::GRAPHICS,
+                pipeline.pipeline_layout(),
+                0,
+                &[write],
+            );
+            cmd_set_viewport(
+                &device,
+                command_buffer,
+                &[Viewport {
+                    width: extent.width as f32,
+                    height: extent.height as f32,
+                    max_depth: 1.0,
+                    ..Default::default()
+                }],
+            );
+            cmd_set_scissor(
+                &device,
+                command_buffer,
+                &[Rect2D {
+                    extent,
+                    ..Default::default()
+                }],
+            );
+            cmd_bind_vertex_buffer(
+                &device,
+                command_buffer,
+                &[vertex_buffer.buffer(), instance_buffer.buffer()],
+                &[0, 0],
+            );
+            cmd_draw(&device, command_buffer, 3, 1);
+            cmd_end_render_pass(&device, command_buffer);
+        })?;
+        pushed?;
+
+        // The center pixel is only covered if the pushed view matrix was read
+        let pixels = target.read_pixels(&device)?;
+        let center = ((2 * extent.width + 2) * 4) as usize;
+        assert_eq!(pixels[center..center + 4], [255, 255, 255, 255]);
+
+        destroy_pipelines(&device, &[&pipeline], &[vertex_module, fragment_module]);
+        for buffer in [vertex_buffer, instance_buffer, uniform_buffer] {
+            buffer.destroy(&device);
+        }
+        unsafe {
+            device
+                .get()
+                .destroy_descriptor_set_layout(layout.get(), None);
+        }
+        target.destroy(&device);
+        Ok(())
+    }
 }
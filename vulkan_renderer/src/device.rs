@@ -1,25 +1,146 @@
 use crate::{
+    command_pool::VCommandPool,
     enums::EOperationType,
+    error::PhysicalDeviceError,
     instance::VInstance,
     queue_family::{VQueueFamilyIndices, VQueues},
+    render_pass::VRenderPassTracker,
     RendererResult,
 };
 use ash::{
-    extensions::khr::{Surface, Swapchain},
+    extensions::{
+        ext::DebugUtils,
+        khr::{DynamicRendering, PushDescriptor, Surface, Swapchain},
+    },
     vk::{
-        CommandBuffer, DeviceCreateInfo, DeviceQueueCreateInfo, Fence, PhysicalDevice,
-        PhysicalDeviceMemoryProperties, PhysicalDeviceProperties, PipelineStageFlags, Queue,
-        QueueFlags, Semaphore, SubmitInfo, SurfaceCapabilitiesKHR, SurfaceKHR,
+        api_version_major, api_version_minor, api_version_patch, Bool32, CommandBuffer,
+        CommandPoolCreateFlags, DeviceCreateInfo, DeviceQueueCreateInfo, Fence, Format,
+        FormatFeatureFlags, FormatProperties, ImageTiling, KhrPortabilitySubsetFn, PhysicalDevice,
+        PhysicalDeviceDynamicRenderingFeaturesKHR, PhysicalDeviceFeatures, PhysicalDeviceFeatures2,
+        PhysicalDeviceMemoryProperties, PhysicalDeviceProperties,
+        PhysicalDeviceTimelineSemaphoreFeatures, PhysicalDeviceType, PipelineStageFlags,
+        PresentModeKHR, Queue, QueueFamilyProperties, QueueFlags, SampleCountFlags, Semaphore,
+        SubmitInfo, SurfaceCapabilitiesKHR, SurfaceFormatKHR, SurfaceKHR, API_VERSION_1_2, TRUE,
     },
     Device, Instance,
 };
+use serde::{Serialize, Serializer};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    ffi::{c_void, CStr},
+    fmt::Debug,
+    ptr,
+};
 use winit::window::Window;
 
+macro_rules! physical_device_feature_fields {
+    ($($field:ident),* $(,)?) => {
+        /// The name and value of every core feature
+        const PHYSICAL_DEVICE_FEATURE_FIELDS: &[(&str, fn(&PhysicalDeviceFeatures) -> Bool32)] =
+            &[$((stringify!($field), |features| features.$field)),*];
+    };
+}
+
+physical_device_feature_fields!(
+    robust_buffer_access,
+    full_draw_index_uint32,
+    image_cube_array,
+    independent_blend,
+    geometry_shader,
+    tessellation_shader,
+    sample_rate_shading,
+    dual_src_blend,
+    logic_op,
+    multi_draw_indirect,
+    draw_indirect_first_instance,
+    depth_clamp,
+    depth_bias_clamp,
+    fill_mode_non_solid,
+    depth_bounds,
+    wide_lines,
+    large_points,
+    alpha_to_one,
+    multi_viewport,
+    sampler_anisotropy,
+    texture_compression_etc2,
+    texture_compression_astc_ldr,
+    texture_compression_bc,
+    occlusion_query_precise,
+    pipeline_statistics_query,
+    vertex_pipeline_stores_and_atomics,
+    fragment_stores_and_atomics,
+    shader_tessellation_and_geometry_point_size,
+    shader_image_gather_extended,
+    shader_storage_image_extended_formats,
+    shader_storage_image_multisample,
+    shader_storage_image_read_without_format,
+    shader_storage_image_write_without_format,
+    shader_uniform_buffer_array_dynamic_indexing,
+    shader_sampled_image_array_dynamic_indexing,
+    shader_storage_buffer_array_dynamic_indexing,
+    shader_storage_image_array_dynamic_indexing,
+    shader_clip_distance,
+    shader_cull_distance,
+    shader_float64,
+    shader_int64,
+    shader_int16,
+    shader_resource_residency,
+    shader_resource_min_lod,
+    sparse_binding,
+    sparse_residency_buffer,
+    sparse_residency_image2_d,
+    sparse_residency_image3_d,
+    sparse_residency2_samples,
+    sparse_residency4_samples,
+    sparse_residency8_samples,
+    sparse_residency16_samples,
+    sparse_residency_aliased,
+    variable_multisample_rate,
+    inherited_queries,
+);
+
+/// Returned by [`VDevice::diagnostics`], all plain data so it can be logged with `{:#?}` or
+/// serialized, Vulkan enums and flags serialize to their `Debug` names
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeviceDiagnostics {
+    pub device_name: String,
+    #[serde(serialize_with = "serialize_debug")]
+    pub device_type: PhysicalDeviceType,
+    /// `major.minor.patch` of the Vulkan version the device supports
+    pub api_version: String,
+    /// Encoded the vendor's own way
+    pub driver_version: u32,
+    pub enabled_extensions: Vec<String>,
+    /// Field names of the enabled core features
+    pub enabled_features: Vec<&'static str>,
+    pub timeline_semaphore: bool,
+    pub max_image_dimension_2d: u32,
+    pub max_push_constants_size: u32,
+    pub max_bound_descriptor_sets: u32,
+    pub max_sampler_anisotropy: f32,
+    pub min_uniform_buffer_offset_alignment: u64,
+    pub max_compute_work_group_invocations: u32,
+    #[serde(serialize_with = "serialize_debug")]
+    pub max_usable_sample_count: SampleCountFlags,
+}
+
+fn serialize_debug<T: Debug, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_args!("{:?}", value))
+}
+
 /// Keeps tracks of the logical device, queues, command_pools and the render_pass
+///
+/// Destroys the logical device and the surface on drop, so everything created from it must be
+/// destroyed first
 pub struct VDevice {
     device: Device,
+    instance: Instance,
+    /// Only loaded when validation is enabled, see [`VInstance::debug_utils`]
+    debug_utils: Option<DebugUtils>,
 
     // Surface
+    surface: Surface,
     surface_khr: SurfaceKHR,
     surface_capabilities: SurfaceCapabilitiesKHR,
 
@@ -27,16 +148,66 @@ pub struct VDevice {
     physical_device: PhysicalDevice,
     memory_properties: PhysicalDeviceMemoryProperties,
     device_properties: PhysicalDeviceProperties,
+    enabled_features: PhysicalDeviceFeatures,
+    enabled_extensions: Vec<&'static CStr>,
+    timeline_semaphore_enabled: bool,
+    /// Only loaded when `VK_KHR_dynamic_rendering` is enabled
+    dynamic_rendering: Option<DynamicRendering>,
+    /// Only loaded when `VK_KHR_push_descriptor` is enabled
+    push_descriptor: Option<PushDescriptor>,
 
     // Queue
     queues: VQueues,
     queue_family_indices: VQueueFamilyIndices,
+    /// Transient pools for one-time submits, one per queue family
+    one_time_command_pools: RefCell<Vec<(u32, VCommandPool)>>,
+    /// Only filled in debug builds
+    render_pass_tracker: RefCell<VRenderPassTracker>,
+    /// Open debug labels per command buffer, only filled in debug builds
+    debug_label_depths: RefCell<HashMap<CommandBuffer, u32>>,
 }
 
 impl VDevice {
+    /// Enables sampler anisotropy if supported and no other features
     pub fn new(instance: &VInstance, window: &Window) -> RendererResult<Self> {
-        // Physical Device
+        Self::new_with_features(instance, window, PhysicalDeviceFeatures::default())
+    }
+
+    /// Enables every feature set in `requested_features`, erroring with the names of those the
+    /// physical device doesn't support
+    ///
+    /// Sampler anisotropy is enabled whenever it's supported, requested or not
+    pub fn new_with_features(
+        instance: &VInstance,
+        window: &Window,
+        requested_features: PhysicalDeviceFeatures,
+    ) -> RendererResult<Self> {
         let physical_device = instance.select_physical_device()?;
+        Self::create(instance, window, physical_device, requested_features)
+    }
+
+    /// Same as [`new`](Self::new) on `physical_device` instead of the highest rated one, e.g. one
+    /// picked from [`VInstance::enumerate_devices`]
+    pub fn new_with_device(
+        instance: &VInstance,
+        window: &Window,
+        physical_device: PhysicalDevice,
+    ) -> RendererResult<Self> {
+        Self::create(
+            instance,
+            window,
+            physical_device,
+            PhysicalDeviceFeatures::default(),
+        )
+    }
+
+    fn create(
+        instance: &VInstance,
+        window: &Window,
+        physical_device: PhysicalDevice,
+        requested_features: PhysicalDeviceFeatures,
+    ) -> RendererResult<Self> {
+        // Physical Device
         let memory_properties = unsafe {
             instance
                 .get()
@@ -48,6 +219,35 @@ impl VDevice {
                 .get_physical_device_properties(physical_device)
         };
 
+        let supported_features =
+            unsafe { instance.get().get_physical_device_features(physical_device) };
+        let unsupported_features =
+            Self::unsupported_features(&requested_features, &supported_features);
+        if !unsupported_features.is_empty() {
+            return Err(PhysicalDeviceError::UnsupportedFeatures {
+                features: unsupported_features,
+            }
+            .into());
+        }
+        // Optional features are only enabled when supported
+        let enabled_features = PhysicalDeviceFeatures {
+            sampler_anisotropy: requested_features.sampler_anisotropy
+                | supported_features.sampler_anisotropy,
+            ..requested_features
+        };
+        let supports_dynamic_rendering =
+            Self::supports_dynamic_rendering(instance, physical_device, &device_properties)?;
+        let mut dynamic_rendering_features = PhysicalDeviceDynamicRenderingFeaturesKHR {
+            dynamic_rendering: TRUE,
+            ..Default::default()
+        };
+        let supports_timeline_semaphore =
+            Self::supports_timeline_semaphore(instance.get(), physical_device, &device_properties);
+        let mut timeline_semaphore_features = PhysicalDeviceTimelineSemaphoreFeatures {
+            timeline_semaphore: TRUE,
+            ..Default::default()
+        };
+
         // Surface
         let entry = ash::Entry::linked();
         let surface = Surface::new(&entry, instance.get());
@@ -63,11 +263,22 @@ impl VDevice {
             physical_device,
             &surface,
             surface_khr,
-        );
+        )?;
 
         let queue_create_infos = Self::device_queue_create_infos(queue_family_indices);
-        let extensions = [Swapchain::name().as_ptr()];
-        let device_create_info = Self::device_create_info(&queue_create_infos, &extensions);
+        let enabled_extensions =
+            Self::device_extensions(instance, physical_device, supports_dynamic_rendering)?;
+        let extensions = enabled_extensions
+            .iter()
+            .map(|extension| extension.as_ptr())
+            .collect::<Vec<_>>();
+        let device_create_info = Self::device_create_info(
+            &queue_create_infos,
+            &extensions,
+            &enabled_features,
+            supports_timeline_semaphore.then_some(&mut timeline_semaphore_features),
+            supports_dynamic_rendering.then_some(&mut dynamic_rendering_features),
+        );
         let device = unsafe {
             instance
                 .get()
@@ -75,14 +286,30 @@ impl VDevice {
         };
 
         let queues = VQueues::new(&device, queue_family_indices);
+        let dynamic_rendering =
+            supports_dynamic_rendering.then(|| DynamicRendering::new(instance.get(), &device));
+        let push_descriptor = enabled_extensions
+            .contains(&PushDescriptor::name())
+            .then(|| PushDescriptor::new(instance.get(), &device));
 
         Ok(Self {
             device,
+            instance: instance.get().clone(),
+            debug_utils: instance.debug_utils().cloned(),
             physical_device,
             memory_properties,
             device_properties,
+            enabled_features,
+            enabled_extensions,
+            timeline_semaphore_enabled: supports_timeline_semaphore,
+            dynamic_rendering,
+            push_descriptor,
             queue_family_indices,
             queues,
+            one_time_command_pools: RefCell::new(Vec::new()),
+            render_pass_tracker: RefCell::new(VRenderPassTracker::default()),
+            debug_label_depths: RefCell::new(HashMap::new()),
+            surface,
             surface_khr,
             surface_capabilities,
         })
@@ -108,6 +335,64 @@ impl VDevice {
         self.queue_family_indices.get(operation_type)
     }
 
+    /// Transient pool for one-time-submit uploads on the transfer queue
+    pub fn transfer_command_pool(&self) -> RendererResult<VCommandPool> {
+        self.one_time_command_pool(EOperationType::Transfer)
+    }
+
+    /// Transient pool for one-time submits to the queue of `operation_type`, created on first use
+    /// and destroyed with the device
+    ///
+    /// Operation types sharing a queue family share the pool, its command buffers must be freed
+    /// after use
+    pub fn one_time_command_pool(
+        &self,
+        operation_type: EOperationType,
+    ) -> RendererResult<VCommandPool> {
+        let queue_family_index = self.get_queue_family_index(operation_type);
+        let mut command_pools = self.one_time_command_pools.borrow_mut();
+        if let Some(&(_, command_pool)) = command_pools
+            .iter()
+            .find(|(family_index, _)| *family_index == queue_family_index)
+        {
+            return Ok(command_pool);
+        }
+        let command_pool =
+            VCommandPool::new(self, queue_family_index, CommandPoolCreateFlags::TRANSIENT)?;
+        command_pools.push((queue_family_index, command_pool));
+        Ok(command_pool)
+    }
+
+    pub(crate) fn render_pass_tracker(&self) -> &RefCell<VRenderPassTracker> {
+        &self.render_pass_tracker
+    }
+
+    /// The debug utils loader for command buffer labels, only available when validation is enabled
+    pub fn debug_utils(&self) -> Option<&DebugUtils> {
+        self.debug_utils.as_ref()
+    }
+
+    pub(crate) fn debug_label_depths(&self) -> &RefCell<HashMap<CommandBuffer, u32>> {
+        &self.debug_label_depths
+    }
+
+    /// The distinct queue families buffers are shared between, so they can be written on the
+    /// transfer queue and used on the graphics and compute queues
+    pub(crate) fn buffer_queue_family_indices(&self) -> Vec<u32> {
+        Self::distinct_families(&[
+            self.get_queue_family_index(EOperationType::Graphics),
+            self.get_queue_family_index(EOperationType::Transfer),
+            self.get_queue_family_index(EOperationType::Compute),
+        ])
+    }
+
+    fn distinct_families(families: &[u32]) -> Vec<u32> {
+        let mut families = families.to_vec();
+        families.sort_unstable();
+        families.dedup();
+        families
+    }
+
     pub fn get_memory_properties(&self) -> PhysicalDeviceMemoryProperties {
         self.memory_properties
     }
@@ -116,16 +401,166 @@ impl VDevice {
         self.device_properties
     }
 
+    pub fn get_enabled_features(&self) -> PhysicalDeviceFeatures {
+        self.enabled_features
+    }
+
+    /// A snapshot of the device and what it was created with, e.g. to attach to bug reports
+    pub fn diagnostics(&self) -> DeviceDiagnostics {
+        let properties = &self.device_properties;
+        let limits = &properties.limits;
+        let device_name = unsafe { CStr::from_ptr(properties.device_name.as_ptr()) };
+        DeviceDiagnostics {
+            device_name: device_name.to_string_lossy().into_owned(),
+            device_type: properties.device_type,
+            api_version: format!(
+                "{}.{}.{}",
+                api_version_major(properties.api_version),
+                api_version_minor(properties.api_version),
+                api_version_patch(properties.api_version)
+            ),
+            driver_version: properties.driver_version,
+            enabled_extensions: self
+                .enabled_extensions
+                .iter()
+                .map(|extension| extension.to_string_lossy().into_owned())
+                .collect(),
+            enabled_features: Self::enabled_feature_names(&self.enabled_features),
+            timeline_semaphore: self.timeline_semaphore_enabled,
+            max_image_dimension_2d: limits.max_image_dimension2_d,
+            max_push_constants_size: limits.max_push_constants_size,
+            max_bound_descriptor_sets: limits.max_bound_descriptor_sets,
+            max_sampler_anisotropy: limits.max_sampler_anisotropy,
+            min_uniform_buffer_offset_alignment: limits.min_uniform_buffer_offset_alignment,
+            max_compute_work_group_invocations: limits.max_compute_work_group_invocations,
+            max_usable_sample_count: self.get_max_usable_sample_count(),
+        }
+    }
+
+    /// Whether [`VSemaphore::new_timeline`](crate::sync::VSemaphore::new_timeline) can be used
+    pub fn timeline_semaphore_enabled(&self) -> bool {
+        self.timeline_semaphore_enabled
+    }
+
+    /// The loader for [`cmd_begin_rendering`](crate::cmd::cmd_begin_rendering), only available
+    /// when the device supports `VK_KHR_dynamic_rendering`, which Vulkan 1.3 drivers all do
+    pub fn dynamic_rendering(&self) -> Option<&DynamicRendering> {
+        self.dynamic_rendering.as_ref()
+    }
+
+    /// The loader for [`cmd_push_descriptor_set`](crate::cmd::cmd_push_descriptor_set), only
+    /// available when the device supports `VK_KHR_push_descriptor`
+    pub fn push_descriptor(&self) -> Option<&PushDescriptor> {
+        self.push_descriptor.as_ref()
+    }
+
+    pub fn get_surface_formats(&self) -> RendererResult<Vec<SurfaceFormatKHR>> {
+        Ok(unsafe {
+            self.surface
+                .get_physical_device_surface_formats(self.physical_device, self.surface_khr)?
+        })
+    }
+
+    pub fn get_surface_present_modes(&self) -> RendererResult<Vec<PresentModeKHR>> {
+        Ok(unsafe {
+            self.surface
+                .get_physical_device_surface_present_modes(self.physical_device, self.surface_khr)?
+        })
+    }
+
     pub fn get_surface_capabilities(&self) -> SurfaceCapabilitiesKHR {
         self.surface_capabilities
     }
 
+    /// The linear, optimal and buffer features the physical device supports for `format`
+    pub fn get_format_properties(&self, format: Format) -> FormatProperties {
+        unsafe {
+            self.instance
+                .get_physical_device_format_properties(self.physical_device, format)
+        }
+    }
+
+    /// The properties of the queue family used for `operation_type`, e.g. its timestamp valid bits
+    pub fn get_queue_family_properties(
+        &self,
+        operation_type: EOperationType,
+    ) -> QueueFamilyProperties {
+        self.get_all_queue_family_properties()[self.get_queue_family_index(operation_type) as usize]
+    }
+
+    /// The properties of every queue family of the physical device, indexed by family
+    pub fn get_all_queue_family_properties(&self) -> Vec<QueueFamilyProperties> {
+        unsafe {
+            self.instance
+                .get_physical_device_queue_family_properties(self.physical_device)
+        }
+    }
+
+    /// Whether timestamps can be written on the graphics queue
+    pub fn graphics_family_supports_timestamps(&self) -> bool {
+        self.get_queue_family_properties(EOperationType::Graphics)
+            .timestamp_valid_bits
+            > 0
+    }
+
+    /// A family that supports transfers but neither graphics nor compute, usually backed by a DMA engine
+    pub fn dedicated_transfer_family(&self) -> Option<u32> {
+        let queue_flags = self
+            .get_all_queue_family_properties()
+            .iter()
+            .map(|queue_family| queue_family.queue_flags)
+            .collect::<Vec<_>>();
+        Self::find_transfer_only_family(&queue_flags)
+    }
+
+    /// How many queues `family` has, 0 for a family the physical device doesn't have
+    pub fn queue_count(&self, family: u32) -> u32 {
+        self.get_all_queue_family_properties()
+            .get(family as usize)
+            .map_or(0, |queue_family| queue_family.queue_count)
+    }
+
+    /// The highest sample count both color and depth framebuffer attachments support
+    pub fn get_max_usable_sample_count(&self) -> SampleCountFlags {
+        let limits = self.device_properties.limits;
+        let counts =
+            limits.framebuffer_color_sample_counts & limits.framebuffer_depth_sample_counts;
+        [
+            SampleCountFlags::TYPE_64,
+            SampleCountFlags::TYPE_32,
+            SampleCountFlags::TYPE_16,
+            SampleCountFlags::TYPE_8,
+            SampleCountFlags::TYPE_4,
+            SampleCountFlags::TYPE_2,
+        ]
+        .into_iter()
+        .find(|&samples| counts.contains(samples))
+        .unwrap_or(SampleCountFlags::TYPE_1)
+    }
+
+    /// The first of `candidates` whose `tiling` supports all of `features`
+    pub fn find_supported_depth_format(
+        &self,
+        candidates: &[Format],
+        tiling: ImageTiling,
+        features: FormatFeatureFlags,
+    ) -> Option<Format> {
+        candidates.iter().copied().find(|&format| {
+            let properties = self.get_format_properties(format);
+            let supported = match tiling {
+                ImageTiling::LINEAR => properties.linear_tiling_features,
+                _ => properties.optimal_tiling_features,
+            };
+            supported.contains(features)
+        })
+    }
+
     fn select_queue_family_indices(
         instance: &Instance,
         physical_device: PhysicalDevice,
         surface: &Surface,
         surface_khr: SurfaceKHR,
-    ) -> VQueueFamilyIndices {
+    ) -> RendererResult<VQueueFamilyIndices> {
         let queue_family_properties =
             unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
 
@@ -166,18 +601,57 @@ impl VDevice {
             }
         }
 
-        for (ind, queue_family) in queue_family_properties.iter().enumerate() {
-            if queue_family.queue_flags.contains(QueueFlags::COMPUTE) {
-                if queue_family_indices.compute == u32::MAX {
-                    queue_family_indices.compute = ind as u32;
-                }
-                if ind as u32 != queue_family_indices.graphics {
-                    queue_family_indices.compute = ind as u32;
-                    break;
-                }
-            }
-        }
-        queue_family_indices
+        let queue_flags = queue_family_properties
+            .iter()
+            .map(|queue_family| queue_family.queue_flags)
+            .collect::<Vec<_>>();
+        queue_family_indices.compute = Self::select_compute_family(&queue_flags)
+            .ok_or(PhysicalDeviceError::NoComputeQueueFamily)?;
+        queue_family_indices.transfer =
+            Self::select_transfer_family(&queue_flags, queue_family_indices.graphics);
+        Ok(queue_family_indices)
+    }
+
+    /// Prefers a compute family without graphics, for async compute, then one shared with graphics
+    fn select_compute_family(queue_flags: &[QueueFlags]) -> Option<u32> {
+        let dedicated = queue_flags.iter().position(|&flags| {
+            flags.contains(QueueFlags::COMPUTE) && !flags.contains(QueueFlags::GRAPHICS)
+        });
+        let shared = || {
+            queue_flags
+                .iter()
+                .position(|&flags| flags.contains(QueueFlags::COMPUTE))
+        };
+        dedicated.or_else(shared).map(|ind| ind as u32)
+    }
+
+    /// Prefers a transfer-only family, then any other family distinct from graphics
+    ///
+    /// Graphics and compute families support transfers implicitly, so they count as transfer capable
+    fn select_transfer_family(queue_flags: &[QueueFlags], graphics: u32) -> u32 {
+        let supports_transfer = |flags: QueueFlags| {
+            flags.intersects(QueueFlags::TRANSFER | QueueFlags::GRAPHICS | QueueFlags::COMPUTE)
+        };
+        let transfer_only = Self::find_transfer_only_family(queue_flags);
+        let distinct = || {
+            queue_flags
+                .iter()
+                .enumerate()
+                .position(|(ind, &flags)| ind as u32 != graphics && supports_transfer(flags))
+        };
+        transfer_only
+            .or_else(|| distinct().map(|ind| ind as u32))
+            .unwrap_or(graphics)
+    }
+
+    fn find_transfer_only_family(queue_flags: &[QueueFlags]) -> Option<u32> {
+        queue_flags
+            .iter()
+            .position(|&flags| {
+                flags.contains(QueueFlags::TRANSFER)
+                    && !flags.intersects(QueueFlags::GRAPHICS | QueueFlags::COMPUTE)
+            })
+            .map(|ind| ind as u32)
     }
 
     pub fn create_queue_submit_info(
@@ -218,15 +692,110 @@ impl VDevice {
         Ok(())
     }
 
+    fn enabled_feature_names(features: &PhysicalDeviceFeatures) -> Vec<&'static str> {
+        PHYSICAL_DEVICE_FEATURE_FIELDS
+            .iter()
+            .filter(|(_, value)| value(features) == TRUE)
+            .map(|&(name, _)| name)
+            .collect()
+    }
+
+    /// The names of the features set in `requested` but not in `supported`
+    fn unsupported_features(
+        requested: &PhysicalDeviceFeatures,
+        supported: &PhysicalDeviceFeatures,
+    ) -> Vec<&'static str> {
+        PHYSICAL_DEVICE_FEATURE_FIELDS
+            .iter()
+            .filter(|(_, value)| value(requested) == TRUE && value(supported) != TRUE)
+            .map(|&(name, _)| name)
+            .collect()
+    }
+
+    /// Timeline semaphores are core in Vulkan 1.2, older devices don't get them
+    fn supports_timeline_semaphore(
+        instance: &Instance,
+        physical_device: PhysicalDevice,
+        device_properties: &PhysicalDeviceProperties,
+    ) -> bool {
+        if device_properties.api_version < API_VERSION_1_2 {
+            return false;
+        }
+        let mut timeline_semaphore_features = PhysicalDeviceTimelineSemaphoreFeatures::default();
+        let mut features = PhysicalDeviceFeatures2 {
+            p_next: &mut timeline_semaphore_features as *mut _ as *mut c_void,
+            ..Default::default()
+        };
+        unsafe { instance.get_physical_device_features2(physical_device, &mut features) };
+        timeline_semaphore_features.timeline_semaphore == TRUE
+    }
+
+    /// The extension needs Vulkan 1.2, where the render pass 2 and depth stencil resolve
+    /// extensions it depends on are core
+    fn supports_dynamic_rendering(
+        instance: &VInstance,
+        physical_device: PhysicalDevice,
+        device_properties: &PhysicalDeviceProperties,
+    ) -> RendererResult<bool> {
+        if device_properties.api_version < API_VERSION_1_2
+            || !Self::supports_extension(instance, physical_device, DynamicRendering::name())?
+        {
+            return Ok(false);
+        }
+        let mut dynamic_rendering_features = PhysicalDeviceDynamicRenderingFeaturesKHR::default();
+        let mut features = PhysicalDeviceFeatures2 {
+            p_next: &mut dynamic_rendering_features as *mut _ as *mut c_void,
+            ..Default::default()
+        };
+        unsafe {
+            instance
+                .get()
+                .get_physical_device_features2(physical_device, &mut features)
+        };
+        Ok(dynamic_rendering_features.dynamic_rendering == TRUE)
+    }
+
+    fn supports_extension(
+        instance: &VInstance,
+        physical_device: PhysicalDevice,
+        extension: &CStr,
+    ) -> RendererResult<bool> {
+        let extension_properties = unsafe {
+            instance
+                .get()
+                .enumerate_device_extension_properties(physical_device)?
+        };
+        Ok(extension_properties.iter().any(|properties| {
+            let name = unsafe { CStr::from_ptr(properties.extension_name.as_ptr()) };
+            name == extension
+        }))
+    }
+
+    /// Chains the feature structs into `p_next`, each only when the device version or extension
+    /// it belongs to is enabled
     fn device_create_info(
         queue_infos: &[DeviceQueueCreateInfo],
         extensions: &[*const i8],
+        enabled_features: &PhysicalDeviceFeatures,
+        timeline_semaphore_features: Option<&mut PhysicalDeviceTimelineSemaphoreFeatures>,
+        dynamic_rendering_features: Option<&mut PhysicalDeviceDynamicRenderingFeaturesKHR>,
     ) -> DeviceCreateInfo {
+        let mut p_next = ptr::null_mut::<c_void>();
+        if let Some(features) = dynamic_rendering_features {
+            features.p_next = p_next;
+            p_next = features as *mut _ as *mut c_void;
+        }
+        if let Some(features) = timeline_semaphore_features {
+            features.p_next = p_next;
+            p_next = features as *mut _ as *mut c_void;
+        }
         DeviceCreateInfo {
+            p_next,
             queue_create_info_count: queue_infos.len() as u32,
             p_queue_create_infos: queue_infos.as_ptr(),
             enabled_extension_count: extensions.len() as u32,
             pp_enabled_extension_names: extensions.as_ptr(),
+            p_enabled_features: enabled_features,
             ..Default::default()
         }
     }
@@ -235,8 +804,13 @@ impl VDevice {
     fn device_queue_create_infos(
         queue_family_indices: VQueueFamilyIndices,
     ) -> Vec<DeviceQueueCreateInfo> {
-        let unique_indices =
-            Vec::from_iter([queue_family_indices.compute, queue_family_indices.graphics]);
+        let mut unique_indices = Vec::from_iter([
+            queue_family_indices.compute,
+            queue_family_indices.graphics,
+            queue_family_indices.transfer,
+        ]);
+        unique_indices.sort_unstable();
+        unique_indices.dedup();
         unique_indices
             .iter()
             .map(|&queue_family_index| DeviceQueueCreateInfo {
@@ -248,17 +822,302 @@ impl VDevice {
             .collect()
     }
 
-    #[allow(dead_code)]
-    fn get_device_extensions(
+    /// The swapchain, plus `VK_KHR_portability_subset` which must be enabled whenever the device
+    /// advertises it, as MoltenVK does, and `VK_KHR_dynamic_rendering` and `VK_KHR_push_descriptor`
+    /// when supported
+    fn device_extensions(
         instance: &VInstance,
         physical_device: PhysicalDevice,
-    ) -> RendererResult<()> {
-        let extension_props = unsafe {
-            instance
-                .get()
-                .enumerate_device_extension_properties(physical_device)?
+        dynamic_rendering: bool,
+    ) -> RendererResult<Vec<&'static CStr>> {
+        let portability_subset = KhrPortabilitySubsetFn::name();
+        let mut extensions = vec![Swapchain::name()];
+        if Self::supports_extension(instance, physical_device, portability_subset)? {
+            extensions.push(portability_subset);
+        }
+        if dynamic_rendering {
+            extensions.push(DynamicRendering::name());
+        }
+        if Self::supports_extension(instance, physical_device, PushDescriptor::name())? {
+            extensions.push(PushDescriptor::name());
+        }
+        Ok(extensions)
+    }
+}
+
+impl Drop for VDevice {
+    fn drop(&mut self) {
+        unsafe {
+            self.device
+                .device_wait_idle()
+                .expect("Failed to wait for device idle.");
+            for (_, command_pool) in self.one_time_command_pools.get_mut().drain(..) {
+                self.device.destroy_command_pool(command_pool.get(), None);
+            }
+            self.device.destroy_device(None);
+            self.surface.destroy_surface(self.surface_khr, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils::create_window, RendererError};
+
+    #[test]
+    fn creates_and_drops_device_repeatedly() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        for _ in 0..100 {
+            let device = VDevice::new(&instance, &window)?;
+            drop(device);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn creates_device_on_chosen_physical_device() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let (physical_device, name) = instance.enumerate_devices()?.pop().unwrap();
+
+        let device = VDevice::new_with_device(&instance, &window, physical_device)?;
+        assert_eq!(device.get_physical_device(), physical_device);
+        assert_eq!(device.diagnostics().device_name, name);
+        Ok(())
+    }
+
+    #[test]
+    fn prefers_depth_stencil_format_with_fallback() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+        let tiling = ImageTiling::OPTIMAL;
+        let features = FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT;
+
+        let format = device
+            .find_supported_depth_format(
+                &[Format::D32_SFLOAT_S8_UINT, Format::D32_SFLOAT],
+                tiling,
+                features,
+            )
+            .expect("Neither D32_SFLOAT_S8_UINT nor D32_SFLOAT is supported.");
+        let stencil_supported = device
+            .find_supported_depth_format(&[Format::D32_SFLOAT_S8_UINT], tiling, features)
+            .is_some();
+        let expected = if stencil_supported {
+            Format::D32_SFLOAT_S8_UINT
+        } else {
+            Format::D32_SFLOAT
         };
-        println!("{:#?}", extension_props);
+        assert_eq!(format, expected);
+
+        assert!(device
+            .find_supported_depth_format(&[Format::R8G8B8A8_UNORM], tiling, features)
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn reports_enabled_extensions_and_features() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let diagnostics = device.diagnostics();
+        assert!(!diagnostics.device_name.is_empty());
+        assert!(diagnostics
+            .enabled_extensions
+            .iter()
+            .any(|extension| extension == "VK_KHR_swapchain"));
+        assert_eq!(
+            diagnostics.enabled_features.contains(&"sampler_anisotropy"),
+            device.get_enabled_features().sampler_anisotropy == TRUE
+        );
+
+        let json = serde_json::to_value(&diagnostics).expect("Diagnostics serialize to JSON.");
+        assert_eq!(json["device_name"], diagnostics.device_name.as_str());
+        assert_eq!(
+            json["device_type"],
+            format!("{:?}", diagnostics.device_type).as_str()
+        );
+        assert_eq!(json["api_version"], diagnostics.api_version.as_str());
+        assert_eq!(
+            json["enabled_extensions"],
+            serde_json::json!(diagnostics.enabled_extensions)
+        );
+        assert_eq!(
+            json["enabled_features"],
+            serde_json::json!(diagnostics.enabled_features)
+        );
+        assert_eq!(json["timeline_semaphore"], diagnostics.timeline_semaphore);
+        assert_eq!(
+            json["max_push_constants_size"],
+            diagnostics.max_push_constants_size
+        );
+        assert_eq!(
+            json["max_usable_sample_count"],
+            format!("{:?}", diagnostics.max_usable_sample_count).as_str()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn enables_requested_sampler_anisotropy() -> RendererResult<()> {
+        let requested = PhysicalDeviceFeatures {
+            sampler_anisotropy: TRUE,
+            fill_mode_non_solid: TRUE,
+            ..Default::default()
+        };
+        let supported = PhysicalDeviceFeatures {
+            fill_mode_non_solid: TRUE,
+            ..Default::default()
+        };
+        assert_eq!(
+            VDevice::unsupported_features(&requested, &supported),
+            ["sampler_anisotropy"]
+        );
+
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let requested = PhysicalDeviceFeatures {
+            sampler_anisotropy: TRUE,
+            ..Default::default()
+        };
+        match VDevice::new_with_features(&instance, &window, requested) {
+            Ok(device) => assert_eq!(device.get_enabled_features().sampler_anisotropy, TRUE),
+            Err(RendererError::PhysicalDevice(PhysicalDeviceError::UnsupportedFeatures {
+                features,
+            })) => assert_eq!(features, ["sampler_anisotropy"]),
+            Err(err) => return Err(err),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn chains_only_enabled_feature_structs() {
+        let features = PhysicalDeviceFeatures::default();
+        let mut timeline_semaphore_features = PhysicalDeviceTimelineSemaphoreFeatures::default();
+        let mut dynamic_rendering_features = PhysicalDeviceDynamicRenderingFeaturesKHR::default();
+        let timeline_ptr = &mut timeline_semaphore_features as *mut _ as *const c_void;
+        let dynamic_rendering_ptr = &mut dynamic_rendering_features as *mut _ as *const c_void;
+
+        let create_info = VDevice::device_create_info(&[], &[], &features, None, None);
+        assert!(create_info.p_next.is_null());
+
+        let create_info = VDevice::device_create_info(
+            &[],
+            &[],
+            &features,
+            None,
+            Some(&mut dynamic_rendering_features),
+        );
+        assert_eq!(create_info.p_next, dynamic_rendering_ptr);
+
+        let create_info = VDevice::device_create_info(
+            &[],
+            &[],
+            &features,
+            Some(&mut timeline_semaphore_features),
+            Some(&mut dynamic_rendering_features),
+        );
+        assert_eq!(create_info.p_next, timeline_ptr);
+        assert_eq!(
+            timeline_semaphore_features.p_next as *const c_void,
+            dynamic_rendering_ptr
+        );
+    }
+
+    #[test]
+    fn selects_transfer_family_apart_from_graphics() -> RendererResult<()> {
+        let graphics = QueueFlags::GRAPHICS | QueueFlags::COMPUTE | QueueFlags::TRANSFER;
+        let compute = QueueFlags::COMPUTE | QueueFlags::TRANSFER;
+        assert_eq!(
+            VDevice::select_transfer_family(&[graphics, compute, QueueFlags::TRANSFER], 0),
+            2
+        );
+        assert_eq!(VDevice::select_transfer_family(&[graphics, compute], 0), 1);
+        assert_eq!(VDevice::select_transfer_family(&[graphics], 0), 0);
+
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+        println!(
+            "Graphics family {}, transfer family {}",
+            device.get_queue_family_index(EOperationType::Graphics),
+            device.get_queue_family_index(EOperationType::Transfer)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn selects_compute_family_apart_from_graphics() -> RendererResult<()> {
+        let graphics = QueueFlags::GRAPHICS | QueueFlags::COMPUTE | QueueFlags::TRANSFER;
+        let compute = QueueFlags::COMPUTE | QueueFlags::TRANSFER;
+        assert_eq!(
+            VDevice::select_compute_family(&[graphics, QueueFlags::TRANSFER, compute]),
+            Some(2)
+        );
+        assert_eq!(VDevice::select_compute_family(&[graphics]), Some(0));
+        assert_eq!(
+            VDevice::select_compute_family(&[QueueFlags::GRAPHICS, QueueFlags::TRANSFER]),
+            None
+        );
+        assert_eq!(VDevice::distinct_families(&[0, 2, 0]), [0, 2]);
+        assert_eq!(VDevice::distinct_families(&[1, 1, 1]), [1]);
+
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+        let compute_family = device.get_queue_family_index(EOperationType::Compute);
+        let queue_family_properties = device.get_all_queue_family_properties();
+        assert!((compute_family as usize) < queue_family_properties.len());
+        assert!(queue_family_properties[compute_family as usize]
+            .queue_flags
+            .contains(QueueFlags::COMPUTE));
+        assert!(device
+            .buffer_queue_family_indices()
+            .contains(&compute_family));
+        Ok(())
+    }
+
+    #[test]
+    fn reports_queue_family_capabilities() -> RendererResult<()> {
+        let graphics = QueueFlags::GRAPHICS | QueueFlags::COMPUTE | QueueFlags::TRANSFER;
+        assert_eq!(
+            VDevice::find_transfer_only_family(&[graphics, QueueFlags::TRANSFER]),
+            Some(1)
+        );
+        assert_eq!(VDevice::find_transfer_only_family(&[graphics]), None);
+
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+        for (family, queue_family) in device.get_all_queue_family_properties().iter().enumerate() {
+            println!(
+                "Family {}: {:?}, {} queues, {} timestamp bits",
+                family,
+                queue_family.queue_flags,
+                queue_family.queue_count,
+                queue_family.timestamp_valid_bits
+            );
+        }
+        println!(
+            "Graphics timestamps {}, dedicated transfer family {:?}",
+            device.graphics_family_supports_timestamps(),
+            device.dedicated_transfer_family()
+        );
+
+        let graphics_family = device.get_queue_family_index(EOperationType::Graphics);
+        assert!(device.queue_count(graphics_family) >= 1);
+        assert_eq!(device.queue_count(u32::MAX), 0);
+        if let Some(family) = device.dedicated_transfer_family() {
+            assert_eq!(
+                device.get_queue_family_index(EOperationType::Transfer),
+                family
+            );
+        }
         Ok(())
     }
 }
@@ -1,5 +1,53 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EOperationType {
     Compute,
     Graphics,
     Present,
+    /// A transfer-only family when the device has one, otherwise the graphics family
+    Transfer,
+}
+
+/// How the texels of a texture are encoded
+///
+/// Color textures (base color, emissive) are `Srgb`, data textures (normal, metallic-roughness, occlusion) are `Linear`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ETextureColorSpace {
+    Srgb,
+    Linear,
+}
+
+/// The filtering a texture sampler ended up with after checking device and format support
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ESamplerQuality {
+    /// Trilinear filtering with up to 16x anisotropy
+    Anisotropic,
+    /// Linear filtering within and between mip levels
+    Trilinear,
+    /// Point sampling, used when the format can't be filtered linearly
+    #[default]
+    Nearest,
+}
+
+/// What [`VSwapchain::acquire_next_image`](crate::swapchain::VSwapchain::acquire_next_image) got back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EAcquireResult {
+    Acquired(u32),
+    /// The image can still be rendered and presented, but the swapchain no longer matches the surface
+    Suboptimal(u32),
+    /// No image became available before the timeout
+    Timeout,
+    /// No image was available and the timeout was zero
+    NotReady,
+    /// The surface changed, the swapchain has to be recreated before acquiring again
+    OutOfDate,
+}
+
+impl EAcquireResult {
+    /// The acquired image, if there is one to render to
+    pub fn image_index(&self) -> Option<u32> {
+        match *self {
+            Self::Acquired(image_index) | Self::Suboptimal(image_index) => Some(image_index),
+            Self::Timeout | Self::NotReady | Self::OutOfDate => None,
+        }
+    }
 }
@@ -0,0 +1,68 @@
+use ash::vk::{self, Format, MemoryPropertyFlags};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum RendererError {
+    #[error("Vulkan call failed: {0}")]
+    Vulkan(#[from] vk::Result),
+    #[error("Failed to read file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to load glTF: {0}")]
+    Gltf(#[from] gltf::Error),
+    #[error("Failed to encode image: {0}")]
+    Image(#[from] ::image::ImageError),
+    #[cfg(feature = "shaderc")]
+    #[error("Failed to compile shader: {0}")]
+    ShaderCompilation(#[from] shaderc::Error),
+    #[cfg(feature = "shaderc")]
+    #[error("Failed to initialize the shader compiler.")]
+    ShaderCompilerInit,
+    #[cfg(feature = "hot-reload")]
+    #[error("Failed to watch shader files: {0}")]
+    Watch(#[from] notify::Error),
+    #[error(transparent)]
+    PhysicalDevice(#[from] PhysicalDeviceError),
+    #[error(transparent)]
+    Memory(#[from] MemoryError),
+    /// The renderer was asked for something it can't do with the given arguments
+    #[error("{0}")]
+    InvalidArgument(String),
+}
+
+#[derive(Debug, Error)]
+pub enum PhysicalDeviceError {
+    #[error("Failed to find a physical device.")]
+    NoPhysicalDevice,
+    #[error("The physical device has no compute queue family.")]
+    NoComputeQueueFamily,
+    #[error("None of the formats {candidates:?} support the required features.")]
+    NoSupportedFormat { candidates: Vec<Format> },
+    #[error("The physical device does not support the requested features {features:?}.")]
+    UnsupportedFeatures { features: Vec<&'static str> },
+}
+
+#[derive(Debug, Error)]
+pub enum MemoryError {
+    #[error("No memory type supports the requested properties {requested:?}.")]
+    NoSuitableMemoryType { requested: MemoryPropertyFlags },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RendererResult;
+
+    #[test]
+    fn converts_vulkan_results_into_matchable_errors() {
+        fn allocate() -> RendererResult<()> {
+            Err(vk::Result::ERROR_OUT_OF_DEVICE_MEMORY)?;
+            Ok(())
+        }
+        assert!(matches!(
+            allocate(),
+            Err(RendererError::Vulkan(
+                vk::Result::ERROR_OUT_OF_DEVICE_MEMORY
+            ))
+        ));
+    }
+}
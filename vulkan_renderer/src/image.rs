@@ -1,19 +1,176 @@
-use crate::{device::VDevice, impl_get, RendererResult};
+use crate::{
+    allocator::VAllocator,
+    barrier::{BarrierBatch, VImageBarrier},
+    device::VDevice,
+    error::MemoryError,
+    impl_get, RendererError, RendererResult,
+};
 use ash::vk::{
-    DeviceMemory, Extent3D, Format, Image, ImageAspectFlags, ImageCreateInfo,
+    AccessFlags, ClearColorValue, ClearDepthStencilValue, CommandBuffer, DependencyFlags,
+    DeviceMemory, Extent3D, Filter, Format, FormatFeatureFlags, Image, ImageAspectFlags, ImageBlit,
+    ImageCreateFlags, ImageCreateInfo, ImageLayout, ImageMemoryBarrier, ImageSubresourceLayers,
     ImageSubresourceRange, ImageTiling, ImageType, ImageUsageFlags, ImageView, ImageViewCreateInfo,
-    ImageViewType, MemoryAllocateInfo, MemoryPropertyFlags, MemoryRequirements,
-    PhysicalDeviceMemoryProperties, SampleCountFlags, SharingMode,
+    ImageViewType, MemoryAllocateInfo, MemoryPropertyFlags, MemoryRequirements, Offset3D,
+    PhysicalDeviceFeatures, PhysicalDeviceMemoryProperties, PipelineStageFlags, SampleCountFlags,
+    SharingMode, QUEUE_FAMILY_IGNORED, TRUE,
 };
 
-#[derive(Default, Debug, Clone, Copy)]
+/// The dimensionality of a [`VImage`] and its view, a single 2D layer by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VImageShape {
+    pub image_type: ImageType,
+    pub view_type: ImageViewType,
+    pub array_layers: u32,
+}
+
+impl Default for VImageShape {
+    fn default() -> Self {
+        Self {
+            image_type: ImageType::TYPE_2D,
+            view_type: ImageViewType::TYPE_2D,
+            array_layers: 1,
+        }
+    }
+}
+
+impl VImageShape {
+    /// Six square layers in +X, -X, +Y, -Y, +Z, -Z order, e.g. for a skybox
+    pub fn cube() -> Self {
+        Self {
+            view_type: ImageViewType::CUBE,
+            array_layers: 6,
+            ..Default::default()
+        }
+    }
+
+    /// `layers` 2D layers viewed as an array, e.g. for shadow cascades
+    pub fn array_2d(layers: u32) -> Self {
+        Self {
+            view_type: ImageViewType::TYPE_2D_ARRAY,
+            array_layers: layers,
+            ..Default::default()
+        }
+    }
+
+    /// A single 3D layer, e.g. for volume textures
+    pub fn volume() -> Self {
+        Self {
+            image_type: ImageType::TYPE_3D,
+            view_type: ImageViewType::TYPE_3D,
+            array_layers: 1,
+        }
+    }
+
+    fn is_cube(&self) -> bool {
+        matches!(
+            self.view_type,
+            ImageViewType::CUBE | ImageViewType::CUBE_ARRAY
+        )
+    }
+
+    fn create_flags(&self) -> ImageCreateFlags {
+        match self.is_cube() {
+            true => ImageCreateFlags::CUBE_COMPATIBLE,
+            false => ImageCreateFlags::empty(),
+        }
+    }
+
+    /// The image type views of `view_type` can be created on
+    fn view_image_type(view_type: ImageViewType) -> ImageType {
+        match view_type {
+            ImageViewType::TYPE_1D | ImageViewType::TYPE_1D_ARRAY => ImageType::TYPE_1D,
+            ImageViewType::TYPE_3D => ImageType::TYPE_3D,
+            _ => ImageType::TYPE_2D,
+        }
+    }
+
+    fn is_array(&self) -> bool {
+        matches!(
+            self.view_type,
+            ImageViewType::TYPE_1D_ARRAY | ImageViewType::TYPE_2D_ARRAY | ImageViewType::CUBE_ARRAY
+        )
+    }
+
+    /// Errors for the combinations Vulkan doesn't allow, `features` are the device's enabled ones
+    fn validate(&self, extent: Extent3D, features: &PhysicalDeviceFeatures) -> RendererResult<()> {
+        if self.array_layers == 0 {
+            return Err(RendererError::InvalidArgument(
+                "An image needs at least one array layer.".to_owned(),
+            ));
+        }
+        if Self::view_image_type(self.view_type) != self.image_type {
+            return Err(RendererError::InvalidArgument(format!(
+                "{:?} views can't be created on {:?} images.",
+                self.view_type, self.image_type
+            )));
+        }
+        if self.is_cube() && (!self.array_layers.is_multiple_of(6) || extent.width != extent.height)
+        {
+            return Err(RendererError::InvalidArgument(format!(
+                "Cube images need square faces and a multiple of 6 layers, got {}x{} with {} layers.",
+                extent.width, extent.height, self.array_layers
+            )));
+        }
+        if self.view_type == ImageViewType::CUBE && self.array_layers != 6 {
+            return Err(RendererError::InvalidArgument(format!(
+                "Cube views have exactly 6 layers, got {}, use a cube array view for more.",
+                self.array_layers
+            )));
+        }
+        if !self.is_array() && !self.is_cube() && self.array_layers != 1 {
+            return Err(RendererError::InvalidArgument(format!(
+                "{:?} views have a single array layer, got {}.",
+                self.view_type, self.array_layers
+            )));
+        }
+        if self.view_type == ImageViewType::CUBE_ARRAY && features.image_cube_array != TRUE {
+            return Err(RendererError::InvalidArgument(
+                "Cube array views need the imageCubeArray feature.".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default, Debug)]
 pub struct VImage {
     image: Image,
     image_view: ImageView,
     memory: DeviceMemory,
+    aspect_mask: ImageAspectFlags,
+    usage: ImageUsageFlags,
+    format: Format,
+    extent: Extent3D,
+    mip_levels: u32,
+    view_type: ImageViewType,
+    array_layers: u32,
+    /// The memory belongs to a [`VAllocator`] and outlives the image
+    suballocated: bool,
 }
 
 impl VImage {
+    /// Wraps a single-level color image owned elsewhere, such as a swapchain image
+    ///
+    /// Has no view or memory, so it must not be destroyed like the images created here
+    pub(crate) fn from_external(
+        image: Image,
+        usage: ImageUsageFlags,
+        format: Format,
+        extent: Extent3D,
+    ) -> Self {
+        Self {
+            image,
+            aspect_mask: ImageAspectFlags::COLOR,
+            usage,
+            format,
+            extent,
+            mip_levels: 1,
+            view_type: ImageViewType::TYPE_2D,
+            array_layers: 1,
+            ..Default::default()
+        }
+    }
+
     pub fn new(
         device: &VDevice,
         usage: ImageUsageFlags,
@@ -21,53 +178,524 @@ impl VImage {
         extent: Extent3D,
         aspect_mask: ImageAspectFlags,
     ) -> RendererResult<Self> {
-        let create_info = Self::image_create_info(usage, ImageType::TYPE_2D, format, extent);
+        Self::new_with_mips(device, usage, format, extent, aspect_mask, 1)
+    }
+
+    /// Creates an image and a view covering `mip_levels` levels, see [`mip_level_count`](Self::mip_level_count)
+    pub fn new_with_mips(
+        device: &VDevice,
+        usage: ImageUsageFlags,
+        format: Format,
+        extent: Extent3D,
+        aspect_mask: ImageAspectFlags,
+        mip_levels: u32,
+    ) -> RendererResult<Self> {
+        Self::new_with_shape(
+            device,
+            usage,
+            format,
+            extent,
+            aspect_mask,
+            mip_levels,
+            VImageShape::default(),
+        )
+    }
+
+    /// Like [`new_with_mips`](Self::new_with_mips) for cube, array and 3D images, the view
+    /// covers every layer
+    pub fn new_with_shape(
+        device: &VDevice,
+        usage: ImageUsageFlags,
+        format: Format,
+        extent: Extent3D,
+        aspect_mask: ImageAspectFlags,
+        mip_levels: u32,
+        shape: VImageShape,
+    ) -> RendererResult<Self> {
+        Self::create(
+            device,
+            None,
+            usage,
+            format,
+            extent,
+            aspect_mask,
+            mip_levels,
+            shape,
+        )
+    }
+
+    /// Like [`new_with_mips`](Self::new_with_mips), but bound to a range of one of `allocator`'s
+    /// blocks. Destroy only the image and view, the memory is freed by [`VAllocator::destroy`]
+    pub fn new_with_allocator(
+        device: &VDevice,
+        allocator: &VAllocator,
+        usage: ImageUsageFlags,
+        format: Format,
+        extent: Extent3D,
+        aspect_mask: ImageAspectFlags,
+        mip_levels: u32,
+    ) -> RendererResult<Self> {
+        Self::create(
+            device,
+            Some(allocator),
+            usage,
+            format,
+            extent,
+            aspect_mask,
+            mip_levels,
+            VImageShape::default(),
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn create(
+        device: &VDevice,
+        allocator: Option<&VAllocator>,
+        usage: ImageUsageFlags,
+        format: Format,
+        extent: Extent3D,
+        aspect_mask: ImageAspectFlags,
+        mip_levels: u32,
+        shape: VImageShape,
+    ) -> RendererResult<Self> {
+        shape.validate(extent, &device.get_enabled_features())?;
+        let create_info = Self::image_create_info(usage, shape, format, extent, mip_levels);
         let image = unsafe { device.get().create_image(&create_info, None)? };
 
         // Device Memory
         let mem_req = Self::memory_requirements(device, image);
-        let mem_type_ind = Self::find_memory_type_index(
-            mem_req,
-            device.get_memory_properties(),
-            MemoryPropertyFlags::DEVICE_LOCAL,
+        let (memory, offset) = match Self::allocate_memory(device, allocator, mem_req) {
+            Ok(memory) => memory,
+            Err(err) => {
+                unsafe { device.get().destroy_image(image, None) };
+                return Err(err);
+            }
+        };
+        let mut vimage = Self {
+            image,
+            memory,
+            aspect_mask,
+            usage,
+            format,
+            extent,
+            mip_levels,
+            view_type: shape.view_type,
+            array_layers: shape.array_layers,
+            suballocated: allocator.is_some(),
+            ..Default::default()
+        };
+
+        // ImageView
+        let create_info = Self::image_view_create_info(
+            image,
+            shape.view_type,
+            format,
+            aspect_mask,
+            mip_levels,
+            shape.array_layers,
         );
+        let image_view = unsafe {
+            device
+                .get()
+                .bind_image_memory(image, memory, offset)
+                .and_then(|_| device.get().create_image_view(&create_info, None))
+        };
+        match image_view {
+            Ok(image_view) => vimage.image_view = image_view,
+            Err(err) => {
+                // Destroying the still null view is a no-op
+                vimage.destroy(device);
+                return Err(err.into());
+            }
+        }
 
-        let allocate_info = Self::memory_allocate_info(mem_type_ind, mem_req.size);
-        let memory = unsafe { device.get().allocate_memory(&allocate_info, None)? };
+        Ok(vimage)
+    }
 
+    /// Memory for an image with `mem_req`, from `allocator` if given, and its offset
+    fn allocate_memory(
+        device: &VDevice,
+        allocator: Option<&VAllocator>,
+        mem_req: MemoryRequirements,
+    ) -> RendererResult<(DeviceMemory, u64)> {
+        match allocator {
+            Some(allocator) => {
+                let allocation =
+                    allocator.allocate(device, mem_req, MemoryPropertyFlags::DEVICE_LOCAL)?;
+                Ok((allocation.memory(), allocation.offset()))
+            }
+            None => {
+                let mem_type_ind = Self::find_memory_type_index(
+                    mem_req,
+                    device.get_memory_properties(),
+                    MemoryPropertyFlags::DEVICE_LOCAL,
+                )?;
+                let allocate_info = Self::memory_allocate_info(mem_type_ind, mem_req.size);
+                Ok((
+                    unsafe { device.get().allocate_memory(&allocate_info, None)? },
+                    0,
+                ))
+            }
+        }
+    }
+
+    /// Destroys the view and the image and frees its memory unless it came from a [`VAllocator`]
+    ///
+    /// Only the view of an [`external`](Self::from_external) image is destroyed, its owner destroys
+    /// the rest. The GPU must be done with it and nothing else may destroy it
+    pub fn destroy(&self, device: &VDevice) {
         unsafe {
-            device
-                .get()
-                .bind_image_memory(image, memory, 0)
-                .expect("Failed to bind buffer memory.")
+            device.get().destroy_image_view(self.image_view, None);
+            if self.memory == DeviceMemory::null() {
+                return;
+            }
+            device.get().destroy_image(self.image, None);
+            if !self.suballocated {
+                device.get().free_memory(self.memory, None);
+            }
         }
+    }
 
-        // ImageView
-        let create_info =
-            Self::image_view_create_info(image, ImageViewType::TYPE_2D, format, aspect_mask);
-        let image_view = unsafe { device.get().create_image_view(&create_info, None)? };
+    /// Whether `format` has a stencil component, alone or next to depth
+    pub fn has_stencil(format: Format) -> bool {
+        matches!(
+            format,
+            Format::S8_UINT
+                | Format::D16_UNORM_S8_UINT
+                | Format::D24_UNORM_S8_UINT
+                | Format::D32_SFLOAT_S8_UINT
+        )
+    }
 
-        Ok(Self {
-            image,
-            image_view,
-            memory,
-        })
+    /// Depth-stencil formats need both aspects in an attachment's view, stencil-only ones just one
+    pub fn depth_aspect_mask(format: Format) -> ImageAspectFlags {
+        match (format, Self::has_stencil(format)) {
+            (Format::S8_UINT, _) => ImageAspectFlags::STENCIL,
+            (_, true) => ImageAspectFlags::DEPTH | ImageAspectFlags::STENCIL,
+            (_, false) => ImageAspectFlags::DEPTH,
+        }
+    }
+
+    /// Number of levels in a full mip chain, `floor(log2(max(width, height))) + 1`
+    pub fn mip_level_count(extent: Extent3D) -> u32 {
+        let max_dimension = extent.width.max(extent.height).max(1);
+        u32::BITS - max_dimension.leading_zeros()
+    }
+
+    /// Fills every mip level below the first by blitting each level into the next one
+    ///
+    /// All levels must be in `TRANSFER_DST_OPTIMAL` with the first one written, e.g. by
+    /// [`VBuffer::copy_buffer_to_image`](crate::buffer::VBuffer::copy_buffer_to_image), and the image
+    /// needs `TRANSFER_SRC | TRANSFER_DST` usage. Leaves every level in `SHADER_READ_ONLY_OPTIMAL`.
+    /// Errors without recording anything if the format can't be blitted with linear filtering
+    pub fn generate_mipmaps(
+        &self,
+        device: &VDevice,
+        command_buffer: CommandBuffer,
+    ) -> RendererResult<()> {
+        let supports_linear_blit = device
+            .get_format_properties(self.format)
+            .optimal_tiling_features
+            .contains(FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR);
+        if !supports_linear_blit {
+            return Err(RendererError::InvalidArgument(format!(
+                "Can't generate mipmaps, {:?} doesn't support linear filtering.",
+                self.format
+            )));
+        }
+
+        let mut batch = BarrierBatch::new();
+        let mut src_extent = self.extent;
+        for level in 1..self.mip_levels {
+            let dst_extent = Extent3D {
+                width: (src_extent.width / 2).max(1),
+                height: (src_extent.height / 2).max(1),
+                depth: 1,
+            };
+            let to_src = self.image_memory_barrier(
+                self.mip_subresource_range(level - 1),
+                (
+                    ImageLayout::TRANSFER_DST_OPTIMAL,
+                    ImageLayout::TRANSFER_SRC_OPTIMAL,
+                ),
+                (AccessFlags::TRANSFER_WRITE, AccessFlags::TRANSFER_READ),
+            );
+            batch
+                .add_image_barrier(
+                    to_src,
+                    PipelineStageFlags::TRANSFER,
+                    PipelineStageFlags::TRANSFER,
+                )
+                .flush(device, command_buffer);
+
+            let blit = ImageBlit {
+                src_subresource: self.mip_subresource_layers(level - 1),
+                src_offsets: [Offset3D::default(), Self::extent_offset(src_extent)],
+                dst_subresource: self.mip_subresource_layers(level),
+                dst_offsets: [Offset3D::default(), Self::extent_offset(dst_extent)],
+            };
+            unsafe {
+                device.get().cmd_blit_image(
+                    command_buffer,
+                    self.image,
+                    ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    self.image,
+                    ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &[blit],
+                    Filter::LINEAR,
+                );
+            }
+
+            let to_shader = self.image_memory_barrier(
+                self.mip_subresource_range(level - 1),
+                (
+                    ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                ),
+                (AccessFlags::TRANSFER_READ, AccessFlags::SHADER_READ),
+            );
+            batch.add_image_barrier(
+                to_shader,
+                PipelineStageFlags::TRANSFER,
+                PipelineStageFlags::FRAGMENT_SHADER,
+            );
+            src_extent = dst_extent;
+        }
+
+        // The last level was only ever written to
+        let last_to_shader = self.image_memory_barrier(
+            self.mip_subresource_range(self.mip_levels - 1),
+            (
+                ImageLayout::TRANSFER_DST_OPTIMAL,
+                ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            ),
+            (AccessFlags::TRANSFER_WRITE, AccessFlags::SHADER_READ),
+        );
+        batch
+            .add_image_barrier(
+                last_to_shader,
+                PipelineStageFlags::TRANSFER,
+                PipelineStageFlags::FRAGMENT_SHADER,
+            )
+            .flush(device, command_buffer);
+        Ok(())
+    }
+
+    /// A view of the single mip `level`, e.g. to bind one level as a storage image
+    ///
+    /// The view belongs to the caller and must be destroyed before the image. Cube images get a
+    /// 2D array view of their faces, as cube views can't be bound as storage images
+    pub fn create_mip_view(&self, device: &VDevice, level: u32) -> RendererResult<ImageView> {
+        let view_type = match self.view_type {
+            ImageViewType::CUBE | ImageViewType::CUBE_ARRAY => ImageViewType::TYPE_2D_ARRAY,
+            view_type => view_type,
+        };
+        let create_info = ImageViewCreateInfo {
+            subresource_range: self.mip_subresource_range(level),
+            ..Self::image_view_create_info(
+                self.image,
+                view_type,
+                self.format,
+                self.aspect_mask,
+                1,
+                self.array_layers,
+            )
+        };
+        Ok(unsafe { device.get().create_image_view(&create_info, None)? })
+    }
+
+    /// Clears the whole image outside a render pass and leaves it in `final_layout`
+    ///
+    /// The previous contents are discarded, so the image may be in any layout before.
+    /// It must have been created with `TRANSFER_DST` usage
+    pub fn clear_color(
+        &self,
+        device: &VDevice,
+        command_buffer: CommandBuffer,
+        color: ClearColorValue,
+        final_layout: ImageLayout,
+    ) {
+        let range = self.subresource_range(ImageAspectFlags::COLOR);
+        self.transition_for_clear(
+            device,
+            command_buffer,
+            range,
+            final_layout,
+            |device| unsafe {
+                device.get().cmd_clear_color_image(
+                    command_buffer,
+                    self.image,
+                    ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &color,
+                    &[range],
+                );
+            },
+        );
+    }
+
+    /// Same as [`clear_color`](Self::clear_color) for depth images, the stencil is only cleared if the image has a stencil aspect
+    pub fn clear_depth(
+        &self,
+        device: &VDevice,
+        command_buffer: CommandBuffer,
+        depth: f32,
+        stencil: u32,
+        final_layout: ImageLayout,
+    ) {
+        let range = self.subresource_range(
+            self.aspect_mask & (ImageAspectFlags::DEPTH | ImageAspectFlags::STENCIL),
+        );
+        let value = ClearDepthStencilValue { depth, stencil };
+        self.transition_for_clear(
+            device,
+            command_buffer,
+            range,
+            final_layout,
+            |device| unsafe {
+                device.get().cmd_clear_depth_stencil_image(
+                    command_buffer,
+                    self.image,
+                    ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &value,
+                    &[range],
+                );
+            },
+        );
+    }
+
+    /// Records a pipeline barrier moving every mip level of `aspect` from `old_layout` to `new_layout`
+    ///
+    /// Fails for layouts without known access masks, see [`VImageBarrier::known_transition`]
+    pub fn transition_layout(
+        &self,
+        device: &VDevice,
+        command_buffer: CommandBuffer,
+        old_layout: ImageLayout,
+        new_layout: ImageLayout,
+        aspect: ImageAspectFlags,
+    ) -> RendererResult<()> {
+        VImageBarrier::known_transition(self, old_layout, new_layout)?
+            .subresource_range(self.subresource_range(aspect))
+            .record(device, command_buffer);
+        Ok(())
+    }
+
+    fn transition_for_clear(
+        &self,
+        device: &VDevice,
+        command_buffer: CommandBuffer,
+        range: ImageSubresourceRange,
+        final_layout: ImageLayout,
+        clear: impl FnOnce(&VDevice),
+    ) {
+        let to_transfer = self.image_memory_barrier(
+            range,
+            (ImageLayout::UNDEFINED, ImageLayout::TRANSFER_DST_OPTIMAL),
+            (AccessFlags::MEMORY_WRITE, AccessFlags::TRANSFER_WRITE),
+        );
+        let to_final = self.image_memory_barrier(
+            range,
+            (ImageLayout::TRANSFER_DST_OPTIMAL, final_layout),
+            (
+                AccessFlags::TRANSFER_WRITE,
+                AccessFlags::MEMORY_READ | AccessFlags::MEMORY_WRITE,
+            ),
+        );
+        unsafe {
+            device.get().cmd_pipeline_barrier(
+                command_buffer,
+                PipelineStageFlags::ALL_COMMANDS,
+                PipelineStageFlags::TRANSFER,
+                DependencyFlags::empty(),
+                &[],
+                &[],
+                &[to_transfer],
+            );
+            clear(device);
+            device.get().cmd_pipeline_barrier(
+                command_buffer,
+                PipelineStageFlags::TRANSFER,
+                PipelineStageFlags::ALL_COMMANDS,
+                DependencyFlags::empty(),
+                &[],
+                &[],
+                &[to_final],
+            );
+        }
+    }
+
+    pub(crate) fn image_memory_barrier(
+        &self,
+        subresource_range: ImageSubresourceRange,
+        (old_layout, new_layout): (ImageLayout, ImageLayout),
+        (src_access_mask, dst_access_mask): (AccessFlags, AccessFlags),
+    ) -> ImageMemoryBarrier {
+        ImageMemoryBarrier {
+            image: self.image,
+            old_layout,
+            new_layout,
+            src_access_mask,
+            dst_access_mask,
+            src_queue_family_index: QUEUE_FAMILY_IGNORED,
+            dst_queue_family_index: QUEUE_FAMILY_IGNORED,
+            subresource_range,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn subresource_range(&self, aspect_mask: ImageAspectFlags) -> ImageSubresourceRange {
+        ImageSubresourceRange {
+            aspect_mask,
+            base_mip_level: 0,
+            level_count: self.mip_levels,
+            base_array_layer: 0,
+            layer_count: self.array_layers,
+        }
+    }
+
+    pub(crate) fn mip_subresource_range(&self, level: u32) -> ImageSubresourceRange {
+        ImageSubresourceRange {
+            aspect_mask: self.aspect_mask,
+            base_mip_level: level,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: self.array_layers,
+        }
+    }
+
+    fn mip_subresource_layers(&self, level: u32) -> ImageSubresourceLayers {
+        ImageSubresourceLayers {
+            aspect_mask: self.aspect_mask,
+            mip_level: level,
+            base_array_layer: 0,
+            layer_count: self.array_layers,
+        }
+    }
+
+    fn extent_offset(extent: Extent3D) -> Offset3D {
+        Offset3D {
+            x: extent.width as i32,
+            y: extent.height as i32,
+            z: extent.depth as i32,
+        }
     }
 
     fn image_create_info(
         usage: ImageUsageFlags,
-        image_type: ImageType,
+        shape: VImageShape,
         format: Format,
         extent: Extent3D,
+        mip_levels: u32,
     ) -> ImageCreateInfo {
         ImageCreateInfo {
+            flags: shape.create_flags(),
             usage,
             sharing_mode: SharingMode::EXCLUSIVE,
-            image_type,
+            image_type: shape.image_type,
             format,
             extent,
-            mip_levels: 1,
-            array_layers: 1,
+            mip_levels,
+            array_layers: shape.array_layers,
             samples: SampleCountFlags::TYPE_1,
             tiling: ImageTiling::OPTIMAL,
             ..Default::default()
@@ -79,6 +707,8 @@ impl VImage {
         view_type: ImageViewType,
         format: Format,
         aspect_mask: ImageAspectFlags,
+        mip_levels: u32,
+        array_layers: u32,
     ) -> ImageViewCreateInfo {
         ImageViewCreateInfo {
             image,
@@ -87,8 +717,8 @@ impl VImage {
             subresource_range: ImageSubresourceRange {
                 base_array_layer: 0,
                 base_mip_level: 0,
-                layer_count: 1,
-                level_count: 1,
+                layer_count: array_layers,
+                level_count: mip_levels,
                 aspect_mask,
             },
             ..Default::default()
@@ -111,19 +741,290 @@ impl VImage {
         memory_requirements: MemoryRequirements,
         memory_properties: PhysicalDeviceMemoryProperties,
         flags: MemoryPropertyFlags,
-    ) -> u32 {
-        for (ind, mem_type) in memory_properties.memory_types.iter().enumerate() {
+    ) -> RendererResult<u32> {
+        let memory_types =
+            &memory_properties.memory_types[..memory_properties.memory_type_count as usize];
+        for (ind, mem_type) in memory_types.iter().enumerate() {
             if mem_type.property_flags & flags == flags
                 && (1 << ind) & memory_requirements.memory_type_bits != 0
             {
-                return ind as u32;
+                return Ok(ind as u32);
             }
         }
 
-        panic!("Failed to find a suitable memory type.");
+        Err(MemoryError::NoSuitableMemoryType { requested: flags }.into())
     }
 }
 
 impl_get!(VImage, image, Image);
 impl_get!(VImage, image_view, ImageView);
 impl_get!(VImage, memory, DeviceMemory);
+impl_get!(VImage, aspect_mask, ImageAspectFlags);
+impl_get!(VImage, usage, ImageUsageFlags);
+impl_get!(VImage, format, Format);
+impl_get!(VImage, extent, Extent3D);
+impl_get!(VImage, mip_levels, u32);
+impl_get!(VImage, view_type, ImageViewType);
+impl_get!(VImage, array_layers, u32);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{buffer::VBuffer, instance::VInstance, test_utils::create_window};
+    use ash::vk::{BufferUsageFlags, MemoryPropertyFlags};
+
+    #[test]
+    fn counts_mip_levels_of_largest_dimension() {
+        let extent = |width, height| Extent3D {
+            width,
+            height,
+            depth: 1,
+        };
+        assert_eq!(VImage::mip_level_count(extent(1, 1)), 1);
+        assert_eq!(VImage::mip_level_count(extent(64, 32)), 7);
+        assert_eq!(VImage::mip_level_count(extent(1920, 1080)), 11);
+    }
+
+    #[test]
+    fn views_stencil_formats_with_both_aspects() {
+        assert_eq!(
+            VImage::depth_aspect_mask(Format::D24_UNORM_S8_UINT),
+            ImageAspectFlags::DEPTH | ImageAspectFlags::STENCIL
+        );
+        assert_eq!(
+            VImage::depth_aspect_mask(Format::D32_SFLOAT),
+            ImageAspectFlags::DEPTH
+        );
+        assert_eq!(
+            VImage::depth_aspect_mask(Format::S8_UINT),
+            ImageAspectFlags::STENCIL
+        );
+    }
+
+    #[test]
+    fn rejects_shapes_vulkan_does_not_allow() {
+        let face = |width, height| Extent3D {
+            width,
+            height,
+            depth: 1,
+        };
+        let features = PhysicalDeviceFeatures::default();
+        assert!(VImageShape::cube()
+            .validate(face(32, 16), &features)
+            .is_err());
+        assert!(VImageShape::array_2d(0)
+            .validate(face(32, 16), &features)
+            .is_err());
+        let layered_2d = VImageShape {
+            array_layers: 4,
+            ..Default::default()
+        };
+        assert!(layered_2d.validate(face(32, 16), &features).is_err());
+        let cube_volume = VImageShape {
+            view_type: ImageViewType::CUBE,
+            ..VImageShape::volume()
+        };
+        assert!(cube_volume.validate(face(32, 32), &features).is_err());
+        let cube_array = VImageShape {
+            view_type: ImageViewType::CUBE_ARRAY,
+            array_layers: 12,
+            ..Default::default()
+        };
+        assert!(cube_array.validate(face(32, 32), &features).is_err());
+        let cube_array_features = PhysicalDeviceFeatures {
+            image_cube_array: TRUE,
+            ..Default::default()
+        };
+        assert!(cube_array
+            .validate(face(32, 32), &cube_array_features)
+            .is_ok());
+    }
+
+    #[test]
+    fn creates_cube_image_with_cube_view() -> RendererResult<()> {
+        let face = |width, height| Extent3D {
+            width,
+            height,
+            depth: 1,
+        };
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let shape = VImageShape::cube();
+        let create_info = VImage::image_create_info(
+            ImageUsageFlags::SAMPLED,
+            shape,
+            Format::R8G8B8A8_UNORM,
+            face(32, 32),
+            1,
+        );
+        assert!(create_info
+            .flags
+            .contains(ImageCreateFlags::CUBE_COMPATIBLE));
+        assert_eq!(create_info.array_layers, 6);
+
+        let image = VImage::new_with_shape(
+            &device,
+            ImageUsageFlags::SAMPLED | ImageUsageFlags::TRANSFER_DST,
+            Format::R8G8B8A8_UNORM,
+            face(32, 32),
+            ImageAspectFlags::COLOR,
+            1,
+            shape,
+        )?;
+        assert_eq!(image.view_type(), ImageViewType::CUBE);
+        assert_eq!(image.array_layers(), 6);
+        assert_eq!(
+            image.subresource_range(ImageAspectFlags::COLOR).layer_count,
+            6
+        );
+        // Clearing covers every face, which the validation layers check against the image
+        VBuffer::submit_one_time(&device, |command_buffer| {
+            image.clear_color(
+                &device,
+                command_buffer,
+                ClearColorValue::default(),
+                ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            );
+        })?;
+
+        image.destroy(&device);
+        Ok(())
+    }
+
+    #[test]
+    fn generates_mipmaps_for_uploaded_texture() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let extent = Extent3D {
+            width: 64,
+            height: 32,
+            depth: 1,
+        };
+        let image = VImage::new_with_mips(
+            &device,
+            ImageUsageFlags::TRANSFER_SRC
+                | ImageUsageFlags::TRANSFER_DST
+                | ImageUsageFlags::SAMPLED,
+            Format::R8G8B8A8_UNORM,
+            extent,
+            ImageAspectFlags::COLOR,
+            VImage::mip_level_count(extent),
+        )?;
+        let pixels = vec![255u8; (extent.width * extent.height * 4) as usize];
+        let staging = VBuffer::new_mapped(
+            &device,
+            &pixels,
+            BufferUsageFlags::TRANSFER_SRC,
+            MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT,
+        )?;
+        VBuffer::copy_buffer_to_image(
+            &device,
+            staging.buffer(),
+            &image,
+            extent,
+            ImageLayout::TRANSFER_DST_OPTIMAL,
+        )?;
+        let mut result = Ok(());
+        VBuffer::submit_one_time(&device, |command_buffer| {
+            result = image.generate_mipmaps(&device, command_buffer);
+        })?;
+        result?;
+
+        unsafe {
+            device.get().destroy_buffer(staging.buffer(), None);
+            device.get().free_memory(staging.memory(), None);
+            device.get().destroy_image_view(image.image_view(), None);
+            device.get().destroy_image(image.image(), None);
+            device.get().free_memory(image.memory(), None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn records_supported_layout_transitions() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let extent = Extent3D {
+            width: 16,
+            height: 16,
+            depth: 1,
+        };
+        let color = VImage::new(
+            &device,
+            ImageUsageFlags::TRANSFER_DST
+                | ImageUsageFlags::TRANSFER_SRC
+                | ImageUsageFlags::SAMPLED
+                | ImageUsageFlags::COLOR_ATTACHMENT,
+            Format::R8G8B8A8_UNORM,
+            extent,
+            ImageAspectFlags::COLOR,
+        )?;
+        let depth = VImage::new(
+            &device,
+            ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+            Format::D32_SFLOAT,
+            extent,
+            ImageAspectFlags::DEPTH,
+        )?;
+        assert!(color
+            .transition_layout(
+                &device,
+                CommandBuffer::null(),
+                ImageLayout::UNDEFINED,
+                ImageLayout::GENERAL,
+                ImageAspectFlags::COLOR,
+            )
+            .is_err());
+
+        let color_transitions = [
+            (ImageLayout::UNDEFINED, ImageLayout::TRANSFER_DST_OPTIMAL),
+            (
+                ImageLayout::TRANSFER_DST_OPTIMAL,
+                ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            ),
+            (
+                ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            ),
+            (
+                ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                ImageLayout::TRANSFER_SRC_OPTIMAL,
+            ),
+        ];
+        let mut results = Vec::new();
+        VBuffer::submit_one_time(&device, |command_buffer| {
+            for (old_layout, new_layout) in color_transitions {
+                results.push(color.transition_layout(
+                    &device,
+                    command_buffer,
+                    old_layout,
+                    new_layout,
+                    ImageAspectFlags::COLOR,
+                ));
+            }
+            results.push(depth.transition_layout(
+                &device,
+                command_buffer,
+                ImageLayout::UNDEFINED,
+                ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                ImageAspectFlags::DEPTH,
+            ));
+        })?;
+        results.into_iter().collect::<RendererResult<()>>()?;
+
+        unsafe {
+            for image in [color, depth] {
+                device.get().destroy_image_view(image.image_view(), None);
+                device.get().destroy_image(image.image(), None);
+                device.get().free_memory(image.memory(), None);
+            }
+        }
+        Ok(())
+    }
+}
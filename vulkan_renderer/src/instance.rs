@@ -1,4 +1,4 @@
-use crate::RendererResult;
+use crate::{error::PhysicalDeviceError, RendererResult};
 use ash::{
     extensions::ext::DebugUtils,
     vk::{self, DebugUtilsMessengerEXT, PhysicalDevice, PhysicalDeviceType},
@@ -7,7 +7,6 @@ use ash::{
 use colored::*;
 use std::{
     borrow::Cow,
-    collections::HashMap,
     ffi::{c_void, CStr, CString},
 };
 
@@ -53,55 +52,87 @@ unsafe extern "system" fn vulkan_debug_callback(
     vk::FALSE
 }
 
+/// Whether validation is enabled unless chosen otherwise with [`VInstanceBuilder::enable_validation`]
 #[cfg(debug_assertions)]
 const IS_VALIDATION_ENABLED: bool = true;
 #[cfg(not(debug_assertions))]
 const IS_VALIDATION_ENABLED: bool = false;
 
+const VALIDATION_LAYERS: [&str; 1] = ["VK_LAYER_KHRONOS_validation\0"];
+
+/// `VK_KHR_portability_enumeration` and its create flag, which ash 0.35 predates
+#[cfg(target_os = "macos")]
+const PORTABILITY_ENUMERATION_NAME: &[u8] = b"VK_KHR_portability_enumeration\0";
+#[cfg(target_os = "macos")]
+const ENUMERATE_PORTABILITY_KHR: vk::InstanceCreateFlags = vk::InstanceCreateFlags::from_raw(0x1);
+
+/// On macOS the instance runs on MoltenVK, which needs the Vulkan SDK with MoltenVK installed
+/// and enumerates its devices only through the portability extensions
 pub struct VInstance {
     instance: Instance,
-    _debug_utils: Option<DebugUtils>,
+    debug_utils: Option<DebugUtils>,
     _debug_callback: Option<vk::DebugUtilsMessengerEXT>,
 }
 
 impl VInstance {
+    /// Validation is enabled in debug builds only, use [`VInstanceBuilder`] to choose
     pub fn new(name: &str, version: u32) -> RendererResult<Self> {
         let entry = Entry::linked();
 
         let application_info = Self::application_info(name, version);
-        let layers = Self::layers();
-        let extensions = Self::extensions();
-        let create_info = Self::create_info(&application_info, &layers, &extensions);
+        let layers = Self::available_layers(&entry, Self::layers(IS_VALIDATION_ENABLED))?;
+        let extensions = Self::extensions(&entry, IS_VALIDATION_ENABLED)?;
+        let debug_utils_create_info = Self::debug_utils_create_info();
+        let create_info = Self::create_info(
+            &application_info,
+            &layers,
+            &extensions,
+            IS_VALIDATION_ENABLED.then_some(&debug_utils_create_info),
+        );
 
         let instance = unsafe { entry.create_instance(&create_info, None)? };
         let (debug_utils, debug_callback) =
-            Self::create_debug_utils_and_callback(&entry, &instance)?;
+            Self::create_debug_utils_and_callback(&entry, &instance, IS_VALIDATION_ENABLED)?;
 
         Ok(Self {
             instance,
-            _debug_utils: debug_utils,
+            debug_utils,
             _debug_callback: debug_callback,
         })
     }
 
+    /// The highest rated device, discrete GPUs before integrated ones
     pub fn select_physical_device(&self) -> RendererResult<PhysicalDevice> {
         let devices = unsafe { self.instance.enumerate_physical_devices()? };
+        Ok(devices
+            .into_iter()
+            .max_by_key(|&device| Self::rate_device(&self.instance, device))
+            .ok_or(PhysicalDeviceError::NoPhysicalDevice)?)
+    }
 
-        let mut score_map = HashMap::new();
-        for device in devices {
-            score_map.insert(Self::rate_device(&self.instance, device), device);
-        }
-        Ok(score_map
+    /// Every physical device with its name, to pass one to
+    /// [`VDevice::new_with_device`](crate::device::VDevice::new_with_device)
+    pub fn enumerate_devices(&self) -> RendererResult<Vec<(PhysicalDevice, String)>> {
+        let devices = unsafe { self.instance.enumerate_physical_devices()? };
+        Ok(devices
             .into_iter()
-            .next()
-            .ok_or("Failed to find a physical device.")?
-            .1)
+            .map(|device| {
+                let properties = unsafe { self.instance.get_physical_device_properties(device) };
+                let name = unsafe { CStr::from_ptr(properties.device_name.as_ptr()) };
+                (device, name.to_string_lossy().into_owned())
+            })
+            .collect())
     }
 
     pub fn get(&self) -> &Instance {
         &self.instance
     }
 
+    /// The debug utils loader, only available when validation is enabled
+    pub fn debug_utils(&self) -> Option<&DebugUtils> {
+        self.debug_utils.as_ref()
+    }
+
     fn rate_device(instance: &Instance, device: PhysicalDevice) -> usize {
         let device_properties = unsafe { instance.get_physical_device_properties(device) };
 
@@ -139,18 +170,23 @@ impl VInstance {
             .build()
     }
 
+    /// `debug_utils_create_info` also reports messages from instance creation and destruction
     fn create_info(
         application_info: &vk::ApplicationInfo,
         layers: &[*const i8],
         extensions: &[*const i8],
+        debug_utils_create_info: Option<&vk::DebugUtilsMessengerCreateInfoEXT>,
     ) -> vk::InstanceCreateInfo {
-        let mut p_next = std::ptr::null();
-        if IS_VALIDATION_ENABLED {
-            p_next = &Self::debug_utils_create_info() as *const vk::DebugUtilsMessengerCreateInfoEXT
-                as *const c_void;
-        }
+        let p_next = debug_utils_create_info.map_or(std::ptr::null(), |create_info| {
+            create_info as *const vk::DebugUtilsMessengerCreateInfoEXT as *const c_void
+        });
+        #[cfg(target_os = "macos")]
+        let flags = ENUMERATE_PORTABILITY_KHR;
+        #[cfg(not(target_os = "macos"))]
+        let flags = vk::InstanceCreateFlags::empty();
         vk::InstanceCreateInfo {
             p_next,
+            flags,
             p_application_info: application_info,
             enabled_layer_count: layers.len() as u32,
             pp_enabled_layer_names: layers.as_ptr(),
@@ -160,9 +196,36 @@ impl VInstance {
         }
     }
 
-    fn layers() -> Vec<*const i8> {
-        let layers = vec!["VK_LAYER_LUNARG_monitor\0", "VK_LAYER_KHRONOS_validation\0"];
+    fn layers(is_validation_enabled: bool) -> Vec<*const i8> {
+        if !is_validation_enabled {
+            return Vec::new();
+        }
+        Self::layer_names(&VALIDATION_LAYERS)
+    }
 
+    /// Drops the layers that aren't installed with a warning, requesting them would fail instance creation
+    fn available_layers(
+        entry: &Entry,
+        requested_layers: Vec<*const i8>,
+    ) -> RendererResult<Vec<*const i8>> {
+        let layer_properties = entry.enumerate_instance_layer_properties()?;
+        Ok(requested_layers
+            .into_iter()
+            .filter(|&layer| {
+                let layer = unsafe { CStr::from_ptr(layer) };
+                let is_available = layer_properties.iter().any(|properties| unsafe {
+                    CStr::from_ptr(properties.layer_name.as_ptr()) == layer
+                });
+                if !is_available {
+                    eprintln!("Skipping the missing instance layer {:?}.", layer);
+                }
+                is_available
+            })
+            .collect())
+    }
+
+    /// Layer names must be nul-terminated, others are skipped
+    fn layer_names(layers: &[&str]) -> Vec<*const i8> {
         layers
             .iter()
             .filter_map(|ext| CStr::from_bytes_with_nul(ext.as_bytes()).ok())
@@ -170,19 +233,65 @@ impl VInstance {
             .collect()
     }
 
-    fn extensions() -> Vec<*const i8> {
-        let mut extensions = vec![
-            ash::extensions::khr::Surface::name(),
-            #[cfg(target_os = "windows")]
-            ash::extensions::khr::Win32Surface::name(),
-        ];
-        if IS_VALIDATION_ENABLED {
+    fn extensions(entry: &Entry, is_validation_enabled: bool) -> RendererResult<Vec<*const i8>> {
+        let extension_properties = entry.enumerate_instance_extension_properties()?;
+        let available_extensions = extension_properties
+            .iter()
+            .map(|properties| unsafe { CStr::from_ptr(properties.extension_name.as_ptr()) })
+            .collect::<Vec<_>>();
+
+        let mut extensions = vec![ash::extensions::khr::Surface::name()];
+        extensions.extend(Self::surface_extensions(&available_extensions));
+        extensions.extend(Self::portability_extensions());
+        if is_validation_enabled {
             extensions.push(ash::vk::ExtDebugUtilsFn::name());
         }
 
-        extensions
+        Ok(extensions
             .iter()
             .map(|extension| extension.as_ptr())
+            .collect())
+    }
+
+    /// The extensions [`create_info`](Self::create_info)'s flags require, only on macOS
+    fn portability_extensions() -> Vec<&'static CStr> {
+        #[cfg(target_os = "macos")]
+        return vec![CStr::from_bytes_with_nul(PORTABILITY_ENUMERATION_NAME)
+            .expect("Portability enumeration name is not nul-terminated.")];
+        #[cfg(not(target_os = "macos"))]
+        Vec::new()
+    }
+
+    /// Appends `extension` unless it's already in `extensions`
+    fn push_extension(extensions: &mut Vec<*const i8>, extension: &'static CStr) {
+        if !extensions
+            .iter()
+            .any(|&name| unsafe { CStr::from_ptr(name) } == extension)
+        {
+            extensions.push(extension.as_ptr());
+        }
+    }
+
+    /// The window system surface extensions of the platform that the loader offers
+    ///
+    /// Linux installs may support any mix of X11 and Wayland, so each one is only requested
+    /// when available
+    fn surface_extensions(available_extensions: &[&CStr]) -> Vec<&'static CStr> {
+        let candidates: Vec<&'static CStr> = vec![
+            #[cfg(target_os = "windows")]
+            ash::extensions::khr::Win32Surface::name(),
+            #[cfg(target_os = "macos")]
+            ash::extensions::ext::MetalSurface::name(),
+            #[cfg(all(unix, not(target_os = "macos")))]
+            ash::extensions::khr::XlibSurface::name(),
+            #[cfg(all(unix, not(target_os = "macos")))]
+            ash::extensions::khr::XcbSurface::name(),
+            #[cfg(all(unix, not(target_os = "macos")))]
+            ash::extensions::khr::WaylandSurface::name(),
+        ];
+        candidates
+            .into_iter()
+            .filter(|candidate| available_extensions.contains(candidate))
             .collect()
     }
 
@@ -200,10 +309,11 @@ impl VInstance {
     fn create_debug_utils_and_callback(
         entry: &Entry,
         instance: &Instance,
+        is_validation_enabled: bool,
     ) -> RendererResult<(Option<DebugUtils>, Option<DebugUtilsMessengerEXT>)> {
         let mut debug_utils = None;
         let mut debug_callback = None;
-        if IS_VALIDATION_ENABLED {
+        if is_validation_enabled {
             debug_utils = Some(DebugUtils::new(entry, instance));
             debug_callback = Self::debug_callback(debug_utils.as_ref().unwrap())?;
         }
@@ -211,25 +321,64 @@ impl VInstance {
     }
 }
 
-#[derive(Default, Debug)]
+/// Destroys the debug messenger and the instance, so every `VDevice` must be dropped first
+impl Drop for VInstance {
+    fn drop(&mut self) {
+        unsafe {
+            if let (Some(debug_utils), Some(debug_callback)) =
+                (&self.debug_utils, self._debug_callback)
+            {
+                debug_utils.destroy_debug_utils_messenger(debug_callback, None);
+            }
+            self.instance.destroy_instance(None);
+        }
+    }
+}
+
+/// Validation defaults to on in debug builds with the Khronos validation layer
+#[derive(Debug)]
 pub struct VInstanceBuilder {
     layers: Vec<*const i8>,
     extensions: Vec<*const i8>,
+    is_validation_enabled: bool,
+    validation_layers: Vec<*const i8>,
     application_info: vk::ApplicationInfo,
     allocation_callbacks: Option<vk::AllocationCallbacks>,
 }
 
+impl Default for VInstanceBuilder {
+    fn default() -> Self {
+        Self {
+            layers: Vec::new(),
+            extensions: Vec::new(),
+            is_validation_enabled: IS_VALIDATION_ENABLED,
+            validation_layers: VInstance::layer_names(&VALIDATION_LAYERS),
+            application_info: vk::ApplicationInfo::default(),
+            allocation_callbacks: None,
+        }
+    }
+}
+
 impl VInstanceBuilder {
     pub fn start() -> Self {
         Self::default()
     }
 
+    /// Layers enabled regardless of validation, e.g. `VK_LAYER_LUNARG_monitor`
     pub fn layers(mut self, layers: Vec<&str>) -> Self {
-        self.layers = layers
-            .iter()
-            .filter_map(|ext| CStr::from_bytes_with_nul(ext.as_bytes()).ok())
-            .map(|layer| layer.as_ptr())
-            .collect::<Vec<_>>();
+        self.layers = VInstance::layer_names(&layers);
+        self
+    }
+
+    /// Enables the validation layers and the debug messenger, which also enables `VK_EXT_debug_utils`
+    pub fn enable_validation(mut self, is_validation_enabled: bool) -> Self {
+        self.is_validation_enabled = is_validation_enabled;
+        self
+    }
+
+    /// Replaces the layers enabled along with validation
+    pub fn validation_layers(mut self, validation_layers: Vec<&str>) -> Self {
+        self.validation_layers = VInstance::layer_names(&validation_layers);
         self
     }
 
@@ -257,23 +406,38 @@ impl VInstanceBuilder {
 
     pub fn create_instance(self) -> RendererResult<VInstance> {
         let entry = Entry::linked();
-        let create_info = vk::InstanceCreateInfo {
-            p_application_info: &self.application_info,
-            enabled_extension_count: self.extensions.len() as u32,
-            enabled_layer_count: self.layers.len() as u32,
-            pp_enabled_extension_names: self.extensions.as_ptr(),
-            pp_enabled_layer_names: self.layers.as_ptr(),
-            ..Default::default()
-        };
+        let mut layers = self.layers.clone();
+        let mut extensions = self.extensions.clone();
+        if self.is_validation_enabled {
+            layers.extend(&self.validation_layers);
+        }
+        let layers = VInstance::available_layers(&entry, layers)?;
+        for extension in VInstance::portability_extensions() {
+            VInstance::push_extension(&mut extensions, extension);
+        }
+        if self.is_validation_enabled {
+            VInstance::push_extension(&mut extensions, ash::vk::ExtDebugUtilsFn::name());
+        }
+        let debug_utils_create_info = VInstance::debug_utils_create_info();
+        let create_info = VInstance::create_info(
+            &self.application_info,
+            &layers,
+            &extensions,
+            self.is_validation_enabled
+                .then_some(&debug_utils_create_info),
+        );
 
         let instance =
             unsafe { entry.create_instance(&create_info, self.allocation_callbacks.as_ref())? };
-        let (debug_utils, debug_callback) =
-            VInstance::create_debug_utils_and_callback(&entry, &instance)?;
+        let (debug_utils, debug_callback) = VInstance::create_debug_utils_and_callback(
+            &entry,
+            &instance,
+            self.is_validation_enabled,
+        )?;
 
         Ok(VInstance {
             instance,
-            _debug_utils: debug_utils,
+            debug_utils,
             _debug_callback: debug_callback,
         })
     }
@@ -289,6 +453,22 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn enumerates_devices_by_name() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let devices = instance.enumerate_devices()?;
+        assert!(!devices.is_empty());
+        for (device, name) in &devices {
+            let properties = unsafe { instance.get().get_physical_device_properties(*device) };
+            println!("{} ({:?})", name, properties.device_type);
+            assert!(!name.is_empty());
+        }
+        assert!(devices
+            .iter()
+            .any(|&(device, _)| device == instance.select_physical_device().unwrap()));
+        Ok(())
+    }
+
     #[test]
     fn builder_creates_instance() -> RendererResult<()> {
         let application_info = VInstance::application_info("Test", 0);
@@ -307,4 +487,35 @@ mod tests {
         builder.create_instance()?;
         Ok(())
     }
+
+    #[test]
+    #[cfg(all(unix, not(target_os = "macos")))]
+    fn requests_only_available_linux_surface_extensions() {
+        let wayland = ash::extensions::khr::WaylandSurface::name();
+        let xcb = ash::extensions::khr::XcbSurface::name();
+        let available = [ash::extensions::khr::Surface::name(), wayland, xcb];
+        assert_eq!(VInstance::surface_extensions(&available), [xcb, wayland]);
+        assert!(VInstance::surface_extensions(&[]).is_empty());
+    }
+
+    #[test]
+    fn skips_missing_layers() -> RendererResult<()> {
+        VInstanceBuilder::start()
+            .application_info(VInstance::application_info("Test", 0))
+            .extensions(vec!["VK_KHR_surface\0"])
+            .layers(vec!["VK_LAYER_bogus_missing\0"])
+            .create_instance()?;
+        Ok(())
+    }
+
+    #[test]
+    fn creates_instance_with_validation_disabled() -> RendererResult<()> {
+        let instance = VInstanceBuilder::start()
+            .application_info(VInstance::application_info("Test", 0))
+            .extensions(vec!["VK_KHR_surface\0"])
+            .enable_validation(false)
+            .create_instance()?;
+        assert!(instance.debug_utils().is_none());
+        Ok(())
+    }
 }
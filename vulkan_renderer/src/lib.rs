@@ -1,20 +1,36 @@
+pub mod allocator;
+pub mod barrier;
 pub mod buffer;
 pub mod cmd;
 pub mod command_pool;
 pub mod descriptorset;
 pub mod device;
 pub mod enums;
+pub mod error;
 pub mod image;
 pub mod instance;
 pub mod macros;
+pub mod mip_generator;
+pub mod object_buffer;
 pub mod pipeline;
+pub mod pipeline_cache;
+pub mod query;
 pub mod queue_family;
 pub mod render_pass;
+pub mod render_target;
+pub mod renderer;
+pub mod sampler;
 pub mod shader_utils;
+#[cfg(feature = "hot-reload")]
+pub mod shader_watcher;
 pub mod swapchain;
 pub mod sync;
+#[cfg(test)]
+mod test_utils;
 pub mod utils;
+pub mod vertex_input;
 
+pub use error::RendererError;
+/// The crate's only math library, re-exported so users build matrices and vectors with the same version
 pub use glam;
-pub(crate) type RendererError = Box<dyn std::error::Error>;
 pub type RendererResult<T> = Result<T, RendererError>;
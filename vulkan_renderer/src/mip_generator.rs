@@ -0,0 +1,441 @@
+use crate::{
+    barrier::BarrierBatch,
+    cmd::{cmd_bind_descriptor_sets, cmd_bind_pipeline, cmd_dispatch},
+    descriptorset::{VDescriptorPool, VDescriptorSet, VDescriptorSetLayout},
+    device::VDevice,
+    image::VImage,
+    pipeline::{VComputePipeline, VComputePipelineBuilder},
+    shader_utils::VShaderUtils,
+    RendererError, RendererResult,
+};
+use ash::{
+    util::read_spv,
+    vk::{
+        AccessFlags, CommandBuffer, DescriptorImageInfo, DescriptorPoolSize, DescriptorSet,
+        DescriptorType, Format, FormatFeatureFlags, ImageLayout, ImageUsageFlags, ImageView,
+        ImageViewType, PipelineBindPoint, PipelineStageFlags, ShaderModule, ShaderStageFlags,
+    },
+};
+use std::io::Cursor;
+
+/// Width and height of the downsample shader's workgroups
+const WORKGROUP_SIZE: u32 = 8;
+
+/// Generates mip chains with a compute shader, one 2x2 box-filter dispatch per level
+///
+/// Unlike [`VImage::generate_mipmaps`] it doesn't need linear blit support, but the image must be
+/// a single layer 2D `R8G8B8A8_UNORM` image with `STORAGE` usage
+pub struct VMipGenerator {
+    shader_module: ShaderModule,
+    set_layout: VDescriptorSetLayout,
+    pipeline: VComputePipeline,
+}
+
+/// The per-level views and descriptors of a recorded [`VMipGenerator::generate`], to destroy
+/// once the command buffer is done
+pub struct VMipDownsample {
+    image_views: Vec<ImageView>,
+    descriptor_pool: VDescriptorPool,
+}
+
+impl VMipDownsample {
+    pub fn destroy(self, device: &VDevice) {
+        unsafe {
+            device
+                .get()
+                .destroy_descriptor_pool(self.descriptor_pool.get(), None);
+            for image_view in self.image_views {
+                device.get().destroy_image_view(image_view, None);
+            }
+        }
+    }
+}
+
+impl VMipGenerator {
+    pub const FORMAT: Format = Format::R8G8B8A8_UNORM;
+
+    pub fn new(device: &VDevice) -> RendererResult<Self> {
+        let shader_code = read_spv(&mut Cursor::new(
+            &include_bytes!("../shaders/downsample.comp.spv")[..],
+        ))?;
+        let shader_module = VShaderUtils::create_shader_module(device, &shader_code)?;
+        let bindings = &[0, 1].map(|binding| {
+            VDescriptorSetLayout::layout_binding(
+                binding,
+                1,
+                DescriptorType::STORAGE_IMAGE,
+                ShaderStageFlags::COMPUTE,
+            )
+        });
+        let set_layout = VDescriptorSetLayout::new(device, bindings)?;
+        let pipeline = VComputePipelineBuilder::start()
+            .shader(shader_module)
+            .pipeline_layout(&[set_layout.get()], &[])
+            .build(device)?;
+        Ok(Self {
+            shader_module,
+            set_layout,
+            pipeline,
+        })
+    }
+
+    /// Fills every mip level below the first from the one above it
+    ///
+    /// Same layout contract as [`VImage::generate_mipmaps`], all levels must be in
+    /// `TRANSFER_DST_OPTIMAL` with the first one written and they're left in
+    /// `SHADER_READ_ONLY_OPTIMAL`. Errors without recording anything if the image can't be
+    /// written as a storage image or its level views and descriptor sets can't be created
+    pub fn generate(
+        &self,
+        device: &VDevice,
+        command_buffer: CommandBuffer,
+        image: &VImage,
+    ) -> RendererResult<VMipDownsample> {
+        Self::validate(device, image)?;
+
+        let mut downsample = VMipDownsample {
+            image_views: Vec::new(),
+            descriptor_pool: VDescriptorPool::default(),
+        };
+        let descriptor_sets = match self.prepare(device, image, &mut downsample) {
+            Ok(descriptor_sets) => descriptor_sets,
+            Err(err) => {
+                downsample.destroy(device);
+                return Err(err);
+            }
+        };
+
+        let level_count = image.mip_levels();
+        let mut batch = BarrierBatch::new();
+        let to_general = image.image_memory_barrier(
+            image.mip_subresource_range(0),
+            (ImageLayout::TRANSFER_DST_OPTIMAL, ImageLayout::GENERAL),
+            (AccessFlags::TRANSFER_WRITE, AccessFlags::SHADER_READ),
+        );
+        batch.add_image_barrier(
+            to_general,
+            PipelineStageFlags::TRANSFER,
+            PipelineStageFlags::COMPUTE_SHADER,
+        );
+        for level in 1..level_count {
+            let to_general = image.image_memory_barrier(
+                image.mip_subresource_range(level),
+                (ImageLayout::TRANSFER_DST_OPTIMAL, ImageLayout::GENERAL),
+                (AccessFlags::empty(), AccessFlags::SHADER_WRITE),
+            );
+            batch.add_image_barrier(
+                to_general,
+                PipelineStageFlags::TRANSFER,
+                PipelineStageFlags::COMPUTE_SHADER,
+            );
+        }
+        batch.flush(device, command_buffer);
+
+        cmd_bind_pipeline(
+            device,
+            command_buffer,
+            PipelineBindPoint::COMPUTE,
+            self.pipeline.pipeline(),
+        );
+        let extent = image.extent();
+        for (level, &descriptor_set) in (1..level_count).zip(&descriptor_sets) {
+            cmd_bind_descriptor_sets(
+                device,
+                command_buffer,
+                PipelineBindPoint::COMPUTE,
+                self.pipeline.pipeline_layout(),
+                0,
+                &[descriptor_set],
+                &[],
+            );
+            let width = (extent.width >> level).max(1);
+            let height = (extent.height >> level).max(1);
+            cmd_dispatch(
+                device,
+                command_buffer,
+                width.div_ceil(WORKGROUP_SIZE),
+                height.div_ceil(WORKGROUP_SIZE),
+                1,
+            );
+
+            // The next dispatch reads what this one wrote
+            let written = image.image_memory_barrier(
+                image.mip_subresource_range(level),
+                (ImageLayout::GENERAL, ImageLayout::GENERAL),
+                (AccessFlags::SHADER_WRITE, AccessFlags::SHADER_READ),
+            );
+            batch
+                .add_image_barrier(
+                    written,
+                    PipelineStageFlags::COMPUTE_SHADER,
+                    PipelineStageFlags::COMPUTE_SHADER,
+                )
+                .flush(device, command_buffer);
+        }
+
+        let to_shader = image.image_memory_barrier(
+            image.subresource_range(image.aspect_mask()),
+            (ImageLayout::GENERAL, ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+            (AccessFlags::SHADER_READ, AccessFlags::SHADER_READ),
+        );
+        batch
+            .add_image_barrier(
+                to_shader,
+                PipelineStageFlags::COMPUTE_SHADER,
+                PipelineStageFlags::FRAGMENT_SHADER,
+            )
+            .flush(device, command_buffer);
+        Ok(downsample)
+    }
+
+    pub fn destroy(&self, device: &VDevice) {
+        unsafe {
+            device
+                .get()
+                .destroy_pipeline(self.pipeline.pipeline(), None);
+            device
+                .get()
+                .destroy_pipeline_layout(self.pipeline.pipeline_layout(), None);
+            device
+                .get()
+                .destroy_descriptor_set_layout(self.set_layout.get(), None);
+            device.get().destroy_shader_module(self.shader_module, None);
+        }
+    }
+
+    fn validate(device: &VDevice, image: &VImage) -> RendererResult<()> {
+        if image.mip_levels() == 0 {
+            return Err(RendererError::InvalidArgument(
+                "Compute mip generation needs at least one mip level.".to_owned(),
+            ));
+        }
+        if image.format() != Self::FORMAT {
+            return Err(RendererError::InvalidArgument(format!(
+                "Compute mip generation needs {:?} images, got {:?}.",
+                Self::FORMAT,
+                image.format()
+            )));
+        }
+        // The shader binds each level as a single image2D
+        if image.view_type() != ImageViewType::TYPE_2D {
+            return Err(RendererError::InvalidArgument(format!(
+                "Compute mip generation needs single layer 2D images, got a {:?} view.",
+                image.view_type()
+            )));
+        }
+        if !image.usage().contains(ImageUsageFlags::STORAGE) {
+            return Err(RendererError::InvalidArgument(format!(
+                "Compute mip generation needs STORAGE usage, got {:?}.",
+                image.usage()
+            )));
+        }
+        let supports_storage = device
+            .get_format_properties(image.format())
+            .optimal_tiling_features
+            .contains(FormatFeatureFlags::STORAGE_IMAGE);
+        if !supports_storage {
+            return Err(RendererError::InvalidArgument(format!(
+                "{:?} can't be used as a storage image.",
+                image.format()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Creates the view of every level and the descriptor set of every dispatch into
+    /// `downsample`, so nothing is recorded if one fails and the caller can destroy the rest
+    fn prepare(
+        &self,
+        device: &VDevice,
+        image: &VImage,
+        downsample: &mut VMipDownsample,
+    ) -> RendererResult<Vec<DescriptorSet>> {
+        let level_count = image.mip_levels();
+        for level in 0..level_count {
+            let image_view = image.create_mip_view(device, level)?;
+            downsample.image_views.push(image_view);
+        }
+        let set_count = (level_count - 1).max(1);
+        downsample.descriptor_pool = VDescriptorPool::new_with_sizes(
+            device,
+            set_count,
+            &[DescriptorPoolSize {
+                ty: DescriptorType::STORAGE_IMAGE,
+                descriptor_count: set_count * 2,
+            }],
+        )?;
+        downsample
+            .image_views
+            .windows(2)
+            .map(|views| {
+                self.level_descriptor_set(device, &downsample.descriptor_pool, views[0], views[1])
+            })
+            .collect()
+    }
+
+    fn level_descriptor_set(
+        &self,
+        device: &VDevice,
+        descriptor_pool: &VDescriptorPool,
+        src_view: ImageView,
+        dst_view: ImageView,
+    ) -> RendererResult<DescriptorSet> {
+        let descriptor_set =
+            VDescriptorSet::new(device, descriptor_pool.get(), &[self.set_layout.get()])?.get();
+        let image_infos = [src_view, dst_view].map(|image_view| DescriptorImageInfo {
+            image_view,
+            image_layout: ImageLayout::GENERAL,
+            ..Default::default()
+        });
+        let write_sets = [0, 1].map(|binding| {
+            VDescriptorSet::write_image_descriptor_set(
+                descriptor_set,
+                binding,
+                DescriptorType::STORAGE_IMAGE,
+                &image_infos[binding as usize],
+            )
+        });
+        unsafe { device.get().update_descriptor_sets(&write_sets, &[]) };
+        Ok(descriptor_set)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{buffer::VBuffer, instance::VInstance, test_utils::create_window};
+    use ash::vk::{
+        BufferImageCopy, BufferUsageFlags, Extent3D, ImageAspectFlags, ImageSubresourceLayers,
+        MemoryPropertyFlags,
+    };
+
+    #[test]
+    fn averages_checkerboard_down_to_grey() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+        let generator = VMipGenerator::new(&device)?;
+
+        let extent = Extent3D {
+            width: 4,
+            height: 4,
+            depth: 1,
+        };
+        let new_image = |usage| {
+            VImage::new_with_mips(
+                &device,
+                usage,
+                VMipGenerator::FORMAT,
+                extent,
+                ImageAspectFlags::COLOR,
+                VImage::mip_level_count(extent),
+            )
+        };
+        let sampled_only = new_image(ImageUsageFlags::TRANSFER_DST | ImageUsageFlags::SAMPLED)?;
+        let image = new_image(
+            ImageUsageFlags::TRANSFER_SRC
+                | ImageUsageFlags::TRANSFER_DST
+                | ImageUsageFlags::STORAGE
+                | ImageUsageFlags::SAMPLED,
+        )?;
+
+        let pixels = (0..extent.width * extent.height)
+            .flat_map(|ind| {
+                let value = if (ind + ind / extent.width).is_multiple_of(2) {
+                    255
+                } else {
+                    0
+                };
+                [value, value, value, 255u8]
+            })
+            .collect::<Vec<_>>();
+        let staging = VBuffer::new_mapped(
+            &device,
+            &pixels,
+            BufferUsageFlags::TRANSFER_SRC,
+            MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT,
+        )?;
+        let readback = VBuffer::new_mapped(
+            &device,
+            &[0u8; 4],
+            BufferUsageFlags::TRANSFER_DST,
+            MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT,
+        )?;
+        VBuffer::copy_buffer_to_image(
+            &device,
+            staging.buffer(),
+            &image,
+            extent,
+            ImageLayout::TRANSFER_DST_OPTIMAL,
+        )?;
+
+        let last_level = image.mip_levels() - 1;
+        let mut result = Ok(None);
+        VBuffer::submit_one_time(&device, |command_buffer| {
+            assert!(generator
+                .generate(&device, command_buffer, &sampled_only)
+                .is_err());
+            result = generator
+                .generate(&device, command_buffer, &image)
+                .map(Some);
+            let to_transfer = image.image_memory_barrier(
+                image.mip_subresource_range(last_level),
+                (
+                    ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    ImageLayout::TRANSFER_SRC_OPTIMAL,
+                ),
+                (AccessFlags::SHADER_READ, AccessFlags::TRANSFER_READ),
+            );
+            BarrierBatch::new()
+                .add_image_barrier(
+                    to_transfer,
+                    PipelineStageFlags::FRAGMENT_SHADER,
+                    PipelineStageFlags::TRANSFER,
+                )
+                .flush(&device, command_buffer);
+            let region = BufferImageCopy {
+                image_subresource: ImageSubresourceLayers {
+                    aspect_mask: ImageAspectFlags::COLOR,
+                    mip_level: last_level,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                },
+                image_extent: Extent3D {
+                    width: 1,
+                    height: 1,
+                    depth: 1,
+                },
+                ..Default::default()
+            };
+            unsafe {
+                device.get().cmd_copy_image_to_buffer(
+                    command_buffer,
+                    image.image(),
+                    ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    readback.buffer(),
+                    &[region],
+                );
+            }
+        })?;
+        let downsample = result?.expect("Mip generation was not recorded.");
+
+        let texel = readback.read_back::<u8>(&device)?;
+        assert!(texel[..3].iter().all(|&value| value.abs_diff(128) <= 1));
+        assert_eq!(texel[3], 255);
+
+        downsample.destroy(&device);
+        generator.destroy(&device);
+        unsafe {
+            for buffer in [staging, readback] {
+                device.get().destroy_buffer(buffer.buffer(), None);
+                device.get().free_memory(buffer.memory(), None);
+            }
+            for image in [sampled_only, image] {
+                device.get().destroy_image_view(image.image_view(), None);
+                device.get().destroy_image(image.image(), None);
+                device.get().free_memory(image.memory(), None);
+            }
+        }
+        Ok(())
+    }
+}
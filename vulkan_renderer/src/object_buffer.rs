@@ -0,0 +1,108 @@
+use crate::{
+    buffer::{VBuffer, VMappedBuffer},
+    device::VDevice,
+    utils::{pad_uniform_buffer_size, AsBytes},
+    RendererError, RendererResult,
+};
+use ash::vk::{BufferUsageFlags, MemoryPropertyFlags};
+use std::{marker::PhantomData, mem::size_of};
+
+/// Packs per-object data (e.g. model matrices) of every frame into one dynamic uniform buffer
+///
+/// Each frame owns `capacity` slots, so writing a frame never touches data of another frame in flight.
+/// The memory stays mapped, so writes are plain copies. Slots are sized for `T`, the only type
+/// that can be written
+#[derive(Debug)]
+pub struct VObjectBuffer<T> {
+    buffer: VMappedBuffer,
+    object_size: u64,
+    capacity: usize,
+    frame_count: usize,
+    objects: PhantomData<T>,
+}
+
+impl<T> Default for VObjectBuffer<T> {
+    fn default() -> Self {
+        Self {
+            buffer: VMappedBuffer::default(),
+            object_size: 0,
+            capacity: 0,
+            frame_count: 0,
+            objects: PhantomData,
+        }
+    }
+}
+
+impl<T: AsBytes> VObjectBuffer<T> {
+    pub fn new(device: &VDevice, capacity: usize, frame_count: usize) -> RendererResult<Self> {
+        let object_size = pad_uniform_buffer_size(device, size_of::<T>());
+        let buffer = VBuffer::new_persistently_mapped(
+            device,
+            object_size * (capacity * frame_count) as u64,
+            BufferUsageFlags::UNIFORM_BUFFER,
+            MemoryPropertyFlags::HOST_COHERENT | MemoryPropertyFlags::HOST_VISIBLE,
+        )?;
+        Ok(Self {
+            buffer,
+            object_size,
+            capacity,
+            frame_count,
+            objects: PhantomData,
+        })
+    }
+
+    /// Writes `objects` into the slots of `frame_index` and returns the dynamic offset of each
+    pub fn write(&self, frame_index: usize, objects: &[T]) -> RendererResult<Vec<u32>> {
+        self.write_at(frame_index, 0, objects)
+    }
+
+    /// Same as [`write`](Self::write) but starts at the slot `first_index`
+    pub fn write_at(
+        &self,
+        frame_index: usize,
+        first_index: usize,
+        objects: &[T],
+    ) -> RendererResult<Vec<u32>> {
+        if first_index + objects.len() > self.capacity || frame_index >= self.frame_count {
+            return Err(RendererError::InvalidArgument(format!(
+                "VObjectBuffer holds {} objects for {} frames, got {} objects at {} for frame {}.",
+                self.capacity,
+                self.frame_count,
+                objects.len(),
+                first_index,
+                frame_index
+            )));
+        }
+
+        self.buffer.write_strided(
+            objects,
+            self.offset(frame_index, first_index) as u64,
+            self.object_size,
+        )?;
+
+        Ok((first_index..first_index + objects.len())
+            .map(|ind| self.offset(frame_index, ind))
+            .collect())
+    }
+
+    pub fn buffer(&self) -> &VBuffer {
+        self.buffer.buffer()
+    }
+
+    pub fn destroy(&self, device: &VDevice) {
+        self.buffer.destroy(device);
+    }
+
+    /// Dynamic offset of the slot `index` of `frame_index`
+    pub fn offset(&self, frame_index: usize, index: usize) -> u32 {
+        ((frame_index * self.capacity + index) as u64 * self.object_size) as u32
+    }
+
+    pub fn object_size(&self) -> u64 {
+        self.object_size
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
@@ -1,78 +1,328 @@
-use crate::{device::VDevice, impl_get, RendererResult};
+use crate::{
+    device::VDevice, impl_get, pipeline_cache::VPipelineCache, utils::AsBytes, RendererError,
+    RendererResult,
+};
 use ash::vk::{
-    CompareOp, CullModeFlags, DescriptorSetLayout, FrontFace, GraphicsPipelineCreateInfo, LogicOp,
+    BlendFactor, BlendOp, ColorComponentFlags, CompareOp, ComputePipelineCreateInfo, CullModeFlags,
+    DescriptorSetLayout, DynamicState, Format, FrontFace, GraphicsPipelineCreateInfo, LogicOp,
     Pipeline, PipelineCache, PipelineColorBlendAttachmentState, PipelineColorBlendStateCreateInfo,
-    PipelineDepthStencilStateCreateInfo, PipelineInputAssemblyStateCreateInfo, PipelineLayout,
-    PipelineLayoutCreateInfo, PipelineMultisampleStateCreateInfo,
-    PipelineRasterizationStateCreateInfo, PipelineShaderStageCreateInfo,
+    PipelineDepthStencilStateCreateInfo, PipelineDynamicStateCreateInfo,
+    PipelineInputAssemblyStateCreateInfo, PipelineLayout, PipelineLayoutCreateInfo,
+    PipelineMultisampleStateCreateInfo, PipelineRasterizationStateCreateInfo,
+    PipelineRenderingCreateInfoKHR, PipelineShaderStageCreateInfo,
     PipelineVertexInputStateCreateInfo, PipelineViewportStateCreateInfo, PolygonMode,
     PrimitiveTopology, PushConstantRange, Rect2D, RenderPass, SampleCountFlags, ShaderModule,
-    ShaderStageFlags, VertexInputAttributeDescription, VertexInputBindingDescription, Viewport,
+    ShaderStageFlags, SpecializationInfo, SpecializationMapEntry, StencilOpState,
+    VertexInputAttributeDescription, VertexInputBindingDescription, Viewport, TRUE,
+};
+use std::{
+    ffi::{c_void, CString},
+    ptr,
 };
-use std::ffi::CStr;
 
-#[derive(Default, Debug, Clone, Copy)]
+#[derive(Default, Debug, Clone)]
 pub struct VGraphicsPipeline {
     pipeline: Pipeline,
     pipeline_layout: PipelineLayout,
+    /// The render pass the pipeline was built for, it can be used in any compatible one
+    render_pass: RenderPass,
+    push_constant_ranges: Vec<PushConstantRange>,
+    state_summary: PipelineStateSummary,
+}
+
+/// The fixed-function state a [`VGraphicsPipeline`] was built with
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineStateSummary {
+    pub cull_mode: CullModeFlags,
+    pub polygon_mode: PolygonMode,
+    pub front_face: FrontFace,
+    pub depth_test: bool,
+    pub depth_write: bool,
+    pub depth_compare_op: CompareOp,
+    pub depth_bias: bool,
+    pub stencil_test: bool,
+    /// Whether any color attachment has blending enabled
+    pub blend_enable: bool,
+    pub sample_count: SampleCountFlags,
+}
+
+impl VGraphicsPipeline {
+    /// Returns the push constant range of the pipeline layout that covers `stage_flags`
+    pub fn push_constant_range(&self, stage_flags: ShaderStageFlags) -> Option<PushConstantRange> {
+        self.push_constant_ranges
+            .iter()
+            .copied()
+            .find(|range| range.stage_flags.contains(stage_flags))
+    }
 }
 
 impl_get!(VGraphicsPipeline, pipeline, Pipeline);
 impl_get!(VGraphicsPipeline, pipeline_layout, PipelineLayout);
+impl_get!(VGraphicsPipeline, render_pass, RenderPass);
+impl_get!(VGraphicsPipeline, state_summary, PipelineStateSummary);
+
+/// A shader stage of a pipeline, entering `main` unless [`entry_name`](Self::entry_name) is set
+#[derive(Debug, Clone)]
+pub struct VShaderStage {
+    stage: ShaderStageFlags,
+    module: ShaderModule,
+    entry_name: CString,
+    map_entries: Vec<SpecializationMapEntry>,
+    data: Vec<u8>,
+}
+
+impl VShaderStage {
+    pub fn new(stage: ShaderStageFlags, module: ShaderModule) -> Self {
+        Self {
+            stage,
+            module,
+            entry_name: CString::new("main").expect("Entry name contains a nul byte."),
+            map_entries: Vec::new(),
+            data: Vec::new(),
+        }
+    }
+
+    /// Panics if `entry_name` contains a nul byte
+    pub fn entry_name(mut self, entry_name: &str) -> Self {
+        self.entry_name = CString::new(entry_name).expect("Entry name contains a nul byte.");
+        self
+    }
+
+    /// Sets the specialization constant `constant_id`, `value` must match its type in the shader,
+    /// so booleans are passed as `u32`
+    pub fn specialize(mut self, constant_id: u32, value: &impl AsBytes) -> Self {
+        let bytes = value.as_bytes();
+        self.map_entries.push(SpecializationMapEntry {
+            constant_id,
+            offset: self.data.len() as u32,
+            size: bytes.len(),
+        });
+        self.data.extend_from_slice(bytes);
+        self
+    }
+
+    fn specialization_info(&self) -> SpecializationInfo {
+        SpecializationInfo {
+            map_entry_count: self.map_entries.len() as u32,
+            p_map_entries: self.map_entries.as_ptr(),
+            data_size: self.data.len(),
+            p_data: self.data.as_ptr().cast(),
+        }
+    }
+
+    /// `specialization_info` must be this stage's and outlive the returned create info
+    fn create_info(
+        &self,
+        specialization_info: &SpecializationInfo,
+    ) -> PipelineShaderStageCreateInfo {
+        PipelineShaderStageCreateInfo {
+            stage: self.stage,
+            module: self.module,
+            p_name: self.entry_name.as_ptr(),
+            p_specialization_info: match self.map_entries.is_empty() {
+                true => ptr::null(),
+                false => specialization_info,
+            },
+            ..Default::default()
+        }
+    }
+}
+
+/// Constant depth bias of [`VGraphicsPipelineBuilder::shadow_preset`], in units of the smallest depth step
+pub const SHADOW_DEPTH_BIAS_CONSTANT: f32 = 1.25;
+/// Slope-scaled depth bias of [`VGraphicsPipelineBuilder::shadow_preset`]
+pub const SHADOW_DEPTH_BIAS_SLOPE: f32 = 1.75;
 
 #[derive(Default)]
 pub struct VGraphicsPipelineBuilder {
-    shader_stages: Vec<PipelineShaderStageCreateInfo>,
+    shader_stages: Vec<VShaderStage>,
     input_assembly: PipelineInputAssemblyStateCreateInfo,
     vertex_input: PipelineVertexInputStateCreateInfo,
     rasterization: PipelineRasterizationStateCreateInfo,
     color_blend_state: PipelineColorBlendStateCreateInfo,
     multisample: PipelineMultisampleStateCreateInfo,
     pipeline_layout_create_info: PipelineLayoutCreateInfo,
+    push_constant_ranges: Vec<PushConstantRange>,
     depth_stencil_create_info: PipelineDepthStencilStateCreateInfo,
     viewport: PipelineViewportStateCreateInfo,
+    dynamic_states: Vec<DynamicState>,
+    blend_enable: bool,
+    rendering_formats: Option<(Vec<Format>, Format)>,
 }
 
 impl VGraphicsPipelineBuilder {
     pub fn start() -> Self {
         Self {
-            input_assembly: Self::input_assembly_create_info(PrimitiveTopology::TRIANGLE_LIST),
+            input_assembly: Self::input_assembly_create_info(
+                PrimitiveTopology::TRIANGLE_LIST,
+                false,
+            ),
             vertex_input: Self::vertex_input_create_info(&[], &[]),
-            rasterization: Self::rasterization_create_info(CullModeFlags::BACK, PolygonMode::FILL),
+            rasterization: Self::rasterization_create_info(
+                CullModeFlags::BACK,
+                PolygonMode::FILL,
+                FrontFace::COUNTER_CLOCKWISE,
+            ),
             color_blend_state: Self::color_blend_state_create_info(&[]),
-            multisample: Self::multisample_create_info(),
+            multisample: Self::multisample_create_info(SampleCountFlags::TYPE_1),
             pipeline_layout_create_info: Self::pipeline_layout_create_info(&[], &[]),
-            depth_stencil_create_info: Self::depth_stencil_create_info(),
+            depth_stencil_create_info: Self::depth_stencil_create_info(
+                true,
+                true,
+                CompareOp::LESS_OR_EQUAL,
+            ),
             ..Default::default()
         }
     }
 
+    /// Depth-only pipeline for shadow maps, to build against [`VRenderPass::new_depth_only`](crate::render_pass::VRenderPass::new_depth_only)
+    ///
+    /// Culls front faces and biases depth against shadow acne. The vertex stage gets a push
+    /// constant of `light_space_size` bytes at offset 0 for the light's view projection.
+    /// Vertex input and viewport still need to be set
+    pub fn shadow_preset(vertex_shader: ShaderModule, light_space_size: u32) -> Self {
+        let mut builder = Self::start()
+            .shader_stages(&[(ShaderStageFlags::VERTEX, vertex_shader)])
+            .rasterization(
+                CullModeFlags::FRONT,
+                PolygonMode::FILL,
+                FrontFace::COUNTER_CLOCKWISE,
+            )
+            .depth_bias(SHADOW_DEPTH_BIAS_CONSTANT, SHADOW_DEPTH_BIAS_SLOPE);
+        // The create info points into the builder's own ranges, which outlive the caller's slice
+        builder.push_constant_ranges = vec![PushConstantRange {
+            stage_flags: ShaderStageFlags::VERTEX,
+            offset: 0,
+            size: light_space_size,
+        }];
+        builder.pipeline_layout_create_info =
+            Self::pipeline_layout_create_info(&[], &builder.push_constant_ranges);
+        builder
+    }
+
     pub fn build(
         &self,
         device: &VDevice,
         render_pass: RenderPass,
     ) -> RendererResult<VGraphicsPipeline> {
+        self.build_with_pipeline_cache(device, render_pass, PipelineCache::null(), None)
+    }
+
+    /// Reuses and adds to the compiled state in `cache`
+    pub fn build_with_cache(
+        &self,
+        device: &VDevice,
+        render_pass: RenderPass,
+        cache: &VPipelineCache,
+    ) -> RendererResult<VGraphicsPipeline> {
+        self.build_with_pipeline_cache(device, render_pass, cache.get(), None)
+    }
+
+    /// Builds without a render pass for [`cmd_begin_rendering`](crate::cmd::cmd_begin_rendering)
+    ///
+    /// Needs [`rendering_formats`](Self::rendering_formats) and Vulkan 1.3 or
+    /// `VK_KHR_dynamic_rendering`, errors otherwise
+    pub fn build_for_rendering(&self, device: &VDevice) -> RendererResult<VGraphicsPipeline> {
+        if device.dynamic_rendering().is_none() {
+            return Err(RendererError::InvalidArgument(
+                "Dynamic rendering is not enabled.".to_owned(),
+            ));
+        }
+        let (color_formats, depth_format) = self.rendering_formats.as_ref().ok_or_else(|| {
+            RendererError::InvalidArgument(
+                "The attachment formats are needed to build without a render pass.".to_owned(),
+            )
+        })?;
+        let rendering_create_info = PipelineRenderingCreateInfoKHR {
+            color_attachment_count: color_formats.len() as u32,
+            p_color_attachment_formats: color_formats.as_ptr(),
+            depth_attachment_format: *depth_format,
+            ..Default::default()
+        };
+        self.build_with_pipeline_cache(
+            device,
+            RenderPass::null(),
+            PipelineCache::null(),
+            Some(&rendering_create_info),
+        )
+    }
+
+    fn build_with_pipeline_cache(
+        &self,
+        device: &VDevice,
+        render_pass: RenderPass,
+        pipeline_cache: PipelineCache,
+        rendering_create_info: Option<&PipelineRenderingCreateInfoKHR>,
+    ) -> RendererResult<VGraphicsPipeline> {
+        if self.input_assembly.primitive_restart_enable == TRUE
+            && !Self::supports_primitive_restart(self.input_assembly.topology)
+        {
+            return Err(RendererError::InvalidArgument(format!(
+                "Primitive restart is not allowed with {:?}.",
+                self.input_assembly.topology
+            )));
+        }
+
         let pipeline_layout = unsafe {
             device
                 .get()
                 .create_pipeline_layout(&self.pipeline_layout_create_info, None)?
         };
-        let create_infos = &[Self::graphics_pipeline_create_info(
+        let specialization_infos = self
+            .shader_stages
+            .iter()
+            .map(VShaderStage::specialization_info)
+            .collect::<Vec<_>>();
+        let shader_stages = self
+            .shader_stages
+            .iter()
+            .zip(&specialization_infos)
+            .map(|(stage, specialization_info)| stage.create_info(specialization_info))
+            .collect::<Vec<_>>();
+        let viewport = self.viewport_state();
+        let dynamic_state = Self::dynamic_state_create_info(&self.dynamic_states);
+        let mut create_info = Self::graphics_pipeline_create_info(
             self,
             pipeline_layout,
             render_pass,
-        )];
+            &shader_stages,
+            &viewport,
+            &dynamic_state,
+        );
+        if let Some(rendering_create_info) = rendering_create_info {
+            create_info.p_next = rendering_create_info as *const _ as *const c_void;
+        }
+        let create_infos = &[create_info];
         let pipelines_result = unsafe {
             device
                 .get()
-                .create_graphics_pipelines(PipelineCache::null(), create_infos, None)
+                .create_graphics_pipelines(pipeline_cache, create_infos, None)
         };
         match pipelines_result {
             Ok(pipelines) => Ok(VGraphicsPipeline {
                 pipeline: pipelines[0],
                 pipeline_layout,
+                render_pass,
+                push_constant_ranges: self.push_constant_ranges.clone(),
+                state_summary: self.state_summary(),
             }),
-            Err((_, err)) => Err(Box::new(err)),
+            Err((_, err)) => Err(err.into()),
+        }
+    }
+
+    /// The state the pipeline would be built with, also available on the built pipeline
+    pub fn state_summary(&self) -> PipelineStateSummary {
+        PipelineStateSummary {
+            cull_mode: self.rasterization.cull_mode,
+            polygon_mode: self.rasterization.polygon_mode,
+            front_face: self.rasterization.front_face,
+            depth_test: self.depth_stencil_create_info.depth_test_enable == TRUE,
+            depth_write: self.depth_stencil_create_info.depth_write_enable == TRUE,
+            depth_compare_op: self.depth_stencil_create_info.depth_compare_op,
+            depth_bias: self.rasterization.depth_bias_enable == TRUE,
+            stencil_test: self.depth_stencil_create_info.stencil_test_enable == TRUE,
+            blend_enable: self.blend_enable,
+            sample_count: self.multisample.rasterization_samples,
         }
     }
 
@@ -80,17 +330,21 @@ impl VGraphicsPipelineBuilder {
         &self,
         layout: PipelineLayout,
         render_pass: RenderPass,
+        shader_stages: &[PipelineShaderStageCreateInfo],
+        viewport: &PipelineViewportStateCreateInfo,
+        dynamic_state: &PipelineDynamicStateCreateInfo,
     ) -> GraphicsPipelineCreateInfo {
         GraphicsPipelineCreateInfo {
-            stage_count: self.shader_stages.len() as u32,
-            p_stages: self.shader_stages.as_ptr(),
+            stage_count: shader_stages.len() as u32,
+            p_stages: shader_stages.as_ptr(),
             p_vertex_input_state: &self.vertex_input,
             p_input_assembly_state: &self.input_assembly,
-            p_viewport_state: &self.viewport,
+            p_viewport_state: viewport,
             p_rasterization_state: &self.rasterization,
             p_multisample_state: &self.multisample,
             p_depth_stencil_state: &self.depth_stencil_create_info,
             p_color_blend_state: &self.color_blend_state,
+            p_dynamic_state: dynamic_state,
             layout,
             render_pass,
             subpass: 0,
@@ -98,17 +352,30 @@ impl VGraphicsPipelineBuilder {
         }
     }
 
-    /// Must be called
+    /// Must be called, or [`stages`](Self::stages) for other entry points or specialization
     pub fn shader_stages(mut self, shader_infos: &[(ShaderStageFlags, ShaderModule)]) -> Self {
         self.shader_stages = shader_infos
             .iter()
-            .map(|&(stage, module)| Self::shader_stage_create_info(stage, module))
+            .map(|&(stage, module)| VShaderStage::new(stage, module))
             .collect();
         self
     }
 
-    pub fn input_assembly(mut self, topology: PrimitiveTopology) -> Self {
-        self.input_assembly = Self::input_assembly_create_info(topology);
+    pub fn stages(mut self, stages: &[VShaderStage]) -> Self {
+        self.shader_stages = stages.to_vec();
+        self
+    }
+
+    /// The attachment formats for [`build_for_rendering`](Self::build_for_rendering), in place of
+    /// a render pass. `depth_format` is `Format::UNDEFINED` without a depth attachment
+    pub fn rendering_formats(mut self, color_formats: &[Format], depth_format: Format) -> Self {
+        self.rendering_formats = Some((color_formats.to_vec(), depth_format));
+        self
+    }
+
+    /// `primitive_restart` is only valid for strip and fan topologies
+    pub fn input_assembly(mut self, topology: PrimitiveTopology, primitive_restart: bool) -> Self {
+        self.input_assembly = Self::input_assembly_create_info(topology, primitive_restart);
         self
     }
 
@@ -124,19 +391,75 @@ impl VGraphicsPipelineBuilder {
         self
     }
 
-    pub fn rasterization(mut self, cull_mode: CullModeFlags, polygon_mode: PolygonMode) -> Self {
-        self.rasterization = Self::rasterization_create_info(cull_mode, polygon_mode);
+    /// Resets the depth bias, so call [`depth_bias`](Self::depth_bias) afterwards
+    ///
+    /// Defaults to back-face culling of counter-clockwise wound triangles
+    pub fn rasterization(
+        mut self,
+        cull_mode: CullModeFlags,
+        polygon_mode: PolygonMode,
+        front_face: FrontFace,
+    ) -> Self {
+        self.rasterization = Self::rasterization_create_info(cull_mode, polygon_mode, front_face);
+        self
+    }
+
+    /// Offsets fragment depth by `constant_factor` depth steps plus `slope_factor` times the polygon's slope
+    pub fn depth_bias(mut self, constant_factor: f32, slope_factor: f32) -> Self {
+        self.rasterization.depth_bias_enable = TRUE;
+        self.rasterization.depth_bias_constant_factor = constant_factor;
+        self.rasterization.depth_bias_slope_factor = slope_factor;
+        self
+    }
+
+    /// Defaults to testing and writing depth with `LESS_OR_EQUAL`, use `GREATER` for reverse-Z
+    ///
+    /// Keeps the stencil state set with [`stencil`](Self::stencil)
+    pub fn depth_stencil(
+        mut self,
+        test_enable: bool,
+        write_enable: bool,
+        compare_op: CompareOp,
+    ) -> Self {
+        self.depth_stencil_create_info = PipelineDepthStencilStateCreateInfo {
+            stencil_test_enable: self.depth_stencil_create_info.stencil_test_enable,
+            front: self.depth_stencil_create_info.front,
+            back: self.depth_stencil_create_info.back,
+            ..Self::depth_stencil_create_info(test_enable, write_enable, compare_op)
+        };
+        self
+    }
+
+    /// Enables the stencil test with the ops for front and back facing polygons
+    ///
+    /// The render pass needs a depth attachment with a stencil format, e.g. from
+    /// [`STENCIL_FORMAT_CANDIDATES`](crate::swapchain::STENCIL_FORMAT_CANDIDATES)
+    pub fn stencil(mut self, front: StencilOpState, back: StencilOpState) -> Self {
+        self.depth_stencil_create_info.stencil_test_enable = TRUE;
+        self.depth_stencil_create_info.front = front;
+        self.depth_stencil_create_info.back = back;
         self
     }
 
+    /// Neither tests nor writes depth, e.g. for UI overlays
+    ///
+    /// The render pass can still have a depth attachment
+    pub fn no_depth(self) -> Self {
+        let compare_op = self.depth_stencil_create_info.depth_compare_op;
+        self.depth_stencil(false, false, compare_op)
+    }
+
     pub fn color_blend_state(mut self, attachments: &[PipelineColorBlendAttachmentState]) -> Self {
         self.color_blend_state = Self::color_blend_state_create_info(attachments);
+        self.blend_enable = attachments
+            .iter()
+            .any(|attachment| attachment.blend_enable == TRUE);
         self
     }
 
-    // Add multisampling
-    pub fn multisample(mut self) -> Self {
-        self.multisample = Self::multisample_create_info();
+    /// Must match the sample count of the render pass' attachments
+    pub fn multisample(mut self, samples: SampleCountFlags) -> Self {
+        self.multisample = Self::multisample_create_info(samples);
         self
     }
 
@@ -147,6 +470,7 @@ impl VGraphicsPipelineBuilder {
     ) -> Self {
         self.pipeline_layout_create_info =
             Self::pipeline_layout_create_info(descriptor_set_layouts, push_constants);
+        self.push_constant_ranges = push_constants.to_vec();
         self
     }
 
@@ -155,29 +479,37 @@ impl VGraphicsPipelineBuilder {
         self
     }
 
-    fn shader_stage_create_info(
-        stage: ShaderStageFlags,
-        module: ShaderModule,
-    ) -> PipelineShaderStageCreateInfo {
-        PipelineShaderStageCreateInfo {
-            stage,
-            module,
-            p_name: CStr::from_bytes_with_nul(b"main\0")
-                .expect("Module name not null-terminated.")
-                .as_ptr(),
-            ..Default::default()
-        }
+    /// States listed here are set at record time, e.g. with [`cmd_set_viewport`](crate::cmd::cmd_set_viewport)
+    ///
+    /// With a dynamic viewport or scissor, [`viewport`](Self::viewport) can be skipped and a
+    /// single one is set per command buffer
+    pub fn dynamic_state(mut self, dynamic_states: &[DynamicState]) -> Self {
+        self.dynamic_states = dynamic_states.to_vec();
+        self
     }
 
     fn input_assembly_create_info(
         topology: PrimitiveTopology,
+        primitive_restart: bool,
     ) -> PipelineInputAssemblyStateCreateInfo {
         PipelineInputAssemblyStateCreateInfo {
             topology,
+            primitive_restart_enable: primitive_restart.into(),
             ..Default::default()
         }
     }
 
+    fn supports_primitive_restart(topology: PrimitiveTopology) -> bool {
+        matches!(
+            topology,
+            PrimitiveTopology::LINE_STRIP
+                | PrimitiveTopology::TRIANGLE_STRIP
+                | PrimitiveTopology::TRIANGLE_FAN
+                | PrimitiveTopology::LINE_STRIP_WITH_ADJACENCY
+                | PrimitiveTopology::TRIANGLE_STRIP_WITH_ADJACENCY
+        )
+    }
+
     fn vertex_input_create_info(
         vertex_binding_descriptions: &[VertexInputBindingDescription],
         vertex_attribute_descriptions: &[VertexInputAttributeDescription],
@@ -194,19 +526,20 @@ impl VGraphicsPipelineBuilder {
     fn rasterization_create_info(
         cull_mode: CullModeFlags,
         polygon_mode: PolygonMode,
+        front_face: FrontFace,
     ) -> PipelineRasterizationStateCreateInfo {
         PipelineRasterizationStateCreateInfo {
             line_width: 1.0,
             cull_mode,
             polygon_mode,
-            front_face: FrontFace::COUNTER_CLOCKWISE,
+            front_face,
             ..Default::default()
         }
     }
 
-    fn multisample_create_info() -> PipelineMultisampleStateCreateInfo {
+    fn multisample_create_info(samples: SampleCountFlags) -> PipelineMultisampleStateCreateInfo {
         PipelineMultisampleStateCreateInfo {
-            rasterization_samples: SampleCountFlags::TYPE_1,
+            rasterization_samples: samples,
             min_sample_shading: 1.0,
             ..Default::default()
         }
@@ -238,6 +571,30 @@ impl VGraphicsPipelineBuilder {
         }
     }
 
+    /// Dynamic viewports and scissors keep their count, at least one, but aren't read at build time
+    fn viewport_state(&self) -> PipelineViewportStateCreateInfo {
+        let mut viewport = self.viewport;
+        if self.dynamic_states.contains(&DynamicState::VIEWPORT) {
+            viewport.viewport_count = viewport.viewport_count.max(1);
+            viewport.p_viewports = ptr::null();
+        }
+        if self.dynamic_states.contains(&DynamicState::SCISSOR) {
+            viewport.scissor_count = viewport.scissor_count.max(1);
+            viewport.p_scissors = ptr::null();
+        }
+        viewport
+    }
+
+    fn dynamic_state_create_info(
+        dynamic_states: &[DynamicState],
+    ) -> PipelineDynamicStateCreateInfo {
+        PipelineDynamicStateCreateInfo {
+            dynamic_state_count: dynamic_states.len() as u32,
+            p_dynamic_states: dynamic_states.as_ptr(),
+            ..Default::default()
+        }
+    }
+
     pub fn color_blend_state_create_info(
         attachments: &[PipelineColorBlendAttachmentState],
     ) -> PipelineColorBlendStateCreateInfo {
@@ -249,14 +606,936 @@ impl VGraphicsPipelineBuilder {
         }
     }
 
-    fn depth_stencil_create_info() -> PipelineDepthStencilStateCreateInfo {
+    /// Writes the fragment color as is
+    pub fn blend_opaque() -> PipelineColorBlendAttachmentState {
+        PipelineColorBlendAttachmentState {
+            color_write_mask: ColorComponentFlags::RGBA,
+            ..Default::default()
+        }
+    }
+
+    /// Blends the fragment over the attachment by its alpha, for non-premultiplied colors
+    pub fn blend_alpha() -> PipelineColorBlendAttachmentState {
+        PipelineColorBlendAttachmentState {
+            blend_enable: TRUE,
+            src_color_blend_factor: BlendFactor::SRC_ALPHA,
+            dst_color_blend_factor: BlendFactor::ONE_MINUS_SRC_ALPHA,
+            color_blend_op: BlendOp::ADD,
+            src_alpha_blend_factor: BlendFactor::ONE,
+            dst_alpha_blend_factor: BlendFactor::ONE_MINUS_SRC_ALPHA,
+            alpha_blend_op: BlendOp::ADD,
+            color_write_mask: ColorComponentFlags::RGBA,
+        }
+    }
+
+    /// Adds the fragment color scaled by its alpha to the attachment, e.g. for particles
+    pub fn blend_additive() -> PipelineColorBlendAttachmentState {
+        PipelineColorBlendAttachmentState {
+            blend_enable: TRUE,
+            src_color_blend_factor: BlendFactor::SRC_ALPHA,
+            dst_color_blend_factor: BlendFactor::ONE,
+            color_blend_op: BlendOp::ADD,
+            src_alpha_blend_factor: BlendFactor::ONE,
+            dst_alpha_blend_factor: BlendFactor::ONE,
+            alpha_blend_op: BlendOp::ADD,
+            color_write_mask: ColorComponentFlags::RGBA,
+        }
+    }
+
+    fn depth_stencil_create_info(
+        test_enable: bool,
+        write_enable: bool,
+        compare_op: CompareOp,
+    ) -> PipelineDepthStencilStateCreateInfo {
         PipelineDepthStencilStateCreateInfo {
-            depth_test_enable: 1,
-            depth_write_enable: 1,
-            depth_compare_op: CompareOp::LESS_OR_EQUAL,
+            depth_test_enable: test_enable.into(),
+            depth_write_enable: write_enable.into(),
+            depth_compare_op: compare_op,
             min_depth_bounds: 0.0,
             max_depth_bounds: 1.0,
             ..Default::default()
         }
     }
 }
+
+#[derive(Default, Debug, Clone)]
+pub struct VComputePipeline {
+    pipeline: Pipeline,
+    pipeline_layout: PipelineLayout,
+}
+
+impl_get!(VComputePipeline, pipeline, Pipeline);
+impl_get!(VComputePipeline, pipeline_layout, PipelineLayout);
+
+#[derive(Default)]
+pub struct VComputePipelineBuilder {
+    shader_stage: Option<VShaderStage>,
+    pipeline_layout_create_info: PipelineLayoutCreateInfo,
+}
+
+impl VComputePipelineBuilder {
+    pub fn start() -> Self {
+        Self {
+            pipeline_layout_create_info: VGraphicsPipelineBuilder::pipeline_layout_create_info(
+                &[],
+                &[],
+            ),
+            ..Default::default()
+        }
+    }
+
+    pub fn build(&self, device: &VDevice) -> RendererResult<VComputePipeline> {
+        let shader_stage = match &self.shader_stage {
+            Some(shader_stage) if shader_stage.module != ShaderModule::null() => shader_stage,
+            _ => {
+                return Err(RendererError::InvalidArgument(
+                    "A compute pipeline needs a shader module.".to_owned(),
+                ))
+            }
+        };
+
+        let pipeline_layout = unsafe {
+            device
+                .get()
+                .create_pipeline_layout(&self.pipeline_layout_create_info, None)?
+        };
+        let specialization_info = shader_stage.specialization_info();
+        let create_infos = &[ComputePipelineCreateInfo {
+            stage: shader_stage.create_info(&specialization_info),
+            layout: pipeline_layout,
+            ..Default::default()
+        }];
+        let pipelines_result = unsafe {
+            device
+                .get()
+                .create_compute_pipelines(PipelineCache::null(), create_infos, None)
+        };
+        match pipelines_result {
+            Ok(pipelines) => Ok(VComputePipeline {
+                pipeline: pipelines[0],
+                pipeline_layout,
+            }),
+            Err((_, err)) => Err(err.into()),
+        }
+    }
+
+    /// Must be called, or [`stage`](Self::stage) for another entry point or specialization
+    pub fn shader(self, module: ShaderModule) -> Self {
+        self.stage(VShaderStage::new(ShaderStageFlags::COMPUTE, module))
+    }
+
+    pub fn stage(mut self, stage: VShaderStage) -> Self {
+        self.shader_stage = Some(stage);
+        self
+    }
+
+    pub fn pipeline_layout(
+        mut self,
+        descriptor_set_layouts: &[DescriptorSetLayout],
+        push_constants: &[PushConstantRange],
+    ) -> Self {
+        self.pipeline_layout_create_info = VGraphicsPipelineBuilder::pipeline_layout_create_info(
+            descriptor_set_layouts,
+            push_constants,
+        );
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        buffer::VBuffer,
+        cmd::*,
+        command_pool::VCommandPool,
+        descriptorset::{VDescriptorPool, VDescriptorSet, VDescriptorSetLayout},
+        enums::EOperationType,
+        glam::{Mat4, Vec3},
+        image::VImage,
+        instance::VInstance,
+        render_pass::VRenderPass,
+        render_target::VOffscreenTarget,
+        shader_utils::VShaderUtils,
+        swapchain::STENCIL_FORMAT_CANDIDATES,
+        test_utils::{create_window, destroy_pipelines, shader_module, triangle_modules},
+        vertex_input::VVertexInputBuilder,
+    };
+    use ash::vk::{
+        AccessFlags, AttachmentLoadOp, AttachmentStoreOp, BufferUsageFlags, ColorComponentFlags,
+        CommandBufferLevel, CommandPoolCreateFlags, DependencyFlags, DescriptorBufferInfo,
+        DescriptorType, Extent2D, Extent3D, Fence, Format, FormatFeatureFlags,
+        FramebufferCreateInfo, ImageAspectFlags, ImageTiling, ImageUsageFlags, MemoryBarrier,
+        MemoryPropertyFlags, PipelineBindPoint, PipelineStageFlags, StencilOp, VertexInputRate,
+        FALSE, WHOLE_SIZE,
+    };
+    use std::{mem::size_of, time::Instant};
+
+    #[test]
+    fn finds_push_constant_range_for_stage() {
+        let pipeline = VGraphicsPipeline {
+            push_constant_ranges: vec![PushConstantRange {
+                stage_flags: ShaderStageFlags::VERTEX | ShaderStageFlags::FRAGMENT,
+                offset: 0,
+                size: 64,
+            }],
+            ..Default::default()
+        };
+
+        let range = pipeline.push_constant_range(ShaderStageFlags::VERTEX);
+        assert_eq!(range.map(|range| range.size), Some(64));
+        assert!(pipeline
+            .push_constant_range(ShaderStageFlags::COMPUTE)
+            .is_none());
+    }
+
+    #[test]
+    fn summarizes_builder_state() {
+        let attachments = &[PipelineColorBlendAttachmentState {
+            blend_enable: TRUE,
+            ..Default::default()
+        }];
+        let summary = VGraphicsPipelineBuilder::start()
+            .rasterization(
+                CullModeFlags::NONE,
+                PolygonMode::LINE,
+                FrontFace::COUNTER_CLOCKWISE,
+            )
+            .color_blend_state(attachments)
+            .state_summary();
+
+        assert_eq!(
+            summary,
+            PipelineStateSummary {
+                cull_mode: CullModeFlags::NONE,
+                polygon_mode: PolygonMode::LINE,
+                front_face: FrontFace::COUNTER_CLOCKWISE,
+                depth_test: true,
+                depth_write: true,
+                depth_compare_op: CompareOp::LESS_OR_EQUAL,
+                depth_bias: false,
+                stencil_test: false,
+                blend_enable: true,
+                sample_count: SampleCountFlags::TYPE_1,
+            }
+        );
+    }
+
+    #[test]
+    fn blends_alpha_preset_by_source_alpha() {
+        let alpha = VGraphicsPipelineBuilder::blend_alpha();
+        assert_eq!(alpha.blend_enable, TRUE);
+        assert_eq!(alpha.src_color_blend_factor, BlendFactor::SRC_ALPHA);
+        assert_eq!(
+            alpha.dst_color_blend_factor,
+            BlendFactor::ONE_MINUS_SRC_ALPHA
+        );
+        assert_eq!(alpha.color_write_mask, ColorComponentFlags::RGBA);
+
+        let opaque = VGraphicsPipelineBuilder::blend_opaque();
+        assert_eq!(opaque.blend_enable, FALSE);
+        assert_eq!(opaque.color_write_mask, ColorComponentFlags::RGBA);
+        let additive = VGraphicsPipelineBuilder::blend_additive();
+        assert_eq!(additive.dst_color_blend_factor, BlendFactor::ONE);
+    }
+
+    #[test]
+    fn builds_shadow_pipeline_for_depth_only_render_pass() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let render_pass = VRenderPass::new_depth_only(&device, Format::D32_SFLOAT)?;
+        let vertex_module = shader_module(&device, "shadow.vert.spv")?;
+        let vertex_input = VVertexInputBuilder::start()
+            .binding(0, size_of::<Vec3>() as u32, VertexInputRate::VERTEX)
+            .attribute(0, 0, Format::R32G32B32_SFLOAT, 0)
+            .build();
+        let extent = Extent2D {
+            width: 1024,
+            height: 1024,
+        };
+        let builder =
+            VGraphicsPipelineBuilder::shadow_preset(vertex_module, size_of::<Mat4>() as u32)
+                .vertex_input(&vertex_input.bindings, &vertex_input.attributes)
+                .viewport(
+                    &[Viewport {
+                        width: extent.width as f32,
+                        height: extent.height as f32,
+                        max_depth: 1.0,
+                        ..Default::default()
+                    }],
+                    &[Rect2D {
+                        extent,
+                        ..Default::default()
+                    }],
+                );
+        let summary = builder.state_summary();
+        assert_eq!(summary.cull_mode, CullModeFlags::FRONT);
+        assert!(summary.depth_write && summary.depth_bias && !summary.blend_enable);
+
+        let pipeline = builder.build(&device, render_pass.get())?;
+        let range = pipeline.push_constant_range(ShaderStageFlags::VERTEX);
+        assert_eq!(range.map(|range| range.size), Some(64));
+
+        destroy_pipelines(&device, &[&pipeline], &[vertex_module]);
+        unsafe { device.get().destroy_render_pass(render_pass.get(), None) };
+        Ok(())
+    }
+
+    #[test]
+    fn compute_pipeline_requires_a_shader() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+        assert!(VComputePipelineBuilder::start().build(&device).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn builds_multisampled_pipeline_and_render_pass() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+        let samples = SampleCountFlags::TYPE_4;
+        assert!(device.get_max_usable_sample_count() >= samples);
+
+        let (color_format, depth_format) = (Format::B8G8R8A8_SRGB, Format::D32_SFLOAT);
+        let render_pass =
+            VRenderPass::new_multisampled(&device, color_format, depth_format, samples)?;
+        assert_eq!(
+            render_pass.signature().attachments(),
+            [
+                (color_format, samples),
+                (depth_format, samples),
+                (color_format, SampleCountFlags::TYPE_1),
+            ]
+        );
+        let (vertex_module, fragment_module) = triangle_modules(&device)?;
+
+        let viewports = &[Viewport {
+            width: 64.0,
+            height: 64.0,
+            max_depth: 1.0,
+            ..Default::default()
+        }];
+        let scissors = &[Rect2D {
+            extent: Extent2D {
+                width: 64,
+                height: 64,
+            },
+            ..Default::default()
+        }];
+        let attachments = &[PipelineColorBlendAttachmentState {
+            color_write_mask: ColorComponentFlags::RGBA,
+            ..Default::default()
+        }];
+        let pipeline = VGraphicsPipelineBuilder::start()
+            .shader_stages(&[
+                (ShaderStageFlags::VERTEX, vertex_module),
+                (ShaderStageFlags::FRAGMENT, fragment_module),
+            ])
+            .viewport(viewports, scissors)
+            .color_blend_state(attachments)
+            .multisample(samples)
+            .build(&device, render_pass.get())?;
+        assert_eq!(pipeline.state_summary().sample_count, samples);
+
+        destroy_pipelines(&device, &[&pipeline], &[vertex_module, fragment_module]);
+        unsafe { device.get().destroy_render_pass(render_pass.get(), None) };
+        Ok(())
+    }
+
+    #[test]
+    fn records_dynamic_viewport_and_scissor() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let render_pass =
+            VRenderPass::new_default(&device, Format::B8G8R8A8_SRGB, Format::D32_SFLOAT)?;
+        let (vertex_module, fragment_module) = triangle_modules(&device)?;
+
+        let builder = VGraphicsPipelineBuilder::start()
+            .shader_stages(&[
+                (ShaderStageFlags::VERTEX, vertex_module),
+                (ShaderStageFlags::FRAGMENT, fragment_module),
+            ])
+            .color_blend_state(&[VGraphicsPipelineBuilder::blend_opaque()])
+            .dynamic_state(&[DynamicState::VIEWPORT, DynamicState::SCISSOR]);
+        let viewport = builder.viewport_state();
+        assert_eq!((viewport.viewport_count, viewport.scissor_count), (1, 1));
+        assert!(viewport.p_viewports.is_null() && viewport.p_scissors.is_null());
+
+        let pipeline = builder.build(&device, render_pass.get())?;
+        let extent = Extent2D {
+            width: 32,
+            height: 32,
+        };
+        VBuffer::submit_one_time(&device, |command_buffer| {
+            cmd_bind_pipeline(
+                &device,
+                command_buffer,
+                PipelineBindPoint::GRAPHICS,
+                pipeline.pipeline(),
+            );
+            cmd_set_viewport(
+                &device,
+                command_buffer,
+                &[Viewport {
+                    width: extent.width as f32,
+                    height: extent.height as f32,
+                    max_depth: 1.0,
+                    ..Default::default()
+                }],
+            );
+            cmd_set_scissor(
+                &device,
+                command_buffer,
+                &[Rect2D {
+                    extent,
+                    ..Default::default()
+                }],
+            );
+        })?;
+
+        destroy_pipelines(&device, &[&pipeline], &[vertex_module, fragment_module]);
+        unsafe { device.get().destroy_render_pass(render_pass.get(), None) };
+        Ok(())
+    }
+
+    #[test]
+    fn builds_clockwise_reverse_z_pipeline() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let render_pass =
+            VRenderPass::new_default(&device, Format::B8G8R8A8_SRGB, Format::D32_SFLOAT)?;
+        let (vertex_module, fragment_module) = triangle_modules(&device)?;
+
+        let pipeline = VGraphicsPipelineBuilder::start()
+            .shader_stages(&[
+                (ShaderStageFlags::VERTEX, vertex_module),
+                (ShaderStageFlags::FRAGMENT, fragment_module),
+            ])
+            .rasterization(CullModeFlags::BACK, PolygonMode::FILL, FrontFace::CLOCKWISE)
+            .depth_stencil(true, true, CompareOp::GREATER)
+            .color_blend_state(&[VGraphicsPipelineBuilder::blend_opaque()])
+            .dynamic_state(&[DynamicState::VIEWPORT, DynamicState::SCISSOR])
+            .build(&device, render_pass.get())?;
+        let summary = pipeline.state_summary();
+        assert_eq!(summary.front_face, FrontFace::CLOCKWISE);
+        assert_eq!(summary.depth_compare_op, CompareOp::GREATER);
+        assert!(summary.depth_test && summary.depth_write);
+
+        destroy_pipelines(&device, &[&pipeline], &[vertex_module, fragment_module]);
+        unsafe { device.get().destroy_render_pass(render_pass.get(), None) };
+        Ok(())
+    }
+
+    #[test]
+    fn builds_stencil_pipeline_for_stencil_render_pass() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+        let depth_format = match device.find_supported_depth_format(
+            &STENCIL_FORMAT_CANDIDATES,
+            ImageTiling::OPTIMAL,
+            FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT,
+        ) {
+            Some(depth_format) => depth_format,
+            None => return Ok(()),
+        };
+
+        let render_pass = VRenderPass::new_default(&device, Format::B8G8R8A8_SRGB, depth_format)?;
+        let depth_attachment =
+            VRenderPass::attachment_descriptions(Format::B8G8R8A8_SRGB, depth_format)
+                [VRenderPass::DEPTH_ATTACHMENT as usize];
+        assert_eq!(depth_attachment.stencil_load_op, AttachmentLoadOp::CLEAR);
+        assert_eq!(depth_attachment.stencil_store_op, AttachmentStoreOp::STORE);
+
+        let (vertex_module, fragment_module) = triangle_modules(&device)?;
+
+        // Draws only where an earlier pass wrote 1, e.g. inside a portal
+        let inside = StencilOpState {
+            fail_op: StencilOp::KEEP,
+            pass_op: StencilOp::KEEP,
+            depth_fail_op: StencilOp::KEEP,
+            compare_op: CompareOp::EQUAL,
+            compare_mask: 0xff,
+            write_mask: 0,
+            reference: 1,
+        };
+        let pipeline = VGraphicsPipelineBuilder::start()
+            .shader_stages(&[
+                (ShaderStageFlags::VERTEX, vertex_module),
+                (ShaderStageFlags::FRAGMENT, fragment_module),
+            ])
+            .stencil(inside, inside)
+            .depth_stencil(true, false, CompareOp::LESS)
+            .color_blend_state(&[VGraphicsPipelineBuilder::blend_opaque()])
+            .dynamic_state(&[DynamicState::VIEWPORT, DynamicState::SCISSOR])
+            .build(&device, render_pass.get())?;
+        let summary = pipeline.state_summary();
+        assert!(summary.stencil_test && summary.depth_test && !summary.depth_write);
+
+        destroy_pipelines(&device, &[&pipeline], &[vertex_module, fragment_module]);
+        unsafe { device.get().destroy_render_pass(render_pass.get(), None) };
+        Ok(())
+    }
+
+    #[test]
+    fn builds_pipeline_without_depth_for_depth_render_pass() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let render_pass =
+            VRenderPass::new_default(&device, Format::B8G8R8A8_SRGB, Format::D32_SFLOAT)?;
+        let (vertex_module, fragment_module) = triangle_modules(&device)?;
+
+        let pipeline = VGraphicsPipelineBuilder::start()
+            .shader_stages(&[
+                (ShaderStageFlags::VERTEX, vertex_module),
+                (ShaderStageFlags::FRAGMENT, fragment_module),
+            ])
+            .no_depth()
+            .color_blend_state(&[VGraphicsPipelineBuilder::blend_opaque()])
+            .dynamic_state(&[DynamicState::VIEWPORT, DynamicState::SCISSOR])
+            .build(&device, render_pass.get())?;
+        let summary = pipeline.state_summary();
+        assert!(!summary.depth_test && !summary.depth_write);
+
+        destroy_pipelines(&device, &[&pipeline], &[vertex_module, fragment_module]);
+        unsafe { device.get().destroy_render_pass(render_pass.get(), None) };
+        Ok(())
+    }
+
+    #[test]
+    fn specializes_fragment_shader_constant() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let extent = Extent2D {
+            width: 4,
+            height: 4,
+        };
+        let target = VOffscreenTarget::new(&device, extent)?;
+        let vertex_module = shader_module(&device, "triangle.vert.spv")?;
+        let fragment_module = shader_module(&device, "specialized.frag.spv")?;
+
+        let fragment_stage =
+            VShaderStage::new(ShaderStageFlags::FRAGMENT, fragment_module).specialize(0, &51i32);
+        assert_eq!(
+            fragment_stage.specialization_info().data_size,
+            size_of::<i32>()
+        );
+        let pipeline = VGraphicsPipelineBuilder::start()
+            .stages(&[
+                VShaderStage::new(ShaderStageFlags::VERTEX, vertex_module).entry_name("main"),
+                fragment_stage,
+            ])
+            .rasterization(
+                CullModeFlags::NONE,
+                PolygonMode::FILL,
+                FrontFace::COUNTER_CLOCKWISE,
+            )
+            .color_blend_state(&[VGraphicsPipelineBuilder::blend_opaque()])
+            .dynamic_state(&[DynamicState::VIEWPORT, DynamicState::SCISSOR])
+            .build(&device, target.render_pass().get())?;
+
+        let clear_values = ClearValues::start().build();
+        VBuffer::submit_one_time(&device, |command_buffer| {
+            cmd_begin_render_pass(
+                &device,
+                command_buffer,
+                target.render_pass().get(),
+                target.framebuffer(),
+                &clear_values,
+                extent,
+            );
+            cmd_bind_graphics_pipeline(&device, command_buffer, &pipeline);
+            cmd_set_viewport(
+                &device,
+                command_buffer,
+                &[Viewport {
+                    width: extent.width as f32,
+                    height: extent.height as f32,
+                    max_depth: 1.0,
+                    ..Default::default()
+                }],
+            );
+            cmd_set_scissor(
+                &device,
+                command_buffer,
+                &[Rect2D {
+                    extent,
+                    ..Default::default()
+                }],
+            );
+            cmd_draw(&device, command_buffer, 3, 1);
+            cmd_end_render_pass(&device, command_buffer);
+        })?;
+
+        // The center pixel is covered by the triangle
+        let pixels = target.read_pixels(&device)?;
+        let center = ((2 * extent.width + 2) * 4) as usize;
+        assert_eq!(pixels[center..center + 4], [51, 51, 51, 51]);
+
+        destroy_pipelines(&device, &[&pipeline], &[vertex_module, fragment_module]);
+        target.destroy(&device);
+        Ok(())
+    }
+
+    #[test]
+    fn rebuilds_pipeline_from_saved_cache() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let render_pass =
+            VRenderPass::new_default(&device, Format::B8G8R8A8_SRGB, Format::D32_SFLOAT)?;
+        let (vertex_module, fragment_module) = triangle_modules(&device)?;
+        let builder = VGraphicsPipelineBuilder::start()
+            .shader_stages(&[
+                (ShaderStageFlags::VERTEX, vertex_module),
+                (ShaderStageFlags::FRAGMENT, fragment_module),
+            ])
+            .color_blend_state(&[VGraphicsPipelineBuilder::blend_opaque()])
+            .dynamic_state(&[DynamicState::VIEWPORT, DynamicState::SCISSOR]);
+
+        let path = std::env::temp_dir().join("rebuilds_pipeline_from_saved_cache.bin");
+        let _ = std::fs::remove_file(&path);
+        let cache = VPipelineCache::load_from_file(&device, &path)?;
+        let start = Instant::now();
+        let first = builder.build_with_cache(&device, render_pass.get(), &cache)?;
+        let cold = start.elapsed();
+        cache.save_to_file(&device, &path)?;
+        let saved = std::fs::read(&path)?;
+        assert!(VPipelineCache::is_compatible(
+            &saved,
+            &device.get_device_properties()
+        ));
+
+        let reloaded = VPipelineCache::load_from_file(&device, &path)?;
+        let start = Instant::now();
+        let second = builder.build_with_cache(&device, render_pass.get(), &reloaded)?;
+        let warm = start.elapsed();
+        println!("Cold build took {:?}, cached build {:?}.", cold, warm);
+
+        destroy_pipelines(
+            &device,
+            &[&first, &second],
+            &[vertex_module, fragment_module],
+        );
+        unsafe { device.get().destroy_render_pass(render_pass.get(), None) };
+        cache.destroy(&device);
+        reloaded.destroy(&device);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn doubles_storage_buffer_round_trip() -> RendererResult<()> {
+        const COUNT: usize = 64;
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let input = (0..COUNT as u32).collect::<Vec<_>>();
+        let buffer = VBuffer::new_storage_buffer(
+            &device,
+            (COUNT * size_of::<u32>()) as u64,
+            MemoryPropertyFlags::HOST_COHERENT | MemoryPropertyFlags::HOST_VISIBLE,
+        )?;
+        buffer.map_memory(&device, &input)?;
+
+        let bindings = &[VDescriptorSetLayout::layout_binding(
+            0,
+            1,
+            DescriptorType::STORAGE_BUFFER,
+            ShaderStageFlags::COMPUTE,
+        )];
+        let set_layout = VDescriptorSetLayout::new(&device, bindings)?;
+        let pool = VDescriptorPool::new(&device)?;
+        let descriptor_set = VDescriptorSet::new(&device, pool.get(), &[set_layout.get()])?.get();
+        let buffer_info = DescriptorBufferInfo {
+            buffer: buffer.buffer(),
+            offset: 0,
+            range: WHOLE_SIZE,
+        };
+        let write_set = VDescriptorSet::write_descriptor_set(
+            descriptor_set,
+            0,
+            DescriptorType::STORAGE_BUFFER,
+            &buffer_info,
+        );
+        unsafe { device.get().update_descriptor_sets(&[write_set], &[]) };
+
+        let shader_code = VShaderUtils::load_shader(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/shaders/double.comp.spv"
+        ))?;
+        let shader_module = VShaderUtils::create_shader_module(&device, &shader_code)?;
+        let pipeline = VComputePipelineBuilder::start()
+            .shader(shader_module)
+            .pipeline_layout(&[set_layout.get()], &[])
+            .build(&device)?;
+
+        let queue_family_index = device.get_queue_family_index(EOperationType::Compute);
+        let command_pool = VCommandPool::new(
+            &device,
+            queue_family_index,
+            CommandPoolCreateFlags::TRANSIENT,
+        )?;
+        let command_buffer = command_pool.allocate(&device, CommandBufferLevel::PRIMARY, 1)?[0];
+        begin_command_buffer(&device, command_buffer)?;
+        cmd_bind_pipeline(
+            &device,
+            command_buffer,
+            PipelineBindPoint::COMPUTE,
+            pipeline.pipeline(),
+        );
+        cmd_bind_descriptor_sets(
+            &device,
+            command_buffer,
+            PipelineBindPoint::COMPUTE,
+            pipeline.pipeline_layout(),
+            0,
+            &[descriptor_set],
+            &[],
+        );
+        cmd_dispatch(&device, command_buffer, 1, 1, 1);
+        let host_barrier = MemoryBarrier {
+            src_access_mask: AccessFlags::SHADER_WRITE,
+            dst_access_mask: AccessFlags::HOST_READ,
+            ..Default::default()
+        };
+        unsafe {
+            device.get().cmd_pipeline_barrier(
+                command_buffer,
+                PipelineStageFlags::COMPUTE_SHADER,
+                PipelineStageFlags::HOST,
+                DependencyFlags::empty(),
+                &[host_barrier],
+                &[],
+                &[],
+            );
+        }
+        end_command_buffer(&device, command_buffer)?;
+
+        let command_buffers = &[command_buffer];
+        let submit_info = VDevice::create_queue_submit_info(command_buffers, &[], &[], &[]);
+        let queue = device.get_queue(EOperationType::Compute);
+        device.queue_submit(queue, &[submit_info], Fence::null())?;
+        unsafe { device.get().queue_wait_idle(queue)? };
+
+        let output = buffer.read_back::<u32>(&device)?;
+        assert_eq!(
+            output,
+            input.iter().map(|value| value * 2).collect::<Vec<_>>()
+        );
+
+        unsafe {
+            device.get().destroy_pipeline(pipeline.pipeline(), None);
+            device
+                .get()
+                .destroy_pipeline_layout(pipeline.pipeline_layout(), None);
+            device.get().destroy_shader_module(shader_module, None);
+            device.get().destroy_command_pool(command_pool.get(), None);
+            device.get().destroy_descriptor_pool(pool.get(), None);
+            device
+                .get()
+                .destroy_descriptor_set_layout(set_layout.get(), None);
+            device.get().destroy_buffer(buffer.buffer(), None);
+            device.get().free_memory(buffer.memory(), None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn draws_thousand_instances_in_one_call() -> RendererResult<()> {
+        const INSTANCE_COUNT: usize = 1000;
+        const SIZE: u32 = 64;
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+        let host_visible = MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT;
+
+        let color_format = Format::B8G8R8A8_SRGB;
+        let depth_format = Format::D32_SFLOAT;
+        let render_pass = VRenderPass::new_default(&device, color_format, depth_format)?;
+        let extent = Extent3D {
+            width: SIZE,
+            height: SIZE,
+            depth: 1,
+        };
+        let color_image = VImage::new(
+            &device,
+            ImageUsageFlags::COLOR_ATTACHMENT,
+            color_format,
+            extent,
+            ImageAspectFlags::COLOR,
+        )?;
+        let depth_image = VImage::new(
+            &device,
+            ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+            depth_format,
+            extent,
+            ImageAspectFlags::DEPTH,
+        )?;
+        let attachments = [color_image.image_view(), depth_image.image_view()];
+        let framebuffer_info = FramebufferCreateInfo {
+            render_pass: render_pass.get(),
+            attachment_count: attachments.len() as u32,
+            p_attachments: attachments.as_ptr(),
+            width: SIZE,
+            height: SIZE,
+            layers: 1,
+            ..Default::default()
+        };
+        let framebuffer = unsafe { device.get().create_framebuffer(&framebuffer_info, None)? };
+
+        let vertices = [
+            Vec3::new(0.0, -0.01, 0.0),
+            Vec3::new(0.01, 0.01, 0.0),
+            Vec3::new(-0.01, 0.01, 0.0),
+        ];
+        let indices = [0u32, 1, 2];
+        let models = (0..INSTANCE_COUNT)
+            .map(|ind| {
+                let x = (ind % 40) as f32 / 20.0 - 1.0;
+                let y = (ind / 40) as f32 / 12.5 - 1.0;
+                Mat4::from_translation(Vec3::new(x, y, 0.5))
+            })
+            .collect::<Vec<_>>();
+        let vertex_buffer = VBuffer::new_mapped(
+            &device,
+            &vertices,
+            BufferUsageFlags::VERTEX_BUFFER,
+            host_visible,
+        )?;
+        let index_buffer = VBuffer::new_mapped(
+            &device,
+            &indices,
+            BufferUsageFlags::INDEX_BUFFER,
+            host_visible,
+        )?;
+        let instance_buffer = VBuffer::new_mapped(
+            &device,
+            &models,
+            BufferUsageFlags::VERTEX_BUFFER,
+            host_visible,
+        )?;
+
+        let vertex_input = VVertexInputBuilder::start()
+            .binding(0, size_of::<Vec3>() as u32, VertexInputRate::VERTEX)
+            .attribute(0, 0, Format::R32G32B32_SFLOAT, 0)
+            .binding(1, size_of::<Mat4>() as u32, VertexInputRate::INSTANCE)
+            .mat4_attribute(1, 1, 0)
+            .build();
+        let vertex_module = shader_module(&device, "instanced.vert.spv")?;
+        let fragment_module = shader_module(&device, "solid.frag.spv")?;
+        let render_extent = Extent2D {
+            width: SIZE,
+            height: SIZE,
+        };
+        let pipeline = VGraphicsPipelineBuilder::start()
+            .shader_stages(&[
+                (ShaderStageFlags::VERTEX, vertex_module),
+                (ShaderStageFlags::FRAGMENT, fragment_module),
+            ])
+            .vertex_input(&vertex_input.bindings, &vertex_input.attributes)
+            .viewport(
+                &[Viewport {
+                    width: SIZE as f32,
+                    height: SIZE as f32,
+                    max_depth: 1.0,
+                    ..Default::default()
+                }],
+                &[Rect2D {
+                    extent: render_extent,
+                    ..Default::default()
+                }],
+            )
+            .color_blend_state(&[PipelineColorBlendAttachmentState {
+                color_write_mask: ColorComponentFlags::RGBA,
+                ..Default::default()
+            }])
+            .build(&device, render_pass.get())?;
+
+        let command_pool = VCommandPool::new(
+            &device,
+            device.get_queue_family_index(EOperationType::Graphics),
+            CommandPoolCreateFlags::TRANSIENT,
+        )?;
+        let command_buffer = command_pool.allocate(&device, CommandBufferLevel::PRIMARY, 1)?[0];
+        begin_command_buffer(&device, command_buffer)?;
+        cmd_begin_render_pass(
+            &device,
+            command_buffer,
+            render_pass.get(),
+            framebuffer,
+            &ClearValues::start().build(),
+            render_extent,
+        );
+        cmd_bind_graphics_pipeline(&device, command_buffer, &pipeline);
+        cmd_bind_vertex_buffer(
+            &device,
+            command_buffer,
+            &[vertex_buffer.buffer(), instance_buffer.buffer()],
+            &[0, 0],
+        );
+        cmd_bind_index_buffer(&device, command_buffer, index_buffer.buffer(), 0);
+        cmd_draw_indexed(
+            &device,
+            command_buffer,
+            indices.len() as u32,
+            INSTANCE_COUNT as u32,
+            0,
+        );
+        cmd_end_render_pass(&device, command_buffer);
+        end_command_buffer(&device, command_buffer)?;
+
+        let command_buffers = &[command_buffer];
+        let submit_info = VDevice::create_queue_submit_info(command_buffers, &[], &[], &[]);
+        let queue = device.get_queue(EOperationType::Graphics);
+        device.queue_submit(queue, &[submit_info], Fence::null())?;
+        unsafe { device.get().queue_wait_idle(queue)? };
+
+        destroy_pipelines(&device, &[&pipeline], &[vertex_module, fragment_module]);
+        unsafe {
+            device.get().destroy_command_pool(command_pool.get(), None);
+            for buffer in [vertex_buffer, index_buffer, instance_buffer] {
+                device.get().destroy_buffer(buffer.buffer(), None);
+                device.get().free_memory(buffer.memory(), None);
+            }
+            device.get().destroy_framebuffer(framebuffer, None);
+            for image in [color_image, depth_image] {
+                device.get().destroy_image_view(image.image_view(), None);
+                device.get().destroy_image(image.image(), None);
+                device.get().free_memory(image.memory(), None);
+            }
+            device.get().destroy_render_pass(render_pass.get(), None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn primitive_restart_requires_strip_or_fan() {
+        assert!(VGraphicsPipelineBuilder::supports_primitive_restart(
+            PrimitiveTopology::TRIANGLE_STRIP
+        ));
+        assert!(VGraphicsPipelineBuilder::supports_primitive_restart(
+            PrimitiveTopology::LINE_STRIP
+        ));
+        assert!(!VGraphicsPipelineBuilder::supports_primitive_restart(
+            PrimitiveTopology::TRIANGLE_LIST
+        ));
+        assert!(!VGraphicsPipelineBuilder::supports_primitive_restart(
+            PrimitiveTopology::LINE_LIST_WITH_ADJACENCY
+        ));
+    }
+}
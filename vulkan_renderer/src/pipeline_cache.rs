@@ -0,0 +1,131 @@
+use crate::{device::VDevice, RendererResult};
+use ash::vk::{
+    PhysicalDeviceProperties, PipelineCache, PipelineCacheCreateInfo, PipelineCacheHeaderVersion,
+};
+use std::{fs, io::ErrorKind, mem::size_of, path::Path};
+
+/// Size of the `VkPipelineCacheHeaderVersionOne` header every cache blob starts with
+const HEADER_SIZE: usize = 4 * size_of::<u32>() + 16;
+
+/// Pipeline cache that can be saved to disk so pipelines compile faster on the next run
+///
+/// Pass it to [`VGraphicsPipelineBuilder::build_with_cache`](crate::pipeline::VGraphicsPipelineBuilder::build_with_cache)
+#[derive(Default, Debug, Clone)]
+pub struct VPipelineCache {
+    cache: PipelineCache,
+}
+
+impl VPipelineCache {
+    pub fn new(device: &VDevice) -> RendererResult<Self> {
+        Self::with_data(device, &[])
+    }
+
+    /// Starts empty if the file doesn't exist or was written by a different GPU or driver
+    pub fn load_from_file(device: &VDevice, path: impl AsRef<Path>) -> RendererResult<Self> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+        if Self::is_compatible(&data, &device.get_device_properties()) {
+            Self::with_data(device, &data)
+        } else {
+            Self::new(device)
+        }
+    }
+
+    pub fn save_to_file(&self, device: &VDevice, path: impl AsRef<Path>) -> RendererResult<()> {
+        let data = unsafe { device.get().get_pipeline_cache_data(self.cache)? };
+        fs::write(path, data)?;
+        Ok(())
+    }
+
+    pub fn get(&self) -> PipelineCache {
+        self.cache
+    }
+
+    pub fn destroy(&self, device: &VDevice) {
+        unsafe { device.get().destroy_pipeline_cache(self.cache, None) };
+    }
+
+    fn with_data(device: &VDevice, data: &[u8]) -> RendererResult<Self> {
+        let create_info = PipelineCacheCreateInfo {
+            initial_data_size: data.len(),
+            p_initial_data: data.as_ptr().cast(),
+            ..Default::default()
+        };
+        let cache = unsafe { device.get().create_pipeline_cache(&create_info, None)? };
+        Ok(Self { cache })
+    }
+
+    /// Whether `data` has a version one header matching the device's vendor, device and cache UUID
+    pub(crate) fn is_compatible(data: &[u8], properties: &PhysicalDeviceProperties) -> bool {
+        if data.len() < HEADER_SIZE {
+            return false;
+        }
+        let read_u32 = |ind: usize| {
+            let offset = ind * size_of::<u32>();
+            u32::from_ne_bytes([
+                data[offset],
+                data[offset + 1],
+                data[offset + 2],
+                data[offset + 3],
+            ])
+        };
+        read_u32(0) as usize >= HEADER_SIZE
+            && read_u32(1) == PipelineCacheHeaderVersion::ONE.as_raw() as u32
+            && read_u32(2) == properties.vendor_id
+            && read_u32(3) == properties.device_id
+            && data[16..HEADER_SIZE] == properties.pipeline_cache_uuid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(properties: &PhysicalDeviceProperties) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(HEADER_SIZE as u32).to_ne_bytes());
+        data.extend_from_slice(&(PipelineCacheHeaderVersion::ONE.as_raw() as u32).to_ne_bytes());
+        data.extend_from_slice(&properties.vendor_id.to_ne_bytes());
+        data.extend_from_slice(&properties.device_id.to_ne_bytes());
+        data.extend_from_slice(&properties.pipeline_cache_uuid);
+        data
+    }
+
+    #[test]
+    fn discards_cache_of_other_devices() {
+        let properties = PhysicalDeviceProperties {
+            vendor_id: 0x10de,
+            device_id: 0x2204,
+            pipeline_cache_uuid: [7; 16],
+            ..Default::default()
+        };
+        assert!(VPipelineCache::is_compatible(
+            &header(&properties),
+            &properties
+        ));
+
+        let other_driver = PhysicalDeviceProperties {
+            pipeline_cache_uuid: [8; 16],
+            ..properties
+        };
+        let other_gpu = PhysicalDeviceProperties {
+            device_id: 0x2206,
+            ..properties
+        };
+        assert!(!VPipelineCache::is_compatible(
+            &header(&other_driver),
+            &properties
+        ));
+        assert!(!VPipelineCache::is_compatible(
+            &header(&other_gpu),
+            &properties
+        ));
+        assert!(!VPipelineCache::is_compatible(
+            &header(&properties)[..8],
+            &properties
+        ));
+    }
+}
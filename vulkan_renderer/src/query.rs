@@ -0,0 +1,180 @@
+use crate::{device::VDevice, enums::EOperationType, RendererError, RendererResult};
+use ash::vk::{CommandBuffer, QueryPool, QueryPoolCreateInfo, QueryResultFlags, QueryType};
+
+/// Timestamp queries for measuring GPU time between points of the graphics queue's command buffers
+///
+/// Write them with [`cmd_write_timestamp`](crate::cmd::cmd_write_timestamp)
+#[derive(Default, Debug, Clone, Copy)]
+pub struct VQueryPool {
+    query_pool: QueryPool,
+    query_count: u32,
+    valid_bits: u32,
+    timestamp_period: f32,
+}
+
+impl VQueryPool {
+    /// Errors if the graphics queue family doesn't support timestamps
+    pub fn new_timestamp(device: &VDevice, query_count: u32) -> RendererResult<Self> {
+        let valid_bits = device
+            .get_queue_family_properties(EOperationType::Graphics)
+            .timestamp_valid_bits;
+        if valid_bits == 0 {
+            return Err(RendererError::InvalidArgument(
+                "The graphics queue family does not support timestamps.".to_owned(),
+            ));
+        }
+
+        let create_info = QueryPoolCreateInfo {
+            query_type: QueryType::TIMESTAMP,
+            query_count,
+            ..Default::default()
+        };
+        let query_pool = unsafe { device.get().create_query_pool(&create_info, None)? };
+        Ok(Self {
+            query_pool,
+            query_count,
+            valid_bits,
+            timestamp_period: device.get_device_properties().limits.timestamp_period,
+        })
+    }
+
+    pub fn get(&self) -> QueryPool {
+        self.query_pool
+    }
+
+    /// Queries must be reset before they are written, this has to be recorded outside a render pass
+    pub fn cmd_reset(&self, device: &VDevice, command_buffer: CommandBuffer) {
+        unsafe {
+            device
+                .get()
+                .cmd_reset_query_pool(command_buffer, self.query_pool, 0, self.query_count);
+        }
+    }
+
+    /// Waits until every query is written and returns the timestamps in nanoseconds
+    ///
+    /// Only differences between them are meaningful, use [`elapsed`](Self::elapsed) for those so
+    /// a counter wrapping in between is handled
+    pub fn get_results(&self, device: &VDevice) -> RendererResult<Vec<u64>> {
+        Ok(self
+            .get_ticks(device)?
+            .into_iter()
+            .map(|ticks| Self::ticks_to_nanoseconds(ticks, self.valid_bits, self.timestamp_period))
+            .collect())
+    }
+
+    /// Waits until every query is written and returns the nanoseconds from query `start` to `end`
+    pub fn elapsed(&self, device: &VDevice, start: u32, end: u32) -> RendererResult<u64> {
+        if start.max(end) >= self.query_count {
+            return Err(RendererError::InvalidArgument(format!(
+                "Queries {} and {} are out of range for a pool of {}.",
+                start, end, self.query_count
+            )));
+        }
+        let ticks = self.get_ticks(device)?;
+        Ok(Self::elapsed_nanoseconds(
+            ticks[start as usize],
+            ticks[end as usize],
+            self.valid_bits,
+            self.timestamp_period,
+        ))
+    }
+
+    pub fn destroy(&self, device: &VDevice) {
+        unsafe { device.get().destroy_query_pool(self.query_pool, None) };
+    }
+
+    fn get_ticks(&self, device: &VDevice) -> RendererResult<Vec<u64>> {
+        let mut ticks = vec![0u64; self.query_count as usize];
+        unsafe {
+            device.get().get_query_pool_results(
+                self.query_pool,
+                0,
+                self.query_count,
+                &mut ticks,
+                QueryResultFlags::TYPE_64 | QueryResultFlags::WAIT,
+            )?;
+        }
+        Ok(ticks)
+    }
+
+    /// Bits above `valid_bits` are undefined, `timestamp_period` is in nanoseconds per tick
+    fn ticks_to_nanoseconds(ticks: u64, valid_bits: u32, timestamp_period: f32) -> u64 {
+        let valid_ticks = match valid_bits {
+            64.. => ticks,
+            _ => ticks & ((1 << valid_bits) - 1),
+        };
+        (valid_ticks as f64 * timestamp_period as f64) as u64
+    }
+
+    /// The counter wraps at `valid_bits`, so the difference is taken modulo that
+    fn elapsed_nanoseconds(
+        start_ticks: u64,
+        end_ticks: u64,
+        valid_bits: u32,
+        timestamp_period: f32,
+    ) -> u64 {
+        Self::ticks_to_nanoseconds(
+            end_ticks.wrapping_sub(start_ticks),
+            valid_bits,
+            timestamp_period,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        buffer::VBuffer, cmd::cmd_write_timestamp, instance::VInstance, test_utils::create_window,
+    };
+    use ash::vk::PipelineStageFlags;
+
+    #[test]
+    fn masks_invalid_bits_and_scales_by_period() {
+        assert_eq!(VQueryPool::ticks_to_nanoseconds(1000, 64, 1.0), 1000);
+        assert_eq!(VQueryPool::ticks_to_nanoseconds(0xff00_0010, 8, 1.0), 0x10);
+        assert_eq!(VQueryPool::ticks_to_nanoseconds(100, 36, 52.08), 5208);
+    }
+
+    #[test]
+    fn measures_elapsed_time_across_counter_wrap() {
+        assert_eq!(VQueryPool::elapsed_nanoseconds(0xf0, 0x10, 8, 1.0), 0x20);
+        assert_eq!(VQueryPool::elapsed_nanoseconds(10, 30, 64, 2.0), 40);
+        assert_eq!(VQueryPool::elapsed_nanoseconds(u64::MAX, 4, 64, 1.0), 5);
+    }
+
+    #[test]
+    fn measures_time_between_two_timestamps() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let pool = VQueryPool::new_timestamp(&device, 2)?;
+        VBuffer::submit_one_time(&device, |command_buffer| {
+            pool.cmd_reset(&device, command_buffer);
+            cmd_write_timestamp(
+                &device,
+                command_buffer,
+                &pool,
+                PipelineStageFlags::TOP_OF_PIPE,
+                0,
+            );
+            cmd_write_timestamp(
+                &device,
+                command_buffer,
+                &pool,
+                PipelineStageFlags::BOTTOM_OF_PIPE,
+                1,
+            );
+        })?;
+
+        assert_eq!(pool.get_results(&device)?.len(), 2);
+        let delta = pool.elapsed(&device, 0, 1)?;
+        assert!(delta < 1_000_000_000, "{} ns between timestamps", delta);
+        assert!(pool.elapsed(&device, 0, 2).is_err());
+
+        pool.destroy(&device);
+        Ok(())
+    }
+}
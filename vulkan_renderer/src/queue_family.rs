@@ -6,6 +6,7 @@ pub struct VQueueFamilyIndices {
     pub compute: u32,
     pub graphics: u32,
     pub present: u32,
+    pub transfer: u32,
 }
 
 impl Default for VQueueFamilyIndices {
@@ -14,6 +15,7 @@ impl Default for VQueueFamilyIndices {
             compute: u32::MAX,
             graphics: u32::MAX,
             present: u32::MAX,
+            transfer: u32::MAX,
         }
     }
 }
@@ -24,6 +26,7 @@ impl VQueueFamilyIndices {
             EOperationType::Compute => self.compute,
             EOperationType::Graphics => self.graphics,
             EOperationType::Present => self.present,
+            EOperationType::Transfer => self.transfer,
         }
     }
 }
@@ -33,21 +36,18 @@ pub struct VQueues {
     pub compute: Queue,
     pub graphics: Queue,
     pub present: Queue,
+    pub transfer: Queue,
 }
 
 impl VQueues {
     pub fn new(device: &Device, queue_family_indices: VQueueFamilyIndices) -> Self {
-        let mut queues = Self::default();
-        if queue_family_indices.compute != u32::MAX {
-            queues.compute = unsafe { device.get_device_queue(queue_family_indices.compute, 0) };
-        }
-        queues.graphics = unsafe { device.get_device_queue(queue_family_indices.graphics, 0) };
-        if queue_family_indices.graphics == queue_family_indices.present {
-            queues.present = queues.graphics;
-        } else {
-            queues.present = unsafe { device.get_device_queue(queue_family_indices.present, 0) };
+        let get_queue = |family| unsafe { device.get_device_queue(family, 0) };
+        Self {
+            compute: get_queue(queue_family_indices.compute),
+            graphics: get_queue(queue_family_indices.graphics),
+            present: get_queue(queue_family_indices.present),
+            transfer: get_queue(queue_family_indices.transfer),
         }
-        queues
     }
 
     pub fn get(&self, operation_type: EOperationType) -> Queue {
@@ -55,6 +55,7 @@ impl VQueues {
             EOperationType::Compute => self.compute,
             EOperationType::Graphics => self.graphics,
             EOperationType::Present => self.present,
+            EOperationType::Transfer => self.transfer,
         }
     }
 }
@@ -1,71 +1,237 @@
-use crate::RendererResult;
-use ash::{
-    vk::{
-        AccessFlags, AttachmentDescription, AttachmentLoadOp, AttachmentReference,
-        AttachmentStoreOp, Format, ImageLayout, PipelineBindPoint, PipelineStageFlags, RenderPass,
-        RenderPassCreateInfo, SampleCountFlags, SubpassDependency, SubpassDescription,
-        SUBPASS_EXTERNAL,
-    },
-    Device,
+use crate::{device::VDevice, image::VImage, RendererError, RendererResult};
+use ash::vk::{
+    AccessFlags, AttachmentDescription, AttachmentLoadOp, AttachmentReference, AttachmentStoreOp,
+    CommandBuffer, Format, ImageLayout, PipelineBindPoint, PipelineStageFlags, RenderPass,
+    RenderPassCreateInfo, SampleCountFlags, SubpassDependency, SubpassDescription,
+    SUBPASS_EXTERNAL,
 };
+use std::collections::HashMap;
 
-pub struct VRenderPass {
-    render_pass: RenderPass,
+/// The format and sample count of every attachment, render passes with equal signatures are
+/// compatible so pipelines built for one can be used in the other
+///
+/// Layouts and load/store ops don't matter for compatibility
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct RenderPassSignature {
+    attachments: Vec<(Format, SampleCountFlags)>,
 }
 
-impl VRenderPass {
-    pub fn new(device: &Device, format: Format) -> RendererResult<Self> {
-        let attachments = Self::attachment_descriptions(format);
-        let attachment_refs = Self::attachment_refs();
-        let depth_attachment_ref = Self::depth_attachment_ref();
-        let subpass_descriptions =
-            Self::subpass_descriptions(&attachment_refs, &depth_attachment_ref);
-        let subpass_dependencies = Self::subpass_dependencies();
-        let create_info = Self::render_pass_create_info(
-            &attachments,
-            &subpass_descriptions,
-            &subpass_dependencies,
-        );
+impl RenderPassSignature {
+    pub fn from_attachments(attachments: &[AttachmentDescription]) -> Self {
+        Self {
+            attachments: attachments
+                .iter()
+                .map(|attachment| (attachment.format, attachment.samples))
+                .collect(),
+        }
+    }
 
-        let render_pass = unsafe { device.create_render_pass(&create_info, None)? };
-        Ok(Self { render_pass })
+    /// The format and sample count of each attachment, in attachment order
+    pub fn attachments(&self) -> &[(Format, SampleCountFlags)] {
+        &self.attachments
     }
+}
 
-    pub fn get(&self) -> RenderPass {
-        self.render_pass
+/// Debug bookkeeping of the render passes created through [`VRenderPass`] and of the one each
+/// command buffer is recording
+#[derive(Default, Debug)]
+pub(crate) struct VRenderPassTracker {
+    signatures: HashMap<RenderPass, RenderPassSignature>,
+    active: HashMap<CommandBuffer, RenderPass>,
+}
+
+impl VRenderPassTracker {
+    pub fn register(&mut self, render_pass: RenderPass, signature: RenderPassSignature) {
+        self.signatures.insert(render_pass, signature);
     }
 
-    fn render_pass_create_info(
-        attachments: &[AttachmentDescription],
-        subpass_descriptions: &[SubpassDescription],
-        subpass_dependencies: &[SubpassDependency],
-    ) -> RenderPassCreateInfo {
-        RenderPassCreateInfo {
-            attachment_count: attachments.len() as u32,
-            p_attachments: attachments.as_ptr(),
-            subpass_count: subpass_descriptions.len() as u32,
-            p_subpasses: subpass_descriptions.as_ptr(),
-            dependency_count: subpass_dependencies.len() as u32,
-            p_dependencies: subpass_dependencies.as_ptr(),
-            ..Default::default()
+    pub fn begin(&mut self, command_buffer: CommandBuffer, render_pass: RenderPass) {
+        self.active.insert(command_buffer, render_pass);
+    }
+
+    pub fn end(&mut self, command_buffer: CommandBuffer) {
+        self.active.remove(&command_buffer);
+    }
+
+    /// Panics if `command_buffer` is recording a render pass incompatible with `render_pass`
+    ///
+    /// Render passes that weren't created or begun through this crate aren't checked
+    pub fn assert_compatible(&self, command_buffer: CommandBuffer, render_pass: RenderPass) {
+        let active = match self.active.get(&command_buffer) {
+            Some(&active) if active != render_pass => active,
+            _ => return,
+        };
+        if let (Some(active_signature), Some(signature)) = (
+            self.signatures.get(&active),
+            self.signatures.get(&render_pass),
+        ) {
+            assert_eq!(
+                active_signature, signature,
+                "The pipeline was built for a render pass incompatible with the active one."
+            );
         }
     }
+}
+
+/// The attachments one subpass of a [`VRenderPassBuilder`] uses, by index into its attachments
+#[derive(Default, Debug, Clone)]
+pub struct VSubpass {
+    color_attachments: Vec<AttachmentReference>,
+    resolve_attachments: Vec<AttachmentReference>,
+    depth_attachment: Option<AttachmentReference>,
+}
+
+impl VSubpass {
+    pub fn start() -> Self {
+        Self::default()
+    }
+
+    /// Adds a color output, written to the location matching the order of the calls
+    pub fn color(mut self, attachment: u32) -> Self {
+        self.color_attachments.push(AttachmentReference {
+            attachment,
+            layout: ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        });
+        self
+    }
+
+    /// Resolves the color output of the same position into `attachment`, either every color
+    /// output is resolved or none
+    pub fn resolve(mut self, attachment: u32) -> Self {
+        self.resolve_attachments.push(AttachmentReference {
+            attachment,
+            layout: ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        });
+        self
+    }
+
+    pub fn depth(mut self, attachment: u32) -> Self {
+        self.depth_attachment = Some(AttachmentReference {
+            attachment,
+            layout: ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        });
+        self
+    }
+
+    fn references(&self) -> impl Iterator<Item = &AttachmentReference> {
+        self.color_attachments
+            .iter()
+            .chain(&self.resolve_attachments)
+            .chain(&self.depth_attachment)
+    }
 
-    fn subpass_descriptions(
-        attachment_refs: &[AttachmentReference],
-        depth_attachment_ref: &AttachmentReference,
-    ) -> Vec<SubpassDescription> {
-        let subpass_description = SubpassDescription {
+    /// Points into the subpass, which must outlive the description
+    fn description(&self) -> SubpassDescription {
+        let p_resolve_attachments = if self.resolve_attachments.is_empty() {
+            std::ptr::null()
+        } else {
+            self.resolve_attachments.as_ptr()
+        };
+        let p_depth_stencil_attachment = match &self.depth_attachment {
+            Some(depth_attachment) => depth_attachment as *const AttachmentReference,
+            None => std::ptr::null(),
+        };
+        SubpassDescription {
             pipeline_bind_point: PipelineBindPoint::GRAPHICS,
-            color_attachment_count: attachment_refs.len() as u32,
-            p_color_attachments: attachment_refs.as_ptr(),
-            p_depth_stencil_attachment: depth_attachment_ref,
+            color_attachment_count: self.color_attachments.len() as u32,
+            p_color_attachments: self.color_attachments.as_ptr(),
+            p_resolve_attachments,
+            p_depth_stencil_attachment,
             ..Default::default()
+        }
+    }
+}
+
+/// Builds a [`VRenderPass`] out of attachment descriptions and subpasses
+///
+/// Without explicit dependencies, color and depth writes of the first subpass wait for the same
+/// stages of earlier work
+#[derive(Default, Debug, Clone)]
+pub struct VRenderPassBuilder {
+    attachments: Vec<AttachmentDescription>,
+    subpasses: Vec<VSubpass>,
+    dependencies: Vec<SubpassDependency>,
+}
+
+impl VRenderPassBuilder {
+    pub fn start() -> Self {
+        Self::default()
+    }
+
+    /// Adds an attachment, its index is the number of attachments added before it
+    pub fn attachment(mut self, attachment: AttachmentDescription) -> Self {
+        self.attachments.push(attachment);
+        self
+    }
+
+    pub fn subpass(mut self, subpass: VSubpass) -> Self {
+        self.subpasses.push(subpass);
+        self
+    }
+
+    pub fn dependency(mut self, dependency: SubpassDependency) -> Self {
+        self.dependencies.push(dependency);
+        self
+    }
+
+    pub fn build(&self, device: &VDevice) -> RendererResult<VRenderPass> {
+        self.validate()?;
+        let subpass_descriptions = self
+            .subpasses
+            .iter()
+            .map(VSubpass::description)
+            .collect::<Vec<_>>();
+        let default_dependencies;
+        let dependencies = if self.dependencies.is_empty() {
+            default_dependencies = Self::default_dependencies();
+            &default_dependencies
+        } else {
+            &self.dependencies
         };
-        vec![subpass_description]
+        let create_info = RenderPassCreateInfo {
+            attachment_count: self.attachments.len() as u32,
+            p_attachments: self.attachments.as_ptr(),
+            subpass_count: subpass_descriptions.len() as u32,
+            p_subpasses: subpass_descriptions.as_ptr(),
+            dependency_count: dependencies.len() as u32,
+            p_dependencies: dependencies.as_ptr(),
+            ..Default::default()
+        };
+        VRenderPass::register(device, &create_info, &self.attachments)
+    }
+
+    fn validate(&self) -> RendererResult<()> {
+        if self.subpasses.is_empty() {
+            return Err(RendererError::InvalidArgument(
+                "A render pass needs at least one subpass.".to_owned(),
+            ));
+        }
+        for (ind, subpass) in self.subpasses.iter().enumerate() {
+            if let Some(reference) = subpass
+                .references()
+                .find(|reference| reference.attachment as usize >= self.attachments.len())
+            {
+                return Err(RendererError::InvalidArgument(format!(
+                    "Subpass {} uses attachment {} but the render pass has {}.",
+                    ind,
+                    reference.attachment,
+                    self.attachments.len()
+                )));
+            }
+            let resolve_count = subpass.resolve_attachments.len();
+            if resolve_count != 0 && resolve_count != subpass.color_attachments.len() {
+                return Err(RendererError::InvalidArgument(format!(
+                    "Subpass {} resolves {} of its {} color attachments.",
+                    ind,
+                    resolve_count,
+                    subpass.color_attachments.len()
+                )));
+            }
+        }
+        Ok(())
     }
 
-    fn subpass_dependencies() -> Vec<SubpassDependency> {
+    fn default_dependencies() -> Vec<SubpassDependency> {
         let color_dependency = SubpassDependency {
             src_subpass: SUBPASS_EXTERNAL,
             dst_subpass: 0,
@@ -88,23 +254,152 @@ impl VRenderPass {
         };
         vec![color_dependency, depth_dependency]
     }
+}
 
-    fn attachment_refs() -> Vec<AttachmentReference> {
-        let color_attachment_reference = AttachmentReference {
-            attachment: 0,
-            layout: ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
-        };
-        vec![color_attachment_reference]
+pub struct VRenderPass {
+    render_pass: RenderPass,
+    signature: RenderPassSignature,
+}
+
+impl VRenderPass {
+    pub const COLOR_ATTACHMENT: u32 = 0;
+    pub const DEPTH_ATTACHMENT: u32 = 1;
+    /// Only present in [`new_multisampled`](Self::new_multisampled) render passes
+    pub const RESOLVE_ATTACHMENT: u32 = 2;
+
+    /// A cleared color attachment that gets presented and a cleared depth attachment, in one subpass
+    pub fn new_default(
+        device: &VDevice,
+        format: Format,
+        depth_format: Format,
+    ) -> RendererResult<Self> {
+        Self::new_with_final_layout(device, format, depth_format, ImageLayout::PRESENT_SRC_KHR)
     }
 
-    fn depth_attachment_ref() -> AttachmentReference {
-        AttachmentReference {
-            attachment: 1,
-            layout: ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+    /// Same as [`new_default`](Self::new_default) but leaves the color attachment in `final_layout`
+    ///
+    /// Used to render into images that aren't presented, e.g. `SHADER_READ_ONLY_OPTIMAL` for
+    /// an image sampled afterwards, the result stays compatible with the presenting render pass
+    pub fn new_with_final_layout(
+        device: &VDevice,
+        format: Format,
+        depth_format: Format,
+        final_layout: ImageLayout,
+    ) -> RendererResult<Self> {
+        let mut attachments = Self::attachment_descriptions(format, depth_format);
+        attachments[Self::COLOR_ATTACHMENT as usize].final_layout = final_layout;
+        Self::color_depth_builder(attachments, None).build(device)
+    }
+
+    /// Renders into `samples`-sampled color and depth attachments and resolves color into a
+    /// single-sampled third attachment, which is the one that gets presented
+    ///
+    /// Falls back to [`new_default`](Self::new_default) for `TYPE_1`
+    pub fn new_multisampled(
+        device: &VDevice,
+        format: Format,
+        depth_format: Format,
+        samples: SampleCountFlags,
+    ) -> RendererResult<Self> {
+        if samples == SampleCountFlags::TYPE_1 {
+            return Self::new_default(device, format, depth_format);
+        }
+        let attachments = Self::multisampled_attachment_descriptions(format, depth_format, samples);
+        Self::color_depth_builder(attachments, Some(Self::RESOLVE_ATTACHMENT)).build(device)
+    }
+
+    /// A single depth attachment, e.g. for shadow maps, left in `DEPTH_STENCIL_READ_ONLY_OPTIMAL`
+    /// so fragment shaders can sample it afterwards
+    ///
+    /// The depth attachment is attachment 0 here, not [`DEPTH_ATTACHMENT`](Self::DEPTH_ATTACHMENT)
+    pub fn new_depth_only(device: &VDevice, depth_format: Format) -> RendererResult<Self> {
+        let mut depth_attachment = Self::attachment_descriptions(depth_format, depth_format)
+            [Self::DEPTH_ATTACHMENT as usize];
+        depth_attachment.final_layout = ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL;
+        let [before, after] = Self::depth_only_subpass_dependencies();
+        VRenderPassBuilder::start()
+            .attachment(depth_attachment)
+            .subpass(VSubpass::start().depth(0))
+            .dependency(before)
+            .dependency(after)
+            .build(device)
+    }
+
+    pub fn get(&self) -> RenderPass {
+        self.render_pass
+    }
+
+    pub fn signature(&self) -> &RenderPassSignature {
+        &self.signature
+    }
+
+    /// `attachments` in the order of [`attachment_descriptions`](Self::attachment_descriptions),
+    /// with a subpass drawing into the color and depth attachments
+    fn color_depth_builder(
+        attachments: Vec<AttachmentDescription>,
+        resolve_attachment: Option<u32>,
+    ) -> VRenderPassBuilder {
+        let mut subpass = VSubpass::start()
+            .color(Self::COLOR_ATTACHMENT)
+            .depth(Self::DEPTH_ATTACHMENT);
+        if let Some(resolve_attachment) = resolve_attachment {
+            subpass = subpass.resolve(resolve_attachment);
+        }
+        attachments
+            .into_iter()
+            .fold(VRenderPassBuilder::start(), VRenderPassBuilder::attachment)
+            .subpass(subpass)
+    }
+
+    /// Creates the render pass and records its signature with the device in debug builds
+    fn register(
+        device: &VDevice,
+        create_info: &RenderPassCreateInfo,
+        attachments: &[AttachmentDescription],
+    ) -> RendererResult<Self> {
+        let render_pass = unsafe { device.get().create_render_pass(create_info, None)? };
+        let signature = RenderPassSignature::from_attachments(attachments);
+        if cfg!(debug_assertions) {
+            device
+                .render_pass_tracker()
+                .borrow_mut()
+                .register(render_pass, signature.clone());
         }
+        Ok(Self {
+            render_pass,
+            signature,
+        })
     }
 
-    fn attachment_descriptions(format: Format) -> Vec<AttachmentDescription> {
+    /// Depth writes wait for earlier reads of the map and finish before later fragment shaders sample it
+    fn depth_only_subpass_dependencies() -> [SubpassDependency; 2] {
+        let depth_tests =
+            PipelineStageFlags::EARLY_FRAGMENT_TESTS | PipelineStageFlags::LATE_FRAGMENT_TESTS;
+        let before = SubpassDependency {
+            src_subpass: SUBPASS_EXTERNAL,
+            dst_subpass: 0,
+            src_stage_mask: PipelineStageFlags::FRAGMENT_SHADER,
+            src_access_mask: AccessFlags::SHADER_READ,
+            dst_stage_mask: depth_tests,
+            dst_access_mask: AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            ..Default::default()
+        };
+        let after = SubpassDependency {
+            src_subpass: 0,
+            dst_subpass: SUBPASS_EXTERNAL,
+            src_stage_mask: depth_tests,
+            src_access_mask: AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            dst_stage_mask: PipelineStageFlags::FRAGMENT_SHADER,
+            dst_access_mask: AccessFlags::SHADER_READ,
+            ..Default::default()
+        };
+        [before, after]
+    }
+
+    pub(crate) fn attachment_descriptions(
+        format: Format,
+        depth_format: Format,
+    ) -> Vec<AttachmentDescription> {
         // Just color attachment for now
         let color_attachment = AttachmentDescription {
             format,
@@ -117,17 +412,116 @@ impl VRenderPass {
             final_layout: ImageLayout::PRESENT_SRC_KHR,
             ..Default::default()
         };
+        // The stencil is cleared and kept like depth when the format has one
+        let (stencil_load_op, stencil_store_op) = match VImage::has_stencil(depth_format) {
+            true => (AttachmentLoadOp::CLEAR, AttachmentStoreOp::STORE),
+            false => (AttachmentLoadOp::DONT_CARE, AttachmentStoreOp::DONT_CARE),
+        };
         let depth_attachment = AttachmentDescription {
-            format: Format::D32_SFLOAT,
+            format: depth_format,
             initial_layout: ImageLayout::UNDEFINED,
             load_op: AttachmentLoadOp::CLEAR,
             samples: SampleCountFlags::TYPE_1,
             store_op: AttachmentStoreOp::STORE,
-            stencil_load_op: AttachmentLoadOp::DONT_CARE,
-            stencil_store_op: AttachmentStoreOp::DONT_CARE,
+            stencil_load_op,
+            stencil_store_op,
             final_layout: ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
             ..Default::default()
         };
         vec![color_attachment, depth_attachment]
     }
+
+    /// The multisampled color is only needed until it's resolved, so it isn't stored
+    pub(crate) fn multisampled_attachment_descriptions(
+        format: Format,
+        depth_format: Format,
+        samples: SampleCountFlags,
+    ) -> Vec<AttachmentDescription> {
+        let mut attachments = Self::attachment_descriptions(format, depth_format);
+        let resolve_attachment = attachments[0];
+        attachments[0].samples = samples;
+        attachments[0].store_op = AttachmentStoreOp::DONT_CARE;
+        attachments[0].final_layout = ImageLayout::COLOR_ATTACHMENT_OPTIMAL;
+        attachments[1].samples = samples;
+        attachments.push(AttachmentDescription {
+            load_op: AttachmentLoadOp::DONT_CARE,
+            ..resolve_attachment
+        });
+        attachments
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{instance::VInstance, test_utils::create_window};
+    use ash::vk::Handle;
+
+    #[test]
+    #[should_panic(expected = "incompatible")]
+    fn rejects_pipeline_of_incompatible_render_pass() {
+        let (color_format, depth_format) = (Format::B8G8R8A8_SRGB, Format::D32_SFLOAT);
+        let presented = VRenderPass::attachment_descriptions(color_format, depth_format);
+        let mut sampled = presented.clone();
+        sampled[VRenderPass::COLOR_ATTACHMENT as usize].final_layout =
+            ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+        let multisampled = VRenderPass::multisampled_attachment_descriptions(
+            color_format,
+            depth_format,
+            SampleCountFlags::TYPE_4,
+        );
+
+        let mut tracker = VRenderPassTracker::default();
+        for (raw, attachments) in [(1, &presented), (2, &sampled), (3, &multisampled)] {
+            tracker.register(
+                RenderPass::from_raw(raw),
+                RenderPassSignature::from_attachments(attachments),
+            );
+        }
+        let command_buffer = CommandBuffer::from_raw(1);
+        tracker.begin(command_buffer, RenderPass::from_raw(1));
+        tracker.assert_compatible(command_buffer, RenderPass::from_raw(2));
+        tracker.assert_compatible(command_buffer, RenderPass::from_raw(3));
+    }
+
+    #[test]
+    fn builds_render_pass_with_two_color_attachments() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let attachments =
+            VRenderPass::attachment_descriptions(Format::R8G8B8A8_UNORM, Format::D32_SFLOAT);
+        let g_buffer_attachment = |format| AttachmentDescription {
+            format,
+            final_layout: ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            ..attachments[VRenderPass::COLOR_ATTACHMENT as usize]
+        };
+        let builder = VRenderPassBuilder::start()
+            .attachment(g_buffer_attachment(Format::R8G8B8A8_UNORM))
+            .attachment(g_buffer_attachment(Format::R16G16B16A16_SFLOAT))
+            .attachment(attachments[VRenderPass::DEPTH_ATTACHMENT as usize]);
+        assert!(builder
+            .clone()
+            .subpass(VSubpass::start().color(0).color(3))
+            .build(&device)
+            .is_err());
+
+        let g_buffer = builder
+            .subpass(VSubpass::start().color(0).color(1).depth(2))
+            .build(&device)?;
+        assert_eq!(
+            g_buffer.signature(),
+            &RenderPassSignature {
+                attachments: vec![
+                    (Format::R8G8B8A8_UNORM, SampleCountFlags::TYPE_1),
+                    (Format::R16G16B16A16_SFLOAT, SampleCountFlags::TYPE_1),
+                    (Format::D32_SFLOAT, SampleCountFlags::TYPE_1),
+                ]
+            }
+        );
+
+        unsafe { device.get().destroy_render_pass(g_buffer.get(), None) };
+        Ok(())
+    }
 }
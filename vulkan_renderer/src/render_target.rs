@@ -0,0 +1,326 @@
+use crate::{
+    buffer::VBuffer, device::VDevice, image::VImage, impl_get, impl_get_ref,
+    render_pass::VRenderPass, RendererError, RendererResult,
+};
+use ash::vk::{
+    BufferUsageFlags, Extent2D, Extent3D, Format, Framebuffer, FramebufferCreateInfo,
+    ImageAspectFlags, ImageLayout, ImageUsageFlags, MemoryPropertyFlags, SampleCountFlags,
+};
+
+/// A framebuffer over caller-owned color and depth images, to render into instead of the swapchain
+///
+/// The images stay owned by the caller and must outlive the target
+#[derive(Default, Debug, Clone, Copy)]
+pub struct VRenderTarget {
+    framebuffer: Framebuffer,
+    extent: Extent2D,
+}
+
+impl VRenderTarget {
+    /// `render_pass` needs a single-sampled color and depth attachment matching the images, e.g.
+    /// one made with [`VRenderPass::new_with_final_layout`]
+    pub fn new(
+        device: &VDevice,
+        render_pass: &VRenderPass,
+        color_image: &VImage,
+        depth_image: &VImage,
+    ) -> RendererResult<Self> {
+        let attachments = render_pass.signature().attachments();
+        if attachments.len() != 2 {
+            return Err(RendererError::InvalidArgument(format!(
+                "Render pass has {} attachments but a render target binds a color and a depth image.",
+                attachments.len()
+            )));
+        }
+        Self::validate_attachment(
+            color_image,
+            "Color",
+            ImageUsageFlags::COLOR_ATTACHMENT,
+            attachments[VRenderPass::COLOR_ATTACHMENT as usize],
+        )?;
+        Self::validate_attachment(
+            depth_image,
+            "Depth",
+            ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+            attachments[VRenderPass::DEPTH_ATTACHMENT as usize],
+        )?;
+        if color_image.extent() != depth_image.extent() {
+            return Err(RendererError::InvalidArgument(format!(
+                "Color image is {:?} but depth image is {:?}.",
+                color_image.extent(),
+                depth_image.extent()
+            )));
+        }
+
+        let extent = Extent2D {
+            width: color_image.extent().width,
+            height: color_image.extent().height,
+        };
+        let attachments = [color_image.image_view(), depth_image.image_view()];
+        let create_info = FramebufferCreateInfo {
+            render_pass: render_pass.get(),
+            attachment_count: attachments.len() as u32,
+            p_attachments: attachments.as_ptr(),
+            width: extent.width,
+            height: extent.height,
+            layers: 1,
+            ..Default::default()
+        };
+        let framebuffer = unsafe { device.get().create_framebuffer(&create_info, None)? };
+        Ok(Self {
+            framebuffer,
+            extent,
+        })
+    }
+
+    /// Destroys the framebuffer only, the images belong to the caller
+    pub fn destroy(&self, device: &VDevice) {
+        unsafe { device.get().destroy_framebuffer(self.framebuffer, None) };
+    }
+
+    /// Checks `image` against the `(format, samples)` of its render pass attachment
+    fn validate_attachment(
+        image: &VImage,
+        name: &str,
+        usage: ImageUsageFlags,
+        (format, samples): (Format, SampleCountFlags),
+    ) -> RendererResult<()> {
+        if !image.usage().contains(usage) {
+            return Err(RendererError::InvalidArgument(format!(
+                "{} image needs {:?} usage, got {:?}.",
+                name,
+                usage,
+                image.usage()
+            )));
+        }
+        if image.format() != format {
+            return Err(RendererError::InvalidArgument(format!(
+                "{} image is {:?} but the render pass expects {:?}.",
+                name,
+                image.format(),
+                format
+            )));
+        }
+        if samples != SampleCountFlags::TYPE_1 {
+            return Err(RendererError::InvalidArgument(format!(
+                "{} attachment of the render pass has {:?} samples but target images have one.",
+                name, samples
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl_get!(VRenderTarget, framebuffer, Framebuffer);
+impl_get!(VRenderTarget, extent, Extent2D);
+
+/// Color and depth images with their own render pass and framebuffer, for rendering without a swapchain
+///
+/// The render pass leaves the color image in `TRANSFER_SRC_OPTIMAL` so it can be read back
+pub struct VOffscreenTarget {
+    render_pass: VRenderPass,
+    color_image: VImage,
+    depth_image: VImage,
+    render_target: VRenderTarget,
+}
+
+impl VOffscreenTarget {
+    pub const COLOR_FORMAT: Format = Format::R8G8B8A8_UNORM;
+    pub const DEPTH_FORMAT: Format = Format::D32_SFLOAT;
+
+    pub fn new(device: &VDevice, extent: Extent2D) -> RendererResult<Self> {
+        let render_pass = VRenderPass::new_with_final_layout(
+            device,
+            Self::COLOR_FORMAT,
+            Self::DEPTH_FORMAT,
+            ImageLayout::TRANSFER_SRC_OPTIMAL,
+        )?;
+        let image_extent = Extent3D {
+            width: extent.width,
+            height: extent.height,
+            depth: 1,
+        };
+        let color_image = VImage::new(
+            device,
+            ImageUsageFlags::COLOR_ATTACHMENT | ImageUsageFlags::TRANSFER_SRC,
+            Self::COLOR_FORMAT,
+            image_extent,
+            ImageAspectFlags::COLOR,
+        )?;
+        let depth_image = VImage::new(
+            device,
+            ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+            Self::DEPTH_FORMAT,
+            image_extent,
+            ImageAspectFlags::DEPTH,
+        )?;
+        let render_target = VRenderTarget::new(device, &render_pass, &color_image, &depth_image)?;
+        Ok(Self {
+            render_pass,
+            color_image,
+            depth_image,
+            render_target,
+        })
+    }
+
+    pub fn framebuffer(&self) -> Framebuffer {
+        self.render_target.framebuffer()
+    }
+
+    pub fn extent(&self) -> Extent2D {
+        self.render_target.extent()
+    }
+
+    /// Reads the color image back as tightly packed RGBA bytes, row by row
+    ///
+    /// The render pass must have run at least once so the image is in `TRANSFER_SRC_OPTIMAL`
+    pub fn read_pixels(&self, device: &VDevice) -> RendererResult<Vec<u8>> {
+        let extent = self.extent();
+        let size = extent.width as usize * extent.height as usize * 4;
+        let buffer = VBuffer::new_mapped(
+            device,
+            &vec![0u8; size],
+            BufferUsageFlags::TRANSFER_DST,
+            MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT,
+        )?;
+        let pixels = VBuffer::copy_image_to_buffer(device, &self.color_image, buffer.buffer())
+            .and_then(|_| buffer.read_back::<u8>(device));
+        unsafe {
+            device.get().destroy_buffer(buffer.buffer(), None);
+            device.get().free_memory(buffer.memory(), None);
+        }
+        pixels
+    }
+
+    pub fn destroy(&self, device: &VDevice) {
+        self.render_target.destroy(device);
+        self.color_image.destroy(device);
+        self.depth_image.destroy(device);
+        unsafe {
+            device
+                .get()
+                .destroy_render_pass(self.render_pass.get(), None);
+        }
+    }
+}
+
+impl_get_ref!(VOffscreenTarget, render_pass, &VRenderPass);
+impl_get_ref!(VOffscreenTarget, color_image, &VImage);
+impl_get_ref!(VOffscreenTarget, depth_image, &VImage);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cmd::{cmd_begin_render_pass, cmd_end_render_pass, ClearValues},
+        instance::VInstance,
+        test_utils::create_window,
+    };
+
+    #[test]
+    fn validates_usage_and_format_of_target_images() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let (color_format, depth_format) = (Format::R8G8B8A8_UNORM, Format::D32_SFLOAT);
+        let render_pass = VRenderPass::new_with_final_layout(
+            &device,
+            color_format,
+            depth_format,
+            ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        )?;
+        let extent = Extent3D {
+            width: 32,
+            height: 32,
+            depth: 1,
+        };
+        let color_image = VImage::new(
+            &device,
+            ImageUsageFlags::COLOR_ATTACHMENT | ImageUsageFlags::SAMPLED,
+            color_format,
+            extent,
+            ImageAspectFlags::COLOR,
+        )?;
+        let sampled_image = VImage::new(
+            &device,
+            ImageUsageFlags::SAMPLED,
+            color_format,
+            extent,
+            ImageAspectFlags::COLOR,
+        )?;
+        let depth_image = VImage::new(
+            &device,
+            ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+            depth_format,
+            extent,
+            ImageAspectFlags::DEPTH,
+        )?;
+
+        let srgb_render_pass = VRenderPass::new_with_final_layout(
+            &device,
+            Format::B8G8R8A8_SRGB,
+            depth_format,
+            ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        )?;
+        let depth_only_render_pass = VRenderPass::new_depth_only(&device, depth_format)?;
+
+        let target = VRenderTarget::new(&device, &render_pass, &color_image, &depth_image)?;
+        assert_eq!(target.extent().width, 32);
+        assert!(VRenderTarget::new(&device, &render_pass, &sampled_image, &depth_image).is_err());
+        assert!(
+            VRenderTarget::new(&device, &srgb_render_pass, &color_image, &depth_image).is_err()
+        );
+        assert!(
+            VRenderTarget::new(&device, &depth_only_render_pass, &color_image, &depth_image)
+                .is_err()
+        );
+
+        target.destroy(&device);
+        unsafe {
+            for image in [color_image, sampled_image, depth_image] {
+                device.get().destroy_image_view(image.image_view(), None);
+                device.get().destroy_image(image.image(), None);
+                device.get().free_memory(image.memory(), None);
+            }
+            for render_pass in [render_pass, srgb_render_pass, depth_only_render_pass] {
+                device.get().destroy_render_pass(render_pass.get(), None);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reads_back_cleared_offscreen_pixels() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let target = VOffscreenTarget::new(
+            &device,
+            Extent2D {
+                width: 4,
+                height: 2,
+            },
+        )?;
+        let clear_values = ClearValues::start().color(0.2, 0.4, 0.6, 1.0).build();
+        VBuffer::submit_one_time(&device, |command_buffer| {
+            cmd_begin_render_pass(
+                &device,
+                command_buffer,
+                target.render_pass().get(),
+                target.framebuffer(),
+                &clear_values,
+                target.extent(),
+            );
+            cmd_end_render_pass(&device, command_buffer);
+        })?;
+
+        let pixels = target.read_pixels(&device)?;
+        assert_eq!(pixels.len(), 4 * 2 * 4);
+        assert!(pixels.chunks(4).all(|pixel| pixel == [51, 102, 153, 255]));
+
+        target.destroy(&device);
+        Ok(())
+    }
+}
@@ -0,0 +1,320 @@
+use crate::{
+    buffer::VBuffer,
+    cmd::{cmd_begin_debug_label, cmd_end_debug_label},
+    command_pool::VCommandPool,
+    descriptorset::{VDescriptorPool, VDescriptorSetLayout},
+    device::VDevice,
+    enums::EOperationType,
+    image::VImage,
+    instance::VInstance,
+    pipeline::{VComputePipeline, VGraphicsPipeline},
+    swapchain::VSwapchain,
+    RendererResult,
+};
+use ash::vk::{
+    CommandBuffer, CommandPoolCreateFlags, DescriptorPool, DescriptorPoolCreateFlags,
+    DescriptorPoolSize, DescriptorSetLayout, DescriptorSetLayoutBinding, Extent2D, PresentModeKHR,
+};
+use winit::window::Window;
+
+/// Colour of the capture label regions, picked to stand out in RenderDoc's event browser
+const CAPTURE_LABEL_COLOR: [f32; 4] = [1.0, 0.0, 1.0, 1.0];
+
+/// Counts the frames of a capture requested with [`Renderer::capture_frames`]
+#[derive(Default, Debug, Clone, Copy)]
+struct FrameCapture {
+    frame: u32,
+    frame_count: u32,
+}
+
+impl FrameCapture {
+    fn new(frame_count: u32) -> Self {
+        Self {
+            frame: 0,
+            frame_count,
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.frame < self.frame_count
+    }
+
+    fn label(&self) -> String {
+        format!("Capture frame {}/{}", self.frame + 1, self.frame_count)
+    }
+
+    fn advance(&mut self) {
+        if self.is_active() {
+            self.frame += 1;
+        }
+    }
+}
+
+/// Owns the Vulkan objects of an application and destroys them in dependency order
+///
+/// Pipelines, buffers, images, descriptor objects and command pools handed to the renderer are
+/// destroyed on drop, then the fields are dropped in declaration order: swapchain (and its render
+/// pass), device (and its surface), instance
+pub struct Renderer {
+    graphics_pipelines: Vec<VGraphicsPipeline>,
+    compute_pipelines: Vec<VComputePipeline>,
+    buffers: Vec<VBuffer>,
+    images: Vec<VImage>,
+    descriptor_set_layouts: Vec<VDescriptorSetLayout>,
+    descriptor_pool: VDescriptorPool,
+    descriptor_pools: Vec<VDescriptorPool>,
+    command_pools: Vec<VCommandPool>,
+    swapchain: VSwapchain,
+    device: VDevice,
+    instance: VInstance,
+
+    extent: Extent2D,
+    frame_capture: FrameCapture,
+}
+
+impl Renderer {
+    /// Creates the instance, device and a swapchain covering the window
+    ///
+    /// `present_mode` and `image_count` are validated by [`VSwapchain::new`]
+    pub fn new(
+        name: &str,
+        window: &Window,
+        present_mode: PresentModeKHR,
+        image_count: u32,
+    ) -> RendererResult<Self> {
+        let extent = Extent2D {
+            width: window.inner_size().width,
+            height: window.inner_size().height,
+        };
+        let instance = VInstance::new(name, 0)?;
+        let device = VDevice::new(&instance, window)?;
+        let swapchain = VSwapchain::new(&instance, &device, extent, present_mode, image_count)?;
+        let descriptor_pool = VDescriptorPool::new(&device)?;
+
+        Ok(Self {
+            graphics_pipelines: Vec::new(),
+            compute_pipelines: Vec::new(),
+            buffers: Vec::new(),
+            images: Vec::new(),
+            descriptor_set_layouts: Vec::new(),
+            descriptor_pool,
+            descriptor_pools: Vec::new(),
+            command_pools: Vec::new(),
+            swapchain,
+            device,
+            instance,
+
+            extent,
+            frame_capture: FrameCapture::default(),
+        })
+    }
+
+    pub fn device(&self) -> &VDevice {
+        &self.device
+    }
+
+    pub fn instance(&self) -> &VInstance {
+        &self.instance
+    }
+
+    pub fn swapchain(&self) -> &VSwapchain {
+        &self.swapchain
+    }
+
+    pub fn extent(&self) -> Extent2D {
+        self.extent
+    }
+
+    pub fn descriptor_pool(&self) -> DescriptorPool {
+        self.descriptor_pool.get()
+    }
+
+    /// Marks the next `frame_count` frames for capture, replacing a capture still in progress
+    ///
+    /// Frames recorded between [`begin_frame`](Self::begin_frame) and [`end_frame`](Self::end_frame)
+    /// are wrapped in a `Capture frame i/n` debug label region, which needs `VK_EXT_debug_utils`
+    pub fn capture_frames(&mut self, frame_count: u32) {
+        self.frame_capture = FrameCapture::new(frame_count);
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        self.frame_capture.is_active()
+    }
+
+    /// Opens the capture label region of the frame recorded into `command_buffer`
+    ///
+    /// Does nothing if no capture is in progress or debug utils aren't available
+    pub fn begin_frame(&self, command_buffer: CommandBuffer) {
+        if self.frame_capture.is_active() {
+            cmd_begin_debug_label(
+                &self.device,
+                command_buffer,
+                &self.frame_capture.label(),
+                CAPTURE_LABEL_COLOR,
+            )
+            .expect("Capture labels have no nul bytes.");
+        }
+    }
+
+    /// Closes the region opened by [`begin_frame`](Self::begin_frame) and counts the frame
+    pub fn end_frame(&mut self, command_buffer: CommandBuffer) {
+        if !self.frame_capture.is_active() {
+            return;
+        }
+        cmd_end_debug_label(&self.device, command_buffer);
+        self.frame_capture.advance();
+    }
+
+    /// Takes ownership of `pipeline`, returns the index to look it up with
+    pub fn add_graphics_pipeline(&mut self, pipeline: VGraphicsPipeline) -> usize {
+        self.graphics_pipelines.push(pipeline);
+        self.graphics_pipelines.len() - 1
+    }
+
+    pub fn graphics_pipeline(&self, index: usize) -> &VGraphicsPipeline {
+        &self.graphics_pipelines[index]
+    }
+
+    /// Takes ownership of `pipeline`, returns the index to look it up with
+    pub fn add_compute_pipeline(&mut self, pipeline: VComputePipeline) -> usize {
+        self.compute_pipelines.push(pipeline);
+        self.compute_pipelines.len() - 1
+    }
+
+    pub fn compute_pipeline(&self, index: usize) -> &VComputePipeline {
+        &self.compute_pipelines[index]
+    }
+
+    /// Destroys `buffer` on drop, it must not be destroyed elsewhere
+    pub fn own_buffer(&mut self, buffer: VBuffer) -> &VBuffer {
+        self.buffers.push(buffer);
+        self.buffers.last().expect("The buffer was just pushed.")
+    }
+
+    /// Destroys `image` and its view on drop, it must not be destroyed elsewhere
+    pub fn own_image(&mut self, image: VImage) -> &VImage {
+        self.images.push(image);
+        self.images.last().expect("The image was just pushed.")
+    }
+
+    pub fn create_descriptor_set_layout(
+        &mut self,
+        bindings: &[DescriptorSetLayoutBinding],
+    ) -> RendererResult<DescriptorSetLayout> {
+        let layout = VDescriptorSetLayout::new(&self.device, bindings)?;
+        let handle = layout.get();
+        self.descriptor_set_layouts.push(layout);
+        Ok(handle)
+    }
+
+    /// A pool besides the shared [`descriptor_pool`](Self::descriptor_pool), for sets that need
+    /// their own `flags` or sizes
+    pub fn create_descriptor_pool(
+        &mut self,
+        flags: DescriptorPoolCreateFlags,
+        max_sets: u32,
+        pool_sizes: &[DescriptorPoolSize],
+    ) -> RendererResult<DescriptorPool> {
+        let pool = VDescriptorPool::new_with_flags(&self.device, flags, max_sets, pool_sizes)?;
+        let handle = pool.get();
+        self.descriptor_pools.push(pool);
+        Ok(handle)
+    }
+
+    pub fn create_command_pool(
+        &mut self,
+        operation_type: EOperationType,
+        flags: CommandPoolCreateFlags,
+    ) -> RendererResult<VCommandPool> {
+        let command_pool = VCommandPool::new(
+            &self.device,
+            self.device.get_queue_family_index(operation_type),
+            flags,
+        )?;
+        self.command_pools.push(command_pool);
+        Ok(command_pool)
+    }
+}
+
+impl Drop for Renderer {
+    fn drop(&mut self) {
+        let device = self.device.get();
+        unsafe {
+            device
+                .device_wait_idle()
+                .expect("Failed to wait for device idle.");
+            for pipeline in &self.graphics_pipelines {
+                device.destroy_pipeline(pipeline.pipeline(), None);
+                device.destroy_pipeline_layout(pipeline.pipeline_layout(), None);
+            }
+            for pipeline in &self.compute_pipelines {
+                device.destroy_pipeline(pipeline.pipeline(), None);
+                device.destroy_pipeline_layout(pipeline.pipeline_layout(), None);
+            }
+            for buffer in &self.buffers {
+                buffer.destroy(&self.device);
+            }
+            for image in &self.images {
+                image.destroy(&self.device);
+            }
+            for layout in &self.descriptor_set_layouts {
+                device.destroy_descriptor_set_layout(layout.get(), None);
+            }
+            device.destroy_descriptor_pool(self.descriptor_pool.get(), None);
+            for pool in &self.descriptor_pools {
+                device.destroy_descriptor_pool(pool.get(), None);
+            }
+            for command_pool in &self.command_pools {
+                device.destroy_command_pool(command_pool.get(), None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::create_window;
+    use ash::vk::{DescriptorType, MemoryPropertyFlags, ShaderStageFlags};
+
+    #[test]
+    fn labels_each_captured_frame_then_stops() {
+        let mut capture = FrameCapture::default();
+        assert!(!capture.is_active());
+
+        capture = FrameCapture::new(2);
+        let mut labels = Vec::new();
+        while capture.is_active() {
+            labels.push(capture.label());
+            capture.advance();
+        }
+        assert_eq!(labels, ["Capture frame 1/2", "Capture frame 2/2"]);
+        capture.advance();
+        assert!(!capture.is_active());
+    }
+
+    #[test]
+    fn creates_and_drops_renderer_with_owned_resources() -> RendererResult<()> {
+        let (_event_loop, window) = create_window();
+        // Leaked objects are reported by the validation layer when the device is destroyed
+        for _ in 0..3 {
+            let mut renderer = Renderer::new("Test", &window, PresentModeKHR::FIFO, 3)?;
+            let buffer = VBuffer::new_uniform_buffer(
+                renderer.device(),
+                64,
+                MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT,
+            )?;
+            renderer.own_buffer(buffer);
+            renderer.create_descriptor_set_layout(&[VDescriptorSetLayout::layout_binding(
+                0,
+                1,
+                DescriptorType::UNIFORM_BUFFER,
+                ShaderStageFlags::VERTEX,
+            )])?;
+            renderer
+                .create_command_pool(EOperationType::Graphics, CommandPoolCreateFlags::TRANSIENT)?;
+            drop(renderer);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+use crate::{device::VDevice, enums::ESamplerQuality, impl_get, RendererResult};
+use ash::vk::{
+    BorderColor, CompareOp, Filter, Format, FormatFeatureFlags, Sampler, SamplerAddressMode,
+    SamplerCreateInfo, SamplerMipmapMode, FALSE, LOD_CLAMP_NONE, TRUE,
+};
+
+const TEXTURE_MAX_ANISOTROPY: f32 = 16.0;
+
+#[derive(Default, Debug, Clone, Copy)]
+pub struct VSampler {
+    sampler: Sampler,
+    quality: ESamplerQuality,
+}
+
+impl VSampler {
+    /// Uses the device's maximum anisotropy if the feature is enabled, otherwise none
+    pub fn new(
+        device: &VDevice,
+        filter: Filter,
+        address_mode: SamplerAddressMode,
+    ) -> RendererResult<Self> {
+        let create_info = Self::sampler_create_info(device, filter, address_mode);
+        let sampler = unsafe { device.get().create_sampler(&create_info, None)? };
+        let quality = match (filter, create_info.anisotropy_enable) {
+            (Filter::NEAREST, _) => ESamplerQuality::Nearest,
+            (_, TRUE) => ESamplerQuality::Anisotropic,
+            _ => ESamplerQuality::Trilinear,
+        };
+        Ok(Self { sampler, quality })
+    }
+
+    /// Creates a repeating sampler with the best filtering the device and `format` support
+    ///
+    /// Prefers 16x anisotropy with trilinear filtering, drops anisotropy if the feature isn't enabled and
+    /// falls back to point sampling if optimally tiled images of `format` can't be filtered linearly
+    pub fn for_texture(device: &VDevice, format: Format) -> RendererResult<Self> {
+        let linear_filtering = device
+            .get_format_properties(format)
+            .optimal_tiling_features
+            .contains(FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR);
+        let anisotropy = device.get_enabled_features().sampler_anisotropy == TRUE;
+        let quality = Self::texture_quality(anisotropy, linear_filtering);
+
+        let filter = match quality {
+            ESamplerQuality::Nearest => Filter::NEAREST,
+            _ => Filter::LINEAR,
+        };
+        let mut create_info = Self::sampler_create_info(device, filter, SamplerAddressMode::REPEAT);
+        if quality == ESamplerQuality::Anisotropic {
+            create_info.max_anisotropy = create_info.max_anisotropy.min(TEXTURE_MAX_ANISOTROPY);
+        } else {
+            create_info.anisotropy_enable = FALSE;
+            create_info.max_anisotropy = 1.0;
+        }
+        let sampler = unsafe { device.get().create_sampler(&create_info, None)? };
+        Ok(Self { sampler, quality })
+    }
+
+    pub fn get(&self) -> Sampler {
+        self.sampler
+    }
+
+    pub fn destroy(&self, device: &VDevice) {
+        unsafe { device.get().destroy_sampler(self.sampler, None) };
+    }
+
+    fn texture_quality(anisotropy: bool, linear_filtering: bool) -> ESamplerQuality {
+        match (anisotropy, linear_filtering) {
+            (_, false) => ESamplerQuality::Nearest,
+            (true, true) => ESamplerQuality::Anisotropic,
+            (false, true) => ESamplerQuality::Trilinear,
+        }
+    }
+
+    fn sampler_create_info(
+        device: &VDevice,
+        filter: Filter,
+        address_mode: SamplerAddressMode,
+    ) -> SamplerCreateInfo {
+        let anisotropy_enable = device.get_enabled_features().sampler_anisotropy;
+        let max_anisotropy = if anisotropy_enable == TRUE {
+            device.get_device_properties().limits.max_sampler_anisotropy
+        } else {
+            1.0
+        };
+        let mipmap_mode = match filter {
+            Filter::NEAREST => SamplerMipmapMode::NEAREST,
+            _ => SamplerMipmapMode::LINEAR,
+        };
+        SamplerCreateInfo {
+            mag_filter: filter,
+            min_filter: filter,
+            mipmap_mode,
+            address_mode_u: address_mode,
+            address_mode_v: address_mode,
+            address_mode_w: address_mode,
+            anisotropy_enable,
+            max_anisotropy,
+            compare_op: CompareOp::ALWAYS,
+            max_lod: LOD_CLAMP_NONE,
+            border_color: BorderColor::INT_OPAQUE_BLACK,
+            ..Default::default()
+        }
+    }
+}
+
+impl_get!(VSampler, quality, ESamplerQuality);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{instance::VInstance, test_utils::create_window};
+
+    #[test]
+    fn creates_linear_and_nearest_samplers() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let samplers = [
+            VSampler::new(&device, Filter::LINEAR, SamplerAddressMode::REPEAT)?,
+            VSampler::new(&device, Filter::NEAREST, SamplerAddressMode::CLAMP_TO_EDGE)?,
+        ];
+        for sampler in samplers {
+            assert_ne!(sampler.get(), Sampler::null());
+            unsafe { device.get().destroy_sampler(sampler.get(), None) };
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn degrades_texture_quality_with_support() {
+        assert_eq!(
+            VSampler::texture_quality(true, true),
+            ESamplerQuality::Anisotropic
+        );
+        assert_eq!(
+            VSampler::texture_quality(false, true),
+            ESamplerQuality::Trilinear
+        );
+        assert_eq!(
+            VSampler::texture_quality(true, false),
+            ESamplerQuality::Nearest
+        );
+    }
+}
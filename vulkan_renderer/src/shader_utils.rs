@@ -4,7 +4,11 @@ use ash::{
 };
 use std::fs::File;
 
+#[cfg(feature = "shaderc")]
+use crate::RendererError;
 use crate::{device::VDevice, RendererResult};
+#[cfg(feature = "shaderc")]
+use ash::vk::ShaderStageFlags;
 
 pub struct VShaderUtils;
 impl VShaderUtils {
@@ -24,4 +28,63 @@ impl VShaderUtils {
         };
         Ok(unsafe { device.get().create_shader_module(&create_info, None)? })
     }
+
+    /// Compiles GLSL `source` with entry point `main`, `name` is used as the file name in errors
+    #[cfg(feature = "shaderc")]
+    pub fn compile_glsl(
+        source: &str,
+        stage: ShaderStageFlags,
+        name: &str,
+    ) -> RendererResult<Vec<u32>> {
+        let shader_kind = Self::shader_kind(stage).ok_or_else(|| {
+            RendererError::InvalidArgument(format!(
+                "Cannot compile GLSL for the {:?} stage.",
+                stage
+            ))
+        })?;
+        let compiler = shaderc::Compiler::new().ok_or(RendererError::ShaderCompilerInit)?;
+        let artifact = compiler.compile_into_spirv(source, shader_kind, name, "main", None)?;
+        Ok(artifact.as_binary().to_vec())
+    }
+
+    #[cfg(feature = "shaderc")]
+    fn shader_kind(stage: ShaderStageFlags) -> Option<shaderc::ShaderKind> {
+        match stage {
+            ShaderStageFlags::VERTEX => Some(shaderc::ShaderKind::Vertex),
+            ShaderStageFlags::FRAGMENT => Some(shaderc::ShaderKind::Fragment),
+            ShaderStageFlags::COMPUTE => Some(shaderc::ShaderKind::Compute),
+            ShaderStageFlags::GEOMETRY => Some(shaderc::ShaderKind::Geometry),
+            ShaderStageFlags::TESSELLATION_CONTROL => Some(shaderc::ShaderKind::TessControl),
+            ShaderStageFlags::TESSELLATION_EVALUATION => Some(shaderc::ShaderKind::TessEvaluation),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "shaderc"))]
+mod tests {
+    use super::*;
+    use crate::{instance::VInstance, test_utils::create_window};
+
+    #[test]
+    fn compiles_glsl_vertex_shader_into_module() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let source =
+            "#version 450\n\nvoid main() {\n    gl_Position = vec4(0.0, 0.0, 0.0, 1.0);\n}\n";
+        let code = VShaderUtils::compile_glsl(source, ShaderStageFlags::VERTEX, "trivial.vert")?;
+        assert_eq!(code[0], 0x0723_0203);
+        let module = VShaderUtils::create_shader_module(&device, &code)?;
+
+        let broken = "#version 450\n\nvoid main() {\n    gl_Position = missing;\n}\n";
+        let err = VShaderUtils::compile_glsl(broken, ShaderStageFlags::VERTEX, "broken.vert")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("broken.vert:4"), "{}", err);
+
+        unsafe { device.get().destroy_shader_module(module, None) };
+        Ok(())
+    }
 }
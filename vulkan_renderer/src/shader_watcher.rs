@@ -0,0 +1,331 @@
+use crate::{
+    device::VDevice, pipeline::VGraphicsPipeline, shader_utils::VShaderUtils, RendererError,
+    RendererResult,
+};
+use ash::vk::{ShaderModule, ShaderStageFlags};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    collections::HashSet,
+    fs, mem,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver},
+};
+
+/// Builds a pipeline out of the current modules of the shaders it was registered with, in the
+/// order given to [`VShaderWatcher::watch_pipeline`]
+type PipelineBuild = Box<dyn Fn(&VDevice, &[ShaderModule]) -> RendererResult<VGraphicsPipeline>>;
+
+struct WatchedShader {
+    path: PathBuf,
+    stage: ShaderStageFlags,
+    module: ShaderModule,
+}
+
+impl WatchedShader {
+    fn compile(
+        device: &VDevice,
+        path: &Path,
+        stage: ShaderStageFlags,
+    ) -> RendererResult<ShaderModule> {
+        let source = fs::read_to_string(path)?;
+        let code = VShaderUtils::compile_glsl(&source, stage, &path.to_string_lossy())?;
+        VShaderUtils::create_shader_module(device, &code)
+    }
+}
+
+struct WatchedPipeline {
+    /// Indices of the shaders the pipeline is built from
+    shaders: Vec<usize>,
+    build: PipelineBuild,
+    pipeline: VGraphicsPipeline,
+}
+
+/// Recompiles GLSL files when they change on disk and rebuilds the pipelines using them, so
+/// shaders can be edited without a restart
+///
+/// The watcher owns the modules and the pipelines registered with
+/// [`watch_pipeline`](Self::watch_pipeline), replaced ones are destroyed
+pub struct VShaderWatcher {
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+    shaders: Vec<WatchedShader>,
+    pipelines: Vec<WatchedPipeline>,
+}
+
+impl VShaderWatcher {
+    /// Compiles every shader once, errors if one of them doesn't compile
+    pub fn new(
+        device: &VDevice,
+        shaders: &[(impl AsRef<Path>, ShaderStageFlags)],
+    ) -> RendererResult<Self> {
+        let (sender, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(sender)?;
+        let mut watched_shaders = Vec::with_capacity(shaders.len());
+        let mut directories = HashSet::new();
+        for (path, stage) in shaders {
+            // Editors often save by replacing the file, which only the directory sees
+            let path = fs::canonicalize(path)?;
+            if let Some(directory) = path
+                .parent()
+                .filter(|dir| directories.insert(dir.to_path_buf()))
+            {
+                watcher.watch(directory, RecursiveMode::NonRecursive)?;
+            }
+            let module = WatchedShader::compile(device, &path, *stage)?;
+            watched_shaders.push(WatchedShader {
+                path,
+                stage: *stage,
+                module,
+            });
+        }
+        Ok(Self {
+            _watcher: watcher,
+            events,
+            shaders: watched_shaders,
+            pipelines: Vec::new(),
+        })
+    }
+
+    /// The current module of the shader at `ind` in the list given to [`new`](Self::new)
+    pub fn module(&self, ind: usize) -> ShaderModule {
+        self.shaders[ind].module
+    }
+
+    /// Builds a pipeline with `build` from the current modules of `shaders`, indices into the list
+    /// given to [`new`](Self::new), and rebuilds it whenever one of them is recompiled
+    ///
+    /// Returns the index to look the pipeline up with
+    pub fn watch_pipeline(
+        &mut self,
+        device: &VDevice,
+        shaders: &[usize],
+        build: impl Fn(&VDevice, &[ShaderModule]) -> RendererResult<VGraphicsPipeline> + 'static,
+    ) -> RendererResult<usize> {
+        if let Some(&ind) = shaders.iter().find(|&&ind| ind >= self.shaders.len()) {
+            return Err(RendererError::InvalidArgument(format!(
+                "Shader {} is not watched, the watcher has {}.",
+                ind,
+                self.shaders.len()
+            )));
+        }
+        let modules = shaders
+            .iter()
+            .map(|&ind| self.shaders[ind].module)
+            .collect::<Vec<_>>();
+        let pipeline = build(device, &modules)?;
+        self.pipelines.push(WatchedPipeline {
+            shaders: shaders.to_vec(),
+            build: Box::new(build),
+            pipeline,
+        });
+        Ok(self.pipelines.len() - 1)
+    }
+
+    /// The current pipeline at `ind`, as returned by [`watch_pipeline`](Self::watch_pipeline)
+    pub fn pipeline(&self, ind: usize) -> &VGraphicsPipeline {
+        &self.pipelines[ind].pipeline
+    }
+
+    /// Recompiles the shaders changed since the last poll, rebuilds the pipelines using them and
+    /// returns the indices of the rebuilt pipelines
+    ///
+    /// Waits for the device to be idle before replacing anything. If a shader doesn't compile or
+    /// a pipeline doesn't build, the error is returned and every shader and pipeline keeps its
+    /// last good version
+    pub fn poll(&mut self, device: &VDevice) -> RendererResult<Vec<usize>> {
+        let mut changed_paths = HashSet::new();
+        for event in self.events.try_iter() {
+            let event = event?;
+            if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                changed_paths.extend(event.paths);
+            }
+        }
+        let changed = (0..self.shaders.len())
+            .filter(|&ind| changed_paths.contains(&self.shaders[ind].path))
+            .collect::<Vec<_>>();
+        if changed.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut modules = self
+            .shaders
+            .iter()
+            .map(|shader| shader.module)
+            .collect::<Vec<_>>();
+        for (compiled, &ind) in changed.iter().enumerate() {
+            let shader = &self.shaders[ind];
+            match WatchedShader::compile(device, &shader.path, shader.stage) {
+                Ok(module) => modules[ind] = module,
+                Err(err) => {
+                    Self::destroy_modules(device, changed[..compiled].iter().map(|&i| modules[i]));
+                    return Err(err);
+                }
+            }
+        }
+
+        let dependent = (0..self.pipelines.len())
+            .filter(|&ind| {
+                self.pipelines[ind]
+                    .shaders
+                    .iter()
+                    .any(|shader| changed.contains(shader))
+            })
+            .collect::<Vec<_>>();
+        let mut rebuilt = Vec::with_capacity(dependent.len());
+        for &ind in &dependent {
+            let watched = &self.pipelines[ind];
+            let pipeline_modules = watched
+                .shaders
+                .iter()
+                .map(|&shader| modules[shader])
+                .collect::<Vec<_>>();
+            match (watched.build)(device, &pipeline_modules) {
+                Ok(pipeline) => rebuilt.push(pipeline),
+                Err(err) => {
+                    for pipeline in &rebuilt {
+                        Self::destroy_pipeline(device, pipeline);
+                    }
+                    Self::destroy_modules(device, changed.iter().map(|&i| modules[i]));
+                    return Err(err);
+                }
+            }
+        }
+
+        unsafe { device.get().device_wait_idle()? };
+        for (&ind, pipeline) in dependent.iter().zip(rebuilt) {
+            let replaced = mem::replace(&mut self.pipelines[ind].pipeline, pipeline);
+            Self::destroy_pipeline(device, &replaced);
+        }
+        for &ind in &changed {
+            let replaced = mem::replace(&mut self.shaders[ind].module, modules[ind]);
+            Self::destroy_modules(device, [replaced]);
+        }
+        Ok(dependent)
+    }
+
+    /// The GPU must be done with the pipelines
+    pub fn destroy(&self, device: &VDevice) {
+        for watched in &self.pipelines {
+            Self::destroy_pipeline(device, &watched.pipeline);
+        }
+        Self::destroy_modules(device, self.shaders.iter().map(|shader| shader.module));
+    }
+
+    fn destroy_pipeline(device: &VDevice, pipeline: &VGraphicsPipeline) {
+        unsafe {
+            device.get().destroy_pipeline(pipeline.pipeline(), None);
+            device
+                .get()
+                .destroy_pipeline_layout(pipeline.pipeline_layout(), None);
+        }
+    }
+
+    fn destroy_modules(device: &VDevice, modules: impl IntoIterator<Item = ShaderModule>) {
+        for module in modules {
+            unsafe { device.get().destroy_shader_module(module, None) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        instance::VInstance,
+        pipeline::VGraphicsPipelineBuilder,
+        render_pass::VRenderPass,
+        test_utils::{create_window, shader_module},
+    };
+    use ash::vk::Format;
+    use std::{
+        process, thread,
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    };
+
+    /// A fresh directory under the temp dir, so concurrent runs don't share files
+    fn unique_temp_dir(name: &str) -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("The clock is before the epoch.")
+            .as_nanos();
+        std::env::temp_dir().join(format!("{}_{}_{}", name, process::id(), nanos))
+    }
+
+    /// Polls until a pipeline is rebuilt, a poll errors or `timeout` passes
+    fn poll_for(
+        watcher: &mut VShaderWatcher,
+        device: &VDevice,
+        timeout: Duration,
+    ) -> RendererResult<Vec<usize>> {
+        let start = Instant::now();
+        loop {
+            let rebuilt = watcher.poll(device)?;
+            if !rebuilt.is_empty() || start.elapsed() > timeout {
+                return Ok(rebuilt);
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn rebuilds_pipeline_of_changed_shader_and_keeps_last_good_one() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let directory = unique_temp_dir("rebuilds_pipeline_of_changed_shader");
+        fs::create_dir_all(&directory)?;
+        let path = directory.join("trivial.vert");
+        let source = |x: f32| {
+            format!(
+                "#version 450\n\nvoid main() {{\n    gl_Position = vec4({:.1}, 0.0, 0.0, 1.0);\n}}\n",
+                x
+            )
+        };
+        fs::write(&path, source(0.0))?;
+
+        let render_pass =
+            VRenderPass::new_default(&device, Format::B8G8R8A8_SRGB, Format::D32_SFLOAT)?;
+        let render_pass_handle = render_pass.get();
+        let fragment_module = shader_module(&device, "solid.frag.spv")?;
+        let mut watcher = VShaderWatcher::new(&device, &[(&path, ShaderStageFlags::VERTEX)])?;
+        let pipeline = watcher.watch_pipeline(&device, &[0], move |device, modules| {
+            VGraphicsPipelineBuilder::start()
+                .shader_stages(&[
+                    (ShaderStageFlags::VERTEX, modules[0]),
+                    (ShaderStageFlags::FRAGMENT, fragment_module),
+                ])
+                .color_blend_state(&[VGraphicsPipelineBuilder::blend_opaque()])
+                .build(device, render_pass_handle)
+        })?;
+        assert!(watcher
+            .watch_pipeline(&device, &[1], |_, _| Ok(VGraphicsPipeline::default()))
+            .is_err());
+
+        let first_module = watcher.module(0);
+        let first_pipeline = watcher.pipeline(pipeline).pipeline();
+        fs::write(&path, source(0.5))?;
+        let rebuilt = poll_for(&mut watcher, &device, Duration::from_secs(5))?;
+        assert_eq!(rebuilt, [pipeline]);
+        assert_ne!(watcher.module(0), first_module);
+        assert_ne!(watcher.pipeline(pipeline).pipeline(), first_pipeline);
+
+        // A save can raise more than one event, let the rest of them through first
+        thread::sleep(Duration::from_millis(100));
+        watcher.poll(&device)?;
+        let good_module = watcher.module(0);
+        let good_pipeline = watcher.pipeline(pipeline).pipeline();
+        fs::write(&path, "#version 450\n\nvoid main() {\n    missing();\n}\n")?;
+        assert!(poll_for(&mut watcher, &device, Duration::from_secs(5)).is_err());
+        assert_eq!(watcher.module(0), good_module);
+        assert_eq!(watcher.pipeline(pipeline).pipeline(), good_pipeline);
+
+        watcher.destroy(&device);
+        unsafe {
+            device.get().destroy_shader_module(fragment_module, None);
+            device.get().destroy_render_pass(render_pass.get(), None);
+        }
+        fs::remove_dir_all(&directory)?;
+        Ok(())
+    }
+}
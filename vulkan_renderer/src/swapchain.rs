@@ -1,18 +1,48 @@
 use crate::{
-    device::VDevice, image::VImage, instance::VInstance, render_pass::VRenderPass, RendererResult,
+    barrier::{BarrierBatch, VImageBarrier},
+    buffer::VBuffer,
+    device::VDevice,
+    enums::EAcquireResult,
+    error::PhysicalDeviceError,
+    image::VImage,
+    instance::VInstance,
+    render_pass::VRenderPass,
+    RendererError, RendererResult,
 };
 use ash::{
     extensions::khr::Swapchain,
+    prelude::VkResult,
     vk::{
-        ColorSpaceKHR, ComponentMapping, ComponentSwizzle, CompositeAlphaFlagsKHR, Extent2D,
-        Extent3D, Fence, Format, Framebuffer, FramebufferCreateInfo, Handle, Image,
-        ImageAspectFlags, ImageSubresourceRange, ImageUsageFlags, ImageView, ImageViewCreateInfo,
-        ImageViewType, PresentInfoKHR, PresentModeKHR, Queue, RenderPass, Semaphore, SharingMode,
-        SurfaceTransformFlagsKHR, SwapchainCreateInfoKHR, SwapchainKHR,
+        self, AccessFlags, BufferUsageFlags, ColorSpaceKHR, CommandBuffer, ComponentMapping,
+        ComponentSwizzle, CompositeAlphaFlagsKHR, Extent2D, Extent3D, Fence, Filter, Format,
+        FormatFeatureFlags, Framebuffer, FramebufferCreateInfo, Handle, Image, ImageAspectFlags,
+        ImageBlit, ImageLayout, ImageSubresourceLayers, ImageSubresourceRange, ImageTiling,
+        ImageUsageFlags, ImageView, ImageViewCreateInfo, ImageViewType, MemoryPropertyFlags,
+        Offset3D, PipelineStageFlags, PresentInfoKHR, PresentModeKHR, Queue, RenderPass, Semaphore,
+        SharingMode, SurfaceCapabilitiesKHR, SurfaceFormatKHR, SurfaceTransformFlagsKHR,
+        SwapchainCreateInfoKHR, SwapchainKHR,
     },
+    Device,
 };
+use std::cell::Cell;
 
+/// Depth formats in order of preference, `D32_SFLOAT` isn't supported everywhere
+pub const DEPTH_FORMAT_CANDIDATES: [Format; 3] = [
+    Format::D32_SFLOAT,
+    Format::D32_SFLOAT_S8_UINT,
+    Format::D24_UNORM_S8_UINT,
+];
+
+/// Depth-stencil formats in order of preference, for stencil effects such as outlines
+pub const STENCIL_FORMAT_CANDIDATES: [Format; 2] =
+    [Format::D24_UNORM_S8_UINT, Format::D32_SFLOAT_S8_UINT];
+
+/// Owns the swapchain and everything created for its images
+///
+/// Keeps a clone of the `ash::Device` handle so that it can destroy its resources on drop,
+/// which means it must be dropped before the `VDevice` it was created from
 pub struct VSwapchain {
+    device: Device,
     swapchain: Swapchain,
     swapchain_khr: SwapchainKHR,
 
@@ -24,23 +54,90 @@ pub struct VSwapchain {
     depth_image: VImage,
     depth_format: Format,
 
-    image_index: usize,
+    format: Format,
+    extent: Extent2D,
+    image_usage: ImageUsageFlags,
+    present_mode: PresentModeKHR,
+    acquired_image: Cell<Option<u32>>,
 }
 
 impl VSwapchain {
-    pub fn new(instance: &VInstance, device: &VDevice, extent: Extent2D) -> RendererResult<Self> {
-        let format = Format::B8G8R8A8_SRGB;
-        let color_space = ColorSpaceKHR::SRGB_NONLINEAR;
-        let present_mode = PresentModeKHR::MAILBOX;
+    /// Falls back to `FIFO` if the surface doesn't support `present_mode`, and clamps
+    /// `image_count` to the counts the surface supports
+    pub fn new(
+        instance: &VInstance,
+        device: &VDevice,
+        extent: Extent2D,
+        present_mode: PresentModeKHR,
+        image_count: u32,
+    ) -> RendererResult<Self> {
+        Self::create(
+            instance,
+            device,
+            extent,
+            present_mode,
+            image_count,
+            &DEPTH_FORMAT_CANDIDATES,
+        )
+    }
+
+    /// Same as [`new`](Self::new) but the depth attachment has a stencil aspect, picked from
+    /// [`STENCIL_FORMAT_CANDIDATES`]
+    pub fn new_with_stencil(
+        instance: &VInstance,
+        device: &VDevice,
+        extent: Extent2D,
+        present_mode: PresentModeKHR,
+        image_count: u32,
+    ) -> RendererResult<Self> {
+        Self::create(
+            instance,
+            device,
+            extent,
+            present_mode,
+            image_count,
+            &STENCIL_FORMAT_CANDIDATES,
+        )
+    }
+
+    fn create(
+        instance: &VInstance,
+        device: &VDevice,
+        extent: Extent2D,
+        present_mode: PresentModeKHR,
+        image_count: u32,
+        depth_format_candidates: &[Format],
+    ) -> RendererResult<Self> {
+        let SurfaceFormatKHR {
+            format,
+            color_space,
+        } = Self::choose_surface_format(&device.get_surface_formats()?);
+        let present_mode =
+            Self::choose_present_mode(&device.get_surface_present_modes()?, present_mode);
+        let image_count = Self::clamp_image_count(&device.get_surface_capabilities(), image_count);
 
         let swapchain = Swapchain::new(instance.get(), device.get());
-        let create_info =
-            Self::swapchain_create_info(device, format, color_space, extent, present_mode);
+        let create_info = Self::swapchain_create_info(
+            device,
+            format,
+            color_space,
+            extent,
+            present_mode,
+            image_count,
+        );
         let swapchain_khr = unsafe { swapchain.create_swapchain(&create_info, None) }?;
         let images = unsafe { swapchain.get_swapchain_images(swapchain_khr)? };
         let image_views = Self::create_image_views(device, &images, format)?;
 
-        let depth_format = Format::D32_SFLOAT;
+        let depth_format = device
+            .find_supported_depth_format(
+                depth_format_candidates,
+                ImageTiling::OPTIMAL,
+                FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT,
+            )
+            .ok_or_else(|| PhysicalDeviceError::NoSupportedFormat {
+                candidates: depth_format_candidates.to_vec(),
+            })?;
         let depth_image = VImage::new(
             device,
             ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
@@ -50,10 +147,10 @@ impl VSwapchain {
                 height: extent.height,
                 depth: 1,
             },
-            ImageAspectFlags::DEPTH,
+            VImage::depth_aspect_mask(depth_format),
         )
         .expect("Failed to create depth buffer.");
-        let render_pass = VRenderPass::new(device.get(), format)?;
+        let render_pass = VRenderPass::new_default(device, format, depth_format)?;
         let framebuffers = Self::create_framebuffers(
             device,
             &image_views,
@@ -63,6 +160,7 @@ impl VSwapchain {
         );
 
         Ok(Self {
+            device: device.get().clone(),
             swapchain,
             swapchain_khr,
             images,
@@ -70,10 +168,14 @@ impl VSwapchain {
             framebuffers,
             render_pass,
 
-            depth_format: Format::D32_SFLOAT,
+            depth_format,
             depth_image,
 
-            image_index: 0,
+            format,
+            extent,
+            image_usage: create_info.image_usage,
+            present_mode,
+            acquired_image: Cell::new(None),
         })
     }
 
@@ -85,52 +187,225 @@ impl VSwapchain {
         self.swapchain_khr
     }
 
-    pub fn get_current_image(&self) -> Image {
-        self.images[self.image_index]
+    pub fn get_image(&self, image_index: u32) -> Image {
+        self.images[image_index as usize]
     }
 
-    pub fn get_current_image_view(&self) -> ImageView {
-        self.image_views[self.image_index]
+    pub fn get_image_view(&self, image_index: u32) -> ImageView {
+        self.image_views[image_index as usize]
     }
 
-    pub fn get_current_framebuffer(&self) -> Framebuffer {
-        self.framebuffers[self.image_index]
+    pub fn get_framebuffer(&self, image_index: u32) -> Framebuffer {
+        self.framebuffers[image_index as usize]
     }
 
     pub fn get_image_views(&self) -> &[ImageView] {
         &self.image_views
     }
 
+    /// How many images the swapchain ended up with, per-image resources need one each
+    pub fn image_count(&self) -> usize {
+        self.images.len()
+    }
+
+    /// The image returned by the last successful acquire, `None` before the first one
+    pub fn current_image_index(&self) -> Option<usize> {
+        self.acquired_image
+            .get()
+            .map(|image_index| image_index as usize)
+    }
+
     pub fn get_renderpass(&self) -> RenderPass {
         self.render_pass.get()
     }
 
-    pub fn get_depth_image(&self) -> VImage {
-        self.depth_image
+    pub fn get_depth_image(&self) -> &VImage {
+        &self.depth_image
     }
 
     pub fn get_depth_format(&self) -> Format {
         self.depth_format
     }
 
+    pub fn get_format(&self) -> Format {
+        self.format
+    }
+
+    pub fn get_extent(&self) -> Extent2D {
+        self.extent
+    }
+
+    /// The present mode actually used, which may be the `FIFO` fallback
+    pub fn get_present_mode(&self) -> PresentModeKHR {
+        self.present_mode
+    }
+
+    /// Waits up to `timeout` nanoseconds for the next image
+    ///
+    /// The index of an acquired image is what has to be rendered to and passed to
+    /// [`queue_present`](Self::queue_present), other results signal neither `semaphore` nor `fence`
     pub fn acquire_next_image(
-        &mut self,
+        &self,
         semaphore: Option<Semaphore>,
         fence: Option<Fence>,
-    ) -> RendererResult<bool> {
+        timeout: u64,
+    ) -> RendererResult<EAcquireResult> {
         let fence = fence.unwrap_or_else(|| Fence::from_raw(0));
         let semaphore = semaphore.unwrap_or_else(|| Semaphore::from_raw(0));
-        let (image_index, is_suboptimal) = unsafe {
+        let acquired = Self::acquire_result(unsafe {
             self.swapchain
-                .acquire_next_image(self.swapchain_khr, u64::MAX, semaphore, fence)?
+                .acquire_next_image(self.swapchain_khr, timeout, semaphore, fence)
+        })?;
+        if let Some(image_index) = acquired.image_index() {
+            self.acquired_image.set(Some(image_index));
+        }
+        Ok(acquired)
+    }
+
+    fn acquire_result(result: VkResult<(u32, bool)>) -> RendererResult<EAcquireResult> {
+        match result {
+            Ok((image_index, false)) => Ok(EAcquireResult::Acquired(image_index)),
+            Ok((image_index, true)) => Ok(EAcquireResult::Suboptimal(image_index)),
+            Err(vk::Result::TIMEOUT) => Ok(EAcquireResult::Timeout),
+            Err(vk::Result::NOT_READY) => Ok(EAcquireResult::NotReady),
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => Ok(EAcquireResult::OutOfDate),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Reads the most recently acquired image back as tightly packed RGBA bytes, row by row
+    ///
+    /// Call it after submitting the frame and before presenting it, while the image is still owned
+    /// by the application and in `PRESENT_SRC_KHR`. BGRA formats are swizzled on the host, formats
+    /// without 8-bit channels are blitted to an RGBA8 image first
+    pub fn capture_current_image(&self, device: &VDevice) -> RendererResult<Vec<u8>> {
+        let image_index = self.acquired_image.get().ok_or_else(|| {
+            RendererError::InvalidArgument("No swapchain image has been acquired.".to_owned())
+        })?;
+        if !self.image_usage.contains(ImageUsageFlags::TRANSFER_SRC) {
+            return Err(RendererError::InvalidArgument(format!(
+                "Swapchain images need TRANSFER_SRC usage to be captured, got {:?}.",
+                self.image_usage
+            )));
+        }
+        let needs_blit = !Self::is_rgba8(self.format) && !Self::is_bgra8(self.format);
+        let blit_src = device
+            .get_format_properties(self.format)
+            .optimal_tiling_features
+            .contains(FormatFeatureFlags::BLIT_SRC);
+        if needs_blit && !blit_src {
+            return Err(RendererError::InvalidArgument(format!(
+                "Swapchain format {:?} can neither be copied nor blitted.",
+                self.format
+            )));
+        }
+
+        let extent = Extent3D {
+            width: self.extent.width,
+            height: self.extent.height,
+            depth: 1,
         };
-        self.image_index = image_index as usize;
-        Ok(is_suboptimal)
+        let image = VImage::from_external(
+            self.get_image(image_index),
+            self.image_usage,
+            self.format,
+            extent,
+        );
+        let intermediate = if needs_blit {
+            Some(VImage::new(
+                device,
+                ImageUsageFlags::TRANSFER_DST | ImageUsageFlags::TRANSFER_SRC,
+                Format::R8G8B8A8_UNORM,
+                extent,
+                ImageAspectFlags::COLOR,
+            )?)
+        } else {
+            None
+        };
+        let size = extent.width as usize * extent.height as usize * 4;
+        let buffer = VBuffer::new_mapped(
+            device,
+            &vec![0u8; size],
+            BufferUsageFlags::TRANSFER_DST,
+            MemoryPropertyFlags::HOST_VISIBLE | MemoryPropertyFlags::HOST_COHERENT,
+        )?;
+
+        let pixels = VBuffer::submit_one_time(device, |command_buffer| {
+            let mut batch = BarrierBatch::new();
+            batch.add_transition(
+                VImageBarrier::transition(
+                    &image,
+                    ImageLayout::PRESENT_SRC_KHR,
+                    ImageLayout::TRANSFER_SRC_OPTIMAL,
+                )
+                .src(
+                    AccessFlags::COLOR_ATTACHMENT_WRITE,
+                    PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                ),
+            );
+            if let Some(intermediate) = &intermediate {
+                batch.transition_image(
+                    intermediate,
+                    ImageLayout::UNDEFINED,
+                    ImageLayout::TRANSFER_DST_OPTIMAL,
+                );
+            }
+            batch.flush(device, command_buffer);
+
+            let copy_source = match &intermediate {
+                Some(intermediate) => {
+                    Self::record_blit(device, command_buffer, &image, intermediate);
+                    VImageBarrier::transition(
+                        intermediate,
+                        ImageLayout::TRANSFER_DST_OPTIMAL,
+                        ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    )
+                    .record(device, command_buffer);
+                    intermediate
+                }
+                None => &image,
+            };
+            VBuffer::record_image_to_buffer_copy(
+                device,
+                command_buffer,
+                copy_source,
+                buffer.buffer(),
+            );
+            VImageBarrier::transition(
+                &image,
+                ImageLayout::TRANSFER_SRC_OPTIMAL,
+                ImageLayout::PRESENT_SRC_KHR,
+            )
+            .record(device, command_buffer);
+        })
+        .and_then(|_| buffer.read_back::<u8>(device));
+
+        unsafe {
+            device.get().destroy_buffer(buffer.buffer(), None);
+            device.get().free_memory(buffer.memory(), None);
+            if let Some(intermediate) = intermediate {
+                device
+                    .get()
+                    .destroy_image_view(intermediate.image_view(), None);
+                device.get().destroy_image(intermediate.image(), None);
+                device.get().free_memory(intermediate.memory(), None);
+            }
+        }
+        let mut pixels = pixels?;
+        if Self::is_bgra8(self.format) {
+            Self::swap_red_and_blue(&mut pixels);
+        }
+        Ok(pixels)
     }
 
-    pub fn queue_present(&self, queue: Queue, wait_semaphores: &[Semaphore]) -> RendererResult<()> {
+    pub fn queue_present(
+        &self,
+        queue: Queue,
+        wait_semaphores: &[Semaphore],
+        image_index: u32,
+    ) -> RendererResult<()> {
         let present_info = PresentInfoKHR {
-            p_image_indices: &(self.image_index as u32),
+            p_image_indices: &image_index,
             wait_semaphore_count: wait_semaphores.len() as u32,
             p_wait_semaphores: wait_semaphores.as_ptr(),
             swapchain_count: 1,
@@ -141,6 +416,58 @@ impl VSwapchain {
         Ok(())
     }
 
+    fn is_rgba8(format: Format) -> bool {
+        matches!(
+            format,
+            Format::R8G8B8A8_UNORM | Format::R8G8B8A8_SRGB | Format::R8G8B8A8_SNORM
+        )
+    }
+
+    fn is_bgra8(format: Format) -> bool {
+        matches!(
+            format,
+            Format::B8G8R8A8_UNORM | Format::B8G8R8A8_SRGB | Format::B8G8R8A8_SNORM
+        )
+    }
+
+    fn swap_red_and_blue(pixels: &mut [u8]) {
+        for pixel in pixels.chunks_exact_mut(4) {
+            pixel.swap(0, 2);
+        }
+    }
+
+    /// Blits the whole of `src` in `TRANSFER_SRC_OPTIMAL` to `dst` in `TRANSFER_DST_OPTIMAL`
+    fn record_blit(device: &VDevice, command_buffer: CommandBuffer, src: &VImage, dst: &VImage) {
+        let layers = ImageSubresourceLayers {
+            aspect_mask: ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let corner = Offset3D {
+            x: src.extent().width as i32,
+            y: src.extent().height as i32,
+            z: 1,
+        };
+        let blit = ImageBlit {
+            src_subresource: layers,
+            src_offsets: [Offset3D::default(), corner],
+            dst_subresource: layers,
+            dst_offsets: [Offset3D::default(), corner],
+        };
+        unsafe {
+            device.get().cmd_blit_image(
+                command_buffer,
+                src.image(),
+                ImageLayout::TRANSFER_SRC_OPTIMAL,
+                dst.image(),
+                ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[blit],
+                Filter::NEAREST,
+            );
+        }
+    }
+
     fn create_image_views(
         device: &VDevice,
         images: &[Image],
@@ -155,7 +482,7 @@ impl VSwapchain {
             .collect();
         match image_views_result {
             Ok(image_views) => Ok(image_views),
-            Err(err) => Err(Box::new(err)),
+            Err(err) => Err(err.into()),
         }
     }
 
@@ -189,22 +516,58 @@ impl VSwapchain {
         }
     }
 
+    /// Prefers 8-bit sRGB formats, a lone `UNDEFINED` format means the surface takes any
+    fn choose_surface_format(formats: &[SurfaceFormatKHR]) -> SurfaceFormatKHR {
+        let preferred =
+            [Format::B8G8R8A8_SRGB, Format::R8G8B8A8_SRGB].map(|format| SurfaceFormatKHR {
+                format,
+                color_space: ColorSpaceKHR::SRGB_NONLINEAR,
+            });
+        let any_format = formats
+            .iter()
+            .all(|format| format.format == Format::UNDEFINED);
+        if any_format {
+            return preferred[0];
+        }
+        preferred
+            .into_iter()
+            .find(|format| formats.contains(format))
+            .unwrap_or(formats[0])
+    }
+
+    /// `FIFO` is the only present mode every surface supports
+    fn choose_present_mode(
+        supported: &[PresentModeKHR],
+        desired: PresentModeKHR,
+    ) -> PresentModeKHR {
+        match supported.contains(&desired) {
+            true => desired,
+            false => PresentModeKHR::FIFO,
+        }
+    }
+
+    /// A `max_image_count` of 0 means there is no maximum
+    fn clamp_image_count(surface_capabilities: &SurfaceCapabilitiesKHR, image_count: u32) -> u32 {
+        let image_count = image_count.max(surface_capabilities.min_image_count);
+        match surface_capabilities.max_image_count {
+            0 => image_count,
+            max_image_count => image_count.min(max_image_count),
+        }
+    }
+
     fn swapchain_create_info(
         device: &VDevice,
         image_format: Format,
         image_color_space: ColorSpaceKHR,
         image_extent: Extent2D,
         present_mode: PresentModeKHR,
+        image_count: u32,
     ) -> SwapchainCreateInfoKHR {
         let surface_capabilities = device.get_surface_capabilities();
-        let min_image_count = surface_capabilities.min_image_count;
-        let max_image_count = surface_capabilities.max_image_count;
-        let mut desired_image_count = min_image_count + 1;
-        if max_image_count > 0 && desired_image_count > max_image_count {
-            desired_image_count = max_image_count;
-        }
 
-        let image_usage = ImageUsageFlags::COLOR_ATTACHMENT;
+        // Transfer source usage lets frames be captured with `capture_current_image`
+        let image_usage = ImageUsageFlags::COLOR_ATTACHMENT
+            | (surface_capabilities.supported_usage_flags & ImageUsageFlags::TRANSFER_SRC);
         let sharing_mode = SharingMode::EXCLUSIVE;
         let pre_transform = if surface_capabilities
             .supported_transforms
@@ -219,7 +582,7 @@ impl VSwapchain {
         let image_array_layers = 1;
         SwapchainCreateInfoKHR {
             surface: device.get_surface_khr(),
-            min_image_count: desired_image_count,
+            min_image_count: image_count,
             image_format,
             image_color_space,
             image_extent,
@@ -256,3 +619,226 @@ impl VSwapchain {
         }
     }
 }
+
+impl Drop for VSwapchain {
+    fn drop(&mut self) {
+        unsafe {
+            self.device
+                .device_wait_idle()
+                .expect("Failed to wait for device idle.");
+            for &framebuffer in &self.framebuffers {
+                self.device.destroy_framebuffer(framebuffer, None);
+            }
+            for &image_view in &self.image_views {
+                self.device.destroy_image_view(image_view, None);
+            }
+            self.device
+                .destroy_image_view(self.depth_image.image_view(), None);
+            self.device.destroy_image(self.depth_image.image(), None);
+            self.device.free_memory(self.depth_image.memory(), None);
+            self.device
+                .destroy_render_pass(self.render_pass.get(), None);
+            // The swapchain images themselves are owned by the swapchain
+            self.swapchain.destroy_swapchain(self.swapchain_khr, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cmd::{cmd_begin_render_pass, cmd_end_render_pass, ClearValues},
+        sync::VFence,
+        test_utils::create_window,
+    };
+
+    #[test]
+    fn falls_back_to_fifo_and_clamps_image_count() {
+        let supported = [PresentModeKHR::FIFO, PresentModeKHR::MAILBOX];
+        assert_eq!(
+            VSwapchain::choose_present_mode(&supported, PresentModeKHR::MAILBOX),
+            PresentModeKHR::MAILBOX
+        );
+        assert_eq!(
+            VSwapchain::choose_present_mode(&supported, PresentModeKHR::IMMEDIATE),
+            PresentModeKHR::FIFO
+        );
+
+        let capabilities = SurfaceCapabilitiesKHR {
+            min_image_count: 2,
+            max_image_count: 4,
+            ..Default::default()
+        };
+        assert_eq!(VSwapchain::clamp_image_count(&capabilities, 1), 2);
+        assert_eq!(VSwapchain::clamp_image_count(&capabilities, 8), 4);
+        let unbounded = SurfaceCapabilitiesKHR {
+            max_image_count: 0,
+            ..capabilities
+        };
+        assert_eq!(VSwapchain::clamp_image_count(&unbounded, 8), 8);
+    }
+
+    #[test]
+    fn maps_acquire_results_to_outcomes() {
+        assert_eq!(
+            VSwapchain::acquire_result(Ok((2, false))).unwrap(),
+            EAcquireResult::Acquired(2)
+        );
+        assert_eq!(
+            VSwapchain::acquire_result(Ok((1, true))).unwrap(),
+            EAcquireResult::Suboptimal(1)
+        );
+        for (result, outcome) in [
+            (vk::Result::TIMEOUT, EAcquireResult::Timeout),
+            (vk::Result::NOT_READY, EAcquireResult::NotReady),
+            (vk::Result::ERROR_OUT_OF_DATE_KHR, EAcquireResult::OutOfDate),
+        ] {
+            assert_eq!(VSwapchain::acquire_result(Err(result)).unwrap(), outcome);
+        }
+        assert_eq!(EAcquireResult::Timeout.image_index(), None);
+        assert!(matches!(
+            VSwapchain::acquire_result(Err(vk::Result::ERROR_SURFACE_LOST_KHR)),
+            Err(RendererError::Vulkan(vk::Result::ERROR_SURFACE_LOST_KHR))
+        ));
+    }
+
+    #[test]
+    fn prefers_srgb_surface_formats() {
+        let surface_format = |format| SurfaceFormatKHR {
+            format,
+            color_space: ColorSpaceKHR::SRGB_NONLINEAR,
+        };
+        let formats = [
+            surface_format(Format::B8G8R8A8_UNORM),
+            surface_format(Format::R8G8B8A8_SRGB),
+        ];
+        assert_eq!(
+            VSwapchain::choose_surface_format(&formats),
+            surface_format(Format::R8G8B8A8_SRGB)
+        );
+        assert_eq!(
+            VSwapchain::choose_surface_format(&formats[..1]),
+            surface_format(Format::B8G8R8A8_UNORM)
+        );
+        assert_eq!(
+            VSwapchain::choose_surface_format(&[surface_format(Format::UNDEFINED)]),
+            surface_format(Format::B8G8R8A8_SRGB)
+        );
+    }
+
+    #[test]
+    fn chooses_supported_surface_format_and_present_mode() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+        let extent = Extent2D {
+            width: 64,
+            height: 64,
+        };
+
+        let swapchain = VSwapchain::new(&instance, &device, extent, PresentModeKHR::IMMEDIATE, 3)?;
+        let supported = device.get_surface_present_modes()?;
+        assert!(supported.contains(&swapchain.get_present_mode()));
+        let formats = device.get_surface_formats()?;
+        assert!(formats
+            .iter()
+            .any(|surface_format| surface_format.format == swapchain.get_format()));
+        let capabilities = device.get_surface_capabilities();
+        assert!(swapchain.images.len() as u32 >= capabilities.min_image_count);
+        Ok(())
+    }
+
+    #[test]
+    fn creates_and_drops_swapchain_repeatedly() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+        let extent = Extent2D {
+            width: window.inner_size().width,
+            height: window.inner_size().height,
+        };
+        let min_image_count = device.get_surface_capabilities().min_image_count as usize;
+        // Leaked objects are reported by the validation layer when the device is destroyed
+        for _ in 0..10 {
+            let swapchain = VSwapchain::new(&instance, &device, extent, PresentModeKHR::FIFO, 3)?;
+            assert_ne!(swapchain.get_swapchain_khr(), SwapchainKHR::null());
+            assert_eq!(swapchain.get_present_mode(), PresentModeKHR::FIFO);
+            assert!(swapchain.image_count() >= min_image_count);
+            assert_eq!(swapchain.get_image_views().len(), swapchain.image_count());
+            for image_index in 0..swapchain.image_count() as u32 {
+                assert_ne!(swapchain.get_framebuffer(image_index), Framebuffer::null());
+            }
+            drop(swapchain);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn counts_images_and_tracks_acquired_index() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+        let extent = Extent2D {
+            width: window.inner_size().width,
+            height: window.inner_size().height,
+        };
+        let swapchain = VSwapchain::new(&instance, &device, extent, PresentModeKHR::FIFO, 3)?;
+        assert_eq!(swapchain.image_count(), swapchain.get_image_views().len());
+        assert_eq!(swapchain.current_image_index(), None);
+
+        let fence = VFence::new(&device, false)?;
+        let acquired = swapchain.acquire_next_image(None, Some(fence.get()), u64::MAX)?;
+        device.wait_for_fences(&[fence.get()], u64::MAX)?;
+        assert_eq!(
+            swapchain.current_image_index(),
+            acquired
+                .image_index()
+                .map(|image_index| image_index as usize)
+        );
+
+        unsafe { device.get().destroy_fence(fence.get(), None) };
+        Ok(())
+    }
+
+    #[test]
+    fn captures_cleared_frame_before_presenting() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+        let extent = Extent2D {
+            width: window.inner_size().width,
+            height: window.inner_size().height,
+        };
+        let swapchain = VSwapchain::new(&instance, &device, extent, PresentModeKHR::FIFO, 3)?;
+        assert!(swapchain.capture_current_image(&device).is_err());
+
+        let fence = VFence::new(&device, false)?;
+        let image_index = swapchain
+            .acquire_next_image(None, Some(fence.get()), u64::MAX)?
+            .image_index()
+            .expect("Failed to acquire an image.");
+        device.wait_for_fences(&[fence.get()], u64::MAX)?;
+        let clear_values = ClearValues::start().color(0.0, 0.5, 1.0, 1.0).build();
+        VBuffer::submit_one_time(&device, |command_buffer| {
+            cmd_begin_render_pass(
+                &device,
+                command_buffer,
+                swapchain.get_renderpass(),
+                swapchain.get_framebuffer(image_index),
+                &clear_values,
+                extent,
+            );
+            cmd_end_render_pass(&device, command_buffer);
+        })?;
+
+        let pixels = swapchain.capture_current_image(&device)?;
+        assert_eq!(pixels.len(), (extent.width * extent.height * 4) as usize);
+        assert!(pixels
+            .chunks(4)
+            .all(|pixel| pixel[0] == 0 && pixel[1] > 0 && pixel[2] == 255));
+
+        unsafe { device.get().destroy_fence(fence.get(), None) };
+        Ok(())
+    }
+}
@@ -1,5 +1,10 @@
-use crate::{device::VDevice, RendererResult};
-use ash::vk::{Fence, FenceCreateFlags, FenceCreateInfo, Semaphore, SemaphoreCreateInfo};
+use crate::{device::VDevice, impl_get, swapchain::VSwapchain, RendererError, RendererResult};
+use ash::vk::{
+    CommandBuffer, Fence, FenceCreateFlags, FenceCreateInfo, PipelineStageFlags, Queue, Semaphore,
+    SemaphoreCreateInfo, SemaphoreSignalInfo, SemaphoreType, SemaphoreTypeCreateInfo,
+    SemaphoreWaitInfo, SubmitInfo,
+};
+use std::ffi::c_void;
 
 #[derive(Default, Debug, Clone, Copy)]
 pub struct VFence {
@@ -41,13 +46,377 @@ impl VSemaphore {
         Ok(Self { semaphore })
     }
 
+    /// A timeline semaphore starting at `initial_value`, errors if the device doesn't support them
+    pub fn new_timeline(device: &VDevice, initial_value: u64) -> RendererResult<Self> {
+        if !device.timeline_semaphore_enabled() {
+            return Err(RendererError::InvalidArgument(
+                "The device does not support timeline semaphores.".to_owned(),
+            ));
+        }
+        let type_create_info = Self::semaphore_type_create_info(initial_value);
+        let create_info = SemaphoreCreateInfo {
+            p_next: &type_create_info as *const _ as *const c_void,
+            ..Self::semaphore_create_info()
+        };
+        let semaphore = unsafe { device.get().create_semaphore(&create_info, None)? };
+        Ok(Self { semaphore })
+    }
+
     pub fn get(&self) -> Semaphore {
         self.semaphore
     }
 
+    /// Sets a timeline semaphore to `value` from the host, which must be greater than its current value
+    pub fn signal(&self, device: &VDevice, value: u64) -> RendererResult<()> {
+        let signal_info = SemaphoreSignalInfo {
+            semaphore: self.semaphore,
+            value,
+            ..Default::default()
+        };
+        unsafe { device.get().signal_semaphore(&signal_info)? };
+        Ok(())
+    }
+
+    /// Blocks until a timeline semaphore reaches `value` or `timeout` nanoseconds pass
+    pub fn wait(&self, device: &VDevice, value: u64, timeout: u64) -> RendererResult<()> {
+        let semaphores = [self.semaphore];
+        let values = [value];
+        let wait_info = SemaphoreWaitInfo {
+            semaphore_count: 1,
+            p_semaphores: semaphores.as_ptr(),
+            p_values: values.as_ptr(),
+            ..Default::default()
+        };
+        unsafe { device.get().wait_semaphores(&wait_info, timeout)? };
+        Ok(())
+    }
+
+    /// The current counter value of a timeline semaphore
+    pub fn value(&self, device: &VDevice) -> RendererResult<u64> {
+        Ok(unsafe { device.get().get_semaphore_counter_value(self.semaphore)? })
+    }
+
+    fn semaphore_type_create_info(initial_value: u64) -> SemaphoreTypeCreateInfo {
+        SemaphoreTypeCreateInfo {
+            semaphore_type: SemaphoreType::TIMELINE,
+            initial_value,
+            ..Default::default()
+        }
+    }
+
     fn semaphore_create_info() -> SemaphoreCreateInfo {
         SemaphoreCreateInfo {
             ..Default::default()
         }
     }
 }
+
+/// Pairs the semaphores of a frame with the stages that wait on them
+///
+/// The submit waits for the acquired image only where the color attachment is written, then
+/// signals the render semaphore that the present waits on
+#[derive(Debug, Clone, Copy)]
+pub struct FrameSubmit {
+    wait_semaphores: [Semaphore; 1],
+    wait_stages: [PipelineStageFlags; 1],
+    signal_semaphores: [Semaphore; 1],
+    fence: Fence,
+}
+
+impl FrameSubmit {
+    /// The earliest stage that touches the swapchain image, waiting at `TOP_OF_PIPE` would stall
+    /// the whole frame on the acquire
+    pub const ACQUIRE_WAIT_STAGE: PipelineStageFlags = PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT;
+
+    /// `fence` is signaled once the submitted command buffers are done
+    pub fn new(acquire_semaphore: Semaphore, render_semaphore: Semaphore, fence: Fence) -> Self {
+        Self {
+            wait_semaphores: [acquire_semaphore],
+            wait_stages: [Self::ACQUIRE_WAIT_STAGE],
+            signal_semaphores: [render_semaphore],
+            fence,
+        }
+    }
+
+    pub fn submit(
+        &self,
+        device: &VDevice,
+        queue: Queue,
+        command_buffers: &[CommandBuffer],
+    ) -> RendererResult<()> {
+        let submit_info = self.submit_info(command_buffers);
+        device.queue_submit(queue, &[submit_info], self.fence)
+    }
+
+    /// Presents `image_index` once the submitted frame has finished rendering
+    pub fn present(
+        &self,
+        swapchain: &VSwapchain,
+        queue: Queue,
+        image_index: u32,
+    ) -> RendererResult<()> {
+        swapchain.queue_present(queue, &self.signal_semaphores, image_index)
+    }
+
+    fn submit_info(&self, command_buffers: &[CommandBuffer]) -> SubmitInfo {
+        VDevice::create_queue_submit_info(
+            command_buffers,
+            &self.wait_semaphores,
+            &self.signal_semaphores,
+            &self.wait_stages,
+        )
+    }
+}
+
+/// Hands out swapchain acquire semaphores that no pending acquire or submit still uses
+///
+/// Holds one semaphore per swapchain image plus a spare. A semaphore handed out by [`take`](Self::take)
+/// is tied to the acquired image with [`assign`](Self::assign), which recycles the one the image
+/// held before, whose wait was submitted with that image's previous frame
+#[derive(Default, Debug, Clone)]
+pub struct VSemaphorePool {
+    free: Vec<Semaphore>,
+    per_image: Vec<Option<Semaphore>>,
+}
+
+impl VSemaphorePool {
+    pub fn new(device: &VDevice, swapchain: &VSwapchain) -> RendererResult<Self> {
+        let image_count = swapchain.image_count();
+        let semaphores = (0..=image_count)
+            .map(|_| Ok(VSemaphore::new(device)?.get()))
+            .collect::<RendererResult<Vec<_>>>()?;
+        Ok(Self::from_semaphores(semaphores, image_count))
+    }
+
+    fn from_semaphores(semaphores: Vec<Semaphore>, image_count: usize) -> Self {
+        Self {
+            free: semaphores,
+            per_image: vec![None; image_count],
+        }
+    }
+
+    /// An unused semaphore to acquire the next image with
+    pub fn take(&mut self) -> Semaphore {
+        self.free
+            .pop()
+            .expect("Every acquire semaphore is in use, assign or recycle them after acquiring.")
+    }
+
+    /// Ties `semaphore` to the acquired image and recycles the one it held before
+    pub fn assign(&mut self, image_index: u32, semaphore: Semaphore) {
+        if let Some(previous) = self.per_image[image_index as usize].replace(semaphore) {
+            self.free.push(previous);
+        }
+    }
+
+    /// Returns a semaphore whose acquire failed, so it was never signaled
+    pub fn recycle(&mut self, semaphore: Semaphore) {
+        self.free.push(semaphore);
+    }
+
+    /// The device must be idle
+    pub fn destroy(&mut self, device: &VDevice) {
+        let semaphores = self
+            .free
+            .drain(..)
+            .chain(self.per_image.iter_mut().flat_map(Option::take));
+        for semaphore in semaphores {
+            unsafe { device.get().destroy_semaphore(semaphore, None) };
+        }
+    }
+}
+
+/// The semaphores and fence of one frame in flight, handed out by [`VFrameSync::begin_frame`]
+#[derive(Default, Debug, Clone, Copy)]
+pub struct VFrameSyncObjects {
+    frame_index: usize,
+    image_available: Semaphore,
+    render_finished: Semaphore,
+    in_flight: Fence,
+}
+
+impl_get!(VFrameSyncObjects, frame_index, usize);
+impl_get!(VFrameSyncObjects, image_available, Semaphore);
+impl_get!(VFrameSyncObjects, render_finished, Semaphore);
+impl_get!(VFrameSyncObjects, in_flight, Fence);
+
+/// Cycles through the sync objects of `frame_count` frames in flight
+///
+/// Call [`begin_frame`](Self::begin_frame), acquire with its `image_available` semaphore, pass the
+/// index to [`image_acquired`](Self::image_acquired), submit with `in_flight` and present, then
+/// [`end_frame`](Self::end_frame). An image can come back while an older frame still renders to
+/// it, so the fence of the frame that last used each image is tracked and waited on
+#[derive(Default, Debug, Clone)]
+pub struct VFrameSync {
+    frames: Vec<VFrameSyncObjects>,
+    images_in_flight: Vec<Fence>,
+    frame_index: usize,
+}
+
+impl VFrameSync {
+    /// `image_count` is the number of swapchain images
+    pub fn new(device: &VDevice, frame_count: usize, image_count: usize) -> RendererResult<Self> {
+        let frames = (0..frame_count)
+            .map(|frame_index| {
+                Ok(VFrameSyncObjects {
+                    frame_index,
+                    image_available: VSemaphore::new(device)?.get(),
+                    render_finished: VSemaphore::new(device)?.get(),
+                    in_flight: VFence::new(device, true)?.get(),
+                })
+            })
+            .collect::<RendererResult<Vec<_>>>()?;
+        Ok(Self::from_frames(frames, image_count))
+    }
+
+    fn from_frames(frames: Vec<VFrameSyncObjects>, image_count: usize) -> Self {
+        Self {
+            frames,
+            images_in_flight: vec![Fence::null(); image_count],
+            frame_index: 0,
+        }
+    }
+
+    /// Waits until the GPU is done with the current frame
+    ///
+    /// The fence stays signaled until [`image_acquired`](Self::image_acquired), so a frame skipped
+    /// because no image was acquired doesn't leave the next wait hanging
+    pub fn begin_frame(&self, device: &VDevice) -> RendererResult<VFrameSyncObjects> {
+        let frame = self.current();
+        device.wait_for_fences(&[frame.in_flight], u64::MAX)?;
+        Ok(frame)
+    }
+
+    /// Waits for the older frame still rendering to `image_index`, hands the image to this frame
+    /// and resets its fence for the next submit
+    pub fn image_acquired(&mut self, device: &VDevice, image_index: u32) -> RendererResult<()> {
+        if let Some(fence) = self.track_image(image_index) {
+            device.wait_for_fences(&[fence], u64::MAX)?;
+        }
+        device.reset_fences(&[self.current().in_flight])
+    }
+
+    /// Moves on to the next frame's sync objects
+    pub fn end_frame(&mut self) {
+        self.frame_index = (self.frame_index + 1) % self.frames.len();
+    }
+
+    pub fn current(&self) -> VFrameSyncObjects {
+        self.frames[self.frame_index]
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// The device must be idle
+    pub fn destroy(&self, device: &VDevice) {
+        for frame in &self.frames {
+            unsafe {
+                device.get().destroy_semaphore(frame.image_available, None);
+                device.get().destroy_semaphore(frame.render_finished, None);
+                device.get().destroy_fence(frame.in_flight, None);
+            }
+        }
+    }
+
+    /// The fence of another frame that last used `image_index`; the current frame's own fence was
+    /// already waited on in `begin_frame`, so it must not be waited on again
+    fn track_image(&mut self, image_index: u32) -> Option<Fence> {
+        let current = self.current().in_flight;
+        let previous = std::mem::replace(&mut self.images_in_flight[image_index as usize], current);
+        (previous != Fence::null() && previous != current).then_some(previous)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{instance::VInstance, test_utils::create_window};
+    use ash::vk::Handle;
+
+    #[test]
+    fn waits_for_acquire_at_color_attachment_output() {
+        let acquire_semaphore = Semaphore::from_raw(1);
+        let render_semaphore = Semaphore::from_raw(2);
+        let frame_submit = FrameSubmit::new(acquire_semaphore, render_semaphore, Fence::null());
+        let command_buffers = [CommandBuffer::from_raw(3)];
+
+        let submit_info = frame_submit.submit_info(&command_buffers);
+        assert_eq!(submit_info.wait_semaphore_count, 1);
+        assert_eq!(submit_info.signal_semaphore_count, 1);
+        unsafe {
+            assert_eq!(*submit_info.p_wait_semaphores, acquire_semaphore);
+            assert_eq!(
+                *submit_info.p_wait_dst_stage_mask,
+                PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+            );
+            assert_eq!(*submit_info.p_signal_semaphores, render_semaphore);
+        }
+    }
+
+    #[test]
+    fn waits_for_host_signaled_timeline() -> RendererResult<()> {
+        let instance = VInstance::new("Test", 0)?;
+        let (_event_loop, window) = create_window();
+        let device = VDevice::new(&instance, &window)?;
+
+        let timeline = VSemaphore::new_timeline(&device, 0)?;
+        assert_eq!(timeline.value(&device)?, 0);
+        timeline.signal(&device, 5)?;
+        timeline.wait(&device, 5, 1_000_000_000)?;
+        assert_eq!(timeline.value(&device)?, 5);
+
+        unsafe { device.get().destroy_semaphore(timeline.get(), None) };
+        Ok(())
+    }
+
+    #[test]
+    fn recycles_semaphore_of_reacquired_image() {
+        let semaphores = (1..=3).map(Semaphore::from_raw).collect::<Vec<_>>();
+        let mut pool = VSemaphorePool::from_semaphores(semaphores, 2);
+
+        // With more frames in flight than images, the same image can come back before the others
+        for image_index in [0, 1, 0, 0, 1] {
+            let semaphore = pool.take();
+            assert!(!pool.per_image.contains(&Some(semaphore)));
+            pool.assign(image_index, semaphore);
+        }
+        assert_eq!(pool.free.len(), 1);
+
+        let semaphore = pool.take();
+        pool.recycle(semaphore);
+        assert_eq!(pool.take(), semaphore);
+    }
+
+    #[test]
+    fn waits_for_frame_still_using_acquired_image() {
+        let frames = (0..2)
+            .map(|frame_index| VFrameSyncObjects {
+                frame_index,
+                in_flight: Fence::from_raw(frame_index as u64 + 1),
+                ..Default::default()
+            })
+            .collect();
+        let mut frame_sync = VFrameSync::from_frames(frames, 3);
+
+        // Images come back out of order, the fence to wait on is the frame that last rendered it
+        let expected_waits = [
+            (0, None),
+            (1, None),
+            (1, Some(2)),
+            (2, None),
+            (2, Some(2)),
+            (2, Some(1)),
+            (0, None),
+        ];
+        for (frame, (image_index, expected_wait)) in expected_waits.into_iter().enumerate() {
+            assert_eq!(frame_sync.current().frame_index(), frame % 2);
+            assert_eq!(
+                frame_sync.track_image(image_index),
+                expected_wait.map(Fence::from_raw)
+            );
+            frame_sync.end_frame();
+        }
+    }
+}
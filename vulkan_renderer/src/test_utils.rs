@@ -0,0 +1,60 @@
+use crate::{
+    device::VDevice, pipeline::VGraphicsPipeline, shader_utils::VShaderUtils, RendererResult,
+};
+use ash::vk::ShaderModule;
+#[cfg(all(unix, not(target_os = "macos")))]
+use winit::platform::unix::EventLoopExtUnix;
+#[cfg(target_os = "windows")]
+use winit::platform::windows::EventLoopExtWindows;
+use winit::{
+    event_loop::EventLoop,
+    window::{Window, WindowBuilder},
+};
+
+/// Creates a hidden window for tests, which run outside the main thread
+pub(crate) fn create_window() -> (EventLoop<()>, Window) {
+    #[cfg(not(target_os = "macos"))]
+    let event_loop = EventLoop::new_any_thread();
+    #[cfg(target_os = "macos")]
+    let event_loop = EventLoop::new();
+
+    let window = WindowBuilder::new()
+        .with_visible(false)
+        .build(&event_loop)
+        .expect("Failed to create window.");
+    (event_loop, window)
+}
+
+/// Loads `name` from the crate's `shaders` directory into a shader module
+pub(crate) fn shader_module(device: &VDevice, name: &str) -> RendererResult<ShaderModule> {
+    let path = format!("{}/shaders/{}", env!("CARGO_MANIFEST_DIR"), name);
+    let code = VShaderUtils::load_shader(&path)?;
+    VShaderUtils::create_shader_module(device, &code)
+}
+
+/// Loads the `triangle.vert` and `solid.frag` modules most pipeline tests draw with
+pub(crate) fn triangle_modules(device: &VDevice) -> RendererResult<(ShaderModule, ShaderModule)> {
+    Ok((
+        shader_module(device, "triangle.vert.spv")?,
+        shader_module(device, "solid.frag.spv")?,
+    ))
+}
+
+/// Destroys `pipelines` with their layouts, then `modules`
+pub(crate) fn destroy_pipelines(
+    device: &VDevice,
+    pipelines: &[&VGraphicsPipeline],
+    modules: &[ShaderModule],
+) {
+    unsafe {
+        for pipeline in pipelines {
+            device.get().destroy_pipeline(pipeline.pipeline(), None);
+            device
+                .get()
+                .destroy_pipeline_layout(pipeline.pipeline_layout(), None);
+        }
+        for &module in modules {
+            device.get().destroy_shader_module(module, None);
+        }
+    }
+}
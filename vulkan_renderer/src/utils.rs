@@ -1,4 +1,6 @@
-use crate::device::VDevice;
+use crate::{device::VDevice, RendererResult};
+use ash::vk::Extent2D;
+use std::path::Path;
 
 pub fn pad_uniform_buffer_size(device: &VDevice, size: usize) -> u64 {
     let min_uniform_alignment = device
@@ -11,3 +13,42 @@ pub fn pad_uniform_buffer_size(device: &VDevice, size: usize) -> u64 {
     }
     aligned_size
 }
+
+/// Writes tightly packed RGBA bytes, e.g. from
+/// [`VSwapchain::capture_current_image`](crate::swapchain::VSwapchain::capture_current_image), as a PNG
+pub fn save_png(path: impl AsRef<Path>, extent: Extent2D, rgba: &[u8]) -> RendererResult<()> {
+    ::image::save_buffer_with_format(
+        path,
+        rgba,
+        extent.width,
+        extent.height,
+        ::image::ColorType::Rgba8,
+        ::image::ImageFormat::Png,
+    )?;
+    Ok(())
+}
+
+/// Reinterprets a value as its raw bytes, e.g. for push constants and uniform uploads, and raw
+/// bytes as values for [`VBuffer::read_back`](crate::buffer::VBuffer::read_back).
+///
+/// # Safety
+///
+/// Implementors must be `#[repr(C)]` (matching the shader-side layout) and must not contain
+/// references, pointers or padding bytes. Every bit pattern must be a valid value, which rules
+/// out `bool`, `char` and enums.
+pub unsafe trait AsBytes: Sized {
+    fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(
+                (self as *const Self) as *const u8,
+                std::mem::size_of::<Self>(),
+            )
+        }
+    }
+}
+
+// Scalars, e.g. for specialization constants and read back texels
+unsafe impl AsBytes for u8 {}
+unsafe impl AsBytes for u32 {}
+unsafe impl AsBytes for i32 {}
+unsafe impl AsBytes for f32 {}
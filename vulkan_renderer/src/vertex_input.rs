@@ -0,0 +1,219 @@
+use crate::glam::{Vec2, Vec3};
+use ash::vk::{
+    Format, VertexInputAttributeDescription, VertexInputBindingDescription, VertexInputRate,
+};
+use memoffset::offset_of;
+use std::mem::size_of;
+
+/// A vertex with the attributes glTF meshes provide, read at locations 0 to 2
+///
+/// Layouts with more attributes, e.g. colors or tangents, can be described with
+/// [`VVertexInputBuilder::vertex_layout`]
+#[repr(C)]
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct VVertex {
+    pub position: Vec3,
+    pub normal: Vec3,
+    pub uv: Vec2,
+}
+
+impl VVertex {
+    pub fn new(position: Vec3, normal: Vec3, uv: Vec2) -> Self {
+        Self {
+            position,
+            normal,
+            uv,
+        }
+    }
+
+    /// The `(location, format, offset)` of every field, to combine with other bindings
+    pub fn attributes() -> [(u32, Format, u32); 3] {
+        [
+            (
+                0,
+                Format::R32G32B32_SFLOAT,
+                offset_of!(Self, position) as u32,
+            ),
+            (1, Format::R32G32B32_SFLOAT, offset_of!(Self, normal) as u32),
+            (2, Format::R32G32_SFLOAT, offset_of!(Self, uv) as u32),
+        ]
+    }
+
+    /// The vertices in binding 0
+    pub fn vertex_description() -> VVertexInputDescription {
+        VVertexInputBuilder::start()
+            .vertex_layout(0, size_of::<Self>() as u32, &Self::attributes())
+            .build()
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct VVertexInputDescription {
+    pub attributes: Vec<VertexInputAttributeDescription>,
+    pub bindings: Vec<VertexInputBindingDescription>,
+}
+
+/// Builds a [`VVertexInputDescription`] without tying it to a concrete vertex struct
+#[derive(Default, Debug, Clone)]
+pub struct VVertexInputBuilder {
+    description: VVertexInputDescription,
+}
+
+impl VVertexInputBuilder {
+    pub fn start() -> Self {
+        Self::default()
+    }
+
+    pub fn build(self) -> VVertexInputDescription {
+        self.description
+    }
+
+    pub fn binding(mut self, binding: u32, stride: u32, input_rate: VertexInputRate) -> Self {
+        self.description
+            .bindings
+            .push(VertexInputBindingDescription {
+                binding,
+                stride,
+                input_rate,
+            });
+        self
+    }
+
+    pub fn attribute(mut self, binding: u32, location: u32, format: Format, offset: u32) -> Self {
+        self.description
+            .attributes
+            .push(VertexInputAttributeDescription {
+                binding,
+                location,
+                format,
+                offset,
+            });
+        self
+    }
+
+    /// Adds a per-vertex binding of `stride` bytes with its `(location, format, offset)` attributes
+    pub fn vertex_layout(
+        self,
+        binding: u32,
+        stride: u32,
+        attributes: &[(u32, Format, u32)],
+    ) -> Self {
+        attributes.iter().fold(
+            self.binding(binding, stride, VertexInputRate::VERTEX),
+            |builder, &(location, format, offset)| {
+                builder.attribute(binding, location, format, offset)
+            },
+        )
+    }
+
+    /// Adds a column-major 4x4 float matrix as four `vec4` attributes starting at `first_location`
+    pub fn mat4_attribute(mut self, binding: u32, first_location: u32, offset: u32) -> Self {
+        const COLUMN_SIZE: u32 = 4 * 4;
+        for column in 0..4 {
+            self = self.attribute(
+                binding,
+                first_location + column,
+                Format::R32G32B32A32_SFLOAT,
+                offset + column * COLUMN_SIZE,
+            );
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_vertex_fields_at_their_offsets() {
+        let description = VVertex::vertex_description();
+
+        assert_eq!(
+            description.bindings[0].stride as usize,
+            size_of::<VVertex>()
+        );
+        let offsets = description
+            .attributes
+            .iter()
+            .map(|attribute| (attribute.location, attribute.offset as usize))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            offsets,
+            [
+                (0, offset_of!(VVertex, position)),
+                (1, offset_of!(VVertex, normal)),
+                (2, offset_of!(VVertex, uv)),
+            ]
+        );
+    }
+
+    #[test]
+    fn builds_bindings_and_attributes() {
+        let description = VVertexInputBuilder::start()
+            .binding(0, 12, VertexInputRate::VERTEX)
+            .attribute(0, 0, Format::R32G32B32_SFLOAT, 0)
+            .binding(1, 64, VertexInputRate::INSTANCE)
+            .attribute(1, 1, Format::R32G32B32A32_SFLOAT, 0)
+            .build();
+
+        assert_eq!(description.bindings.len(), 2);
+        assert_eq!(
+            description.bindings[1].input_rate,
+            VertexInputRate::INSTANCE
+        );
+        assert_eq!(description.attributes.len(), 2);
+        assert_eq!(description.attributes[1].location, 1);
+        assert_eq!(description.attributes[1].binding, 1);
+    }
+
+    #[test]
+    fn builds_layout_with_tangent_attribute() {
+        let description = VVertexInputBuilder::start()
+            .vertex_layout(
+                0,
+                48,
+                &[
+                    (0, Format::R32G32B32_SFLOAT, 0),
+                    (1, Format::R32G32B32_SFLOAT, 12),
+                    (2, Format::R32G32_SFLOAT, 24),
+                    (3, Format::R32G32B32A32_SFLOAT, 32),
+                ],
+            )
+            .build();
+
+        assert_eq!(description.bindings.len(), 1);
+        assert_eq!(description.bindings[0].stride, 48);
+        assert_eq!(description.bindings[0].input_rate, VertexInputRate::VERTEX);
+        let tangent = description.attributes[3];
+        assert_eq!(
+            (
+                tangent.binding,
+                tangent.location,
+                tangent.format,
+                tangent.offset
+            ),
+            (0, 3, Format::R32G32B32A32_SFLOAT, 32)
+        );
+    }
+
+    #[test]
+    fn splits_mat4_into_column_attributes() {
+        let description = VVertexInputBuilder::start()
+            .binding(1, 64, VertexInputRate::INSTANCE)
+            .mat4_attribute(1, 3, 0)
+            .build();
+
+        let locations = description
+            .attributes
+            .iter()
+            .map(|attribute| (attribute.location, attribute.offset))
+            .collect::<Vec<_>>();
+        assert_eq!(locations, [(3, 0), (4, 16), (5, 32), (6, 48)]);
+        assert!(description
+            .attributes
+            .iter()
+            .all(|attribute| attribute.binding == 1
+                && attribute.format == Format::R32G32B32A32_SFLOAT));
+    }
+}